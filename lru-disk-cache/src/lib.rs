@@ -302,6 +302,15 @@ impl LruDiskCache {
     pub fn get<K: AsRef<OsStr>>(&mut self, key: K) -> Result<Box<dyn ReadSeek>> {
         self.get_file(key).map(|f| Box::new(f) as Box<dyn ReadSeek>)
     }
+
+    /// Remove the file at `key` from the cache, if present.
+    pub fn remove<K: AsRef<OsStr>>(&mut self, key: K) -> Result<()> {
+        let rel_path = key.as_ref();
+        match self.lru.remove(rel_path) {
+            Some(_) => fs::remove_file(self.rel_to_abs_path(rel_path)).map_err(Into::into),
+            None => Err(Error::FileNotInCache),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -520,4 +529,20 @@ mod tests {
         assert!(!p2.exists());
         assert!(!p3.exists());
     }
+
+    #[test]
+    fn test_remove() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 20).unwrap();
+        c.insert_bytes("a/b/c", &vec![0; 10]).unwrap();
+        assert_eq!(c.size(), 10);
+        c.remove("a/b/c").unwrap();
+        assert_eq!(c.size(), 0);
+        assert!(!c.contains_key("a/b/c"));
+        assert!(!f.tmp().join("a/b/c").exists());
+        match c.remove("a/b/c") {
+            Err(Error::FileNotInCache) => {}
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
 }
@@ -14,22 +14,26 @@
 // limitations under the License.
 
 use crate::azure::credentials::*;
+use crate::cache::{ReadSeek, ReaderStream, CACHE_STREAM_CHUNK_SIZE};
 use futures::{Future, Stream};
+use futures_cpupool::CpuPool;
 use hmac::{Hmac, Mac};
 use hyper::header::HeaderValue;
-use hyper::Method;
+use hyper::{Body, Method};
 use hyperx::header;
 use md5::{Digest, Md5};
 use reqwest::r#async::{Client, Request};
 use sha2::Sha256;
 use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
 use std::str::FromStr;
 use url::Url;
 
 use crate::errors::*;
 use crate::util::HeadersExt;
 
-const BLOB_API_VERSION: &str = "2017-04-17";
+// Bearer tokens need at least 2017-11-09.
+const BLOB_API_VERSION: &str = "2018-03-28";
 
 fn hmac(data: &[u8], secret: &[u8]) -> Vec<u8> {
     let mut hmac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC can take key of any size");
@@ -43,12 +47,28 @@ fn signature(to_sign: &str, secret: &str) -> String {
     base64::encode_config::<Vec<u8>>(&sig, base64::STANDARD)
 }
 
-fn md5(data: &[u8]) -> String {
+/// Return the base64 MD5 digest of `reader` and its length, leaving it
+/// positioned at its start.
+fn md5(reader: &mut dyn ReadSeek) -> Result<(String, u64)> {
     let mut digest = Md5::new();
-    digest.input(data);
-    base64::encode_config(&digest.result(), base64::STANDARD)
+    let mut length = 0;
+    let mut buffer = vec![0; CACHE_STREAM_CHUNK_SIZE];
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        digest.input(&buffer[..count]);
+        length += count as u64;
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    Ok((
+        base64::encode_config(&digest.result(), base64::STANDARD),
+        length,
+    ))
 }
 
+#[derive(Clone)]
 pub struct BlobContainer {
     url: String,
     client: Client,
@@ -73,25 +93,61 @@ impl BlobContainer {
         })
     }
 
+    /// The URL of the blob `key`, with the shared access signature if
+    /// requests are authorized with one. Use `loggable_url` to log it.
+    fn blob_url(&self, key: &str, creds: &AzureCredentials) -> Url {
+        let url_string = match creds.azure_sas_token() {
+            Some(token) => format!("{}{}?{}", self.url, key, token),
+            None => format!("{}{}", self.url, key),
+        };
+        Url::from_str(&url_string).unwrap()
+    }
+
+    /// Add the `Authorization` header for `creds` to `request`, if they need
+    /// one, and send it.
+    ///
+    /// Errors sending the request don't include its shared access signature.
+    fn execute(
+        &self,
+        mut request: Request,
+        shared_key_auth: impl FnOnce(&AzureCredentials) -> String,
+        creds: &AzureCredentials,
+    ) -> SFuture<reqwest::r#async::Response> {
+        let auth: SFuture<Option<String>> = match *creds.azure_auth() {
+            AzureAuth::SharedKey(_) => f_ok(Some(shared_key_auth(creds))),
+            AzureAuth::Sas(_) => f_ok(None),
+            AzureAuth::ManagedIdentity(ref provider) => Box::new(
+                provider
+                    .token(&self.client)
+                    .map(|token| Some(format!("Bearer {}", token.access_token))),
+            ),
+        };
+        let client = self.client.clone();
+        let url = request.url().clone();
+        Box::new(auth.and_then(move |auth| {
+            if let Some(auth) = auth {
+                request.headers_mut().insert(
+                    "Authorization",
+                    HeaderValue::from_str(&auth).expect("Authorization is an invalid header value"),
+                );
+            }
+            client.execute(request).map_err(move |e| {
+                // reqwest puts the whole URL in its errors.
+                let message = e.to_string().replace(url.as_str(), &loggable_url(&url));
+                Error::from(message)
+            })
+        }))
+    }
+
     pub fn get(&self, key: &str, creds: &AzureCredentials) -> SFuture<Vec<u8>> {
-        let url_string = format!("{}{}", self.url, key);
-        let uri = Url::from_str(&url_string).unwrap();
+        let uri = self.blob_url(key, creds);
         let date = time::now_utc().rfc822().to_string();
 
         let canonical_headers = format!("x-ms-date:{}\nx-ms-version:{}\n", date, BLOB_API_VERSION);
 
-        let auth = compute_auth_header(
-            "GET",
-            "", // content_length
-            "", // content_md5
-            "", // content_type
-            &canonical_headers,
-            &uri,
-            creds,
-        );
-
-        let uri_copy = uri.clone();
-        let uri_second_copy = uri.clone();
+        let uri_copy = loggable_url(&uri);
+        let uri_second_copy = uri_copy.clone();
+        let uri_third_copy = uri.clone();
 
         let mut request = Request::new(Method::GET, uri);
         request.headers_mut().insert(
@@ -101,14 +157,21 @@ impl BlobContainer {
         request
             .headers_mut()
             .insert("x-ms-version", HeaderValue::from_static(BLOB_API_VERSION));
-        request.headers_mut().insert(
-            "Authorization",
-            HeaderValue::from_str(&auth).expect("Authorization is an invalid header value"),
-        );
+
+        let shared_key_auth = move |creds: &AzureCredentials| {
+            compute_auth_header(
+                "GET",
+                "", // content_length
+                "", // content_md5
+                "", // content_type
+                &canonical_headers,
+                &uri_third_copy,
+                creds,
+            )
+        };
 
         Box::new(
-            self.client
-                .execute(request)
+            self.execute(request, shared_key_auth, creds)
                 .chain_err(move || format!("failed GET: {}", uri_copy))
                 .and_then(|res| {
                     if res.status().is_success() {
@@ -145,17 +208,46 @@ impl BlobContainer {
         )
     }
 
-    pub fn put(&self, key: &str, content: Vec<u8>, creds: &AzureCredentials) -> SFuture<()> {
-        let url_string = format!("{}{}", self.url, key);
-        let uri = Url::from_str(&url_string).unwrap();
+    /// Upload the contents of `content` to `key`, streaming it from the reader
+    /// on `pool`.
+    pub fn put(
+        &self,
+        key: &str,
+        mut content: Box<dyn ReadSeek>,
+        creds: &AzureCredentials,
+        pool: &CpuPool,
+    ) -> SFuture<()> {
+        // The Content-MD5 header needs a pass over the whole blob first.
+        let digest = pool.spawn_fn(move || -> Result<_> {
+            let (content_md5, length) = md5(&mut content)?;
+            Ok((content, content_md5, length))
+        });
+        let container = self.clone();
+        let key = key.to_owned();
+        let creds = creds.clone();
+        let pool = pool.clone();
+        Box::new(digest.and_then(move |(content, content_md5, length)| {
+            container.put_blob(&key, content, content_md5, length, &creds, &pool)
+        }))
+    }
+
+    fn put_blob(
+        &self,
+        key: &str,
+        content: Box<dyn ReadSeek>,
+        content_md5: String,
+        length: u64,
+        creds: &AzureCredentials,
+        pool: &CpuPool,
+    ) -> SFuture<()> {
+        let uri = self.blob_url(key, creds);
         let date = time::now_utc().rfc822().to_string();
         let content_type = "application/octet-stream";
-        let content_md5 = md5(&content);
 
-        let content_length = if content.is_empty() {
+        let content_length = if length == 0 {
             "".to_owned()
         } else {
-            format!("{}", content.len())
+            format!("{}", length)
         };
 
         let canonical_headers = format!(
@@ -163,23 +255,25 @@ impl BlobContainer {
             date, BLOB_API_VERSION
         );
 
-        let auth = compute_auth_header(
-            "PUT",
-            &content_length,
-            &content_md5,
-            content_type,
-            &canonical_headers,
-            &uri,
-            creds,
-        );
+        let uri_copy = uri.clone();
+        let content_md5_copy = content_md5.clone();
+        let shared_key_auth = move |creds: &AzureCredentials| {
+            compute_auth_header(
+                "PUT",
+                &content_length,
+                &content_md5_copy,
+                content_type,
+                &canonical_headers,
+                &uri_copy,
+                creds,
+            )
+        };
 
         let mut request = Request::new(Method::PUT, uri);
         request
             .headers_mut()
             .set(header::ContentType(content_type.parse().unwrap()));
-        request
-            .headers_mut()
-            .set(header::ContentLength(content.len() as u64));
+        request.headers_mut().set(header::ContentLength(length));
         request
             .headers_mut()
             .insert("x-ms-blob-type", HeaderValue::from_static("BlockBlob"));
@@ -190,35 +284,42 @@ impl BlobContainer {
         request
             .headers_mut()
             .insert("x-ms-version", HeaderValue::from_static(BLOB_API_VERSION));
-        request.headers_mut().insert(
-            "Authorization",
-            HeaderValue::from_str(&auth).expect("Invalid Authorization header"),
-        );
         request.headers_mut().insert(
             "Content-MD5",
             HeaderValue::from_str(&content_md5).expect("Invalid Content-MD5 header"),
         );
 
-        *request.body_mut() = Some(content.into());
-
-        Box::new(self.client.execute(request).then(|result| match result {
-            Ok(res) => {
-                if res.status().is_success() {
-                    trace!("PUT succeeded");
-                    Ok(())
-                } else {
-                    trace!("PUT failed with HTTP status: {}", res.status());
-                    Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
-                }
-            }
-            Err(e) => {
-                trace!("PUT failed with error: {:?}", e);
-                Err(e.into())
-            }
-        }))
+        *request.body_mut() = Some(Body::wrap_stream(ReaderStream::new(content, pool)).into());
+
+        Box::new(
+            self.execute(request, shared_key_auth, creds)
+                .then(|result| match result {
+                    Ok(res) => {
+                        if res.status().is_success() {
+                            trace!("PUT succeeded");
+                            Ok(())
+                        } else {
+                            trace!("PUT failed with HTTP status: {}", res.status());
+                            Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+                        }
+                    }
+                    Err(e) => {
+                        trace!("PUT failed with error: {:?}", e);
+                        Err(e.into())
+                    }
+                }),
+        )
     }
 }
 
+/// `url` without its query string, which holds the shared access signature
+/// if there is one, so that it can be logged or put in errors.
+fn loggable_url(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_query(None);
+    url.into_string()
+}
+
 fn compute_auth_header(
     verb: &str,
     content_length: &str,
@@ -262,7 +363,10 @@ fn compute_auth_header(
     format!(
         "SharedKey {}:{}",
         creds.azure_account_name(),
-        signature(&string_to_sign, creds.azure_account_key())
+        signature(
+            &string_to_sign,
+            creds.azure_account_key().unwrap_or_default()
+        )
     )
 }
 
@@ -272,7 +376,8 @@ fn canonicalize_resource(uri: &Url, account_name: &str) -> String {
     canonical_resource.push_str(account_name);
     canonical_resource.push_str(uri.path());
 
-    // Deliberately ignoring query params, because we aren't using them.
+    // Deliberately ignoring query params: they are only used for shared
+    // access signatures, which aren't signed with the account key.
 
     canonical_resource
 }
@@ -280,6 +385,7 @@ fn canonicalize_resource(uri: &Url, account_name: &str) -> String {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::io;
     use tokio::runtime::current_thread::Runtime;
 
     #[test]
@@ -304,6 +410,65 @@ mod test {
         assert_eq!("/testaccount/container/key", &canon);
     }
 
+    #[test]
+    fn test_sas_token_not_in_errors() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+        use std::thread;
+
+        let get_error = |endpoint: &str| {
+            let container_name = Some("sccache".to_owned());
+            let creds = AzureCredentials::new(
+                endpoint,
+                "account",
+                AzureAuth::Sas("sv=2019-02-02&sig=secret".to_owned()),
+                container_name.clone(),
+            );
+            let container =
+                BlobContainer::new(creds.azure_blob_endpoint(), &container_name).unwrap();
+            let mut runtime = Runtime::new().unwrap();
+            let error = runtime.block_on(container.get("foo", &creds)).unwrap_err();
+            error.iter().map(|e| e.to_string()).collect::<Vec<_>>()
+        };
+
+        // A request that's refused.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let mut stream = stream;
+            stream
+                .write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        });
+        let message = get_error(&endpoint);
+        assert!(message[0].ends_with("/sccache/foo"));
+        assert!(message.iter().all(|m| !m.contains("secret")));
+
+        // A request that can't be sent, as nothing listens on the port.
+        let endpoint = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/", listener.local_addr().unwrap())
+        };
+        let message = get_error(&endpoint);
+        assert!(message.iter().all(|m| !m.contains("secret")));
+    }
+
+    #[test]
+    fn test_loggable_url() {
+        let url =
+            Url::from_str("https://account.blob.core.windows.net/c/key?sv=1&sig=abc").unwrap();
+        assert_eq!(
+            loggable_url(&url),
+            "https://account.blob.core.windows.net/c/key"
+        );
+    }
+
     #[test]
     #[ignore]
     fn test_put_blob() {
@@ -329,7 +494,7 @@ mod test {
         let creds = AzureCredentials::new(
             &blob_endpoint,
             &client_name,
-            &client_key,
+            AzureAuth::SharedKey(client_key.to_owned()),
             container_name.clone(),
         );
 
@@ -337,7 +502,9 @@ mod test {
 
         let container = BlobContainer::new(creds.azure_blob_endpoint(), &container_name).unwrap();
 
-        let put_future = container.put("foo", "barbell".as_bytes().to_vec(), &creds);
+        let pool = CpuPool::new(1);
+        let content = Box::new(io::Cursor::new(b"barbell".to_vec()));
+        let put_future = container.put("foo", content, &creds, &pool);
         runtime.block_on(put_future).unwrap();
 
         let get_future = container.get("foo", &creds);
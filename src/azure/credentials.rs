@@ -13,15 +13,46 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::env::*;
+use crate::config::{AzureCacheAuth, AzureCacheConfig};
+use chrono::{offset::Utc, DateTime, Duration, TimeZone};
+use futures::future::Shared;
+use futures::{Async, Future, Stream};
+use reqwest::r#async::Client;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::env;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration as StdDuration;
+use tokio_timer::Timeout;
 
 use crate::errors::*;
 
+/// The instance metadata service endpoint for managed identity tokens.
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+/// The resource managed identity tokens are requested for.
+const STORAGE_RESOURCE: &str = "https://storage.azure.com/";
+
+/// How long before a token expires to start fetching a new one.
+const TOKEN_EXPIRY_MARGIN_SECS: i64 = 5 * 60;
+
+/// How requests to the blob store are authorized.
+#[derive(Clone, Debug)]
+pub enum AzureAuth {
+    /// Sign requests with the storage account key.
+    SharedKey(String),
+    /// Append a shared access signature to each request's query string.
+    Sas(String),
+    /// Send a bearer token for the machine's managed identity.
+    ManagedIdentity(Rc<ManagedIdentityProvider>),
+}
+
 #[derive(Clone, Debug)]
 pub struct AzureCredentials {
     blob_endpoint: String,
     account_name: String,
-    account_key: String,
+    auth: AzureAuth,
     container_name: Option<String>,
 }
 
@@ -29,7 +60,7 @@ impl AzureCredentials {
     pub fn new(
         blob_endpoint: &str,
         account_name: &str,
-        account_key: &str,
+        auth: AzureAuth,
         container_name: Option<String>,
     ) -> AzureCredentials {
         let endpoint = if blob_endpoint.ends_with("/") {
@@ -41,11 +72,49 @@ impl AzureCredentials {
         AzureCredentials {
             blob_endpoint: endpoint,
             account_name: account_name.to_owned(),
-            account_key: account_key.to_owned(),
+            auth,
             container_name: container_name,
         }
     }
 
+    /// Build credentials from the connection string, account, endpoint and
+    /// authorization settings of an Azure cache configuration.
+    pub fn from_config(config: &AzureCacheConfig) -> Result<AzureCredentials> {
+        let mut creds = match config.connection_string {
+            Some(ref conn) => parse_connection_string(conn, config.container.clone())?,
+            None => {
+                let account_name = config.account_name.clone().unwrap_or_default();
+                let endpoint = match (&config.endpoint, account_name.is_empty()) {
+                    (Some(endpoint), _) => endpoint.clone(),
+                    (None, false) => format!("https://{}.blob.core.windows.net/", account_name),
+                    (None, true) => bail!("Azure cache needs an account name or an endpoint"),
+                };
+                let auth = match config.auth {
+                    Some(ref auth) => auth_from_config(auth),
+                    None => bail!("Azure cache needs a connection string or credentials"),
+                };
+                AzureCredentials::new(&endpoint, &account_name, auth, config.container.clone())
+            }
+        };
+        if let Some(ref endpoint) = config.endpoint {
+            creds = AzureCredentials::new(
+                endpoint,
+                &creds.account_name,
+                creds.auth,
+                creds.container_name,
+            );
+        }
+        if let Some(ref auth) = config.auth {
+            creds.auth = auth_from_config(auth);
+        }
+        if let AzureAuth::SharedKey(_) = creds.auth {
+            if creds.account_name.is_empty() {
+                bail!("Azure shared key authorization needs an account name");
+            }
+        }
+        Ok(creds)
+    }
+
     pub fn azure_blob_endpoint(&self) -> &str {
         &self.blob_endpoint
     }
@@ -54,8 +123,24 @@ impl AzureCredentials {
         &self.account_name
     }
 
-    pub fn azure_account_key(&self) -> &str {
-        &self.account_key
+    pub fn azure_auth(&self) -> &AzureAuth {
+        &self.auth
+    }
+
+    /// The account key, if requests are signed with one.
+    pub fn azure_account_key(&self) -> Option<&str> {
+        match self.auth {
+            AzureAuth::SharedKey(ref key) => Some(key.as_str()),
+            _ => None,
+        }
+    }
+
+    /// The shared access signature, if requests are authorized with one.
+    pub fn azure_sas_token(&self) -> Option<&str> {
+        match self.auth {
+            AzureAuth::Sas(ref token) => Some(token.as_str()),
+            _ => None,
+        }
     }
 
     pub fn blob_container_name(&self) -> &Option<String> {
@@ -63,35 +148,167 @@ impl AzureCredentials {
     }
 }
 
-pub trait AzureCredentialsProvider {
-    fn provide_credentials(&self) -> Result<AzureCredentials>;
+fn auth_from_config(auth: &AzureCacheAuth) -> AzureAuth {
+    match *auth {
+        AzureCacheAuth::SharedKey { ref account_key } => AzureAuth::SharedKey(account_key.clone()),
+        AzureCacheAuth::Sas { ref token } => AzureAuth::Sas(normalize_sas_token(token)),
+        AzureCacheAuth::ManagedIdentity { ref client_id } => {
+            AzureAuth::ManagedIdentity(Rc::new(ManagedIdentityProvider::new(client_id.clone())))
+        }
+    }
+}
+
+/// SAS tokens are often copied along with the `?` that separates them from
+/// the URL they were generated for.
+fn normalize_sas_token(token: &str) -> String {
+    token.trim_start_matches('?').to_owned()
 }
 
-pub struct EnvironmentProvider;
+/// An OAuth token for the storage service.
+#[derive(Clone, Debug)]
+pub struct AzureToken {
+    pub access_token: String,
+    pub expires_on: DateTime<Utc>,
+}
 
-impl AzureCredentialsProvider for EnvironmentProvider {
-    fn provide_credentials(&self) -> Result<AzureCredentials> {
-        credentials_from_environment()
+/// Fetches, and caches until shortly before they expire, tokens for the
+/// machine's managed identity.
+///
+/// The token is requested from the App Service identity endpoint if
+/// `IDENTITY_ENDPOINT` and `IDENTITY_HEADER` are set, and from the instance
+/// metadata service otherwise.
+pub struct ManagedIdentityProvider {
+    client_id: Option<String>,
+    cached_token: RefCell<Option<Shared<SFuture<AzureToken>>>>,
+}
+
+impl fmt::Debug for ManagedIdentityProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ManagedIdentityProvider(client_id={:?})", self.client_id)
     }
 }
 
-fn credentials_from_environment() -> Result<AzureCredentials> {
-    let env_conn_str = var("SCCACHE_AZURE_CONNECTION_STRING")
-        .chain_err(|| "No SCCACHE_AZURE_CONNECTION_STRING in environment")?;
+impl ManagedIdentityProvider {
+    pub fn new(client_id: Option<String>) -> ManagedIdentityProvider {
+        ManagedIdentityProvider {
+            client_id,
+            cached_token: RefCell::new(None),
+        }
+    }
 
-    let container_name = match var("SCCACHE_AZURE_BLOB_CONTAINER") {
-        Ok(text) => {
-            if text.is_empty() {
-                None
-            } else {
-                Some(text)
+    fn request_new_token(&self, client: &Client) -> SFuture<AzureToken> {
+        let request = match (env::var("IDENTITY_ENDPOINT"), env::var("IDENTITY_HEADER")) {
+            (Ok(endpoint), Ok(header)) => {
+                let mut query = vec![
+                    ("api-version", "2019-08-01"),
+                    ("resource", STORAGE_RESOURCE),
+                ];
+                if let Some(ref client_id) = self.client_id {
+                    query.push(("client_id", client_id.as_str()));
+                }
+                client
+                    .get(&endpoint)
+                    .query(&query)
+                    .header("X-IDENTITY-HEADER", header)
+            }
+            _ => {
+                let mut query = vec![
+                    ("api-version", "2018-02-01"),
+                    ("resource", STORAGE_RESOURCE),
+                ];
+                if let Some(ref client_id) = self.client_id {
+                    query.push(("client_id", client_id.as_str()));
+                }
+                client
+                    .get(IMDS_TOKEN_URL)
+                    .query(&query)
+                    .header("Metadata", "true")
             }
+        };
+
+        let token = request
+            .send()
+            .chain_err(|| "failed to send managed identity token request")
+            .and_then(|res| {
+                let status = res.status();
+                res.into_body()
+                    .concat2()
+                    .chain_err(|| "failed to read managed identity token response")
+                    .and_then(move |body| -> Result<AzureToken> {
+                        let body = String::from_utf8(body.to_vec())
+                            .chain_err(|| "managed identity token response is not UTF-8")?;
+                        if !status.is_success() {
+                            debug!("Managed identity token request failed: {}", body);
+                            bail!(ErrorKind::BadHTTPStatus(status));
+                        }
+                        parse_token(&body)
+                    })
+            });
+
+        // Outside of Azure the metadata service doesn't answer at all.
+        let timeout = Timeout::new(token, StdDuration::from_secs(5));
+        Box::new(timeout.then(|result| match result {
+            Ok(token) => Ok(token),
+            Err(err) => match err.into_inner() {
+                None => bail!("took too long to fetch a managed identity token"),
+                Some(e) => {
+                    warn!("Failed to fetch a managed identity token: {}", e);
+                    Err(e)
+                }
+            },
+        }))
+    }
+
+    /// Return a token that is valid for at least a few more minutes.
+    pub fn token(&self, client: &Client) -> SFuture<AzureToken> {
+        let mut future_opt = self.cached_token.borrow_mut();
+
+        let margin = Duration::seconds(TOKEN_EXPIRY_MARGIN_SECS);
+        let needs_refresh = match Option::as_mut(&mut future_opt).map(|f| f.poll()) {
+            None => true,
+            Some(Ok(Async::Ready(ref token))) => token.expires_on - margin < Utc::now(),
+            Some(Err(_)) => true,
+            Some(Ok(Async::NotReady)) => false,
+        };
+
+        if needs_refresh {
+            *future_opt = Some(self.request_new_token(client).shared());
         }
 
-        Err(_) => None,
-    };
+        Box::new(
+            Option::as_mut(&mut future_opt)
+                .unwrap()
+                .clone()
+                .then(|result| match result {
+                    Ok(token) => Ok((*token).clone()),
+                    Err(e) => Err(e.to_string().into()),
+                }),
+        )
+    }
+}
 
-    parse_connection_string(&env_conn_str, container_name)
+/// Parse a token response from the instance metadata service or the App
+/// Service identity endpoint, which give `expires_on` as a string or a
+/// number of seconds since the epoch.
+fn parse_token(body: &str) -> Result<AzureToken> {
+    let json: Value = serde_json::from_str(body).chain_err(|| "invalid token response")?;
+    let access_token = match json.get("access_token").and_then(Value::as_str) {
+        Some(token) => token.to_owned(),
+        None => bail!("token response has no access_token"),
+    };
+    let expires_on = match json.get("expires_on") {
+        Some(&Value::String(ref secs)) => secs.parse().ok(),
+        Some(&Value::Number(ref secs)) => secs.as_i64(),
+        _ => None,
+    };
+    let expires_on = match expires_on {
+        Some(secs) => Utc.timestamp(secs, 0),
+        None => bail!("token response has no valid expires_on"),
+    };
+    Ok(AzureToken {
+        access_token,
+        expires_on,
+    })
 }
 
 fn parse_connection_string(conn: &str, container_name: Option<String>) -> Result<AzureCredentials> {
@@ -99,6 +316,7 @@ fn parse_connection_string(conn: &str, container_name: Option<String>) -> Result
     let mut default_endpoint_protocol: String = "https".to_owned();
     let mut account_name = String::default();
     let mut account_key = String::default();
+    let mut sas_token = String::default();
     let mut endpoint_suffix = String::default();
 
     let split = conn.split(";");
@@ -123,6 +341,11 @@ fn parse_connection_string(conn: &str, container_name: Option<String>) -> Result
             continue;
         }
 
+        if part.starts_with("SharedAccessSignature=") {
+            sas_token = substr(part, "SharedAccessSignature=".len()).to_owned();
+            continue;
+        }
+
         if part.starts_with("EndpointSuffix=") {
             endpoint_suffix = substr(part, "EndpointSuffix=".len()).to_owned();
         }
@@ -142,9 +365,13 @@ fn parse_connection_string(conn: &str, container_name: Option<String>) -> Result
         }
     }
 
-    if blob_endpoint.is_empty() || account_name.is_empty() || account_key.is_empty() {
-        bail!("Azure connection string missing at least one of BlobEndpoint (or DefaultEndpointProtocol and EndpointSuffix), AccountName, or AccountKey.");
-    }
+    let auth = if !account_key.is_empty() && !account_name.is_empty() {
+        AzureAuth::SharedKey(account_key)
+    } else if !sas_token.is_empty() {
+        AzureAuth::Sas(normalize_sas_token(&sas_token))
+    } else {
+        bail!("Azure connection string missing at least one of BlobEndpoint (or DefaultEndpointProtocol and EndpointSuffix), AccountName, or AccountKey (or SharedAccessSignature).");
+    };
 
     if !blob_endpoint.starts_with("http") {
         blob_endpoint = format!("{}://{}", default_endpoint_protocol, blob_endpoint);
@@ -153,7 +380,7 @@ fn parse_connection_string(conn: &str, container_name: Option<String>) -> Result
     Ok(AzureCredentials::new(
         &blob_endpoint,
         &account_name,
-        &account_key,
+        auth,
         container_name,
     ))
 }
@@ -179,7 +406,7 @@ mod test {
             creds.azure_blob_endpoint()
         );
         assert_eq!("devstoreaccount1", creds.azure_account_name());
-        assert_eq!("Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==", creds.azure_account_key());
+        assert_eq!(Some("Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw=="), creds.azure_account_key());
         assert_eq!(&None, creds.blob_container_name());
     }
 
@@ -193,6 +420,83 @@ mod test {
             creds.azure_blob_endpoint()
         );
         assert_eq!("foo", creds.azure_account_name());
-        assert_eq!("bar", creds.azure_account_key());
+        assert_eq!(Some("bar"), creds.azure_account_key());
+    }
+
+    #[test]
+    fn test_conn_str_with_sas() {
+        let conn = "BlobEndpoint=https://foo.blob.core.windows.net/;SharedAccessSignature=sv=2019-02-02&ss=b&sig=abc%3D";
+        let creds = parse_connection_string(&conn, Some("sccache".to_owned())).unwrap();
+
+        assert_eq!(
+            "https://foo.blob.core.windows.net/",
+            creds.azure_blob_endpoint()
+        );
+        assert_eq!(None, creds.azure_account_key());
+        assert_eq!(
+            Some("sv=2019-02-02&ss=b&sig=abc%3D"),
+            creds.azure_sas_token()
+        );
+        assert_eq!(&Some("sccache".to_owned()), creds.blob_container_name());
+    }
+
+    #[test]
+    fn test_credentials_from_config() {
+        let config = AzureCacheConfig {
+            account_name: Some("foo".to_owned()),
+            container: Some("sccache".to_owned()),
+            auth: Some(AzureCacheAuth::Sas {
+                token: "?sv=2019-02-02&sig=abc".to_owned(),
+            }),
+            ..Default::default()
+        };
+        let creds = AzureCredentials::from_config(&config).unwrap();
+        assert_eq!(
+            "https://foo.blob.core.windows.net/",
+            creds.azure_blob_endpoint()
+        );
+        assert_eq!(Some("sv=2019-02-02&sig=abc"), creds.azure_sas_token());
+
+        // Explicit settings override the connection string.
+        let config = AzureCacheConfig {
+            connection_string: Some(
+                "DefaultEndpointsProtocol=https;AccountName=foo;EndpointSuffix=core.windows.net;AccountKey=bar;"
+                    .to_owned(),
+            ),
+            endpoint: Some("http://127.0.0.1:10000/foo".to_owned()),
+            auth: Some(AzureCacheAuth::ManagedIdentity { client_id: None }),
+            ..Default::default()
+        };
+        let creds = AzureCredentials::from_config(&config).unwrap();
+        assert_eq!("http://127.0.0.1:10000/foo/", creds.azure_blob_endpoint());
+        assert_eq!("foo", creds.azure_account_name());
+        match *creds.azure_auth() {
+            AzureAuth::ManagedIdentity(_) => {}
+            ref auth => panic!("Unexpected auth: {:?}", auth),
+        }
+
+        let config = AzureCacheConfig {
+            endpoint: Some("https://foo.blob.core.windows.net/sccache".to_owned()),
+            auth: Some(AzureCacheAuth::SharedKey {
+                account_key: "bar".to_owned(),
+            }),
+            ..Default::default()
+        };
+        assert!(AzureCredentials::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_parse_token() {
+        let token = parse_token(
+            r#"{"access_token":"eyJ0eXAi","refresh_token":"","expires_in":"3599","expires_on":"1506484173","not_before":"1506480273","resource":"https://storage.azure.com/","token_type":"Bearer"}"#,
+        )
+        .unwrap();
+        assert_eq!("eyJ0eXAi", token.access_token);
+        assert_eq!(Utc.timestamp(1506484173, 0), token.expires_on);
+
+        let token = parse_token(r#"{"access_token":"eyJ0eXAi","expires_on":1506484173}"#).unwrap();
+        assert_eq!(Utc.timestamp(1506484173, 0), token.expires_on);
+
+        assert!(parse_token(r#"{"error":"invalid_request"}"#).is_err());
     }
 }
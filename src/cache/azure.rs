@@ -15,8 +15,10 @@
 
 use crate::azure::BlobContainer;
 use crate::azure::*;
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{normalize_key_prefix, Cache, CacheRead, CacheWrite, Storage};
+use crate::config::{AzureCacheConfig, CacheRWMode};
 use futures::future::Future;
+use futures_cpupool::CpuPool;
 use std::io;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
@@ -26,14 +28,15 @@ use crate::errors::*;
 pub struct AzureBlobCache {
     container: Rc<BlobContainer>,
     credentials: AzureCredentials,
+    key_prefix: String,
+    rw_mode: CacheRWMode,
+    pool: CpuPool,
 }
 
 impl AzureBlobCache {
-    pub fn new() -> Result<AzureBlobCache> {
-        let credentials = match EnvironmentProvider.provide_credentials() {
-            Ok(creds) => creds,
-            Err(_) => bail!("Could not find Azure credentials in the environment"),
-        };
+    pub fn new(config: &AzureCacheConfig, pool: &CpuPool) -> Result<AzureBlobCache> {
+        let credentials = AzureCredentials::from_config(config)
+            .chain_err(|| "Could not find Azure credentials")?;
 
         let container = match BlobContainer::new(
             credentials.azure_blob_endpoint(),
@@ -46,15 +49,19 @@ impl AzureBlobCache {
         Ok(AzureBlobCache {
             container: Rc::new(container),
             credentials: credentials,
+            key_prefix: normalize_key_prefix(&config.key_prefix),
+            rw_mode: config.rw_mode,
+            pool: pool.clone(),
         })
     }
 }
 
 impl Storage for AzureBlobCache {
     fn get(&self, key: &str) -> SFuture<Cache> {
+        let key = format!("{}{}", self.key_prefix, key);
         Box::new(
             self.container
-                .get(key, &self.credentials)
+                .get(&key, &self.credentials)
                 .then(|result| match result {
                     Ok(data) => {
                         let hit = CacheRead::from(io::Cursor::new(data))?;
@@ -69,15 +76,16 @@ impl Storage for AzureBlobCache {
     }
 
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let key = format!("{}{}", self.key_prefix, key);
         let start = Instant::now();
-        let data = match entry.finish() {
+        let data = match entry.finish_reader() {
             Ok(data) => data,
             Err(e) => return f_err(e),
         };
 
         let response = self
             .container
-            .put(key, data, &self.credentials)
+            .put(&key, data, &self.credentials, &self.pool)
             .chain_err(|| "Failed to put cache entry in Azure");
 
         Box::new(response.map(move |_| start.elapsed()))
@@ -87,6 +95,10 @@ impl Storage for AzureBlobCache {
         format!("Azure, container: {}", self.container)
     }
 
+    fn rw_mode(&self) -> CacheRWMode {
+        self.rw_mode
+    }
+
     fn current_size(&self) -> SFuture<Option<u64>> {
         f_ok(None)
     }
@@ -14,23 +14,39 @@
 
 #[cfg(feature = "azure")]
 use crate::cache::azure::AzureBlobCache;
+use crate::cache::dedup::DedupCache;
 use crate::cache::disk::DiskCache;
 #[cfg(feature = "gcs")]
 use crate::cache::gcs::{self, GCSCache, GCSCredentialProvider, RWMode, ServiceAccountInfo};
+#[cfg(feature = "http-cache")]
+use crate::cache::http::HttpCache;
 #[cfg(feature = "memcached")]
 use crate::cache::memcached::MemcachedCache;
+#[cfg(feature = "reapi")]
+use crate::cache::reapi::ReapiCache;
 #[cfg(feature = "redis")]
 use crate::cache::redis::RedisCache;
 #[cfg(feature = "s3")]
 use crate::cache::s3::S3Cache;
-use crate::config::{self, CacheType, Config};
-use futures_cpupool::CpuPool;
+use crate::cache::shared_dir::SharedDirCache;
+use crate::cache::tiered::{CacheTier, TieredCache};
+use crate::config::{self, CacheCompression, CacheRWMode, CacheType, Config};
+#[cfg(feature = "s3")]
+use crate::simples3::PutOptions;
+use crate::util::{Digest, DigestReader, DigestWriter};
+use futures::{stream, Async, Future, Poll, Stream};
+use futures_cpupool::{CpuFuture, CpuPool};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
-#[cfg(feature = "gcs")]
 use std::fs::File;
-use std::io::{self, Read, Seek, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+use tempfile::NamedTempFile;
+use tokio::runtime::current_thread::TaskExecutor;
+use zip::result::ZipError;
 use zip::write::FileOptions;
 use zip::{CompressionMethod, ZipArchive, ZipWriter};
 
@@ -61,9 +77,197 @@ pub trait ReadSeek: Read + Seek + Send {}
 
 impl<T: Read + Seek + Send> ReadSeek for T {}
 
+/// The size of the chunks cache entries are streamed to and from storage in.
+pub const CACHE_STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A `Stream` of the contents of a reader, in chunks of at most
+/// `CACHE_STREAM_CHUNK_SIZE` bytes.
+///
+/// The reader is read on a `CpuPool`, so that reading a large cache entry
+/// from disk doesn't block the event loop.
+pub struct ReaderStream {
+    pool: CpuPool,
+    /// The reader, while no read from it is in progress.
+    reader: Option<Box<dyn ReadSeek>>,
+    /// The read in progress, which gives back the reader with the chunk.
+    read: Option<CpuFuture<(Box<dyn ReadSeek>, Vec<u8>), io::Error>>,
+}
+
+impl ReaderStream {
+    pub fn new(reader: Box<dyn ReadSeek>, pool: &CpuPool) -> ReaderStream {
+        ReaderStream {
+            pool: pool.clone(),
+            reader: Some(reader),
+            read: None,
+        }
+    }
+}
+
+impl Stream for ReaderStream {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        if self.read.is_none() {
+            let mut reader = match self.reader.take() {
+                Some(reader) => reader,
+                None => return Ok(Async::Ready(None)),
+            };
+            self.read = Some(self.pool.spawn_fn(move || {
+                let mut chunk = vec![0; CACHE_STREAM_CHUNK_SIZE];
+                let n = reader.read(&mut chunk)?;
+                chunk.truncate(n);
+                Ok((reader, chunk))
+            }));
+        }
+        let (reader, chunk) = try_ready!(self.read.as_mut().unwrap().poll());
+        self.read = None;
+        if chunk.is_empty() {
+            return Ok(Async::Ready(None));
+        }
+        self.reader = Some(reader);
+        Ok(Async::Ready(Some(chunk)))
+    }
+}
+
+/// Normalize a configured key prefix so that it can be prepended to cache
+/// keys: it is either empty, or ends with a single `/`.
+pub fn normalize_key_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", prefix)
+    }
+}
+
+/// Return the length of `reader`, leaving it positioned at its start.
+pub fn reader_len<R: Seek + ?Sized>(reader: &mut R) -> Result<u64> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(len)
+}
+
+/// Copy all of `reader` to a new temporary file, which can be reopened
+/// as many times as needed.
+pub fn spool_to_temp_file<R: Read + ?Sized>(reader: &mut R) -> Result<NamedTempFile> {
+    let mut file = NamedTempFile::new().chain_err(|| "Failed to create temporary file")?;
+    io::copy(reader, &mut file)?;
+    Ok(file)
+}
+
+/// The name of the object recording how the other objects in a cache entry
+/// are compressed. Entries without one are plain deflated zip archives.
+const CODEC_OBJECT_NAME: &str = ".sccache-codec";
+
+/// The name of the object recording the digest of each of the other objects
+/// in a cache entry. Entries without one aren't verified.
+const CHECKSUMS_OBJECT_NAME: &str = ".sccache-checksums";
+
+/// Get the key to store the entry for the hash key `key` under, when its
+/// objects are compressed with `compression`.
+///
+/// Versions of sccache that don't know about codecs would read objects
+/// compressed with zstd back as-is, so those entries get keys of their own.
+pub fn entry_key(key: &str, compression: CacheCompression) -> String {
+    match Codec::for_compression(compression) {
+        Codec::Zstd => {
+            let mut m = Digest::new();
+            m.update(key.as_bytes());
+            m.update(Codec::Zstd.name().as_bytes());
+            m.finish()
+        }
+        Codec::Stored | Codec::Deflate => key.to_owned(),
+    }
+}
+
+/// Look up the entry for the hash key `key` in `storage`, returning it along
+/// with the key it was found under.
+///
+/// Entries written before switching to zstd are stored under the hash key
+/// itself, so they're looked up when there's no entry under the zstd key.
+pub fn get_entry(
+    storage: Arc<dyn Storage>,
+    key: &str,
+    compression: CacheCompression,
+) -> SFuture<(String, Cache)> {
+    let legacy_key = key.to_owned();
+    let key = entry_key(key, compression);
+    if key == legacy_key {
+        return Box::new(storage.get(&key).map(move |cache| (key, cache)));
+    }
+    Box::new(storage.get(&key).and_then(move |cache| -> SFuture<_> {
+        match cache {
+            Cache::Miss => Box::new(
+                storage
+                    .get(&legacy_key)
+                    .map(move |cache| (legacy_key, cache)),
+            ),
+            cache => f_ok((key, cache)),
+        }
+    }))
+}
+
+/// How the objects in a cache entry are compressed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Codec {
+    /// Objects are stored in the zip archive as-is.
+    Stored,
+    /// Objects are deflated by the zip archive.
+    Deflate,
+    /// Objects are compressed with zstd and stored in the zip archive as-is.
+    Zstd,
+}
+
+impl Codec {
+    fn for_compression(compression: CacheCompression) -> Codec {
+        match compression {
+            CacheCompression::None => Codec::Stored,
+            CacheCompression::Deflate => Codec::Deflate,
+            CacheCompression::Zstd { .. } => Codec::Zstd,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Codec::Stored => "none",
+            Codec::Deflate => "deflate",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Codec> {
+        match name {
+            "none" => Some(Codec::Stored),
+            "deflate" => Some(Codec::Deflate),
+            "zstd" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// A reader that remembers whether reading from it failed, to tell corrupt
+/// cache entries apart from errors writing their contents out.
+struct TrackedReader<R> {
+    inner: R,
+    failed: bool,
+}
+
+impl<R: Read> Read for TrackedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            self.failed = true;
+            e
+        })
+    }
+}
+
 /// Data stored in the compiler cache.
 pub struct CacheRead {
     zip: ZipArchive<Box<dyn ReadSeek>>,
+    codec: Codec,
+    /// The expected digest of each object, by name.
+    checksums: HashMap<String, String>,
 }
 
 impl CacheRead {
@@ -72,9 +276,44 @@ impl CacheRead {
     where
         R: ReadSeek + 'static,
     {
-        let z = ZipArchive::new(Box::new(reader) as Box<dyn ReadSeek>)
+        let mut z = ZipArchive::new(Box::new(reader) as Box<dyn ReadSeek>)
             .chain_err(|| "Failed to parse cache entry")?;
-        Ok(CacheRead { zip: z })
+        let codec = match z.by_name(CODEC_OBJECT_NAME) {
+            Ok(mut file) => {
+                let mut name = String::new();
+                file.read_to_string(&mut name)?;
+                Codec::from_name(&name)
+                    .ok_or_else(|| format!("Unknown cache entry codec `{}`", name))?
+            }
+            Err(ZipError::FileNotFound) => Codec::Deflate,
+            Err(e) => return Err(e).chain_err(|| "Failed to read cache entry codec"),
+        };
+        let checksums = match z.by_name(CHECKSUMS_OBJECT_NAME) {
+            Ok(file) => serde_json::from_reader(file)
+                .chain_err(|| "Failed to parse cache entry checksums")?,
+            Err(ZipError::FileNotFound) => HashMap::new(),
+            Err(e) => return Err(e).chain_err(|| "Failed to read cache entry checksums"),
+        };
+        Ok(CacheRead {
+            zip: z,
+            codec,
+            checksums,
+        })
+    }
+
+    /// Get the names of the objects in this cache entry.
+    pub fn object_names(&mut self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        for i in 0..self.zip.len() {
+            let file = self
+                .zip
+                .by_index(i)
+                .chain_err(|| "Failed to read cache entry")?;
+            if file.name() != CODEC_OBJECT_NAME && file.name() != CHECKSUMS_OBJECT_NAME {
+                names.push(file.name().to_owned());
+            }
+        }
+        Ok(names)
     }
 
     /// Get an object from this cache entry at `name` and write it to `to`.
@@ -87,21 +326,89 @@ impl CacheRead {
             .zip
             .by_name(name)
             .chain_err(|| "Failed to read object from cache entry")?;
-        io::copy(&mut file, to)?;
-        Ok(file.unix_mode())
+        let mode = file.unix_mode();
+        let reader: Box<dyn Read + '_> = match self.codec {
+            Codec::Zstd => Box::new(zstd::stream::Decoder::new(file)?),
+            Codec::Stored | Codec::Deflate => Box::new(file),
+        };
+        let mut from = TrackedReader {
+            inner: reader,
+            failed: false,
+        };
+        let mut to = DigestWriter::new(to);
+        if let Err(e) = io::copy(&mut from, &mut to) {
+            // The zip archive checks each object's CRC as it's read.
+            if from.failed {
+                warn!("Failed to read cache entry object `{}`: {}", name, e);
+                bail!(ErrorKind::CorruptCacheEntry(name.to_owned()));
+            }
+            return Err(e.into());
+        }
+        let (digest, _) = to.finish();
+        if let Some(expected) = self.checksums.get(name) {
+            if *expected != digest {
+                bail!(ErrorKind::CorruptCacheEntry(name.to_owned()));
+            }
+        }
+        Ok(mode)
+    }
+
+    /// Return a reader over the raw, serialized contents of this cache entry,
+    /// positioned at its start.
+    pub fn into_reader(self) -> Result<Box<dyn ReadSeek>> {
+        let mut reader = self.zip.into_inner();
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(reader)
     }
 }
 
 /// Data to be stored in the compiler cache.
+///
+/// Entries are built in a temporary file rather than in memory, so that large
+/// outputs can be streamed to storage.
 pub struct CacheWrite {
-    zip: ZipWriter<io::Cursor<Vec<u8>>>,
+    inner: CacheWriteInner,
+}
+
+enum CacheWriteInner {
+    /// An entry that is still being built.
+    Zip {
+        zip: ZipWriter<File>,
+        compression: CacheCompression,
+        /// The digest of each object written so far, by name.
+        checksums: HashMap<String, String>,
+    },
+    /// An entry whose contents have already been serialized.
+    Finished(Box<dyn ReadSeek>),
 }
 
 impl CacheWrite {
-    /// Create a new, empty cache entry.
-    pub fn new() -> CacheWrite {
+    /// Create a new, empty cache entry using the default compression.
+    pub fn new() -> Result<CacheWrite> {
+        CacheWrite::with_compression(Default::default())
+    }
+
+    /// Create a new, empty cache entry whose objects are compressed with
+    /// `compression`.
+    pub fn with_compression(compression: CacheCompression) -> Result<CacheWrite> {
+        let file = tempfile::tempfile().chain_err(|| "Failed to create cache entry file")?;
+        Ok(CacheWrite {
+            inner: CacheWriteInner::Zip {
+                zip: ZipWriter::new(file),
+                compression,
+                checksums: HashMap::new(),
+            },
+        })
+    }
+
+    /// Create a cache entry from the serialized contents of another entry,
+    /// as returned by `CacheWrite::finish_reader` or `CacheRead::into_reader`.
+    pub fn from_reader<R>(reader: R) -> CacheWrite
+    where
+        R: ReadSeek + 'static,
+    {
         CacheWrite {
-            zip: ZipWriter::new(io::Cursor::new(vec![])),
+            inner: CacheWriteInner::Finished(Box::new(reader)),
         }
     }
 
@@ -111,29 +418,102 @@ impl CacheWrite {
     where
         T: Read,
     {
-        let opts = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        let (zip, compression, checksums) = match self.inner {
+            CacheWriteInner::Zip {
+                ref mut zip,
+                compression,
+                ref mut checksums,
+            } => (zip, compression, checksums),
+            CacheWriteInner::Finished(_) => bail!("Cache entry has already been finished"),
+        };
+        let method = match Codec::for_compression(compression) {
+            Codec::Deflate => CompressionMethod::Deflated,
+            Codec::Stored | Codec::Zstd => CompressionMethod::Stored,
+        };
+        let opts = FileOptions::default().compression_method(method);
         let opts = if let Some(mode) = mode {
             opts.unix_permissions(mode)
         } else {
             opts
         };
-        self.zip
-            .start_file(name, opts)
+        zip.start_file(name, opts)
             .chain_err(|| "Failed to start cache entry object")?;
-        io::copy(from, &mut self.zip)?;
+        let mut from = DigestReader::new(from);
+        match compression {
+            CacheCompression::Zstd { level } => zstd::stream::copy_encode(&mut from, zip, level)?,
+            CacheCompression::None | CacheCompression::Deflate => {
+                io::copy(&mut from, zip)?;
+            }
+        }
+        checksums.insert(name.to_owned(), from.finish());
         Ok(())
     }
 
     /// Finish writing data to the cache entry writer, and return the data.
+    ///
+    /// This reads the whole entry into memory; prefer `finish_reader` for
+    /// storage that can accept a stream.
     pub fn finish(self) -> Result<Vec<u8>> {
-        let CacheWrite { mut zip } = self;
-        let cur = zip
-            .finish()
-            .chain_err(|| "Failed to finish cache entry zip")?;
-        Ok(cur.into_inner())
+        let mut reader = self.finish_reader()?;
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Finish writing data to the cache entry writer, and return a reader
+    /// over the data, positioned at its start.
+    pub fn finish_reader(self) -> Result<Box<dyn ReadSeek>> {
+        match self.inner {
+            CacheWriteInner::Zip {
+                mut zip,
+                compression,
+                checksums,
+            } => {
+                let opts = FileOptions::default().compression_method(CompressionMethod::Stored);
+                zip.start_file(CODEC_OBJECT_NAME, opts)
+                    .chain_err(|| "Failed to start cache entry codec")?;
+                zip.write_all(Codec::for_compression(compression).name().as_bytes())?;
+                zip.start_file(CHECKSUMS_OBJECT_NAME, opts)
+                    .chain_err(|| "Failed to start cache entry checksums")?;
+                serde_json::to_writer(&mut zip, &checksums)?;
+                let mut file = zip
+                    .finish()
+                    .chain_err(|| "Failed to finish cache entry zip")?;
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(file))
+            }
+            CacheWriteInner::Finished(mut reader) => {
+                reader.seek(SeekFrom::Start(0))?;
+                Ok(reader)
+            }
+        }
     }
 }
 
+/// The size and age of an entry in a `Storage`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryInfo {
+    /// The key of the entry, without any key prefix.
+    pub key: String,
+    /// The size of the stored entry, in bytes.
+    pub size: u64,
+    /// When the entry was last used, or last written if the storage doesn't
+    /// keep track of use, if known.
+    pub modified: Option<SystemTime>,
+}
+
+/// What pruning a `Storage` removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PruneStats {
+    /// The number of entries removed.
+    pub entries: u64,
+    /// The total size of the entries removed, in bytes.
+    pub bytes: u64,
+}
+
+/// The most entries removed at once while pruning.
+const MAX_CONCURRENT_REMOVES: usize = 16;
+
 /// An interface to cache storage.
 pub trait Storage {
     /// Get a cache entry by `key`.
@@ -147,13 +527,52 @@ pub trait Storage {
 
     /// Put `entry` in the cache under `key`.
     ///
+    /// Storage that can accept a stream should use `CacheWrite::finish_reader`
+    /// rather than reading the whole entry into memory.
+    ///
     /// Returns a `Future` that will provide the result or error when the put is
     /// finished.
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration>;
 
+    /// Remove the entry for `key`, if this storage supports it.
+    ///
+    /// This is used to get rid of entries that turn out to be corrupt, so
+    /// storage that can't remove entries can just do nothing.
+    fn remove(&self, _key: &str) -> SFuture<()> {
+        f_ok(())
+    }
+
+    /// List the entries in the storage.
+    ///
+    /// This is only used to inspect and prune caches, so storage that can't
+    /// list its entries just returns an error.
+    fn list(&self) -> SFuture<Vec<EntryInfo>> {
+        f_err(format!(
+            "Listing entries isn't supported by {}",
+            self.location()
+        ))
+    }
+
+    /// Get the size and age of the entry for `key`, if there is one.
+    ///
+    /// By default, this looks for the entry in `list`.
+    fn stat(&self, key: &str) -> SFuture<Option<EntryInfo>> {
+        let key = key.to_owned();
+        Box::new(
+            self.list()
+                .map(move |entries| entries.into_iter().find(|entry| entry.key == key)),
+        )
+    }
+
     /// Get the storage location.
     fn location(&self) -> String;
 
+    /// Get whether this storage may be read from, written to, or both.
+    ///
+    /// Callers should not `get` from a write-only storage, nor `put` to a
+    /// read-only one.
+    fn rw_mode(&self) -> CacheRWMode;
+
     /// Get the current storage usage, if applicable.
     fn current_size(&self) -> SFuture<Option<u64>>;
 
@@ -161,17 +580,180 @@ pub trait Storage {
     fn max_size(&self) -> SFuture<Option<u64>>;
 }
 
+/// Remove the entries of `storage` that haven't been used, or written if the
+/// storage doesn't keep track of use, for `older_than`.
+///
+/// Entries of unknown age are kept.
+pub fn prune(storage: Arc<dyn Storage>, older_than: Duration) -> SFuture<PruneStats> {
+    let cutoff = match SystemTime::now().checked_sub(older_than) {
+        Some(cutoff) => cutoff,
+        None => return f_ok(PruneStats::default()),
+    };
+    Box::new(storage.list().and_then(move |entries| {
+        let old = entries
+            .into_iter()
+            .filter(move |entry| entry.modified.map_or(false, |t| t < cutoff));
+        stream::iter_ok(old)
+            .map(move |entry| {
+                trace!("prune: removing {}", entry.key);
+                storage.remove(&entry.key).map(move |()| entry.size)
+            })
+            .buffer_unordered(MAX_CONCURRENT_REMOVES)
+            .fold(PruneStats::default(), |stats, size| -> Result<_> {
+                Ok(PruneStats {
+                    entries: stats.entries + 1,
+                    bytes: stats.bytes + size,
+                })
+            })
+    }))
+}
+
+/// How long the size of a remote cache is reused for before it's computed
+/// again.
+pub const SIZE_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The size of a remote cache, which is slow to compute, such as by listing
+/// every object in a bucket, and so is only computed again once it's older
+/// than the refresh interval.
+///
+/// The size is always computed in the background, and the last size computed
+/// is returned straight away, so that stats never wait for it.
+pub struct CachedSize<T = Option<u64>> {
+    interval: Duration,
+    state: Rc<RefCell<CachedSizeState<T>>>,
+}
+
+struct CachedSizeState<T> {
+    /// The last size computed, and when it was computed.
+    last: Option<(Instant, T)>,
+    /// Whether a size is being computed.
+    computing: bool,
+}
+
+impl<T: Copy + Default + 'static> CachedSize<T> {
+    /// Create a new `CachedSize` that's computed again every `interval`.
+    pub fn new(interval: Duration) -> CachedSize<T> {
+        CachedSize {
+            interval,
+            state: Rc::new(RefCell::new(CachedSizeState {
+                last: None,
+                computing: false,
+            })),
+        }
+    }
+
+    /// Return the last size computed, or the default until there is one,
+    /// and start computing a fresh one with `compute` if it's unknown or
+    /// stale. Failures to compute it are logged.
+    pub fn get<F>(&self, compute: F) -> T
+    where
+        F: FnOnce() -> SFuture<T>,
+    {
+        let mut state = self.state.borrow_mut();
+        let fresh = state
+            .last
+            .map_or(false, |(at, _)| at.elapsed() < self.interval);
+        if !fresh && !state.computing {
+            let shared_state = self.state.clone();
+            let refresh = compute().then(move |result| {
+                let mut state = shared_state.borrow_mut();
+                state.computing = false;
+                match result {
+                    Ok(size) => state.last = Some((Instant::now(), size)),
+                    Err(e) => warn!("Failed to compute cache size: {}", e),
+                }
+                Ok::<(), ()>(())
+            });
+            match TaskExecutor::current().spawn_local(Box::new(refresh)) {
+                Ok(()) => state.computing = true,
+                Err(e) => warn!("Failed to spawn cache size computation: {:?}", e),
+            }
+        }
+        state.last.map_or_else(T::default, |(_, size)| size)
+    }
+}
+
 /// Get a suitable `Storage` implementation from configuration.
 pub fn storage_from_config(config: &Config, pool: &CpuPool) -> Arc<dyn Storage> {
+    let remote = remote_storage_from_config(config, pool);
+    let config::DiskCacheConfig {
+        ref dir,
+        size,
+        rw_mode,
+        dedup,
+    } = config.fallback_cache;
+    match (remote, &config.tiered) {
+        (Some(remote), Some(tiered)) => {
+            trace!(
+                "Using DiskCache({:?}, {}) in front of remote cache",
+                dir,
+                size
+            );
+            let disk = Arc::new(DiskCache::new(&dir, size, rw_mode, pool));
+            let disk = maybe_dedup(disk, dedup, config, pool);
+            Arc::new(TieredCache::new(vec![
+                CacheTier::new(disk, tiered.disk_write_policy),
+                CacheTier::new(remote, tiered.remote_write_policy),
+            ]))
+        }
+        (Some(remote), None) => remote,
+        (None, _) => {
+            info!("No configured caches successful, falling back to default");
+            trace!(
+                "Using DiskCache({:?}, {}, {:?}, dedup: {})",
+                dir,
+                size,
+                rw_mode,
+                dedup
+            );
+            let disk = Arc::new(DiskCache::new(&dir, size, rw_mode, pool));
+            maybe_dedup(disk, dedup, config, pool)
+        }
+    }
+}
+
+/// Wrap `storage` in a `DedupCache` if `dedup` is set.
+fn maybe_dedup(
+    storage: Arc<dyn Storage>,
+    dedup: bool,
+    config: &Config,
+    pool: &CpuPool,
+) -> Arc<dyn Storage> {
+    if dedup {
+        Arc::new(DedupCache::new(storage, config.compression, pool))
+    } else {
+        storage
+    }
+}
+
+/// Convert a configured TTL in seconds to a `Duration`, treating 0 as no expiry.
+#[cfg(any(feature = "memcached", feature = "redis"))]
+fn ttl_duration(ttl: Option<u64>) -> Option<Duration> {
+    ttl.filter(|&secs| secs > 0).map(Duration::from_secs)
+}
+
+/// Get the first remote `Storage` implementation from configuration that
+/// can be created successfully.
+#[cfg_attr(not(feature = "memcached"), allow(unused_variables))]
+#[cfg_attr(not(feature = "http-cache"), allow(unused_variables))]
+#[cfg_attr(not(feature = "reapi"), allow(unused_variables))]
+#[cfg_attr(not(feature = "redis"), allow(unused_variables))]
+fn remote_storage_from_config(config: &Config, pool: &CpuPool) -> Option<Arc<dyn Storage>> {
     for cache_type in config.caches.iter() {
         match *cache_type {
-            CacheType::Azure(config::AzureCacheConfig) => {
-                debug!("Trying Azure Blob Store account");
+            CacheType::Azure(ref azure_config) => {
+                debug!(
+                    "Trying Azure Blob Store account({:?}, {:?}, {:?}, {:?})",
+                    azure_config.account_name,
+                    azure_config.container,
+                    azure_config.key_prefix,
+                    azure_config.rw_mode
+                );
                 #[cfg(feature = "azure")]
-                match AzureBlobCache::new() {
+                match AzureBlobCache::new(azure_config, pool) {
                     Ok(storage) => {
                         trace!("Using AzureBlobCache");
-                        return Arc::new(storage);
+                        return Some(Arc::new(storage));
                     }
                     Err(e) => warn!("Failed to create Azure cache: {:?}", e),
                 }
@@ -180,24 +762,19 @@ pub fn storage_from_config(config: &Config, pool: &CpuPool) -> Arc<dyn Storage>
                 ref bucket,
                 ref cred_path,
                 ref url,
+                ref key_prefix,
                 rw_mode,
             }) => {
                 debug!(
-                    "Trying GCS bucket({}, {:?}, {:?}, {:?})",
-                    bucket, cred_path, url, rw_mode
+                    "Trying GCS bucket({}, {:?}, {:?}, {:?}, {:?})",
+                    bucket, cred_path, url, key_prefix, rw_mode
                 );
                 #[cfg(feature = "gcs")]
                 {
                     let service_account_info_opt: Option<gcs::ServiceAccountInfo> =
                         if let Some(ref cred_path) = *cred_path {
                             // Attempt to read the service account key from file
-                            let service_account_key_res: Result<gcs::ServiceAccountKey> = (|| {
-                                let mut file = File::open(&cred_path)?;
-                                let mut service_account_json = String::new();
-                                file.read_to_string(&mut service_account_json)?;
-                                Ok(serde_json::from_str(&service_account_json)?)
-                            })(
-                            );
+                            let service_account_key_res = gcs::read_service_account_key(cred_path);
 
                             // warn! if an error was encountered reading the key from the file
                             if let Err(ref e) = service_account_key_res {
@@ -214,47 +791,126 @@ pub fn storage_from_config(config: &Config, pool: &CpuPool) -> Arc<dyn Storage>
                         } else if let Some(ref url) = *url {
                             Some(ServiceAccountInfo::URL(url.clone()))
                         } else {
-                            warn!(
-                            "No SCCACHE_GCS_KEY_PATH specified-- no authentication will be used."
-                        );
-                            None
+                            let info = ServiceAccountInfo::application_default();
+                            if info.is_none() {
+                                warn!(
+                                    "No SCCACHE_GCS_KEY_PATH specified and no default \
+                                     credentials found-- no authentication will be used."
+                                );
+                            }
+                            info
                         };
 
                     let gcs_read_write_mode = match rw_mode {
-                        config::GCSCacheRWMode::ReadOnly => RWMode::ReadOnly,
-                        config::GCSCacheRWMode::ReadWrite => RWMode::ReadWrite,
+                        CacheRWMode::ReadOnly => RWMode::ReadOnly,
+                        CacheRWMode::WriteOnly | CacheRWMode::ReadWrite => RWMode::ReadWrite,
                     };
 
                     let gcs_cred_provider = service_account_info_opt
                         .map(|info| GCSCredentialProvider::new(gcs_read_write_mode, info));
 
-                    match GCSCache::new(bucket.to_owned(), gcs_cred_provider, gcs_read_write_mode) {
+                    match GCSCache::new(
+                        bucket.to_owned(),
+                        gcs_cred_provider,
+                        &key_prefix,
+                        rw_mode,
+                        pool,
+                    ) {
                         Ok(s) => {
                             trace!("Using GCSCache");
-                            return Arc::new(s);
+                            return Some(Arc::new(s));
                         }
                         Err(e) => warn!("Failed to create GCS Cache: {:?}", e),
                     }
                 }
             }
-            CacheType::Memcached(config::MemcachedCacheConfig { ref url }) => {
-                debug!("Trying Memcached({})", url);
+            CacheType::Http(config::HttpCacheConfig {
+                ref url,
+                ref auth,
+                ref key_prefix,
+                rw_mode,
+            }) => {
+                debug!("Trying HTTP({}, {:?}, {:?})", url, key_prefix, rw_mode);
+                #[cfg(feature = "http-cache")]
+                match HttpCache::new(&url, auth.clone(), &key_prefix, rw_mode, pool) {
+                    Ok(s) => {
+                        trace!("Using HttpCache");
+                        return Some(Arc::new(s));
+                    }
+                    Err(e) => warn!("Failed to create HttpCache: {:?}", e),
+                }
+            }
+            CacheType::Memcached(config::MemcachedCacheConfig {
+                ref url,
+                ref key_prefix,
+                ttl,
+                refresh_on_hit,
+                rw_mode,
+            }) => {
+                debug!(
+                    "Trying Memcached({}, {:?}, ttl: {:?}, {:?})",
+                    url, key_prefix, ttl, rw_mode
+                );
                 #[cfg(feature = "memcached")]
-                match MemcachedCache::new(&url, pool) {
+                match MemcachedCache::new(
+                    &url,
+                    &key_prefix,
+                    ttl_duration(ttl),
+                    refresh_on_hit,
+                    rw_mode,
+                    pool,
+                ) {
                     Ok(s) => {
                         trace!("Using Memcached: {}", url);
-                        return Arc::new(s);
+                        return Some(Arc::new(s));
                     }
                     Err(e) => warn!("Failed to create MemcachedCache: {:?}", e),
                 }
             }
-            CacheType::Redis(config::RedisCacheConfig { ref url }) => {
-                debug!("Trying Redis({})", url);
+            CacheType::Reapi(config::ReapiCacheConfig {
+                ref endpoint,
+                ref instance_name,
+                ref key_prefix,
+                rw_mode,
+            }) => {
+                debug!(
+                    "Trying Remote Execution API({}, {}, {:?}, {:?})",
+                    endpoint, instance_name, key_prefix, rw_mode
+                );
+                #[cfg(feature = "reapi")]
+                match ReapiCache::new(&endpoint, &instance_name, &key_prefix, rw_mode, pool) {
+                    Ok(s) => {
+                        trace!("Using ReapiCache");
+                        return Some(Arc::new(s));
+                    }
+                    Err(e) => warn!("Failed to create ReapiCache: {:?}", e),
+                }
+            }
+            CacheType::Redis(config::RedisCacheConfig {
+                ref url,
+                ref mode,
+                ref key_prefix,
+                ttl,
+                refresh_on_hit,
+                rw_mode,
+            }) => {
+                debug!(
+                    "Trying Redis({}, {:?}, {:?}, ttl: {:?}, {:?})",
+                    url, mode, key_prefix, ttl, rw_mode
+                );
                 #[cfg(feature = "redis")]
-                match RedisCache::new(&url) {
+                match RedisCache::new(
+                    &url,
+                    mode,
+                    &key_prefix,
+                    ttl_duration(ttl),
+                    refresh_on_hit,
+                    rw_mode,
+                    pool,
+                ) {
                     Ok(s) => {
                         trace!("Using Redis: {}", url);
-                        return Arc::new(s);
+                        return Some(Arc::new(s));
                     }
                     Err(e) => warn!("Failed to create RedisCache: {:?}", e),
                 }
@@ -262,23 +918,290 @@ pub fn storage_from_config(config: &Config, pool: &CpuPool) -> Arc<dyn Storage>
             CacheType::S3(config::S3CacheConfig {
                 ref bucket,
                 ref endpoint,
+                addressing,
+                ref region,
                 use_ssl,
+                ref ca_bundle,
+                ref key_prefix,
+                rw_mode,
+                dedup,
+                server_side_encryption,
+                ref sse_kms_key_id,
+                ref storage_class,
+                ref tags,
             }) => {
-                debug!("Trying S3Cache({}, {})", bucket, endpoint);
+                let endpoint = match addressing {
+                    Some(config::S3Addressing::VirtualHost) => format!("{}.{}", bucket, endpoint),
+                    Some(config::S3Addressing::Path) => {
+                        format!("{}/{}", endpoint.trim_end_matches('/'), bucket)
+                    }
+                    None => endpoint.clone(),
+                };
+                debug!(
+                    "Trying S3Cache({}, {}, {:?}, {:?}, {:?}, dedup: {})",
+                    bucket, endpoint, region, key_prefix, rw_mode, dedup
+                );
                 #[cfg(feature = "s3")]
-                match S3Cache::new(&bucket, &endpoint, use_ssl) {
+                {
+                    let put_options = PutOptions {
+                        server_side_encryption: server_side_encryption.map(|mode| {
+                            match mode {
+                                config::S3ServerSideEncryption::Aes256 => "AES256",
+                                config::S3ServerSideEncryption::AwsKms => "aws:kms",
+                            }
+                            .to_owned()
+                        }),
+                        sse_kms_key_id: sse_kms_key_id.clone(),
+                        storage_class: storage_class.clone(),
+                        tags: tags.clone().into_iter().collect(),
+                    };
+                    match S3Cache::new(
+                        &bucket,
+                        &endpoint,
+                        use_ssl,
+                        region.as_ref().map(String::as_str),
+                        ca_bundle.as_ref().map(|p| p.as_path()),
+                        &key_prefix,
+                        put_options,
+                        rw_mode,
+                        pool,
+                    ) {
+                        Ok(s) => {
+                            trace!("Using S3Cache");
+                            return Some(maybe_dedup(Arc::new(s), dedup, config, pool));
+                        }
+                        Err(e) => warn!("Failed to create S3Cache: {:?}", e),
+                    }
+                }
+            }
+            CacheType::SharedDir(config::SharedDirCacheConfig {
+                ref dir,
+                max_size,
+                sweep_interval,
+                ref key_prefix,
+                rw_mode,
+            }) => {
+                debug!(
+                    "Trying SharedDirCache({:?}, {:?}, {:?}, {:?})",
+                    dir, key_prefix, max_size, rw_mode
+                );
+                match SharedDirCache::new(
+                    dir,
+                    &key_prefix,
+                    max_size,
+                    Duration::from_secs(sweep_interval),
+                    rw_mode,
+                    pool,
+                ) {
                     Ok(s) => {
-                        trace!("Using S3Cache");
-                        return Arc::new(s);
+                        trace!("Using SharedDirCache");
+                        return Some(Arc::new(s));
                     }
-                    Err(e) => warn!("Failed to create S3Cache: {:?}", e),
+                    Err(e) => warn!("Failed to create SharedDirCache: {:?}", e),
                 }
             }
         }
     }
 
-    info!("No configured caches successful, falling back to default");
-    let (dir, size) = (&config.fallback_cache.dir, config.fallback_cache.size);
-    trace!("Using DiskCache({:?}, {})", dir, size);
-    Arc::new(DiskCache::new(&dir, size, pool))
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future;
+    use futures::Future;
+    use std::cell::Cell;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn roundtrip(compression: CacheCompression) {
+        let mut entry = CacheWrite::with_compression(compression).unwrap();
+        entry
+            .put_object("obj", &mut &b"some contents"[..], Some(0o644))
+            .unwrap();
+        let data = entry.finish().unwrap();
+        let mut entry = CacheRead::from(io::Cursor::new(data)).unwrap();
+        let mut contents = vec![];
+        let mode = entry.get_object("obj", &mut contents).unwrap();
+        assert_eq!(contents, b"some contents");
+        assert_eq!(mode.map(|m| m & 0o777), Some(0o644));
+    }
+
+    #[test]
+    fn test_get_legacy_entry() {
+        let tempdir = tempdir::TempDir::new("sccache_test_cache").unwrap();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(DiskCache::new(
+            &tempdir.path(),
+            std::u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
+        ));
+        let zstd = CacheCompression::Zstd { level: 3 };
+        let put = |key: &str, compression| {
+            let mut entry = CacheWrite::with_compression(compression).unwrap();
+            entry
+                .put_object("obj", &mut &b"some contents"[..], None)
+                .unwrap();
+            storage.put(key, entry)
+        };
+
+        match runtime.block_on(get_entry(storage.clone(), "abcd", zstd)) {
+            Ok((ref key, Cache::Miss)) if *key == "abcd" => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // An entry written before switching to zstd is still a hit.
+        runtime
+            .block_on(put("abcd", CacheCompression::Deflate))
+            .unwrap();
+        match runtime.block_on(get_entry(storage.clone(), "abcd", zstd)) {
+            Ok((ref key, Cache::Hit(_))) if *key == "abcd" => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // Entries compressed with zstd are found first.
+        let zstd_key = entry_key("abcd", zstd);
+        runtime.block_on(put(&zstd_key, zstd)).unwrap();
+        match runtime.block_on(get_entry(storage.clone(), "abcd", zstd)) {
+            Ok((ref key, Cache::Hit(_))) if *key == zstd_key => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_cache_entry_codecs() {
+        roundtrip(CacheCompression::None);
+        roundtrip(CacheCompression::Deflate);
+        roundtrip(CacheCompression::Zstd { level: 3 });
+    }
+
+    #[test]
+    fn test_entry_key() {
+        assert_eq!(entry_key("abcd", CacheCompression::Deflate), "abcd");
+        assert_eq!(entry_key("abcd", CacheCompression::None), "abcd");
+        let zstd = entry_key("abcd", CacheCompression::Zstd { level: 3 });
+        assert_ne!(zstd, "abcd");
+        assert_eq!(
+            zstd,
+            entry_key("abcd", CacheCompression::Zstd { level: 19 })
+        );
+    }
+
+    #[test]
+    fn test_cache_entry_streaming() {
+        let contents = vec![7u8; CACHE_STREAM_CHUNK_SIZE * 2 + 1];
+        let mut entry = CacheWrite::with_compression(CacheCompression::None).unwrap();
+        entry.put_object("obj", &mut &contents[..], None).unwrap();
+        let mut reader = entry.finish_reader().unwrap();
+        let len = reader_len(&mut reader).unwrap();
+
+        let pool = CpuPool::new(1);
+        let chunks = ReaderStream::new(reader, &pool).collect().wait().unwrap();
+        assert!(chunks.iter().all(|c| c.len() <= CACHE_STREAM_CHUNK_SIZE));
+        let data: Vec<u8> = chunks.concat();
+        assert_eq!(data.len() as u64, len);
+
+        let entry = CacheWrite::from_reader(io::Cursor::new(data));
+        let mut entry = CacheRead::from(entry.finish_reader().unwrap()).unwrap();
+        let mut read = vec![];
+        entry.get_object("obj", &mut read).unwrap();
+        assert_eq!(read, contents);
+    }
+
+    #[test]
+    fn test_cache_entry_corrupt_object() {
+        let mut entry = CacheWrite::with_compression(CacheCompression::None).unwrap();
+        entry
+            .put_object("obj", &mut &b"some contents"[..], None)
+            .unwrap();
+        let mut data = entry.finish().unwrap();
+        let pos = data
+            .windows(13)
+            .position(|w| w == b"some contents")
+            .unwrap();
+        data[pos] ^= 0xff;
+        let mut entry = CacheRead::from(io::Cursor::new(data)).unwrap();
+        match entry.get_object("obj", &mut io::sink()) {
+            Err(Error(ErrorKind::CorruptCacheEntry(ref name), _)) if name == "obj" => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_cache_entry_checksum_mismatch() {
+        let mut zip = ZipWriter::new(io::Cursor::new(vec![]));
+        let opts = FileOptions::default();
+        zip.start_file("obj", opts).unwrap();
+        zip.write_all(b"contents").unwrap();
+        zip.start_file(CHECKSUMS_OBJECT_NAME, opts).unwrap();
+        zip.write_all(br#"{"obj": "0123"}"#).unwrap();
+        let data = zip.finish().unwrap().into_inner();
+        let mut entry = CacheRead::from(io::Cursor::new(data)).unwrap();
+        let mut contents = vec![];
+        match entry.get_object("obj", &mut contents) {
+            Err(Error(ErrorKind::CorruptCacheEntry(..), _)) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_cache_entry_without_codec() {
+        // Entries written before codecs were recorded are deflated zips.
+        let mut zip = ZipWriter::new(io::Cursor::new(vec![]));
+        let opts = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("obj", opts).unwrap();
+        zip.write_all(b"old contents").unwrap();
+        let data = zip.finish().unwrap().into_inner();
+        let mut entry = CacheRead::from(io::Cursor::new(data)).unwrap();
+        let mut contents = vec![];
+        entry.get_object("obj", &mut contents).unwrap();
+        assert_eq!(contents, b"old contents");
+    }
+
+    #[test]
+    fn test_cached_size() {
+        let mut runtime = Runtime::new().unwrap();
+        let computed = Rc::new(Cell::new(0));
+        let compute = || -> SFuture<Option<u64>> {
+            computed.set(computed.get() + 1);
+            f_ok(Some(computed.get()))
+        };
+
+        // Nothing is returned until the size has been computed once.
+        let cached = CachedSize::new(Duration::from_secs(3600));
+        let size = runtime.block_on(future::lazy(|| Ok::<_, ()>(cached.get(compute))));
+        assert_eq!(size.unwrap(), None);
+        runtime.run().unwrap();
+        for _ in 0..2 {
+            let size = runtime.block_on(future::lazy(|| Ok::<_, ()>(cached.get(compute))));
+            assert_eq!(size.unwrap(), Some(1));
+        }
+        runtime.run().unwrap();
+        assert_eq!(computed.get(), 1);
+
+        // A stale value is returned while a fresh one is computed.
+        let cached = CachedSize::new(Duration::from_secs(0));
+        runtime
+            .block_on(future::lazy(|| Ok::<_, ()>(cached.get(compute))))
+            .unwrap();
+        runtime.run().unwrap();
+        let size = runtime.block_on(future::lazy(|| Ok::<_, ()>(cached.get(compute))));
+        assert_eq!(size.unwrap(), Some(2));
+        runtime.run().unwrap();
+        let size = runtime.block_on(future::lazy(|| Ok::<_, ()>(cached.get(compute))));
+        assert_eq!(size.unwrap(), Some(3));
+
+        // Failures leave the size unknown.
+        let cached = CachedSize::new(Duration::from_secs(3600));
+        runtime
+            .block_on(future::lazy(|| {
+                Ok::<_, ()>(cached.get(|| -> SFuture<Option<u64>> { f_err("no size") }))
+            }))
+            .unwrap();
+        runtime.run().unwrap();
+        let size = runtime.block_on(future::lazy(|| {
+            Ok::<_, ()>(cached.get(|| -> SFuture<Option<u64>> { f_err("no size") }))
+        }));
+        assert_eq!(size.unwrap(), None);
+    }
 }
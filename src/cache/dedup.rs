@@ -0,0 +1,462 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{Cache, CacheRead, CacheWrite, EntryInfo, Storage};
+use crate::config::{CacheCompression, CacheRWMode};
+use crate::util::DigestWriter;
+use futures::future::{self, Future};
+use futures_cpupool::CpuPool;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+
+use crate::errors::*;
+
+/// The name of the object listing the blobs a deduplicated entry is made of.
+const MANIFEST_OBJECT_NAME: &str = ".sccache-dedup";
+
+/// The name of the single object in a blob entry.
+const BLOB_OBJECT_NAME: &str = "blob";
+
+/// An object of a deduplicated cache entry.
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestObject {
+    /// The name of the object in the original entry.
+    name: String,
+    /// The digest of the object's contents, which is also the key of its blob.
+    digest: String,
+    /// The object's stored permissions, if any.
+    mode: Option<u32>,
+}
+
+/// A cache that stores each object of an entry only once, keyed by the
+/// digest of its contents.
+///
+/// Entries written through a `DedupCache` only contain a manifest of the blobs
+/// they're made of. Entries without a manifest are returned as-is, so existing
+/// caches can be switched to this layout.
+pub struct DedupCache {
+    /// The storage holding both the entries and the blobs.
+    inner: Arc<dyn Storage>,
+    /// How blobs are compressed.
+    compression: CacheCompression,
+    /// Thread pool to split and reassemble entries on.
+    pool: CpuPool,
+}
+
+impl DedupCache {
+    /// Create a new `DedupCache` storing entries and blobs in `inner`.
+    pub fn new(
+        inner: Arc<dyn Storage>,
+        compression: CacheCompression,
+        pool: &CpuPool,
+    ) -> DedupCache {
+        DedupCache {
+            inner,
+            compression,
+            pool: pool.clone(),
+        }
+    }
+}
+
+/// Split `entry` into a manifest entry and one blob entry per object.
+fn split_entry(
+    entry: CacheWrite,
+    compression: CacheCompression,
+) -> Result<(CacheWrite, Vec<(String, CacheWrite)>)> {
+    let mut entry = CacheRead::from(entry.finish_reader()?)?;
+    let mut objects = vec![];
+    let mut blobs = vec![];
+    for name in entry.object_names()? {
+        let mut writer = DigestWriter::new(NamedTempFile::new()?);
+        let mode = entry.get_object(&name, &mut writer)?;
+        let (digest, contents) = writer.finish();
+        let mut blob = CacheWrite::with_compression(compression)?;
+        blob.put_object(BLOB_OBJECT_NAME, &mut contents.reopen()?, mode)?;
+        blobs.push((digest.clone(), blob));
+        objects.push(ManifestObject { name, digest, mode });
+    }
+    let manifest = serde_json::to_vec(&objects)?;
+    let mut entry = CacheWrite::with_compression(compression)?;
+    entry.put_object(MANIFEST_OBJECT_NAME, &mut &manifest[..], None)?;
+    Ok((entry, blobs))
+}
+
+/// Read the manifest from `entry`, if it has one.
+fn read_manifest(entry: &mut CacheRead) -> Result<Option<Vec<ManifestObject>>> {
+    if !entry
+        .object_names()?
+        .iter()
+        .any(|name| name == MANIFEST_OBJECT_NAME)
+    {
+        return Ok(None);
+    }
+    let mut manifest = vec![];
+    entry.get_object(MANIFEST_OBJECT_NAME, &mut manifest)?;
+    Ok(Some(serde_json::from_slice(&manifest)?))
+}
+
+/// Rebuild an entry from its manifest and the blobs it refers to.
+///
+/// A blob that doesn't match its checksum is reported as a corrupt object of
+/// the entry, under the object's name.
+fn assemble_entry(objects: Vec<ManifestObject>, blobs: Vec<CacheRead>) -> Result<CacheRead> {
+    // The objects have already been compressed in the blobs, and the
+    // reassembled entry only lives in a local temporary file.
+    let mut entry = CacheWrite::with_compression(CacheCompression::None)?;
+    for (object, mut blob) in objects.into_iter().zip(blobs) {
+        let mut contents = NamedTempFile::new()?;
+        match blob.get_object(BLOB_OBJECT_NAME, &mut contents) {
+            Ok(_) => {}
+            Err(Error(ErrorKind::CorruptCacheEntry(_), _)) => {
+                bail!(ErrorKind::CorruptCacheEntry(object.name))
+            }
+            Err(e) => return Err(e),
+        }
+        entry.put_object(&object.name, &mut contents.reopen()?, object.mode)?;
+    }
+    CacheRead::from(entry.finish_reader()?)
+}
+
+impl Storage for DedupCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let inner = self.inner.clone();
+        let pool = self.pool.clone();
+        let key = key.to_owned();
+        Box::new(
+            self.inner
+                .get(&key)
+                .and_then(move |result| -> SFuture<Cache> {
+                    let mut entry = match result {
+                        Cache::Hit(entry) => entry,
+                        result => return f_ok(result),
+                    };
+                    let objects = match ftry!(read_manifest(&mut entry)) {
+                        Some(objects) => objects,
+                        None => return f_ok(Cache::Hit(entry)),
+                    };
+                    let lookups = objects
+                        .iter()
+                        .map(|object| inner.get(&object.digest))
+                        .collect::<Vec<_>>();
+                    Box::new(
+                        future::join_all(lookups).and_then(move |results| -> SFuture<Cache> {
+                            let mut blobs = vec![];
+                            for result in results {
+                                match result {
+                                    Cache::Hit(blob) => blobs.push(blob),
+                                    _ => {
+                                        debug!("DedupCache::get({}): missing blob", key);
+                                        return f_ok(Cache::Miss);
+                                    }
+                                }
+                            }
+                            let digests: HashMap<_, _> = objects
+                                .iter()
+                                .map(|object| (object.name.clone(), object.digest.clone()))
+                                .collect();
+                            let assemble = pool
+                                .spawn_fn(move || assemble_entry(objects, blobs).map(Cache::Hit));
+                            Box::new(assemble.or_else(move |e| -> SFuture<Cache> {
+                                let digest = match e {
+                                    Error(ErrorKind::CorruptCacheEntry(ref name), _) => {
+                                        digests.get(name).cloned()
+                                    }
+                                    _ => None,
+                                };
+                                let digest = match digest {
+                                    Some(digest) => digest,
+                                    None => return f_err(e),
+                                };
+                                // `put` leaves stored blobs alone, so a corrupt blob
+                                // has to be removed for it to be written again.
+                                warn!("DedupCache::get({}): removing corrupt blob {}", key, digest);
+                                Box::new(inner.remove(&digest).then(move |removed| {
+                                    if let Err(removed) = removed {
+                                        warn!(
+                                            "Failed to remove corrupt blob {}: {}",
+                                            digest, removed
+                                        );
+                                    }
+                                    Err(e)
+                                }))
+                            }))
+                        }),
+                    )
+                }),
+        )
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let inner = self.inner.clone();
+        let compression = self.compression;
+        let key = key.to_owned();
+        let split = self.pool.spawn_fn(move || split_entry(entry, compression));
+        Box::new(split.and_then(move |(entry, blobs)| {
+            // Write the blobs first, so that a stored manifest never refers to
+            // a blob that doesn't exist yet. Blobs are keyed by their contents,
+            // so those that are already stored are left alone.
+            let writes = blobs
+                .into_iter()
+                .map(|(digest, blob)| {
+                    let inner = inner.clone();
+                    inner.stat(&digest).then(move |result| -> SFuture<()> {
+                        match result {
+                            Ok(Some(_)) => {
+                                trace!("DedupCache::put: blob {} already stored", digest);
+                                f_ok(())
+                            }
+                            Ok(None) => Box::new(inner.put(&digest, blob).map(|_| ())),
+                            Err(e) => {
+                                debug!("DedupCache::put: failed to check blob {}: {}", digest, e);
+                                Box::new(inner.put(&digest, blob).map(|_| ()))
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            future::join_all(writes)
+                .and_then(move |_| inner.put(&key, entry))
+                .map(move |_| start.elapsed())
+        }))
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        // Blobs may be shared with other entries, so only the manifest can
+        // be removed.
+        self.inner.remove(key)
+    }
+
+    fn list(&self) -> SFuture<Vec<EntryInfo>> {
+        // Blobs are listed alongside the manifests. Pruning one that's still
+        // used just turns the entries that refer to it into misses.
+        self.inner.list()
+    }
+
+    fn stat(&self, key: &str) -> SFuture<Option<EntryInfo>> {
+        self.inner.stat(key)
+    }
+
+    fn location(&self) -> String {
+        format!("{} (deduplicated)", self.inner.location())
+    }
+
+    fn rw_mode(&self) -> CacheRWMode {
+        self.inner.rw_mode()
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.inner.current_size()
+    }
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.inner.max_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::u64;
+    use tempdir::TempDir;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn make_entry(objects: &[(&str, &[u8])]) -> CacheWrite {
+        let mut entry = CacheWrite::new().unwrap();
+        for &(name, contents) in objects {
+            entry.put_object(name, &mut &contents[..], None).unwrap();
+        }
+        entry
+    }
+
+    fn read_object(cache: Cache, name: &str) -> Vec<u8> {
+        match cache {
+            Cache::Hit(mut entry) => {
+                let mut data = vec![];
+                entry.get_object(name, &mut data).unwrap();
+                data
+            }
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_dedup_cache_shares_blobs() {
+        let tempdir = TempDir::new("sccache_test_dedup").unwrap();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let disk = DiskCache::new(&tempdir.path(), u64::MAX, CacheRWMode::ReadWrite, &pool);
+        let cache = DedupCache::new(Arc::new(disk.clone()), Default::default(), &pool);
+
+        runtime
+            .block_on(cache.put(
+                "abcd",
+                make_entry(&[("obj", &b"same"[..]), ("stdout", &b"a"[..])]),
+            ))
+            .unwrap();
+        runtime
+            .block_on(cache.put(
+                "efgh",
+                make_entry(&[("obj", &b"same"[..]), ("stdout", &b"b"[..])]),
+            ))
+            .unwrap();
+
+        let hit = runtime.block_on(cache.get("abcd")).unwrap();
+        assert_eq!(read_object(hit, "stdout"), b"a");
+        let hit = runtime.block_on(cache.get("efgh")).unwrap();
+        assert_eq!(read_object(hit, "obj"), b"same");
+        // Two manifests, and three distinct blobs.
+        let entries = walkdir::WalkDir::new(tempdir.path())
+            .into_iter()
+            .filter(|e| e.as_ref().unwrap().file_type().is_file())
+            .count();
+        assert_eq!(entries, 5);
+    }
+
+    /// A storage that counts the entries put in it.
+    struct CountingStorage {
+        inner: DiskCache,
+        puts: Arc<AtomicUsize>,
+    }
+
+    impl Storage for CountingStorage {
+        fn get(&self, key: &str) -> SFuture<Cache> {
+            self.inner.get(key)
+        }
+        fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+            self.puts.fetch_add(1, Ordering::SeqCst);
+            self.inner.put(key, entry)
+        }
+        fn stat(&self, key: &str) -> SFuture<Option<EntryInfo>> {
+            self.inner.stat(key)
+        }
+        fn location(&self) -> String {
+            self.inner.location()
+        }
+        fn rw_mode(&self) -> CacheRWMode {
+            self.inner.rw_mode()
+        }
+        fn current_size(&self) -> SFuture<Option<u64>> {
+            self.inner.current_size()
+        }
+        fn max_size(&self) -> SFuture<Option<u64>> {
+            self.inner.max_size()
+        }
+    }
+
+    #[test]
+    fn test_dedup_cache_skips_stored_blobs() {
+        let tempdir = TempDir::new("sccache_test_dedup").unwrap();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let puts = Arc::new(AtomicUsize::new(0));
+        let storage = CountingStorage {
+            inner: DiskCache::new(&tempdir.path(), u64::MAX, CacheRWMode::ReadWrite, &pool),
+            puts: puts.clone(),
+        };
+        let cache = DedupCache::new(Arc::new(storage), Default::default(), &pool);
+
+        runtime
+            .block_on(cache.put("abcd", make_entry(&[("obj", &b"same"[..])])))
+            .unwrap();
+        // One blob and the manifest.
+        assert_eq!(puts.load(Ordering::SeqCst), 2);
+        runtime
+            .block_on(cache.put("efgh", make_entry(&[("obj", &b"same"[..])])))
+            .unwrap();
+        // Only the second manifest.
+        assert_eq!(puts.load(Ordering::SeqCst), 3);
+        let hit = runtime.block_on(cache.get("efgh")).unwrap();
+        assert_eq!(read_object(hit, "obj"), b"same");
+    }
+
+    #[test]
+    fn test_dedup_cache_plain_entries() {
+        let tempdir = TempDir::new("sccache_test_dedup").unwrap();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let disk = DiskCache::new(&tempdir.path(), u64::MAX, CacheRWMode::ReadWrite, &pool);
+        runtime
+            .block_on(disk.put("abcd", make_entry(&[("obj", &b"plain"[..])])))
+            .unwrap();
+
+        let cache = DedupCache::new(Arc::new(disk), Default::default(), &pool);
+        let hit = runtime.block_on(cache.get("abcd")).unwrap();
+        assert_eq!(read_object(hit, "obj"), b"plain");
+    }
+
+    #[test]
+    fn test_dedup_cache_list() {
+        let tempdir = TempDir::new("sccache_test_dedup").unwrap();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let disk = DiskCache::new(&tempdir.path(), u64::MAX, CacheRWMode::ReadWrite, &pool);
+        let cache = DedupCache::new(Arc::new(disk), Default::default(), &pool);
+        runtime
+            .block_on(cache.put("abcd", make_entry(&[("obj", &b"contents"[..])])))
+            .unwrap();
+
+        // The manifest and its blob.
+        let entries = runtime.block_on(cache.list()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(runtime.block_on(cache.stat("abcd")).unwrap().is_some());
+        assert!(runtime.block_on(cache.stat("efgh")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_dedup_cache_replaces_corrupt_blobs() {
+        let tempdir = TempDir::new("sccache_test_dedup").unwrap();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let disk = DiskCache::new(&tempdir.path(), u64::MAX, CacheRWMode::ReadWrite, &pool);
+        let cache = DedupCache::new(Arc::new(disk), CacheCompression::None, &pool);
+        let contents = &b"some contents"[..];
+        runtime
+            .block_on(cache.put("abcd", make_entry(&[("obj", contents)])))
+            .unwrap();
+
+        // Flip a byte of the object in its blob.
+        let blob = walkdir::WalkDir::new(tempdir.path())
+            .into_iter()
+            .map(|e| e.unwrap().into_path())
+            .filter(|path| path.is_file())
+            .find(|path| {
+                let data = fs::read(path).unwrap();
+                data.windows(contents.len()).any(|w| w == contents)
+            })
+            .unwrap();
+        let mut data = fs::read(&blob).unwrap();
+        let pos = data
+            .windows(contents.len())
+            .position(|w| w == contents)
+            .unwrap();
+        data[pos] ^= 0xff;
+        fs::write(&blob, data).unwrap();
+        match runtime.block_on(cache.get("abcd")) {
+            Err(Error(ErrorKind::CorruptCacheEntry(ref name), _)) if name == "obj" => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        // Storing the entry again replaces the corrupt blob.
+        runtime
+            .block_on(cache.put("abcd", make_entry(&[("obj", contents)])))
+            .unwrap();
+        let hit = runtime.block_on(cache.get("abcd")).unwrap();
+        assert_eq!(read_object(hit, "obj"), contents);
+    }
+}
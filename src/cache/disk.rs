@@ -12,14 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{Cache, CacheRead, CacheWrite, EntryInfo, Storage};
+use crate::config::CacheRWMode;
 use futures_cpupool::CpuPool;
 use lru_disk_cache::Error as LruError;
 use lru_disk_cache::LruDiskCache;
 use std::ffi::OsStr;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use walkdir::WalkDir;
 
 use crate::errors::*;
 
@@ -30,26 +34,84 @@ pub struct DiskCache {
     lru: Arc<Mutex<LruDiskCache>>,
     /// Thread pool to execute disk I/O
     pool: CpuPool,
+    /// Whether the cache may be read from and written to.
+    rw_mode: CacheRWMode,
 }
 
 impl DiskCache {
     /// Create a new `DiskCache` rooted at `root`, with `max_size` as the maximum cache size on-disk, in bytes.
-    pub fn new<T: AsRef<OsStr>>(root: &T, max_size: u64, pool: &CpuPool) -> DiskCache {
+    pub fn new<T: AsRef<OsStr>>(
+        root: &T,
+        max_size: u64,
+        rw_mode: CacheRWMode,
+        pool: &CpuPool,
+    ) -> DiskCache {
         DiskCache {
             //TODO: change this function to return a Result
             lru: Arc::new(Mutex::new(
                 LruDiskCache::new(root, max_size).expect("Couldn't instantiate disk cache!"),
             )),
             pool: pool.clone(),
+            rw_mode,
         }
     }
 }
 
 /// Make a path to the cache entry with key `key`.
-fn make_key_path(key: &str) -> PathBuf {
+pub(crate) fn make_key_path(key: &str) -> PathBuf {
     Path::new(&key[0..1]).join(&key[1..2]).join(key)
 }
 
+/// Get the size and modification time of the entry with key `key` stored
+/// under `root`, if there is one.
+pub(crate) fn stat_entry(root: &Path, key: &str) -> Result<Option<EntryInfo>> {
+    match fs::metadata(root.join(make_key_path(key))) {
+        Ok(metadata) => Ok(Some(EntryInfo {
+            key: key.to_owned(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        })),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// List the entries stored under `root` at the paths `make_key_path` gives,
+/// skipping any other files.
+pub(crate) fn list_entries(root: &Path) -> Result<Vec<EntryInfo>> {
+    let mut entries = vec![];
+    for entry in WalkDir::new(root).min_depth(3).max_depth(3) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Entries may be removed while listing.
+            Err(e) => {
+                trace!("list_entries: {}", e);
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let key = match entry.file_name().to_str() {
+            Some(key) if key.len() >= 2 && key.is_char_boundary(2) => key,
+            _ => continue,
+        };
+        if entry.path().strip_prefix(root).ok() != Some(make_key_path(key).as_path()) {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        entries.push(EntryInfo {
+            key: key.to_owned(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
 impl Storage for DiskCache {
     fn get(&self, key: &str) -> SFuture<Cache> {
         trace!("DiskCache::get({})", key);
@@ -76,23 +138,51 @@ impl Storage for DiskCache {
     }
 
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
-        // We should probably do this on a background thread if we're going to buffer
-        // everything in memory...
         trace!("DiskCache::finish_put({})", key);
         let lru = self.lru.clone();
         let key = make_key_path(key);
         Box::new(self.pool.spawn_fn(move || {
             let start = Instant::now();
-            let v = entry.finish()?;
-            lru.lock().unwrap().insert_bytes(key, &v)?;
+            let mut reader = entry.finish_reader()?;
+            lru.lock().unwrap().insert_with(key, |mut f| {
+                io::copy(&mut reader, &mut f)?;
+                Ok(())
+            })?;
             Ok(start.elapsed())
         }))
     }
 
+    fn remove(&self, key: &str) -> SFuture<()> {
+        trace!("DiskCache::remove({})", key);
+        let lru = self.lru.clone();
+        let path = make_key_path(key);
+        Box::new(self.pool.spawn_fn(move || -> Result<()> {
+            match lru.lock().unwrap().remove(&path) {
+                Ok(()) | Err(LruError::FileNotInCache) => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        }))
+    }
+
+    fn list(&self) -> SFuture<Vec<EntryInfo>> {
+        let root = self.lru.lock().unwrap().path().to_owned();
+        Box::new(self.pool.spawn_fn(move || list_entries(&root)))
+    }
+
+    fn stat(&self, key: &str) -> SFuture<Option<EntryInfo>> {
+        let root = self.lru.lock().unwrap().path().to_owned();
+        let key = key.to_owned();
+        Box::new(self.pool.spawn_fn(move || stat_entry(&root, &key)))
+    }
+
     fn location(&self) -> String {
         format!("Local disk: {:?}", self.lru.lock().unwrap().path())
     }
 
+    fn rw_mode(&self) -> CacheRWMode {
+        self.rw_mode
+    }
+
     fn current_size(&self) -> SFuture<Option<u64>> {
         f_ok(Some(self.lru.lock().unwrap().size()))
     }
@@ -13,10 +13,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{cell::RefCell, fmt, io, rc::Rc, time};
+use std::{cell::RefCell, env, fmt, fs, io, path::Path, rc::Rc, time};
 
 use crate::{
-    cache::{Cache, CacheRead, CacheWrite, Storage},
+    cache::{
+        normalize_key_prefix, reader_len, Cache, CacheRead, CacheWrite, ReadSeek, ReaderStream,
+        Storage,
+    },
+    config::CacheRWMode,
     errors::*,
     util::HeadersExt,
 };
@@ -24,7 +28,8 @@ use futures::{
     future::{self, Shared},
     Async, Future, Stream,
 };
-use hyper::Method;
+use futures_cpupool::CpuPool;
+use hyper::{Body, Method};
 use hyperx::header::{Authorization, Bearer, ContentLength, ContentType};
 use reqwest::r#async::{Client, Request};
 use serde::de;
@@ -33,6 +38,13 @@ use url::{
     percent_encoding::{percent_encode, PATH_SEGMENT_ENCODE_SET, QUERY_ENCODE_SET},
 };
 
+/// The host of the metadata server on Compute Engine and GKE, unless
+/// `GCE_METADATA_HOST` says otherwise.
+const METADATA_HOST: &str = "metadata.google.internal";
+
+/// How long before a token expires to start requesting a new one.
+const TOKEN_EXPIRY_MARGIN_SECS: i64 = 60;
+
 /// GCS bucket
 struct Bucket {
     name: String,
@@ -94,12 +106,16 @@ impl Bucket {
         }))
     }
 
+    /// Upload the contents of `content` to `key`, streaming it from the reader
+    /// on `pool`.
     fn put(
         &self,
         key: &str,
-        content: Vec<u8>,
+        mut content: Box<dyn ReadSeek>,
         cred_provider: &Option<GCSCredentialProvider>,
+        pool: &CpuPool,
     ) -> SFuture<()> {
+        let content_length = ftry!(reader_len(&mut content));
         let url = format!(
             "https://www.googleapis.com/upload/storage/v1/b/{}/o?name={}&uploadType=media",
             percent_encode(self.name.as_bytes(), PATH_SEGMENT_ENCODE_SET),
@@ -107,6 +123,7 @@ impl Bucket {
         );
 
         let client = self.client.clone();
+        let pool = pool.clone();
 
         let creds_opt_future = if let &Some(ref cred_provider) = cred_provider {
             future::Either::A(cred_provider.credentials(&self.client).map(Some))
@@ -122,9 +139,9 @@ impl Bucket {
                     headers.set(Authorization(Bearer { token: creds.token }));
                 }
                 headers.set(ContentType::octet_stream());
-                headers.set(ContentLength(content.len() as u64));
+                headers.set(ContentLength(content_length));
             }
-            *request.body_mut() = Some(content.into());
+            *request.body_mut() = Some(Body::wrap_stream(ReaderStream::new(content, &pool)).into());
 
             client.execute(request).then(|result| match result {
                 Ok(res) => {
@@ -155,11 +172,55 @@ pub struct GCSCredentialProvider {
     cached_credentials: RefCell<Option<Shared<SFuture<GCSCredential>>>>,
 }
 
-/// ServiceAccountInfo either contains a URL to fetch the oauth token
-/// or the service account key
+/// ServiceAccountInfo either contains a URL to fetch the oauth token,
+/// the service account key, or the host of the metadata server to fetch
+/// tokens for the default service account from.
 pub enum ServiceAccountInfo {
     URL(String),
     AccountKey(ServiceAccountKey),
+    /// On GKE with workload identity, the metadata server hands out tokens
+    /// for the pod's Kubernetes service account instead.
+    MetadataServer(String),
+}
+
+impl ServiceAccountInfo {
+    /// Find the application default credentials: the service account key
+    /// named by `GOOGLE_APPLICATION_CREDENTIALS`, or else the metadata server
+    /// when running on Google Cloud.
+    pub fn application_default() -> Option<ServiceAccountInfo> {
+        if let Some(path) = env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+            match read_service_account_key(Path::new(&path)) {
+                Ok(key) => return Some(ServiceAccountInfo::AccountKey(key)),
+                Err(e) => warn!(
+                    "Failed to read GOOGLE_APPLICATION_CREDENTIALS {:?}: {}",
+                    path, e
+                ),
+            }
+        }
+        if let Ok(host) = env::var("GCE_METADATA_HOST") {
+            return Some(ServiceAccountInfo::MetadataServer(host));
+        }
+        if on_google_cloud() {
+            return Some(ServiceAccountInfo::MetadataServer(METADATA_HOST.to_owned()));
+        }
+        None
+    }
+}
+
+/// Read a JSON service account key from `path`.
+pub fn read_service_account_key(path: &Path) -> Result<ServiceAccountKey> {
+    let service_account_json = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&service_account_json)?)
+}
+
+/// Whether this is a Compute Engine instance, or a GKE node or pod, and so
+/// has a metadata server. Probing the server itself would stall for a while
+/// everywhere else, so go by the machine's DMI product name, like the
+/// Google Cloud client libraries do.
+fn on_google_cloud() -> bool {
+    fs::read_to_string("/sys/class/dmi/id/product_name")
+        .map(|name| name.trim().starts_with("Google"))
+        .unwrap_or(false)
 }
 
 fn deserialize_gcp_key<'de, D>(deserializer: D) -> std::result::Result<Vec<u8>, D::Error>
@@ -238,6 +299,14 @@ struct TokenMsg {
     access_token: String,
 }
 
+/// MetadataTokenMsg is a subset of the metadata server's response to a token request.
+#[derive(Deserialize)]
+struct MetadataTokenMsg {
+    access_token: String,
+    /// The number of seconds the token is valid for.
+    expires_in: i64,
+}
+
 /// AuthResponse represents the json response body from taskcluster-auth.gcsCredentials endpoint
 #[derive(Deserialize)]
 struct AuthResponse {
@@ -435,13 +504,59 @@ impl GCSCredentialProvider {
         )
     }
 
+    fn request_new_token_from_metadata_server(
+        &self,
+        host: &str,
+        client: &Client,
+    ) -> SFuture<GCSCredential> {
+        let url = format!(
+            "http://{}/computeMetadata/v1/instance/service-accounts/default/token",
+            host
+        );
+        Box::new(
+            client
+                .get(&url)
+                .header("Metadata-Flavor", "Google")
+                .send()
+                .map_err(Into::into)
+                .and_then(move |res| {
+                    if res.status().is_success() {
+                        Ok(res.into_body())
+                    } else {
+                        Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+                    }
+                })
+                .and_then(move |body| {
+                    body.fold(Vec::new(), |mut body, chunk| {
+                        body.extend_from_slice(&chunk);
+                        Ok::<_, reqwest::Error>(body)
+                    })
+                    .chain_err(|| "failed to read HTTP body")
+                })
+                .and_then(move |body| {
+                    let body_str = String::from_utf8(body)?;
+                    let token_msg: MetadataTokenMsg = serde_json::from_str(&body_str)?;
+                    Ok(GCSCredential {
+                        token: token_msg.access_token,
+                        expiration_time: chrono::offset::Utc::now()
+                            + chrono::Duration::seconds(token_msg.expires_in),
+                    })
+                }),
+        )
+    }
+
+    /// Return a token, requesting a new one if the cached one is about to
+    /// expire, or if requesting it failed.
     pub fn credentials(&self, client: &Client) -> SFuture<GCSCredential> {
         let mut future_opt = self.cached_credentials.borrow_mut();
 
+        let refresh_at =
+            chrono::offset::Utc::now() + chrono::Duration::seconds(TOKEN_EXPIRY_MARGIN_SECS);
         let needs_refresh = match Option::as_mut(&mut future_opt).map(|f| f.poll()) {
             None => true,
-            Some(Ok(Async::Ready(ref creds))) => creds.expiration_time < chrono::offset::Utc::now(),
-            _ => false,
+            Some(Ok(Async::Ready(ref creds))) => creds.expiration_time < refresh_at,
+            Some(Err(_)) => true,
+            Some(Ok(Async::NotReady)) => false,
         };
 
         if needs_refresh {
@@ -450,6 +565,9 @@ impl GCSCredentialProvider {
                     self.request_new_token(sa_key, client)
                 }
                 ServiceAccountInfo::URL(ref url) => self.request_new_token_from_tcauth(url, client),
+                ServiceAccountInfo::MetadataServer(ref host) => {
+                    self.request_new_token_from_metadata_server(host, client)
+                }
             };
             *future_opt = Some(credentials.shared());
         };
@@ -472,8 +590,12 @@ pub struct GCSCache {
     bucket: Rc<Bucket>,
     /// Credential provider for GCS
     credential_provider: Option<GCSCredentialProvider>,
+    /// The prefix of the object names entries are stored under
+    key_prefix: String,
     /// Read-only or not
-    rw_mode: RWMode,
+    rw_mode: CacheRWMode,
+    /// The pool to read entries being uploaded on
+    pool: CpuPool,
 }
 
 impl GCSCache {
@@ -481,18 +603,23 @@ impl GCSCache {
     pub fn new(
         bucket: String,
         credential_provider: Option<GCSCredentialProvider>,
-        rw_mode: RWMode,
+        key_prefix: &str,
+        rw_mode: CacheRWMode,
+        pool: &CpuPool,
     ) -> Result<GCSCache> {
         Ok(GCSCache {
             bucket: Rc::new(Bucket::new(bucket)?),
+            key_prefix: normalize_key_prefix(key_prefix),
             rw_mode: rw_mode,
             credential_provider: credential_provider,
+            pool: pool.clone(),
         })
     }
 }
 
 impl Storage for GCSCache {
     fn get(&self, key: &str) -> SFuture<Cache> {
+        let key = format!("{}{}", self.key_prefix, key);
         Box::new(
             self.bucket
                 .get(&key, &self.credential_provider)
@@ -510,18 +637,19 @@ impl Storage for GCSCache {
     }
 
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<time::Duration> {
-        if let RWMode::ReadOnly = self.rw_mode {
+        if !self.rw_mode.can_write() {
             return Box::new(future::ok(time::Duration::new(0, 0)));
         }
 
+        let key = format!("{}{}", self.key_prefix, key);
         let start = time::Instant::now();
-        let data = match entry.finish() {
+        let data = match entry.finish_reader() {
             Ok(data) => data,
             Err(e) => return Box::new(future::err(e.into())),
         };
         let bucket = self.bucket.clone();
         let response = bucket
-            .put(&key, data, &self.credential_provider)
+            .put(&key, data, &self.credential_provider, &self.pool)
             .chain_err(|| "failed to put cache entry in GCS");
 
         Box::new(response.map(move |_| start.elapsed()))
@@ -531,6 +659,10 @@ impl Storage for GCSCache {
         format!("GCS, bucket: {}", self.bucket)
     }
 
+    fn rw_mode(&self) -> CacheRWMode {
+        self.rw_mode
+    }
+
     fn current_size(&self) -> SFuture<Option<u64>> {
         Box::new(future::ok(None))
     }
@@ -577,3 +709,76 @@ fn test_gcs_credential_provider() {
 
     server.with_graceful_shutdown(cred_fut);
 }
+
+#[test]
+fn test_gcs_metadata_server_credential_provider() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::runtime::current_thread::Runtime;
+
+    // Serve tokens that expire within the refresh margin, so that every
+    // call to `credentials` requests a new one.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let server_requests = requests.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut flavor = false;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if line.to_ascii_lowercase() == "metadata-flavor: google" {
+                    flavor = true;
+                }
+            }
+            let n = server_requests.fetch_add(1, Ordering::SeqCst);
+            let expected_path = "/computeMetadata/v1/instance/service-accounts/default/token";
+            let (status, body) = if flavor && request_line.contains(expected_path) {
+                let token = serde_json::json!({
+                    "access_token": format!("token{}", n),
+                    "expires_in": 30,
+                    "token_type": "Bearer",
+                });
+                ("200 OK", token.to_string())
+            } else {
+                ("403 Forbidden", String::new())
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+    });
+
+    let credential_provider =
+        GCSCredentialProvider::new(RWMode::ReadWrite, ServiceAccountInfo::MetadataServer(host));
+    let client = Client::new();
+    let mut runtime = Runtime::new().unwrap();
+
+    let credential = runtime
+        .block_on(future::lazy(|| credential_provider.credentials(&client)))
+        .unwrap();
+    assert_eq!(credential.token, "token0");
+    assert!(credential.expiration_time > chrono::offset::Utc::now());
+
+    let credential = runtime
+        .block_on(future::lazy(|| credential_provider.credentials(&client)))
+        .unwrap();
+    assert_eq!(credential.token, "token1");
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
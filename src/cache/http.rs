@@ -0,0 +1,380 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{
+    normalize_key_prefix, reader_len, Cache, CacheRead, CacheWrite, EntryInfo, ReaderStream,
+    Storage,
+};
+use crate::config::{CacheRWMode, HttpCacheAuth};
+use crate::util::HeadersExt;
+use futures::{Future, Stream};
+use futures_cpupool::CpuPool;
+use hyper::{Body, Method, StatusCode};
+use hyperx::header::{Authorization, Basic, Bearer, ContentLength, ContentType, LastModified};
+use reqwest::r#async::{Client, Request};
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::errors::*;
+
+/// A cache that stores entries on an HTTP server supporting `GET`, `PUT` and
+/// `DELETE`, such as a WebDAV share.
+pub struct HttpCache {
+    /// The URL entries are stored under, including any key prefix, ending
+    /// with a `/`.
+    base_url: String,
+    /// Credentials to send with each request.
+    auth: Option<HttpCacheAuth>,
+    client: Client,
+    /// Whether the cache may be read from and written to.
+    rw_mode: CacheRWMode,
+    /// The pool to read entries being uploaded on.
+    pool: CpuPool,
+}
+
+impl HttpCache {
+    /// Create a new `HttpCache` storing entries under `url`, in the
+    /// `key_prefix` subdirectory.
+    pub fn new(
+        url: &str,
+        auth: Option<HttpCacheAuth>,
+        key_prefix: &str,
+        rw_mode: CacheRWMode,
+        pool: &CpuPool,
+    ) -> Result<HttpCache> {
+        let base_url = format!(
+            "{}/{}",
+            url.trim_end_matches('/'),
+            normalize_key_prefix(key_prefix)
+        );
+        base_url
+            .parse::<reqwest::Url>()
+            .chain_err(|| format!("Invalid HTTP cache URL: {}", url))?;
+        Ok(HttpCache {
+            base_url,
+            auth,
+            client: Client::new(),
+            rw_mode,
+            pool: pool.clone(),
+        })
+    }
+
+    /// Create a request for the entry with key `key`.
+    fn request(&self, method: Method, key: &str) -> Request {
+        let url = format!("{}{}", self.base_url, key);
+        let mut request = Request::new(method, url.parse().unwrap());
+        match self.auth {
+            Some(HttpCacheAuth::Bearer { ref token }) => {
+                request.headers_mut().set(Authorization(Bearer {
+                    token: token.to_owned(),
+                }));
+            }
+            Some(HttpCacheAuth::Basic {
+                ref username,
+                ref password,
+            }) => {
+                request.headers_mut().set(Authorization(Basic {
+                    username: username.to_owned(),
+                    password: password.to_owned(),
+                }));
+            }
+            None => {}
+        }
+        request
+    }
+}
+
+impl Storage for HttpCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let request = self.request(Method::GET, key);
+        let url = request.url().to_string();
+        debug!("GET {}", url);
+        Box::new(
+            self.client
+                .execute(request)
+                .chain_err(move || format!("failed GET: {}", url))
+                .and_then(|res| -> SFuture<Cache> {
+                    if res.status() == StatusCode::NOT_FOUND {
+                        return f_ok(Cache::Miss);
+                    }
+                    if !res.status().is_success() {
+                        return f_err(ErrorKind::BadHTTPStatus(res.status().clone()));
+                    }
+                    let file =
+                        ftry!(tempfile::tempfile().chain_err(|| "failed to create temporary file"));
+                    Box::new(
+                        res.into_body()
+                            .map_err(Error::from)
+                            .fold(file, |mut file, chunk| -> Result<_> {
+                                file.write_all(&chunk)?;
+                                Ok(file)
+                            })
+                            .chain_err(|| "failed to read HTTP body")
+                            .and_then(|mut file: File| {
+                                file.seek(SeekFrom::Start(0))?;
+                                Ok(Cache::Hit(CacheRead::from(file)?))
+                            }),
+                    )
+                }),
+        )
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let mut reader = ftry!(entry.finish_reader());
+        let content_length = ftry!(reader_len(&mut reader));
+        let mut request = self.request(Method::PUT, key);
+        debug!("PUT {}", request.url());
+        request.headers_mut().set(ContentType::octet_stream());
+        request.headers_mut().set(ContentLength(content_length));
+        *request.body_mut() = Some(Body::wrap_stream(ReaderStream::new(reader, &self.pool)).into());
+        Box::new(
+            self.client
+                .execute(request)
+                .then(move |result| match result {
+                    Ok(res) => {
+                        if res.status().is_success() {
+                            trace!("PUT succeeded");
+                            Ok(start.elapsed())
+                        } else {
+                            trace!("PUT failed with HTTP status: {}", res.status());
+                            Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+                        }
+                    }
+                    Err(e) => {
+                        trace!("PUT failed with error: {:?}", e);
+                        Err(e.into())
+                    }
+                }),
+        )
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let request = self.request(Method::DELETE, key);
+        debug!("DELETE {}", request.url());
+        Box::new(self.client.execute(request).then(|result| match result {
+            Ok(ref res) if res.status().is_success() || res.status() == StatusCode::NOT_FOUND => {
+                Ok(())
+            }
+            Ok(res) => Err(ErrorKind::BadHTTPStatus(res.status().clone()).into()),
+            Err(e) => Err(e.into()),
+        }))
+    }
+
+    fn stat(&self, key: &str) -> SFuture<Option<EntryInfo>> {
+        let request = self.request(Method::HEAD, key);
+        let url = request.url().to_string();
+        debug!("HEAD {}", url);
+        let key = key.to_owned();
+        Box::new(
+            self.client
+                .execute(request)
+                .chain_err(move || format!("failed HEAD: {}", url))
+                .and_then(move |res| -> Result<Option<EntryInfo>> {
+                    if res.status() == StatusCode::NOT_FOUND {
+                        return Ok(None);
+                    }
+                    if !res.status().is_success() {
+                        bail!(ErrorKind::BadHTTPStatus(res.status().clone()));
+                    }
+                    let size = res
+                        .headers()
+                        .get_hyperx::<ContentLength>()
+                        .map(|ContentLength(len)| len)
+                        .ok_or("HEAD response has no Content-Length")?;
+                    let modified = res
+                        .headers()
+                        .get_hyperx::<LastModified>()
+                        .map(|LastModified(date)| SystemTime::from(date));
+                    Ok(Some(EntryInfo {
+                        key,
+                        size,
+                        modified,
+                    }))
+                }),
+        )
+    }
+
+    fn location(&self) -> String {
+        format!("HTTP, url: {}", self.base_url)
+    }
+
+    fn rw_mode(&self) -> CacheRWMode {
+        self.rw_mode
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        f_ok(None)
+    }
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        f_ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use tokio::runtime::current_thread::Runtime;
+
+    type Files = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// Serve a single keep-alive connection, storing `PUT`s in `files`.
+    fn serve_connection(stream: TcpStream, files: Files) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        loop {
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().unwrap().to_owned();
+            let path = parts.next().unwrap().to_owned();
+            let mut content_length = 0;
+            let mut authorized = false;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let lower = line.to_ascii_lowercase();
+                if lower.starts_with("content-length:") {
+                    content_length = line[15..].trim().parse().unwrap();
+                }
+                if lower == "authorization: bearer secret" {
+                    authorized = true;
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let mut files = files.lock().unwrap();
+            let (status, response) = match (authorized, method.as_str()) {
+                (false, _) => ("401 Unauthorized", vec![]),
+                (true, "GET") | (true, "HEAD") => match files.get(&path) {
+                    Some(data) => ("200 OK", data.clone()),
+                    None => ("404 Not Found", vec![]),
+                },
+                (true, "PUT") => {
+                    files.insert(path, body);
+                    ("201 Created", vec![])
+                }
+                (true, "DELETE") => match files.remove(&path) {
+                    Some(_) => ("204 No Content", vec![]),
+                    None => ("404 Not Found", vec![]),
+                },
+                _ => ("405 Method Not Allowed", vec![]),
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+                status,
+                response.len()
+            )
+            .unwrap();
+            if method != "HEAD" {
+                stream.write_all(&response).unwrap();
+            }
+        }
+    }
+
+    fn start_server() -> (String, Files) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/cache", listener.local_addr().unwrap());
+        let files = Files::default();
+        let server_files = files.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let files = server_files.clone();
+                thread::spawn(move || serve_connection(stream.unwrap(), files));
+            }
+        });
+        (url, files)
+    }
+
+    #[test]
+    fn test_http_cache() {
+        let (url, files) = start_server();
+        let mut runtime = Runtime::new().unwrap();
+        let auth = HttpCacheAuth::Bearer {
+            token: "secret".to_owned(),
+        };
+        let pool = CpuPool::new(1);
+        let cache =
+            HttpCache::new(&url, Some(auth), "project", CacheRWMode::ReadWrite, &pool).unwrap();
+
+        match runtime
+            .block_on(future::lazy(|| cache.get("abcd")))
+            .unwrap()
+        {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut &b"contents"[..], None)
+            .unwrap();
+        runtime
+            .block_on(future::lazy(|| cache.put("abcd", entry)))
+            .unwrap();
+        assert!(files.lock().unwrap().contains_key("/cache/project/abcd"));
+        let size = files.lock().unwrap()["/cache/project/abcd"].len() as u64;
+        let info = runtime
+            .block_on(future::lazy(|| cache.stat("abcd")))
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.size, size);
+        assert!(runtime
+            .block_on(future::lazy(|| cache.stat("efgh")))
+            .unwrap()
+            .is_none());
+
+        match runtime
+            .block_on(future::lazy(|| cache.get("abcd")))
+            .unwrap()
+        {
+            Cache::Hit(mut entry) => {
+                let mut data = vec![];
+                entry.get_object("obj", &mut data).unwrap();
+                assert_eq!(data, b"contents");
+            }
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+
+        runtime
+            .block_on(future::lazy(|| cache.remove("abcd")))
+            .unwrap();
+        assert!(files.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_http_cache_unauthorized() {
+        let (url, _) = start_server();
+        let mut runtime = Runtime::new().unwrap();
+        let pool = CpuPool::new(1);
+        let cache = HttpCache::new(&url, None, "", CacheRWMode::ReadWrite, &pool).unwrap();
+        assert!(runtime
+            .block_on(future::lazy(|| cache.get("abcd")))
+            .is_err());
+    }
+}
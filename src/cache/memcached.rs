@@ -13,7 +13,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{
+    normalize_key_prefix, Cache, CacheRead, CacheWrite, CachedSize, Storage, SIZE_REFRESH_INTERVAL,
+};
+use crate::config::CacheRWMode;
 use crate::errors::*;
 use futures_cpupool::CpuPool;
 use memcached::client::Client;
@@ -21,27 +24,94 @@ use memcached::proto::NoReplyOperation;
 use memcached::proto::Operation;
 use memcached::proto::ProtoType::Binary;
 use std::cell::RefCell;
-use std::io::Cursor;
-use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Cursor, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The longest expiration time memcached treats as relative. Longer ones are
+/// taken to be Unix timestamps.
+const MAX_RELATIVE_EXPIRATION: u64 = 30 * 24 * 60 * 60;
+
+/// How long to wait for a server to answer `stats`.
+const STATS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The memory used and the memory limit of the servers.
+type Sizes = (Option<u64>, Option<u64>);
 
 thread_local! {
     static CLIENT: RefCell<Option<Client>> = RefCell::default();
 }
 
-#[derive(Clone)]
 pub struct MemcachedCache {
-    url: String,
+    client: MemcachedClient,
     pool: CpuPool,
+    key_prefix: String,
+    rw_mode: CacheRWMode,
+    /// The memory used and the memory limit of all the servers together.
+    sizes: CachedSize<Sizes>,
+}
+
+/// The settings of a `MemcachedCache` needed to talk to the servers, on the
+/// thread pool.
+#[derive(Clone)]
+struct MemcachedClient {
+    url: String,
+    /// How long entries are kept for, if they expire.
+    ttl: Option<Duration>,
+    /// Whether reading an entry resets its expiry.
+    refresh_on_hit: bool,
 }
 
 impl MemcachedCache {
-    pub fn new(url: &str, pool: &CpuPool) -> Result<MemcachedCache> {
+    pub fn new(
+        url: &str,
+        key_prefix: &str,
+        ttl: Option<Duration>,
+        refresh_on_hit: bool,
+        rw_mode: CacheRWMode,
+        pool: &CpuPool,
+    ) -> Result<MemcachedCache> {
         Ok(MemcachedCache {
-            url: url.to_owned(),
+            client: MemcachedClient {
+                url: url.to_owned(),
+                ttl,
+                refresh_on_hit,
+            },
             pool: pool.clone(),
+            key_prefix: normalize_key_prefix(key_prefix),
+            rw_mode,
+            sizes: CachedSize::new(SIZE_REFRESH_INTERVAL),
         })
     }
 
+    /// Return the memory used and the memory limit of all the servers
+    /// together, asking them in the background at most every
+    /// `SIZE_REFRESH_INTERVAL`, or `None` until they've answered.
+    fn sizes(&self) -> Sizes {
+        let url = self.client.url.clone();
+        let pool = self.pool.clone();
+        self.sizes.get(move || {
+            Box::new(pool.spawn_fn(move || {
+                servers_sizes(&url).chain_err(|| "failed to get Memcached stats")
+            }))
+        })
+    }
+}
+
+impl MemcachedClient {
+    /// The expiration time to store entries with, or 0 if they don't expire.
+    fn expiration(&self) -> u32 {
+        match self.ttl {
+            None => 0,
+            Some(ttl) if ttl.as_secs() <= MAX_RELATIVE_EXPIRATION => ttl.as_secs() as u32,
+            Some(ttl) => (SystemTime::now() + ttl)
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as u32)
+                .unwrap_or(0),
+        }
+    }
+
     fn parse(&self) -> Vec<(&str, usize)> {
         self.url.split_whitespace().map(|w| (w, 1usize)).collect()
     }
@@ -65,36 +135,121 @@ impl MemcachedCache {
     }
 }
 
+/// Add up the memory used and the memory limits of the servers at `url`.
+fn servers_sizes(url: &str) -> Result<Sizes> {
+    let mut used = 0;
+    let mut limit = 0;
+    for server in url.split_whitespace() {
+        let stats = server_stats(server)?;
+        used += stat(&stats, "bytes")?;
+        limit += stat(&stats, "limit_maxbytes")?;
+    }
+    Ok((Some(used), Some(limit)))
+}
+
+/// Ask the server at `url` for its `stats`, over the text protocol.
+fn server_stats(url: &str) -> Result<HashMap<String, String>> {
+    if !url.starts_with("tcp://") {
+        bail!("Can't get the stats of Memcached server {}", url);
+    }
+    let stream = TcpStream::connect(&url["tcp://".len()..])
+        .chain_err(|| format!("failed to connect to Memcached server {}", url))?;
+    stream.set_read_timeout(Some(STATS_TIMEOUT))?;
+    (&stream).write_all(b"stats\r\n")?;
+    parse_stats(BufReader::new(stream))
+}
+
+/// Parse a reply to `stats`, made of `STAT <name> <value>` lines ending with
+/// `END`.
+fn parse_stats<R: BufRead>(reader: R) -> Result<HashMap<String, String>> {
+    let mut stats = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end();
+        if line == "END" {
+            return Ok(stats);
+        }
+        let mut parts = line.splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("STAT"), Some(name), Some(value)) => {
+                stats.insert(name.to_owned(), value.to_owned());
+            }
+            _ => bail!("Invalid Memcached stats line: {:?}", line),
+        }
+    }
+    bail!("Memcached stats ended early")
+}
+
+/// Return the numeric stat `name` from `stats`.
+fn stat(stats: &HashMap<String, String>, name: &str) -> Result<u64> {
+    stats
+        .get(name)
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("Memcached stats have no valid {}", name).into())
+}
+
 impl Storage for MemcachedCache {
     fn get(&self, key: &str) -> SFuture<Cache> {
-        let key = key.to_owned();
-        let me = self.clone();
+        let key = format!("{}{}", self.key_prefix, key);
+        let me = self.client.clone();
         Box::new(self.pool.spawn_fn(move || {
-            me.exec(|c| c.get(&key.as_bytes()))
-                .map(|(d, _)| CacheRead::from(Cursor::new(d)).map(Cache::Hit))
-                .unwrap_or(Ok(Cache::Miss))
+            let d = match me.exec(|c| c.get(&key.as_bytes())) {
+                Ok((d, _)) => d,
+                Err(_) => return Ok(Cache::Miss),
+            };
+            if me.refresh_on_hit && me.ttl.is_some() {
+                let expiration = me.expiration();
+                if let Err(e) = me.exec(|c| c.touch(&key.as_bytes(), expiration)) {
+                    trace!("MemcachedCache::get({}): failed to touch: {:?}", key, e);
+                }
+            }
+            CacheRead::from(Cursor::new(d)).map(Cache::Hit)
         }))
     }
 
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
-        let key = key.to_owned();
-        let me = self.clone();
+        let key = format!("{}{}", self.key_prefix, key);
+        let me = self.client.clone();
         Box::new(self.pool.spawn_fn(move || {
             let start = Instant::now();
             let d = entry.finish()?;
-            me.exec(|c| c.set_noreply(&key.as_bytes(), &d, 0, 0))?;
+            let expiration = me.expiration();
+            me.exec(|c| c.set_noreply(&key.as_bytes(), &d, 0, expiration))?;
             Ok(start.elapsed())
         }))
     }
 
     fn location(&self) -> String {
-        format!("Memcached: {}", self.url)
+        format!("Memcached: {}", self.client.url)
+    }
+
+    fn rw_mode(&self) -> CacheRWMode {
+        self.rw_mode
     }
 
+    /// Returns the memory used by all the servers, as reported by `stats`.
     fn current_size(&self) -> SFuture<Option<u64>> {
-        f_ok(None)
+        f_ok(self.sizes().0)
     }
+    /// Returns the memory limit of all the servers, as reported by `stats`.
     fn max_size(&self) -> SFuture<Option<u64>> {
-        f_ok(None)
+        f_ok(self.sizes().1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_stats() {
+        let reply = "STAT pid 1\r\nSTAT bytes 1024\r\nSTAT limit_maxbytes 67108864\r\n\
+                     STAT version 1.6.9\r\nEND\r\n";
+        let stats = parse_stats(Cursor::new(reply)).unwrap();
+        assert_eq!(stat(&stats, "bytes").unwrap(), 1024);
+        assert_eq!(stat(&stats, "limit_maxbytes").unwrap(), 64 * 1024 * 1024);
+        assert!(stat(&stats, "version").is_err());
+        assert!(parse_stats(Cursor::new("STAT bytes 1024\r\n")).is_err());
+        assert!(parse_stats(Cursor::new("ERROR\r\n")).is_err());
     }
 }
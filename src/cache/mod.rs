@@ -15,14 +15,21 @@
 #[cfg(feature = "azure")]
 pub mod azure;
 pub mod cache;
+pub mod dedup;
 pub mod disk;
 #[cfg(feature = "gcs")]
 pub mod gcs;
+#[cfg(feature = "http-cache")]
+pub mod http;
 #[cfg(feature = "memcached")]
 pub mod memcached;
+#[cfg(feature = "reapi")]
+pub mod reapi;
 #[cfg(feature = "redis")]
 pub mod redis;
 #[cfg(feature = "s3")]
 pub mod s3;
+pub mod shared_dir;
+pub mod tiered;
 
 pub use crate::cache::cache::*;
@@ -0,0 +1,652 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{
+    normalize_key_prefix, Cache, CacheRead, CacheWrite, ReadSeek, ReaderStream, Storage,
+    CACHE_STREAM_CHUNK_SIZE,
+};
+use crate::config::CacheRWMode;
+use futures::{future, stream, Future, Sink, Stream};
+use futures_cpupool::CpuPool;
+use grpcio::{
+    CallOption, ChannelBuilder, Client, Environment, Marshaller, Method, MethodType, RpcStatusCode,
+    WriteFlags,
+};
+use ring::digest::{Context, SHA256};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// The subset of the Remote Execution API and ByteStream messages used here.
+///
+/// See https://github.com/bazelbuild/remote-apis and
+/// https://github.com/googleapis/googleapis/blob/master/google/bytestream/bytestream.proto
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Digest {
+        #[prost(string, tag = "1")]
+        pub hash: String,
+        #[prost(int64, tag = "2")]
+        pub size_bytes: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct OutputFile {
+        #[prost(string, tag = "1")]
+        pub path: String,
+        #[prost(message, optional, tag = "2")]
+        pub digest: Option<Digest>,
+        #[prost(bool, tag = "4")]
+        pub is_executable: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ActionResult {
+        #[prost(message, repeated, tag = "2")]
+        pub output_files: Vec<OutputFile>,
+        #[prost(int32, tag = "4")]
+        pub exit_code: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetActionResultRequest {
+        #[prost(string, tag = "1")]
+        pub instance_name: String,
+        #[prost(message, optional, tag = "2")]
+        pub action_digest: Option<Digest>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct UpdateActionResultRequest {
+        #[prost(string, tag = "1")]
+        pub instance_name: String,
+        #[prost(message, optional, tag = "2")]
+        pub action_digest: Option<Digest>,
+        #[prost(message, optional, tag = "3")]
+        pub action_result: Option<ActionResult>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ReadRequest {
+        #[prost(string, tag = "1")]
+        pub resource_name: String,
+        #[prost(int64, tag = "2")]
+        pub read_offset: i64,
+        #[prost(int64, tag = "3")]
+        pub read_limit: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ReadResponse {
+        #[prost(bytes, tag = "10")]
+        pub data: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WriteRequest {
+        #[prost(string, tag = "1")]
+        pub resource_name: String,
+        #[prost(int64, tag = "2")]
+        pub write_offset: i64,
+        #[prost(bool, tag = "3")]
+        pub finish_write: bool,
+        #[prost(bytes, tag = "10")]
+        pub data: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WriteResponse {
+        #[prost(int64, tag = "1")]
+        pub committed_size: i64,
+    }
+}
+
+const GET_ACTION_RESULT: Method<proto::GetActionResultRequest, proto::ActionResult> = Method {
+    ty: MethodType::Unary,
+    name: "/build.bazel.remote.execution.v2.ActionCache/GetActionResult",
+    req_mar: Marshaller {
+        ser: grpcio::pr_ser,
+        de: grpcio::pr_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpcio::pr_ser,
+        de: grpcio::pr_de,
+    },
+};
+
+const UPDATE_ACTION_RESULT: Method<proto::UpdateActionResultRequest, proto::ActionResult> =
+    Method {
+        ty: MethodType::Unary,
+        name: "/build.bazel.remote.execution.v2.ActionCache/UpdateActionResult",
+        req_mar: Marshaller {
+            ser: grpcio::pr_ser,
+            de: grpcio::pr_de,
+        },
+        resp_mar: Marshaller {
+            ser: grpcio::pr_ser,
+            de: grpcio::pr_de,
+        },
+    };
+
+const BYTESTREAM_READ: Method<proto::ReadRequest, proto::ReadResponse> = Method {
+    ty: MethodType::ServerStreaming,
+    name: "/google.bytestream.ByteStream/Read",
+    req_mar: Marshaller {
+        ser: grpcio::pr_ser,
+        de: grpcio::pr_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpcio::pr_ser,
+        de: grpcio::pr_de,
+    },
+};
+
+const BYTESTREAM_WRITE: Method<proto::WriteRequest, proto::WriteResponse> = Method {
+    ty: MethodType::ClientStreaming,
+    name: "/google.bytestream.ByteStream/Write",
+    req_mar: Marshaller {
+        ser: grpcio::pr_ser,
+        de: grpcio::pr_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpcio::pr_ser,
+        de: grpcio::pr_de,
+    },
+};
+
+/// The path of the single output file recorded in each action result, which
+/// holds the whole cache entry.
+const ENTRY_PATH: &str = "sccache-entry";
+
+/// A cache that stores entries in a Remote Execution API server, such as
+/// Bazel's remote cache or Buildfarm.
+///
+/// Each cache entry is uploaded as a single blob to the content-addressable
+/// storage, and an action result referring to it is stored in the action
+/// cache under a digest of the cache key.
+pub struct ReapiCache {
+    client: Client,
+    /// The address of the server.
+    endpoint: String,
+    /// The instance name to send with each request.
+    instance_name: String,
+    /// The prefix of the keys that action digests are computed from.
+    key_prefix: String,
+    /// Whether the cache may be read from and written to.
+    rw_mode: CacheRWMode,
+    /// The pool to read entries being uploaded on.
+    pool: CpuPool,
+}
+
+impl ReapiCache {
+    /// Create a new `ReapiCache` talking to the server at `endpoint`, either
+    /// `host:port` or `grpc://host:port`.
+    pub fn new(
+        endpoint: &str,
+        instance_name: &str,
+        key_prefix: &str,
+        rw_mode: CacheRWMode,
+        pool: &CpuPool,
+    ) -> Result<ReapiCache> {
+        let address = endpoint.trim_start_matches("grpc://").trim_end_matches('/');
+        if address.is_empty() || address.contains("://") {
+            bail!("Invalid remote execution API endpoint: {}", endpoint);
+        }
+        let env = Arc::new(Environment::new(1));
+        let channel = ChannelBuilder::new(env).connect(address);
+        Ok(ReapiCache {
+            client: Client::new(channel),
+            endpoint: endpoint.to_owned(),
+            instance_name: instance_name.to_owned(),
+            key_prefix: normalize_key_prefix(key_prefix),
+            rw_mode,
+            pool: pool.clone(),
+        })
+    }
+}
+
+impl fmt::Display for ReapiCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.instance_name.is_empty() {
+            write!(f, "{}", self.endpoint)
+        } else {
+            write!(f, "{}, instance: {}", self.endpoint, self.instance_name)
+        }
+    }
+}
+
+/// Return the SHA-256 digest of `reader`, leaving it positioned at its start.
+fn digest_reader<R: Read + Seek + ?Sized>(reader: &mut R) -> Result<proto::Digest> {
+    let mut context = Context::new(&SHA256);
+    let mut size = 0;
+    let mut buffer = vec![0; CACHE_STREAM_CHUNK_SIZE];
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        context.update(&buffer[..count]);
+        size += count;
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(proto::Digest {
+        hash: crate::util::hex(context.finish().as_ref()),
+        size_bytes: size as i64,
+    })
+}
+
+/// Prefix `name` with `instance_name`, if there is one.
+fn instance_resource_name(instance_name: &str, name: String) -> String {
+    if instance_name.is_empty() {
+        name
+    } else {
+        format!("{}/{}", instance_name, name)
+    }
+}
+
+/// The ByteStream resource name to read the blob with `digest` from.
+fn read_resource_name(instance_name: &str, digest: &proto::Digest) -> String {
+    let name = format!("blobs/{}/{}", digest.hash, digest.size_bytes);
+    instance_resource_name(instance_name, name)
+}
+
+/// The ByteStream resource name to write the blob with `digest` to.
+fn write_resource_name(instance_name: &str, digest: &proto::Digest) -> String {
+    let name = format!(
+        "uploads/{}/blobs/{}/{}",
+        uuid::Uuid::new_v4(),
+        digest.hash,
+        digest.size_bytes
+    );
+    instance_resource_name(instance_name, name)
+}
+
+/// Download the blob at `resource_name`, of `expected_size` bytes, to a
+/// temporary file.
+fn read_blob(client: &Client, resource_name: String, expected_size: u64) -> SFuture<Cache> {
+    let request = proto::ReadRequest {
+        resource_name,
+        read_offset: 0,
+        read_limit: 0,
+    };
+    let responses =
+        ftry!(client.server_streaming(&BYTESTREAM_READ, &request, CallOption::default()));
+    let file = ftry!(tempfile::tempfile().chain_err(|| "failed to create temporary file"));
+    Box::new(
+        responses
+            .map_err(Error::from)
+            .fold(file, |mut file, response| -> Result<_> {
+                file.write_all(&response.data)?;
+                Ok(file)
+            })
+            .chain_err(|| "failed to read blob from CAS")
+            .and_then(move |mut file| {
+                let size = file.seek(SeekFrom::Current(0))?;
+                if size != expected_size {
+                    bail!("Bad blob size read: {}, expected {}", size, expected_size);
+                }
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Cache::Hit(CacheRead::from(file)?))
+            }),
+    )
+}
+
+/// Upload the blob in `reader`, whose digest is `digest`, to the CAS.
+fn upload_blob(
+    client: &Client,
+    pool: &CpuPool,
+    instance_name: &str,
+    reader: Box<dyn ReadSeek>,
+    digest: &proto::Digest,
+) -> SFuture<()> {
+    let resource_name = write_resource_name(instance_name, digest);
+    let (sink, receiver) = ftry!(client.client_streaming(&BYTESTREAM_WRITE, CallOption::default()));
+
+    // Send the blob in chunks, followed by an empty request finishing the
+    // write.
+    let size = digest.size_bytes;
+    let mut offset = 0;
+    let chunks = ReaderStream::new(reader, pool)
+        .map(Some)
+        .chain(stream::once(Ok(None)))
+        .map(move |chunk| {
+            let request = proto::WriteRequest {
+                resource_name: if offset == 0 {
+                    resource_name.clone()
+                } else {
+                    String::new()
+                },
+                write_offset: offset,
+                finish_write: chunk.is_none(),
+                data: chunk.unwrap_or_default(),
+            };
+            offset += request.data.len() as i64;
+            (request, WriteFlags::default())
+        })
+        .map_err(|e| grpcio::Error::Codec(Box::new(e)));
+    Box::new(
+        sink.send_all(chunks)
+            .map_err(Error::from)
+            .and_then(move |_| receiver.map_err(Error::from))
+            .and_then(move |response| {
+                if response.committed_size != size {
+                    bail!(
+                        "Bad blob size written: {}, expected {}",
+                        response.committed_size,
+                        size
+                    );
+                }
+                Ok(())
+            })
+            .chain_err(|| "failed to write blob to CAS"),
+    )
+}
+
+/// Return the digest of the action that the cache entry for `key` is stored
+/// under.
+fn action_digest(key_prefix: &str, key: &str) -> proto::Digest {
+    let key = format!("{}{}", key_prefix, key);
+    digest_reader(&mut io::Cursor::new(key.as_bytes())).expect("Reading from memory can't fail")
+}
+
+impl Storage for ReapiCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let request = proto::GetActionResultRequest {
+            instance_name: self.instance_name.clone(),
+            action_digest: Some(action_digest(&self.key_prefix, key)),
+        };
+        let result = ftry!(self.client.unary_call_async(
+            &GET_ACTION_RESULT,
+            &request,
+            CallOption::default()
+        ));
+        let blob = result.then(|result| match result {
+            Ok(result) => Ok(result
+                .output_files
+                .into_iter()
+                .find(|f| f.path == ENTRY_PATH)
+                .and_then(|f| f.digest)),
+            Err(grpcio::Error::RpcFailure(ref status))
+                if status.status == RpcStatusCode::NOT_FOUND =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(Error::from(e)).chain_err(|| "failed to get action result"),
+        });
+        let client = self.client.clone();
+        let instance_name = self.instance_name.clone();
+        Box::new(blob.and_then(move |digest| match digest {
+            Some(digest) => {
+                let resource_name = read_resource_name(&instance_name, &digest);
+                read_blob(&client, resource_name, digest.size_bytes as u64)
+            }
+            None => f_ok(Cache::Miss),
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        // The entry is read through once to find its digest, on the pool.
+        let reader = self.pool.spawn_fn(move || -> Result<_> {
+            let mut reader = entry.finish_reader()?;
+            let digest = digest_reader(&mut reader)?;
+            Ok((reader, digest))
+        });
+        let client = self.client.clone();
+        let pool = self.pool.clone();
+        let instance_name = self.instance_name.clone();
+        let action_digest = action_digest(&self.key_prefix, key);
+        Box::new(
+            reader
+                .and_then(move |(reader, digest)| {
+                    let upload = upload_blob(&client, &pool, &instance_name, reader, &digest);
+                    let request = proto::UpdateActionResultRequest {
+                        instance_name,
+                        action_digest: Some(action_digest),
+                        action_result: Some(proto::ActionResult {
+                            output_files: vec![proto::OutputFile {
+                                path: ENTRY_PATH.to_owned(),
+                                digest: Some(digest),
+                                is_executable: false,
+                            }],
+                            exit_code: 0,
+                        }),
+                    };
+                    upload.and_then(move |()| {
+                        future::result(client.unary_call_async(
+                            &UPDATE_ACTION_RESULT,
+                            &request,
+                            CallOption::default(),
+                        ))
+                        .flatten()
+                        .map_err(Error::from)
+                        .chain_err(|| "failed to update action result")
+                    })
+                })
+                .map(move |_| start.elapsed()),
+        )
+    }
+
+    fn location(&self) -> String {
+        format!("Remote Execution API, {}", self)
+    }
+
+    fn rw_mode(&self) -> CacheRWMode {
+        self.rw_mode
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        f_ok(None)
+    }
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        f_ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use grpcio::{RpcStatus, Server, ServerBuilder, ServiceBuilder};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// A remote cache server keeping blobs and action results in memory.
+    #[derive(Clone, Default)]
+    struct FakeServer {
+        blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        actions: Arc<Mutex<HashMap<String, proto::ActionResult>>>,
+    }
+
+    /// Return the hash of the blob named `resource_name`, if it is under
+    /// the "main" instance.
+    fn resource_hash(resource_name: &str, kind: &str) -> Option<String> {
+        let mut parts = resource_name.rsplit('/');
+        let hash = parts.nth(1)?;
+        if parts.next() == Some("blobs") && resource_name.starts_with(kind) {
+            Some(hash.to_owned())
+        } else {
+            None
+        }
+    }
+
+    impl FakeServer {
+        /// Start serving on a local port, returning the server and its port.
+        fn start(&self) -> (Server, u16) {
+            let get_server = self.clone();
+            let update_server = self.clone();
+            let read_server = self.clone();
+            let write_server = self.clone();
+            let service = ServiceBuilder::new()
+                .add_unary_handler(&GET_ACTION_RESULT, move |ctx, request, sink| {
+                    let request: proto::GetActionResultRequest = request;
+                    let hash = request.action_digest.unwrap_or_default().hash;
+                    let result = get_server.actions.lock().unwrap().get(&hash).cloned();
+                    let reply = match result {
+                        Some(ref result) if request.instance_name == "main" => {
+                            sink.success(result.clone())
+                        }
+                        _ => sink.fail(RpcStatus::new(RpcStatusCode::NOT_FOUND, None)),
+                    };
+                    ctx.spawn(reply.map_err(|_| ()));
+                })
+                .add_unary_handler(&UPDATE_ACTION_RESULT, move |ctx, request, sink| {
+                    let request: proto::UpdateActionResultRequest = request;
+                    let hash = request.action_digest.unwrap_or_default().hash;
+                    let result = request.action_result.unwrap_or_default();
+                    update_server
+                        .actions
+                        .lock()
+                        .unwrap()
+                        .insert(hash, result.clone());
+                    ctx.spawn(sink.success(result).map_err(|_| ()));
+                })
+                .add_server_streaming_handler(&BYTESTREAM_READ, move |ctx, request, sink| {
+                    let request: proto::ReadRequest = request;
+                    let blob = resource_hash(&request.resource_name, "main/blobs/")
+                        .and_then(|hash| read_server.blobs.lock().unwrap().get(&hash).cloned());
+                    match blob {
+                        Some(blob) => {
+                            // Send the blob in several responses.
+                            let responses = blob
+                                .chunks(100)
+                                .map(|chunk| {
+                                    let response = proto::ReadResponse {
+                                        data: chunk.to_vec(),
+                                    };
+                                    (response, WriteFlags::default())
+                                })
+                                .collect::<Vec<_>>();
+                            let responses = stream::iter_ok::<_, grpcio::Error>(responses);
+                            ctx.spawn(sink.send_all(responses).map(|_| ()).map_err(|_| ()));
+                        }
+                        None => {
+                            let status = RpcStatus::new(RpcStatusCode::NOT_FOUND, None);
+                            ctx.spawn(sink.fail(status).map_err(|_| ()));
+                        }
+                    }
+                })
+                .add_client_streaming_handler(&BYTESTREAM_WRITE, move |ctx, requests, sink| {
+                    let server = write_server.clone();
+                    let upload = requests
+                        .fold((String::new(), vec![]), |(name, mut data), request| {
+                            let request: proto::WriteRequest = request;
+                            data.extend(request.data);
+                            let name = if name.is_empty() {
+                                request.resource_name
+                            } else {
+                                name
+                            };
+                            Ok::<_, grpcio::Error>((name, data))
+                        })
+                        .and_then(move |(name, data)| {
+                            // Only accept blobs stored under their own digest.
+                            let digest = digest_reader(&mut io::Cursor::new(&data)).unwrap();
+                            let hash = resource_hash(&name, "main/uploads/");
+                            let expected = format!("/{}", digest.size_bytes);
+                            if hash.as_ref() != Some(&digest.hash) || !name.ends_with(&expected) {
+                                let status = RpcStatus::new(RpcStatusCode::INVALID_ARGUMENT, None);
+                                return sink.fail(status);
+                            }
+                            server.blobs.lock().unwrap().insert(digest.hash, data);
+                            sink.success(proto::WriteResponse {
+                                committed_size: digest.size_bytes,
+                            })
+                        });
+                    ctx.spawn(upload.map_err(|_| ()));
+                })
+                .build();
+            let env = Arc::new(Environment::new(1));
+            let mut server = ServerBuilder::new(env)
+                .register_service(service)
+                .bind("127.0.0.1", 0)
+                .build()
+                .unwrap();
+            server.start();
+            let port = server.bind_addrs().into_iter().next().unwrap().1;
+            (server, port)
+        }
+    }
+
+    #[test]
+    fn test_action_digest() {
+        // echo -n abcd | sha256sum
+        let digest = action_digest("", "abcd");
+        assert_eq!(
+            digest.hash,
+            "88d4266fd4e6338d13b845fcf289579d209c897823b9217da3e161936f031589"
+        );
+        assert_eq!(digest.size_bytes, 4);
+        assert_eq!(action_digest("ab/", "cd"), action_digest("", "ab/cd"));
+    }
+
+    #[test]
+    fn test_resource_names() {
+        let digest = action_digest("", "abcd");
+        assert_eq!(
+            read_resource_name("", &digest),
+            format!("blobs/{}/4", digest.hash)
+        );
+        assert_eq!(
+            read_resource_name("main", &digest),
+            format!("main/blobs/{}/4", digest.hash)
+        );
+        let upload = write_resource_name("main", &digest);
+        assert!(upload.starts_with("main/uploads/"));
+        assert!(upload.ends_with(&format!("/blobs/{}/4", digest.hash)));
+    }
+
+    #[test]
+    fn test_reapi_cache_get_put() {
+        let fake = FakeServer::default();
+        let (_server, port) = fake.start();
+        let endpoint = format!("grpc://127.0.0.1:{}", port);
+        let pool = CpuPool::new(1);
+        let cache =
+            ReapiCache::new(&endpoint, "main", "prefix", CacheRWMode::ReadWrite, &pool).unwrap();
+
+        match cache.get("key").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut &b"some contents"[..], None)
+            .unwrap();
+        cache.put("key", entry).wait().unwrap();
+        assert_eq!(fake.blobs.lock().unwrap().len(), 1);
+        let action = action_digest("prefix/", "key");
+        assert!(fake.actions.lock().unwrap().contains_key(&action.hash));
+
+        match cache.get("key").wait().unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut contents = vec![];
+                entry.get_object("obj", &mut contents).unwrap();
+                assert_eq!(contents, b"some contents");
+            }
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+        match cache.get("other").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+
+        // An action result whose blob has gone from the CAS can't be read.
+        fake.blobs.lock().unwrap().clear();
+        assert!(cache.get("key").wait().is_err());
+    }
+}
@@ -13,67 +13,734 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{normalize_key_prefix, Cache, CacheRead, CacheWrite, EntryInfo, Storage};
+use crate::config::{CacheRWMode, RedisMode};
 use crate::errors::*;
-use futures::{future, Future};
+use futures::future::{self, Loop};
+use futures::Future;
+use futures_cpupool::CpuPool;
 use redis::r#async::Connection;
-use redis::{cmd, Client, InfoDict};
+use redis::{
+    cmd, pipe, Client, ConnectionAddr, ConnectionInfo, FromRedisValue, InfoDict,
+    IntoConnectionInfo, Pipeline, RedisError, RedisResult, Value,
+};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::io::Cursor;
-use std::time::{Duration, Instant};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The most idle connections kept open to each server.
+const MAX_IDLE_CONNECTIONS: usize = 16;
+
+/// The most cluster redirections followed for a single request.
+const MAX_REDIRECTIONS: usize = 5;
+
+/// The number of hash slots keys are spread over in a Redis Cluster.
+const CLUSTER_SLOTS: u16 = 16384;
+
+/// The number of keys each `SCAN` is asked to look at.
+const SCAN_COUNT: usize = 1000;
+
+/// The servers a `RedisCache` is stored on.
+enum Topology {
+    /// A single server.
+    Standalone(ConnectionInfo),
+    /// A master found through Sentinel.
+    Sentinel {
+        /// The sentinels to ask for the master's address.
+        sentinels: Vec<ConnectionInfo>,
+        /// The name the sentinels monitor the master under.
+        master_name: String,
+        /// The password and database to use with the master.
+        template: ConnectionInfo,
+        /// The master's address, if it has been looked up.
+        master: RefCell<Option<ConnectionInfo>>,
+    },
+    /// A Redis Cluster.
+    Cluster {
+        /// The nodes to ask for the slot layout.
+        seeds: Vec<ConnectionInfo>,
+        /// The nodes serving each range of slots, if they have been looked up.
+        slots: RefCell<Vec<SlotRange>>,
+    },
+}
+
+/// A range of cluster slots, and the master node serving them.
+#[derive(Clone, Debug)]
+struct SlotRange {
+    start: u16,
+    end: u16,
+    node: ConnectionInfo,
+}
+
+/// Idle connections, by server address.
+type ConnectionPool = RefCell<HashMap<String, Vec<Connection>>>;
 
 /// A cache that stores entries in a Redis.
 #[derive(Clone)]
 pub struct RedisCache {
     url: String,
-    client: Client,
+    topology: Rc<Topology>,
+    connections: Rc<ConnectionPool>,
+    key_prefix: String,
+    /// How long entries are kept for, if they expire.
+    ttl: Option<Duration>,
+    /// Whether reading an entry resets its expiry.
+    refresh_on_hit: bool,
+    rw_mode: CacheRWMode,
+    /// The pool to read entries being stored on.
+    pool: CpuPool,
 }
 
 impl RedisCache {
-    /// Create a new `RedisCache`.
-    pub fn new(url: &str) -> Result<RedisCache> {
+    /// Create a new `RedisCache` stored on the servers at `url`, a
+    /// whitespace-separated list of URLs deployed as `mode` describes.
+    pub fn new(
+        url: &str,
+        mode: &RedisMode,
+        key_prefix: &str,
+        ttl: Option<Duration>,
+        refresh_on_hit: bool,
+        rw_mode: CacheRWMode,
+        pool: &CpuPool,
+    ) -> Result<RedisCache> {
+        let mut infos = url
+            .split_whitespace()
+            .map(|url| url.into_connection_info())
+            .collect::<::std::result::Result<Vec<_>, _>>()?;
+        if infos.is_empty() {
+            bail!("No Redis URL given");
+        }
+        let topology = match *mode {
+            RedisMode::Standalone => {
+                if infos.len() > 1 {
+                    bail!("Only one Redis URL may be given outside of cluster or sentinel mode");
+                }
+                Topology::Standalone(infos.remove(0))
+            }
+            RedisMode::Cluster => Topology::Cluster {
+                seeds: infos,
+                slots: RefCell::new(vec![]),
+            },
+            RedisMode::Sentinel { ref master_name } => {
+                let template = infos[0].clone();
+                // Sentinels only have a single database, and are usually
+                // not password protected.
+                let sentinels = infos
+                    .into_iter()
+                    .map(|info| ConnectionInfo {
+                        db: 0,
+                        passwd: None,
+                        ..info
+                    })
+                    .collect();
+                Topology::Sentinel {
+                    sentinels,
+                    master_name: master_name.to_owned(),
+                    template,
+                    master: RefCell::new(None),
+                }
+            }
+        };
         Ok(RedisCache {
             url: url.to_owned(),
-            client: Client::open(url)?,
+            topology: Rc::new(topology),
+            connections: Rc::new(RefCell::new(HashMap::new())),
+            key_prefix: normalize_key_prefix(key_prefix),
+            ttl,
+            refresh_on_hit,
+            rw_mode,
+            pool: pool.clone(),
         })
     }
 
-    /// Returns a connection with configured read and write timeouts.
-    fn connect(&self) -> impl Future<Item = Connection, Error = Error> {
-        self.client.get_async_connection().map_err(|e| e.into())
+    /// Return the server that should handle requests for `key`, or any
+    /// request if there is no key.
+    fn node_for(&self, key: Option<&str>) -> SFuture<ConnectionInfo> {
+        match *self.topology {
+            Topology::Standalone(ref info) => f_ok(info.clone()),
+            Topology::Sentinel { ref master, .. } => {
+                let master = master.borrow().clone();
+                match master {
+                    Some(info) => f_ok(info),
+                    None => lookup_master(self.clone(), 0),
+                }
+            }
+            Topology::Cluster { ref slots, .. } => {
+                if !slots.borrow().is_empty() {
+                    return f_ok(self.cluster_node(key));
+                }
+                let key = key.map(str::to_owned);
+                let me = self.clone();
+                Box::new(
+                    fetch_slots(self.clone(), 0)
+                        .map(move |()| me.cluster_node(key.as_ref().map(String::as_str))),
+                )
+            }
+        }
+    }
+
+    /// Return the cluster node serving the slot of `key`, falling back to the
+    /// first seed node if the slot layout is unknown.
+    fn cluster_node(&self, key: Option<&str>) -> ConnectionInfo {
+        let (seeds, slots) = match *self.topology {
+            Topology::Cluster {
+                ref seeds,
+                ref slots,
+            } => (seeds, slots.borrow()),
+            _ => unreachable!(),
+        };
+        let node = match key {
+            Some(key) => {
+                let slot = key_slot(key);
+                slots
+                    .iter()
+                    .find(|range| range.start <= slot && slot <= range.end)
+                    .map(|range| range.node.clone())
+            }
+            None => slots.first().map(|range| range.node.clone()),
+        };
+        node.unwrap_or_else(|| seeds[0].clone())
+    }
+
+    /// Forget the cached layout of the servers, so that it is looked up
+    /// again for the next request.
+    fn forget_topology(&self) {
+        match *self.topology {
+            Topology::Standalone(_) => {}
+            Topology::Sentinel { ref master, .. } => *master.borrow_mut() = None,
+            Topology::Cluster { ref slots, .. } => slots.borrow_mut().clear(),
+        }
     }
+
+    /// Return an idle connection to `node`, or open a new one.
+    fn connection(&self, node: &ConnectionInfo) -> SFuture<Connection> {
+        let idle = self
+            .connections
+            .borrow_mut()
+            .get_mut(&node_key(node))
+            .and_then(Vec::pop);
+        match idle {
+            Some(connection) => f_ok(connection),
+            None => connect(node),
+        }
+    }
+
+    /// Return `connection` to `node` to the pool of idle connections.
+    fn release(&self, node: &ConnectionInfo, connection: Connection) {
+        let mut connections = self.connections.borrow_mut();
+        let idle = connections.entry(node_key(node)).or_insert_with(Vec::new);
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(connection);
+        }
+    }
+
+    /// Return every master server, which between them hold all the keys.
+    fn masters(&self) -> SFuture<Vec<ConnectionInfo>> {
+        let slots = match *self.topology {
+            Topology::Cluster { ref slots, .. } => slots,
+            _ => return Box::new(self.node_for(None).map(|node| vec![node])),
+        };
+        let fetch = if slots.borrow().is_empty() {
+            fetch_slots(self.clone(), 0)
+        } else {
+            f_ok(())
+        };
+        let me = self.clone();
+        Box::new(fetch.map(move |()| {
+            let mut masters: Vec<ConnectionInfo> = vec![];
+            if let Topology::Cluster { ref slots, .. } = *me.topology {
+                for range in slots.borrow().iter() {
+                    if !masters.iter().any(|m| node_key(m) == node_key(&range.node)) {
+                        masters.push(range.node.clone());
+                    }
+                }
+            }
+            masters
+        }))
+    }
+
+    /// Run `pipeline` on the server holding `key`, following cluster
+    /// redirections.
+    fn query<T: FromRedisValue + 'static>(
+        &self,
+        key: Option<String>,
+        pipeline: Pipeline,
+    ) -> SFuture<T> {
+        query_node(self.clone(), key, pipeline, None, 0)
+    }
+}
+
+/// Open a new connection to `node`.
+fn connect(node: &ConnectionInfo) -> SFuture<Connection> {
+    let client = ftry!(Client::open(node.clone()));
+    Box::new(client.get_async_connection().from_err())
+}
+
+/// The key to pool connections to `node` under.
+fn node_key(node: &ConnectionInfo) -> String {
+    format!("{:?}/{}", node.addr, node.db)
+}
+
+/// Return the connection info for the server at `host` and `port`, with the
+/// password and database from `template`.
+fn node_info(template: &ConnectionInfo, host: String, port: u16) -> ConnectionInfo {
+    ConnectionInfo {
+        addr: Box::new(ConnectionAddr::Tcp(host, port)),
+        db: template.db,
+        passwd: template.passwd.clone(),
+    }
+}
+
+/// Run `pipeline` on the server holding `key`, or on `redirect` if a cluster
+/// node redirected the request elsewhere.
+fn query_node<T: FromRedisValue + 'static>(
+    me: RedisCache,
+    key: Option<String>,
+    pipeline: Pipeline,
+    redirect: Option<Redirection>,
+    redirections: usize,
+) -> SFuture<T> {
+    let (node, asking) = match redirect {
+        Some(Redirection { node, asking }) => (f_ok(node), asking),
+        None => (me.node_for(key.as_ref().map(String::as_str)), false),
+    };
+    Box::new(node.and_then(move |node| {
+        me.connection(&node).and_then(move |connection| {
+            let connection: SFuture<Connection> = if asking {
+                Box::new(
+                    cmd("ASKING")
+                        .query_async(connection)
+                        .from_err()
+                        .map(|(connection, ())| connection),
+                )
+            } else {
+                f_ok(connection)
+            };
+            connection.and_then(move |connection| {
+                pipeline
+                    .clone()
+                    .query_async(connection)
+                    .then(move |result| -> SFuture<T> {
+                        match result {
+                            Ok((connection, value)) => {
+                                me.release(&node, connection);
+                                f_ok(value)
+                            }
+                            Err(e) => match redirection(&e, &node) {
+                                Some(redirect) if redirections < MAX_REDIRECTIONS => {
+                                    if !redirect.asking {
+                                        // The slot has moved for good.
+                                        me.forget_topology();
+                                    }
+                                    query_node(me, key, pipeline, Some(redirect), redirections + 1)
+                                }
+                                _ => {
+                                    me.forget_topology();
+                                    f_err(e)
+                                }
+                            },
+                        }
+                    })
+            })
+        })
+    }))
+}
+
+/// Escape the glob special characters in `s`, for a `SCAN` pattern.
+fn escape_glob(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if let '*' | '?' | '[' | ']' | '\\' = c {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Convert the replies to `STRLEN` and `OBJECT IDLETIME` for `key` into an
+/// `EntryInfo`, or `None` if the key no longer exists.
+fn entry_info(
+    key: String,
+    size: &Value,
+    idle: Option<&Value>,
+    now: SystemTime,
+) -> Option<EntryInfo> {
+    let size = match *size {
+        Value::Int(size) if size > 0 => size as u64,
+        _ => return None,
+    };
+    let modified = match idle {
+        Some(&Value::Int(idle)) if idle >= 0 => now.checked_sub(Duration::from_secs(idle as u64)),
+        _ => None,
+    };
+    Some(EntryInfo {
+        key,
+        size,
+        modified,
+    })
+}
+
+/// Look up the size and idle time of `keys` on `node`.
+///
+/// Idle times aren't tracked under an LFU eviction policy, in which case the
+/// entries are returned without a modification time.
+fn stat_keys(me: RedisCache, node: ConnectionInfo, keys: Vec<String>) -> SFuture<Vec<EntryInfo>> {
+    let mut sizes = pipe();
+    let mut idle_times = pipe();
+    for key in &keys {
+        sizes.cmd("STRLEN").arg(key);
+        idle_times.cmd("OBJECT").arg("IDLETIME").arg(key);
+    }
+    let me2 = me.clone();
+    let node2 = node.clone();
+    Box::new(
+        me.connection(&node)
+            .and_then(move |connection| sizes.query_async(connection).from_err())
+            .and_then(move |(connection, sizes): (Connection, Vec<Value>)| {
+                idle_times.query_async(connection).then(
+                    move |result: RedisResult<(Connection, Vec<Value>)>| -> Result<Vec<EntryInfo>> {
+                        let idle_times = match result {
+                            Ok((connection, idle_times)) => {
+                                me2.release(&node2, connection);
+                                idle_times
+                            }
+                            Err(e) => {
+                                debug!("Couldn't get Redis idle times: {}", e);
+                                vec![]
+                            }
+                        };
+                        let now = SystemTime::now();
+                        let prefix_len = me2.key_prefix.len();
+                        Ok(keys
+                            .into_iter()
+                            .zip(sizes.iter())
+                            .enumerate()
+                            .filter_map(|(i, (key, size))| {
+                                let key = key[prefix_len..].to_owned();
+                                entry_info(key, size, idle_times.get(i), now)
+                            })
+                            .collect())
+                    },
+                )
+            }),
+    )
+}
+
+/// List the entries stored on `node`, one `SCAN` at a time.
+fn list_node(me: RedisCache, node: ConnectionInfo) -> SFuture<Vec<EntryInfo>> {
+    let pattern = format!("{}*", escape_glob(&me.key_prefix));
+    Box::new(future::loop_fn(
+        (vec![], 0u64),
+        move |(mut entries, cursor): (Vec<EntryInfo>, u64)| {
+            let me = me.clone();
+            let node = node.clone();
+            me.connection(&node)
+                .and_then({
+                    let pattern = pattern.clone();
+                    move |connection| {
+                        cmd("SCAN")
+                            .arg(cursor)
+                            .arg("MATCH")
+                            .arg(pattern)
+                            .arg("COUNT")
+                            .arg(SCAN_COUNT)
+                            .query_async(connection)
+                            .from_err()
+                    }
+                })
+                .and_then(
+                    move |(connection, (cursor, keys)): (Connection, (u64, Vec<Vec<u8>>))| {
+                        me.release(&node, connection);
+                        let keys: Vec<String> = keys
+                            .into_iter()
+                            .filter_map(|k| String::from_utf8(k).ok())
+                            .collect();
+                        let stats = if keys.is_empty() {
+                            f_ok(vec![])
+                        } else {
+                            stat_keys(me, node, keys)
+                        };
+                        stats.map(move |stats| {
+                            entries.extend(stats);
+                            if cursor == 0 {
+                                Loop::Break(entries)
+                            } else {
+                                Loop::Continue((entries, cursor))
+                            }
+                        })
+                    },
+                )
+        },
+    ))
+}
+
+/// Where a cluster node redirected a request to.
+struct Redirection {
+    node: ConnectionInfo,
+    /// Whether the slot is being migrated, and the request has to be
+    /// preceded by `ASKING`.
+    asking: bool,
+}
+
+/// Parse a `MOVED` or `ASK` cluster redirection from `e`.
+fn redirection(e: &RedisError, template: &ConnectionInfo) -> Option<Redirection> {
+    let asking = match e.extension_error_code() {
+        Some("MOVED") => false,
+        Some("ASK") => true,
+        _ => return None,
+    };
+    // The detail is "<slot> <host>:<port>".
+    let addr = e.extension_error_detail()?.split_whitespace().nth(1)?;
+    let colon = addr.rfind(':')?;
+    let port = addr[colon + 1..].parse().ok()?;
+    Some(Redirection {
+        node: node_info(template, addr[..colon].to_owned(), port),
+        asking,
+    })
+}
+
+/// A sentinel's reply to `SENTINEL get-master-addr-by-name`.
+type MasterAddr = Option<(String, u16)>;
+
+/// Ask the sentinels, starting with the one at `index`, for the master's
+/// address.
+fn lookup_master(me: RedisCache, index: usize) -> SFuture<ConnectionInfo> {
+    let (sentinel, master_name, template) = match *me.topology {
+        Topology::Sentinel {
+            ref sentinels,
+            ref master_name,
+            ref template,
+            ..
+        } => match sentinels.get(index) {
+            Some(sentinel) => (sentinel.clone(), master_name.clone(), template.clone()),
+            None => return f_err("No Redis sentinel knows the master's address"),
+        },
+        _ => unreachable!(),
+    };
+    Box::new(
+        connect(&sentinel)
+            .and_then(move |connection| {
+                cmd("SENTINEL")
+                    .arg("get-master-addr-by-name")
+                    .arg(&master_name)
+                    .query_async(connection)
+                    .from_err()
+            })
+            .then(
+                move |result: Result<(Connection, MasterAddr)>| -> SFuture<ConnectionInfo> {
+                    match result {
+                        Ok((_, Some((host, port)))) => {
+                            let info = node_info(&template, host, port);
+                            if let Topology::Sentinel { ref master, .. } = *me.topology {
+                                *master.borrow_mut() = Some(info.clone());
+                            }
+                            f_ok(info)
+                        }
+                        Ok((_, None)) => {
+                            debug!("Redis sentinel {:?} doesn't know the master", sentinel.addr);
+                            lookup_master(me, index + 1)
+                        }
+                        Err(e) => {
+                            debug!("Redis sentinel {:?} failed: {}", sentinel.addr, e);
+                            lookup_master(me, index + 1)
+                        }
+                    }
+                },
+            ),
+    )
+}
+
+/// Ask the cluster nodes, starting with the seed at `index`, for the slot
+/// layout.
+fn fetch_slots(me: RedisCache, index: usize) -> SFuture<()> {
+    let seed = match *me.topology {
+        Topology::Cluster { ref seeds, .. } => match seeds.get(index) {
+            Some(seed) => seed.clone(),
+            None => return f_err("No Redis cluster node returned the slot layout"),
+        },
+        _ => unreachable!(),
+    };
+    Box::new(
+        connect(&seed)
+            .and_then(|connection| {
+                cmd("CLUSTER")
+                    .arg("SLOTS")
+                    .query_async(connection)
+                    .from_err()
+            })
+            .then(move |result: Result<(Connection, Value)>| -> SFuture<()> {
+                match result.and_then(|(_, value)| parse_slots(value, &seed)) {
+                    Ok(ranges) => {
+                        if let Topology::Cluster { ref slots, .. } = *me.topology {
+                            *slots.borrow_mut() = ranges;
+                        }
+                        f_ok(())
+                    }
+                    Err(e) => {
+                        debug!("Redis cluster node {:?} failed: {}", seed.addr, e);
+                        fetch_slots(me, index + 1)
+                    }
+                }
+            }),
+    )
+}
+
+/// Parse the reply to `CLUSTER SLOTS`.
+fn parse_slots(value: Value, template: &ConnectionInfo) -> Result<Vec<SlotRange>> {
+    let invalid = || Error::from("Invalid CLUSTER SLOTS reply");
+    let ranges = match value {
+        Value::Bulk(ranges) => ranges,
+        _ => return Err(invalid()),
+    };
+    let mut slots = vec![];
+    for range in ranges {
+        let items = match range {
+            Value::Bulk(items) => items,
+            _ => return Err(invalid()),
+        };
+        // Each range is [start, end, master, replicas...], where each node is
+        // [host, port, id...].
+        match (items.get(0), items.get(1), items.get(2)) {
+            (Some(&Value::Int(start)), Some(&Value::Int(end)), Some(&Value::Bulk(ref master))) => {
+                let host = match master.get(0) {
+                    Some(&Value::Data(ref host)) => String::from_utf8(host.clone())?,
+                    _ => return Err(invalid()),
+                };
+                let port = match master.get(1) {
+                    Some(&Value::Int(port)) => port as u16,
+                    _ => return Err(invalid()),
+                };
+                slots.push(SlotRange {
+                    start: start as u16,
+                    end: end as u16,
+                    node: node_info(template, host, port),
+                });
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(slots)
+}
+
+/// Return the cluster slot `key` is stored in.
+fn key_slot(key: &str) -> u16 {
+    let key = key.as_bytes();
+    // Only the part of the key between the first `{` and the following `}`
+    // is hashed, if that part isn't empty.
+    let hashed = key
+        .iter()
+        .position(|&b| b == b'{')
+        .and_then(|open| {
+            key[open + 1..]
+                .iter()
+                .position(|&b| b == b'}')
+                .filter(|&len| len > 0)
+                .map(|len| &key[open + 1..open + 1 + len])
+        })
+        .unwrap_or(key);
+    crc16(hashed) % CLUSTER_SLOTS
+}
+
+/// The CRC-16/XMODEM checksum of `data`, as used for cluster slots.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
 }
 
 impl Storage for RedisCache {
-    /// Open a connection and query for a key.
+    /// Query for a key, resetting its expiry if configured to.
     fn get(&self, key: &str) -> SFuture<Cache> {
-        let key = key.to_owned();
+        let key = format!("{}{}", self.key_prefix, key);
+        let mut get = pipe();
+        get.cmd("GET").arg(&key);
+        if let Some(ttl) = self.ttl.filter(|_| self.refresh_on_hit) {
+            get.cmd("EXPIRE").arg(&key).arg(ttl.as_secs()).ignore();
+        }
+        Box::new(self.query(Some(key), get).and_then(|(d,): (Vec<u8>,)| {
+            if d.is_empty() {
+                Ok(Cache::Miss)
+            } else {
+                CacheRead::from(Cursor::new(d)).map(Cache::Hit)
+            }
+        }))
+    }
+
+    /// Store an object in the cache.
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let key = format!("{}{}", self.key_prefix, key);
+        let start = Instant::now();
         let me = self.clone();
+        // `SET` takes the whole value, so the entry is read into memory, on
+        // the pool.
         Box::new(
-            me.connect()
-                .and_then(|c| cmd("GET").arg(key).query_async(c).from_err())
-                .and_then(|(_, d): (_, Vec<u8>)| {
-                    if d.is_empty() {
-                        Ok(Cache::Miss)
-                    } else {
-                        CacheRead::from(Cursor::new(d)).map(Cache::Hit)
+            self.pool
+                .spawn_fn(move || entry.finish())
+                .and_then(move |d| {
+                    let mut set = pipe();
+                    set.cmd("SET").arg(&key).arg(d);
+                    if let Some(ttl) = me.ttl {
+                        set.arg("EX").arg(ttl.as_secs());
                     }
-                }),
+                    set.ignore();
+                    me.query(Some(key), set)
+                })
+                .map(move |()| start.elapsed()),
         )
     }
 
-    /// Open a connection and store a object in the cache.
-    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+    /// Delete an entry from the cache.
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let key = format!("{}{}", self.key_prefix, key);
+        let mut del = pipe();
+        del.cmd("DEL").arg(&key).ignore();
+        self.query(Some(key), del)
+    }
+
+    /// List the entries of every master with `SCAN`. Entries are dated by
+    /// their idle time, so they're pruned by when they were last used.
+    fn list(&self) -> SFuture<Vec<EntryInfo>> {
+        let me = self.clone();
+        Box::new(self.masters().and_then(move |masters| {
+            let lists = masters
+                .into_iter()
+                .map(|node| list_node(me.clone(), node))
+                .collect::<Vec<_>>();
+            future::join_all(lists).map(|lists| lists.into_iter().flatten().collect())
+        }))
+    }
+
+    /// Look up the size and idle time of an entry.
+    fn stat(&self, key: &str) -> SFuture<Option<EntryInfo>> {
+        let full_key = format!("{}{}", self.key_prefix, key);
         let key = key.to_owned();
         let me = self.clone();
-        let start = Instant::now();
+        let mut size = pipe();
+        size.cmd("STRLEN").arg(&full_key);
         Box::new(
-            me.connect()
-                .and_then(move |c| {
-                    future::result(entry.finish())
-                        .and_then(|d| cmd("SET").arg(key).arg(d).query_async(c).from_err())
-                })
-                .map(move |(_, ())| start.elapsed()),
+            self.query(Some(full_key.clone()), size)
+                .and_then(move |(size,): (Value,)| {
+                    let mut idle = pipe();
+                    idle.cmd("OBJECT").arg("IDLETIME").arg(&full_key);
+                    me.query(Some(full_key), idle).then(
+                        move |idle: Result<(Value,)>| -> Result<Option<EntryInfo>> {
+                            let idle = idle.ok().map(|(idle,)| idle);
+                            Ok(entry_info(key, &size, idle.as_ref(), SystemTime::now()))
+                        },
+                    )
+                }),
         )
     }
 
@@ -82,14 +749,20 @@ impl Storage for RedisCache {
         format!("Redis: {}", self.url)
     }
 
+    /// Returns whether the cache may be read from and written to.
+    fn rw_mode(&self) -> CacheRWMode {
+        self.rw_mode
+    }
+
     /// Returns the current cache size. This value is aquired via
-    /// the Redis INFO command (used_memory).
+    /// the Redis INFO command (used_memory). For a cluster, only one
+    /// node is queried.
     fn current_size(&self) -> SFuture<Option<u64>> {
+        let mut info = pipe();
+        info.cmd("INFO");
         Box::new(
-            self.connect()
-                .and_then(|c| cmd("INFO").query_async(c).from_err())
-                .map(|(_, v)| v)
-                .map(|i: InfoDict| i.get("used_memory")),
+            self.query(None, info)
+                .map(|(i,): (InfoDict,)| i.get("used_memory")),
         )
     }
 
@@ -97,20 +770,100 @@ impl Storage for RedisCache {
     /// the Redis CONFIG command (maxmemory). If the server has no
     /// configured limit, the result is None.
     fn max_size(&self) -> SFuture<Option<u64>> {
+        let mut config = pipe();
+        config.cmd("CONFIG").arg("GET").arg("maxmemory");
         Box::new(
-            self.connect()
-                .and_then(|c| {
-                    cmd("CONFIG")
-                        .arg("GET")
-                        .arg("maxmemory")
-                        .query_async(c)
-                        .from_err()
-                })
-                .map(|(_, v)| v)
-                .map(|h: HashMap<String, usize>| {
+            self.query(None, config)
+                .map(|(h,): (HashMap<String, usize>,)| {
                     h.get("maxmemory")
                         .and_then(|&s| if s != 0 { Some(s as u64) } else { None })
                 }),
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_slot("foo"), 12182);
+        assert_eq!(key_slot("{user1000}.following"), key_slot("user1000"));
+        assert_eq!(key_slot("foo{}{bar}"), crc16(b"foo{}{bar}") % CLUSTER_SLOTS);
+    }
+
+    #[test]
+    fn test_escape_glob() {
+        assert_eq!(escape_glob("project/"), "project/");
+        assert_eq!(escape_glob("a*b?[c]\\"), "a\\*b\\?\\[c\\]\\\\");
+    }
+
+    #[test]
+    fn test_entry_info() {
+        let now = SystemTime::now();
+        assert_eq!(
+            entry_info(
+                "abc".to_owned(),
+                &Value::Int(10),
+                Some(&Value::Int(60)),
+                now
+            ),
+            Some(EntryInfo {
+                key: "abc".to_owned(),
+                size: 10,
+                modified: Some(now - Duration::from_secs(60)),
+            })
+        );
+        assert_eq!(
+            entry_info("abc".to_owned(), &Value::Int(10), None, now)
+                .unwrap()
+                .modified,
+            None
+        );
+        // The key was deleted between the `SCAN` and the `STRLEN`.
+        assert_eq!(
+            entry_info("abc".to_owned(), &Value::Int(0), Some(&Value::Nil), now),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_slots() {
+        let template = "redis://:secret@127.0.0.1:7000"
+            .into_connection_info()
+            .unwrap();
+        let node = |host: &str, port| {
+            Value::Bulk(vec![
+                Value::Data(host.as_bytes().to_vec()),
+                Value::Int(port),
+                Value::Data(b"id".to_vec()),
+            ])
+        };
+        let reply = Value::Bulk(vec![
+            Value::Bulk(vec![
+                Value::Int(0),
+                Value::Int(8191),
+                node("10.0.0.1", 7000),
+                node("10.0.0.2", 7001),
+            ]),
+            Value::Bulk(vec![
+                Value::Int(8192),
+                Value::Int(16383),
+                node("10.0.0.3", 7002),
+            ]),
+        ]);
+        let slots = parse_slots(reply, &template).unwrap();
+        assert_eq!(slots.len(), 2);
+        assert_eq!((slots[1].start, slots[1].end), (8192, 16383));
+        match *slots[1].node.addr {
+            ConnectionAddr::Tcp(ref host, port) => {
+                assert_eq!((host.as_str(), port), ("10.0.0.3", 7002))
+            }
+            ref addr => panic!("Unexpected address: {:?}", addr),
+        }
+        assert_eq!(slots[1].node.passwd, Some("secret".to_owned()));
+        assert!(parse_slots(Value::Nil, &template).is_err());
+    }
+}
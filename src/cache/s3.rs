@@ -12,14 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{
+    normalize_key_prefix, Cache, CacheRead, CacheWrite, CachedSize, EntryInfo, Storage,
+    SIZE_REFRESH_INTERVAL,
+};
+use crate::config::CacheRWMode;
 use crate::simples3::{
-    AutoRefreshingProvider, Bucket, ChainProvider, ProfileProvider, ProvideAwsCredentials, Ssl,
+    AutoRefreshingProvider, Bucket, ChainProvider, ProfileProvider, ProvideAwsCredentials,
+    PutOptions, Ssl,
 };
 use directories::UserDirs;
 use futures::future;
 use futures::future::Future;
-use std::io;
+use futures_cpupool::CpuPool;
+use std::env;
+use std::path::Path;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 
@@ -31,16 +38,55 @@ pub struct S3Cache {
     bucket: Rc<Bucket>,
     /// Credentials provider.
     provider: AutoRefreshingProvider<ChainProvider>,
+    /// The prefix of the keys entries are stored under.
+    key_prefix: String,
+    /// The settings entries are stored with.
+    put_options: PutOptions,
+    /// Whether the cache may be read from and written to.
+    rw_mode: CacheRWMode,
+    /// The total size of the objects under the key prefix.
+    size: CachedSize,
+    /// The pool to read entries being uploaded and write those being
+    /// downloaded on.
+    pool: CpuPool,
 }
 
 impl S3Cache {
-    /// Create a new `S3Cache` storing data in `bucket`.
-    pub fn new(bucket: &str, endpoint: &str, use_ssl: bool) -> Result<S3Cache> {
+    /// Create a new `S3Cache` storing data in `bucket`, which is reached at
+    /// `endpoint`. Requests are signed for `region`, or if unset the region
+    /// named by an AWS `endpoint` or else `us-east-1`, and entries are stored
+    /// with the settings in `put_options`.
+    pub fn new(
+        bucket: &str,
+        endpoint: &str,
+        use_ssl: bool,
+        region: Option<&str>,
+        ca_bundle: Option<&Path>,
+        key_prefix: &str,
+        put_options: PutOptions,
+        rw_mode: CacheRWMode,
+        pool: &CpuPool,
+    ) -> Result<S3Cache> {
+        if put_options.sse_kms_key_id.is_some()
+            && put_options
+                .server_side_encryption
+                .as_ref()
+                .map(String::as_str)
+                != Some("aws:kms")
+        {
+            bail!("An SSE KMS key id requires `aws:kms` server-side encryption");
+        }
+        put_options.validate()?;
         let user_dirs = UserDirs::new().ok_or("Couldn't get user directories")?;
         let home = user_dirs.home_dir();
 
+        let mut aws_profile = ProfileProvider::with_configuration(
+            home.join(".aws").join("credentials"),
+            env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_owned()),
+        );
+        aws_profile.set_config_file_path(home.join(".aws").join("config"));
         let profile_providers = vec![
-            ProfileProvider::with_configuration(home.join(".aws").join("credentials"), "default"),
+            aws_profile,
             //TODO: this is hacky, this is where our mac builders store their
             // credentials. We should either match what boto does more directly
             // or make those builders put their credentials in ~/.aws/credentials
@@ -52,25 +98,81 @@ impl S3Cache {
             true => Ssl::Yes,
             false => Ssl::No,
         };
-        let bucket = Rc::new(Bucket::new(bucket, endpoint, ssl_mode)?);
+        let region = match region {
+            Some(region) => region.to_owned(),
+            None => endpoint_region(endpoint).unwrap_or_else(|| "us-east-1".to_owned()),
+        };
+        let bucket = Rc::new(Bucket::new(bucket, endpoint, ssl_mode, &region, ca_bundle)?);
         Ok(S3Cache {
             bucket: bucket,
             provider: provider,
+            key_prefix: normalize_key_prefix(key_prefix),
+            put_options,
+            rw_mode,
+            size: CachedSize::new(SIZE_REFRESH_INTERVAL),
+            pool: pool.clone(),
         })
     }
 }
 
-fn normalize_key(key: &str) -> String {
-    format!("{}/{}/{}/{}", &key[0..1], &key[1..2], &key[2..3], &key)
+/// Get the region named by an AWS S3 `endpoint`, such as `eu-west-1` in
+/// `bucket.s3-eu-west-1.amazonaws.com` or `s3.eu-west-1.amazonaws.com/bucket`.
+fn endpoint_region(endpoint: &str) -> Option<String> {
+    let host = endpoint.split(|c: char| c == '/' || c == ':').next()?;
+    if !host.ends_with(".amazonaws.com") && !host.ends_with(".amazonaws.com.cn") {
+        return None;
+    }
+    let labels: Vec<_> = host.split('.').collect();
+    // Search from the end, as bucket names may look like S3 labels too.
+    let s3 = labels
+        .iter()
+        .rposition(|label| *label == "s3" || label.starts_with("s3-"))?;
+    let region = if labels[s3] == "s3" {
+        labels[s3 + 1..]
+            .iter()
+            .find(|label| **label != "dualstack")?
+    } else {
+        &labels[s3][3..]
+    };
+    match region {
+        "amazonaws" | "accelerate" => None,
+        "external-1" => Some("us-east-1".to_owned()),
+        region => Some(region.to_owned()),
+    }
+}
+
+fn normalize_key(prefix: &str, key: &str) -> String {
+    format!(
+        "{}{}/{}/{}/{}",
+        prefix,
+        &key[0..1],
+        &key[1..2],
+        &key[2..3],
+        &key
+    )
+}
+
+/// Get the cache key an object at `object_key` was stored under by
+/// `normalize_key`, or `None` if it isn't a cache entry.
+fn entry_key(prefix: &str, object_key: &str) -> Option<String> {
+    if !object_key.starts_with(prefix) {
+        return None;
+    }
+    let key = object_key.rsplit('/').next()?;
+    if key.len() >= 3 && normalize_key(prefix, key) == object_key {
+        Some(key.to_owned())
+    } else {
+        None
+    }
 }
 
 impl Storage for S3Cache {
     fn get(&self, key: &str) -> SFuture<Cache> {
-        let key = normalize_key(key);
+        let key = normalize_key(&self.key_prefix, key);
 
         let result_cb = |result| match result {
-            Ok(data) => {
-                let hit = CacheRead::from(io::Cursor::new(data))?;
+            Ok(file) => {
+                let hit = CacheRead::from(file)?;
                 Ok(Cache::Hit(hit))
             }
             Err(e) => {
@@ -80,14 +182,15 @@ impl Storage for S3Cache {
         };
 
         let bucket = self.bucket.clone();
+        let pool = self.pool.clone();
         let response = self
             .provider
             .credentials()
             .then(move |credentials| match credentials {
-                Ok(creds) => bucket.get(&key, Some(&creds)),
+                Ok(creds) => bucket.get(&key, Some(&creds), &pool),
                 Err(e) => {
                     debug!("Could not load AWS creds: {}", e);
-                    bucket.get(&key, None)
+                    bucket.get(&key, None, &pool)
                 }
             })
             .then(result_cb);
@@ -95,9 +198,9 @@ impl Storage for S3Cache {
     }
 
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
-        let key = normalize_key(&key);
+        let key = normalize_key(&self.key_prefix, key);
         let start = Instant::now();
-        let data = match entry.finish() {
+        let data = match entry.finish_reader() {
             Ok(data) => data,
             Err(e) => return f_err(e),
         };
@@ -107,23 +210,149 @@ impl Storage for S3Cache {
             .chain_err(|| "failed to get AWS credentials");
 
         let bucket = self.bucket.clone();
+        let put_options = self.put_options.clone();
+        let pool = self.pool.clone();
         let response = credentials.and_then(move |credentials| {
             bucket
-                .put(&key, data, &credentials)
+                .put(&key, data, &put_options, &credentials, &pool)
                 .chain_err(|| "failed to put cache entry in s3")
         });
 
         Box::new(response.map(move |_| start.elapsed()))
     }
 
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let key = normalize_key(&self.key_prefix, key);
+        let bucket = self.bucket.clone();
+        Box::new(
+            self.provider
+                .credentials()
+                .chain_err(|| "failed to get AWS credentials")
+                .and_then(move |credentials| bucket.delete(&key, &credentials)),
+        )
+    }
+
+    fn list(&self) -> SFuture<Vec<EntryInfo>> {
+        let bucket = self.bucket.clone();
+        let prefix = self.key_prefix.clone();
+        Box::new(self.provider.credentials().then(move |credentials| {
+            let credentials = credentials
+                .map_err(|e| debug!("Could not load AWS creds: {}", e))
+                .ok();
+            bucket
+                .list(&prefix, credentials.as_ref())
+                .map(move |objects| {
+                    objects
+                        .into_iter()
+                        .filter_map(|object| {
+                            Some(EntryInfo {
+                                key: entry_key(&prefix, &object.key)?,
+                                size: object.size,
+                                modified: object.last_modified,
+                            })
+                        })
+                        .collect()
+                })
+        }))
+    }
+
+    fn stat(&self, key: &str) -> SFuture<Option<EntryInfo>> {
+        let object_key = normalize_key(&self.key_prefix, key);
+        let key = key.to_owned();
+        let bucket = self.bucket.clone();
+        Box::new(
+            self.provider
+                .credentials()
+                .then(move |credentials| {
+                    let credentials = credentials
+                        .map_err(|e| debug!("Could not load AWS creds: {}", e))
+                        .ok();
+                    bucket.head(&object_key, credentials.as_ref())
+                })
+                .map(move |object| {
+                    object.map(|object| EntryInfo {
+                        key,
+                        size: object.size,
+                        modified: object.last_modified,
+                    })
+                }),
+        )
+    }
+
     fn location(&self) -> String {
         format!("S3, bucket: {}", self.bucket)
     }
 
+    fn rw_mode(&self) -> CacheRWMode {
+        self.rw_mode
+    }
+
+    /// Returns the total size of the objects under the key prefix, listing
+    /// them in the background at most every `SIZE_REFRESH_INTERVAL`, or
+    /// `None` until they've been listed.
     fn current_size(&self) -> SFuture<Option<u64>> {
-        Box::new(future::ok(None))
+        f_ok(self.size.get(|| {
+            let bucket = self.bucket.clone();
+            let prefix = self.key_prefix.clone();
+            Box::new(self.provider.credentials().then(move |credentials| {
+                let credentials = credentials
+                    .map_err(|e| debug!("Could not load AWS creds: {}", e))
+                    .ok();
+                bucket
+                    .list(&prefix, credentials.as_ref())
+                    .map(|objects| Some(objects.iter().map(|object| object.size).sum()))
+            }))
+        }))
     }
     fn max_size(&self) -> SFuture<Option<u64>> {
         Box::new(future::ok(None))
     }
 }
+
+#[test]
+fn test_normalize_key() {
+    assert_eq!(normalize_key("", "abcdef"), "a/b/c/abcdef");
+    assert_eq!(
+        normalize_key(&normalize_key_prefix("/project/"), "abcdef"),
+        "project/a/b/c/abcdef"
+    );
+}
+
+#[test]
+fn test_entry_key() {
+    assert_eq!(entry_key("", "a/b/c/abcdef"), Some("abcdef".to_owned()));
+    assert_eq!(
+        entry_key("project/", "project/a/b/c/abcdef"),
+        Some("abcdef".to_owned())
+    );
+    assert_eq!(entry_key("project/", "a/b/c/abcdef"), None);
+    assert_eq!(entry_key("", "a/b/d/abcdef"), None);
+    assert_eq!(entry_key("", "abcdef"), None);
+}
+
+#[test]
+fn test_endpoint_region() {
+    for &(endpoint, region) in &[
+        ("bucket.s3-eu-west-1.amazonaws.com", Some("eu-west-1")),
+        ("bucket.s3.eu-west-1.amazonaws.com", Some("eu-west-1")),
+        ("s3.eu-west-1.amazonaws.com/bucket", Some("eu-west-1")),
+        ("s3-eu-west-1.amazonaws.com:443/bucket", Some("eu-west-1")),
+        (
+            "bucket.s3.dualstack.ap-south-1.amazonaws.com",
+            Some("ap-south-1"),
+        ),
+        ("bucket.s3.cn-north-1.amazonaws.com.cn", Some("cn-north-1")),
+        ("s3-bucket.s3-external-1.amazonaws.com", Some("us-east-1")),
+        ("s3-bucket.s3.amazonaws.com", None),
+        ("bucket.s3-accelerate.amazonaws.com", None),
+        ("127.0.0.1:9000/bucket", None),
+        ("s3.example.com/bucket", None),
+    ] {
+        assert_eq!(
+            endpoint_region(endpoint),
+            region.map(str::to_owned),
+            "{}",
+            endpoint
+        );
+    }
+}
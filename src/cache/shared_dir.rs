@@ -0,0 +1,457 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::disk::{list_entries, make_key_path, stat_entry};
+use crate::cache::{normalize_key_prefix, Cache, CacheRead, CacheWrite, EntryInfo, Storage};
+use crate::config::CacheRWMode;
+use filetime::{set_file_times, FileTime};
+use futures_cpupool::CpuPool;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+use crate::errors::*;
+
+/// The prefix of the temporary files entries are written to before being
+/// renamed into place.
+const TEMP_FILE_PREFIX: &str = ".sccache-tmp";
+
+/// How old a temporary file has to be before a sweep assumes its writer died.
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A cache that stores entries in a directory that may be shared between
+/// machines, for example over NFS.
+///
+/// Unlike `DiskCache`, no index of the entries is kept in memory, so other
+/// processes may add and remove entries at any time. Entries are written to a
+/// temporary file and renamed into place, so readers never see a partial
+/// entry. The size of the cache is only enforced by an optional periodic
+/// sweep, which removes the least recently used entries.
+#[derive(Clone)]
+pub struct SharedDirCache {
+    /// The directory swept to enforce the size limit.
+    root: PathBuf,
+    /// The directory entries are stored in, `root` or a subdirectory of it.
+    entries: PathBuf,
+    /// The size to sweep the cache down to, if any.
+    max_size: Option<u64>,
+    /// How often to sweep the cache.
+    sweep_interval: Duration,
+    /// When this process last swept the cache.
+    last_sweep: Arc<Mutex<Option<Instant>>>,
+    /// Thread pool to execute disk I/O
+    pool: CpuPool,
+    /// Whether the cache may be read from and written to.
+    rw_mode: CacheRWMode,
+}
+
+impl SharedDirCache {
+    /// Create a new `SharedDirCache` rooted at `root`, storing entries in the
+    /// `key_prefix` subdirectory. If `max_size` is set, the whole of `root` is
+    /// swept down to that many bytes at most every `sweep_interval`.
+    pub fn new<T: AsRef<Path>>(
+        root: T,
+        key_prefix: &str,
+        max_size: Option<u64>,
+        sweep_interval: Duration,
+        rw_mode: CacheRWMode,
+        pool: &CpuPool,
+    ) -> Result<SharedDirCache> {
+        let root = root.as_ref().to_owned();
+        let entries = root.join(normalize_key_prefix(key_prefix));
+        fs::create_dir_all(&entries)
+            .chain_err(|| format!("failed to create shared cache directory {:?}", entries))?;
+        Ok(SharedDirCache {
+            root,
+            entries,
+            max_size,
+            sweep_interval,
+            last_sweep: Arc::new(Mutex::new(None)),
+            pool: pool.clone(),
+            rw_mode,
+        })
+    }
+
+    /// Return whether a sweep is due, and if so record that one is starting.
+    fn start_sweep(&self) -> bool {
+        if self.max_size.is_none() {
+            return false;
+        }
+        let mut last_sweep = self.last_sweep.lock().unwrap();
+        match *last_sweep {
+            Some(t) if t.elapsed() < self.sweep_interval => false,
+            _ => {
+                *last_sweep = Some(Instant::now());
+                true
+            }
+        }
+    }
+}
+
+fn filetime_now() -> FileTime {
+    let d = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::new(0, 0));
+    FileTime::from_seconds_since_1970(d.as_secs(), d.subsec_nanos())
+}
+
+/// Remove the least recently used entries under `root` until the entries
+/// take up at most `max_size` bytes, along with any stale temporary files.
+///
+/// Other machines may be sweeping or writing at the same time, so files that
+/// disappear while sweeping are ignored. Files that can't be removed are
+/// logged and skipped.
+fn sweep(root: &Path, max_size: u64) {
+    let mut entries = vec![];
+    let mut total = 0;
+    for entry in WalkDir::new(root) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                trace!("SharedDirCache::sweep: {}", e);
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let mtime = metadata.modified().unwrap_or(UNIX_EPOCH);
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(TEMP_FILE_PREFIX)
+        {
+            let stale = mtime
+                .elapsed()
+                .map(|age| age > STALE_TEMP_FILE_AGE)
+                .unwrap_or(false);
+            if stale {
+                if let Err(e) = remove_file(entry.path()) {
+                    warn!(
+                        "SharedDirCache::sweep: failed to remove {:?}: {}",
+                        entry.path(),
+                        e
+                    );
+                }
+            }
+            continue;
+        }
+        total += metadata.len();
+        entries.push((mtime, metadata.len(), entry.path().to_owned()));
+    }
+    if total <= max_size {
+        return;
+    }
+    entries.sort();
+    for (_, len, path) in entries {
+        if total <= max_size {
+            break;
+        }
+        trace!("SharedDirCache::sweep: removing {:?}", path);
+        match remove_file(&path) {
+            Ok(()) => total -= len,
+            Err(e) => warn!("SharedDirCache::sweep: failed to remove {:?}: {}", path, e),
+        }
+    }
+}
+
+/// Remove the file at `path`, ignoring it having already been removed.
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Make an entry readable by everyone sharing the cache. Temporary files are
+/// created readable only by their owner.
+#[cfg(unix)]
+fn set_entry_permissions(file: &File) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(fs::Permissions::from_mode(0o644))?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_entry_permissions(_file: &File) -> Result<()> {
+    Ok(())
+}
+
+impl Storage for SharedDirCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        trace!("SharedDirCache::get({})", key);
+        let path = self.entries.join(make_key_path(key));
+        let track_use = self.max_size.is_some();
+        Box::new(self.pool.spawn_fn(move || {
+            let f = match File::open(&path) {
+                Ok(f) => f,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Cache::Miss),
+                Err(e) => return Err(e.into()),
+            };
+            if track_use {
+                // Sweeps remove the least recently used entries first. The
+                // cache may be mounted read-only, so failing to do so is fine.
+                let t = filetime_now();
+                if let Err(e) = set_file_times(&path, t, t) {
+                    trace!("SharedDirCache::get: failed to update {:?}: {}", path, e);
+                }
+            }
+            Ok(Cache::Hit(CacheRead::from(f)?))
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        trace!("SharedDirCache::put({})", key);
+        let path = self.entries.join(make_key_path(key));
+        if let (true, Some(max_size)) = (self.start_sweep(), self.max_size) {
+            // Sweeping the whole tree can take a while, so it's left to run
+            // on its own rather than holding up this write.
+            let root = self.root.clone();
+            self.pool
+                .spawn_fn(move || -> Result<()> {
+                    sweep(&root, max_size);
+                    Ok(())
+                })
+                .forget();
+        }
+        Box::new(self.pool.spawn_fn(move || -> Result<_> {
+            let start = Instant::now();
+            let dir = path.parent().expect("Cache entry path has no parent");
+            fs::create_dir_all(dir)?;
+            let mut reader = entry.finish_reader()?;
+            // The temporary file is created next to the entry so that it can
+            // be renamed into place atomically.
+            let mut temp = tempfile::Builder::new()
+                .prefix(TEMP_FILE_PREFIX)
+                .tempfile_in(dir)?;
+            io::copy(&mut reader, &mut temp)?;
+            set_entry_permissions(temp.as_file())?;
+            temp.persist(&path)?;
+            Ok(start.elapsed())
+        }))
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        trace!("SharedDirCache::remove({})", key);
+        let path = self.entries.join(make_key_path(key));
+        Box::new(self.pool.spawn_fn(move || remove_file(&path)))
+    }
+
+    fn list(&self) -> SFuture<Vec<EntryInfo>> {
+        let entries = self.entries.clone();
+        Box::new(self.pool.spawn_fn(move || list_entries(&entries)))
+    }
+
+    fn stat(&self, key: &str) -> SFuture<Option<EntryInfo>> {
+        let entries = self.entries.clone();
+        let key = key.to_owned();
+        Box::new(self.pool.spawn_fn(move || stat_entry(&entries, &key)))
+    }
+
+    fn location(&self) -> String {
+        format!("Shared directory: {:?}", self.entries)
+    }
+
+    fn rw_mode(&self) -> CacheRWMode {
+        self.rw_mode
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        f_ok(None)
+    }
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        f_ok(self.max_size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn make_entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new().unwrap();
+        entry.put_object("obj", &mut &contents[..], None).unwrap();
+        entry
+    }
+
+    fn count_files(root: &Path) -> usize {
+        WalkDir::new(root)
+            .into_iter()
+            .filter(|e| e.as_ref().unwrap().file_type().is_file())
+            .count()
+    }
+
+    #[test]
+    fn test_shared_dir_cache() {
+        let tempdir = TempDir::new("sccache_test_shared_dir").unwrap();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let cache = SharedDirCache::new(
+            tempdir.path(),
+            "project",
+            None,
+            Duration::from_secs(0),
+            CacheRWMode::ReadWrite,
+            &pool,
+        )
+        .unwrap();
+
+        match runtime.block_on(cache.get("abcd")).unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+        runtime
+            .block_on(cache.put("abcd", make_entry(b"contents")))
+            .unwrap();
+        assert!(tempdir
+            .path()
+            .join("project")
+            .join("a")
+            .join("b")
+            .join("abcd")
+            .is_file());
+        // Only the entry itself is left behind.
+        assert_eq!(count_files(tempdir.path()), 1);
+
+        // A second cache sharing the directory sees the entry.
+        let other = SharedDirCache::new(
+            tempdir.path(),
+            "project/",
+            None,
+            Duration::from_secs(0),
+            CacheRWMode::ReadWrite,
+            &pool,
+        )
+        .unwrap();
+        match runtime.block_on(other.get("abcd")).unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut data = vec![];
+                entry.get_object("obj", &mut data).unwrap();
+                assert_eq!(data, b"contents");
+            }
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+
+        let entries = runtime.block_on(other.list()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, "abcd");
+        assert_eq!(
+            runtime.block_on(other.stat("abcd")).unwrap(),
+            Some(entries[0].clone())
+        );
+
+        runtime.block_on(other.remove("abcd")).unwrap();
+        assert_eq!(runtime.block_on(other.stat("abcd")).unwrap(), None);
+        match runtime.block_on(cache.get("abcd")).unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_shared_dir_cache_entry_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let tempdir = TempDir::new("sccache_test_shared_dir").unwrap();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let cache = SharedDirCache::new(
+            tempdir.path(),
+            "",
+            None,
+            Duration::from_secs(0),
+            CacheRWMode::ReadWrite,
+            &pool,
+        )
+        .unwrap();
+
+        runtime
+            .block_on(cache.put("abcd", make_entry(b"contents")))
+            .unwrap();
+        let path = tempdir.path().join("a").join("b").join("abcd");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+    }
+
+    #[test]
+    fn test_shared_dir_cache_sweep() {
+        let tempdir = TempDir::new("sccache_test_shared_dir").unwrap();
+        let root = tempdir.path();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let cache = SharedDirCache::new(
+            root,
+            "",
+            None,
+            Duration::from_secs(0),
+            CacheRWMode::ReadWrite,
+            &pool,
+        )
+        .unwrap();
+        for (i, key) in ["aaaa", "bbbb", "cccc"].iter().enumerate() {
+            runtime
+                .block_on(cache.put(key, make_entry(&[0; 1024])))
+                .unwrap();
+            let path = root.join(make_key_path(key));
+            let t = FileTime::from_seconds_since_1970(1000 * (i as u64 + 1), 0);
+            set_file_times(&path, t, t).unwrap();
+        }
+        let size = fs::metadata(root.join(make_key_path("aaaa")))
+            .unwrap()
+            .len();
+
+        sweep(root, size * 2);
+        assert!(!root.join(make_key_path("aaaa")).exists());
+        assert!(root.join(make_key_path("bbbb")).exists());
+        assert!(root.join(make_key_path("cccc")).exists());
+    }
+
+    #[test]
+    fn test_shared_dir_cache_sweeps_on_put() {
+        let tempdir = TempDir::new("sccache_test_shared_dir").unwrap();
+        let root = tempdir.path();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let cache = SharedDirCache::new(
+            root,
+            "",
+            Some(0),
+            Duration::from_secs(0),
+            CacheRWMode::ReadWrite,
+            &pool,
+        )
+        .unwrap();
+        runtime
+            .block_on(cache.put("aaaa", make_entry(b"contents")))
+            .unwrap();
+        // The pool has a single thread, so the sweep started by the second
+        // write runs before that write, and removes only the first entry.
+        runtime
+            .block_on(cache.put("bbbb", make_entry(b"contents")))
+            .unwrap();
+        let entries = runtime.block_on(cache.list()).unwrap();
+        let keys: Vec<_> = entries.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, vec!["bbbb"]);
+    }
+}
@@ -0,0 +1,391 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{spool_to_temp_file, Cache, CacheRead, CacheWrite, EntryInfo, Storage};
+use crate::config::{CacheRWMode, CacheWritePolicy};
+use futures::future::{self, Future};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::current_thread::TaskExecutor;
+
+use crate::errors::*;
+
+/// A single level of a `TieredCache`.
+pub struct CacheTier {
+    /// The storage backing this tier.
+    storage: Arc<dyn Storage>,
+    /// Whether writes to this tier are waited on.
+    write_policy: CacheWritePolicy,
+}
+
+impl CacheTier {
+    pub fn new(storage: Arc<dyn Storage>, write_policy: CacheWritePolicy) -> CacheTier {
+        CacheTier {
+            storage,
+            write_policy,
+        }
+    }
+}
+
+/// A cache made of several `Storage` tiers, ordered from fastest to slowest.
+///
+/// Lookups try each tier in turn, and a hit in a slower tier is copied back
+/// into all of the faster ones. Entries are written to every tier.
+pub struct TieredCache {
+    tiers: Rc<Vec<CacheTier>>,
+}
+
+impl TieredCache {
+    /// Create a new `TieredCache` from `tiers`, fastest first.
+    pub fn new(tiers: Vec<CacheTier>) -> TieredCache {
+        assert!(!tiers.is_empty(), "TieredCache needs at least one tier");
+        TieredCache {
+            tiers: Rc::new(tiers),
+        }
+    }
+}
+
+/// Look up `key` in `tiers`, starting at the tier with index `idx`.
+fn get_from_tier(tiers: Rc<Vec<CacheTier>>, idx: usize, key: String) -> SFuture<Cache> {
+    if idx == tiers.len() {
+        return f_ok(Cache::Miss);
+    }
+    if !tiers[idx].storage.rw_mode().can_read() {
+        return get_from_tier(tiers, idx + 1, key);
+    }
+    let lookup = tiers[idx].storage.get(&key);
+    Box::new(lookup.then(move |result| -> SFuture<Cache> {
+        match result {
+            Ok(Cache::Hit(entry)) => {
+                trace!("TieredCache::get({}): hit in tier {}", key, idx);
+                if idx == 0 {
+                    return f_ok(Cache::Hit(entry));
+                }
+                f_res(backfill(&tiers[..idx], &key, entry).map(Cache::Hit))
+            }
+            Ok(Cache::Miss) => get_from_tier(tiers, idx + 1, key),
+            Ok(Cache::Recache) => f_ok(Cache::Recache),
+            // Only report an error if there's no other tier left to try.
+            Err(e) => {
+                if idx + 1 == tiers.len() {
+                    return f_err(e);
+                }
+                warn!("TieredCache::get({}): error in tier {}: {:?}", key, idx, e);
+                get_from_tier(tiers, idx + 1, key)
+            }
+        }
+    }))
+}
+
+/// Copy `entry` into each of `tiers` in the background, returning an
+/// equivalent entry to be used for the current cache hit.
+fn backfill(tiers: &[CacheTier], key: &str, entry: CacheRead) -> Result<CacheRead> {
+    let spooled = spool_to_temp_file(&mut entry.into_reader()?)?;
+    for tier in tiers.iter().filter(|t| t.storage.rw_mode().can_write()) {
+        let write = tier
+            .storage
+            .put(key, CacheWrite::from_reader(spooled.reopen()?));
+        spawn_background_write(key, write);
+    }
+    CacheRead::from(spooled.reopen()?)
+}
+
+/// Run the cache write `write` without waiting for its result.
+fn spawn_background_write(key: &str, write: SFuture<Duration>) {
+    let key = key.to_owned();
+    let write = write.then(move |result| {
+        if let Err(e) = result {
+            warn!("TieredCache: background write of {} failed: {:?}", key, e);
+        }
+        Ok::<(), ()>(())
+    });
+    if let Err(e) = TaskExecutor::current().spawn_local(Box::new(write)) {
+        warn!("TieredCache: failed to spawn background write: {:?}", e);
+    }
+}
+
+/// Merge the entries listed by each tier, skipping tiers that failed to list
+/// their entries, unless they all did.
+///
+/// An entry stored in several tiers is as old as its most recently used copy.
+fn merge_listings(listings: Vec<Result<Vec<EntryInfo>>>) -> Result<Vec<EntryInfo>> {
+    let mut merged: HashMap<String, EntryInfo> = HashMap::new();
+    let mut error = None;
+    let mut listed = false;
+    for listing in listings {
+        let entries = match listing {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("TieredCache: failed to list a tier: {}", e);
+                error = Some(e);
+                continue;
+            }
+        };
+        listed = true;
+        for entry in entries {
+            let merged_entry = merged
+                .entry(entry.key.clone())
+                .or_insert_with(|| entry.clone());
+            merged_entry.modified = merged_entry.modified.max(entry.modified);
+        }
+    }
+    match error {
+        Some(e) if !listed => Err(e),
+        _ => Ok(merged.into_iter().map(|(_, entry)| entry).collect()),
+    }
+}
+
+impl Storage for TieredCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        get_from_tier(self.tiers.clone(), 0, key.to_owned())
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let spooled = ftry!(entry
+            .finish_reader()
+            .and_then(|mut reader| spool_to_temp_file(&mut reader)));
+        let mut writes = vec![];
+        for tier in self.tiers.iter() {
+            if !tier.storage.rw_mode().can_write() {
+                continue;
+            }
+            let write = tier
+                .storage
+                .put(key, CacheWrite::from_reader(ftry!(spooled.reopen())));
+            match tier.write_policy {
+                CacheWritePolicy::WriteThrough => writes.push(write),
+                CacheWritePolicy::WriteBack => spawn_background_write(key, write),
+            }
+        }
+        Box::new(future::join_all(writes).map(move |_| start.elapsed()))
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let removes: Vec<_> = self
+            .tiers
+            .iter()
+            .filter(|t| t.storage.rw_mode().can_write())
+            .map(|t| t.storage.remove(key))
+            .collect();
+        Box::new(future::join_all(removes).map(|_| ()))
+    }
+
+    fn list(&self) -> SFuture<Vec<EntryInfo>> {
+        let listings: Vec<_> = self
+            .tiers
+            .iter()
+            .map(|t| t.storage.list().then(Ok::<_, Error>))
+            .collect();
+        Box::new(future::join_all(listings).and_then(merge_listings))
+    }
+
+    fn stat(&self, key: &str) -> SFuture<Option<EntryInfo>> {
+        let stats: Vec<_> = self
+            .tiers
+            .iter()
+            .map(|t| {
+                t.storage
+                    .stat(key)
+                    .map(|entry| entry.into_iter().collect::<Vec<_>>())
+                    .then(Ok::<_, Error>)
+            })
+            .collect();
+        Box::new(
+            future::join_all(stats)
+                .and_then(merge_listings)
+                .map(|entries| entries.into_iter().next()),
+        )
+    }
+
+    fn location(&self) -> String {
+        let locations: Vec<_> = self.tiers.iter().map(|t| t.storage.location()).collect();
+        format!("Tiered: {}", locations.join(" -> "))
+    }
+
+    fn rw_mode(&self) -> CacheRWMode {
+        let modes: Vec<_> = self.tiers.iter().map(|t| t.storage.rw_mode()).collect();
+        match (
+            modes.iter().any(|m| m.can_read()),
+            modes.iter().any(|m| m.can_write()),
+        ) {
+            (true, false) => CacheRWMode::ReadOnly,
+            (false, true) => CacheRWMode::WriteOnly,
+            _ => CacheRWMode::ReadWrite,
+        }
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.tiers[0].storage.current_size()
+    }
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.tiers[0].storage.max_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use futures_cpupool::CpuPool;
+    use std::time::UNIX_EPOCH;
+    use std::u64;
+    use tempdir::TempDir;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn make_entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new().unwrap();
+        entry.put_object("obj", &mut &contents[..], None).unwrap();
+        entry
+    }
+
+    fn read_object(cache: Cache) -> Vec<u8> {
+        match cache {
+            Cache::Hit(mut entry) => {
+                let mut data = vec![];
+                entry.get_object("obj", &mut data).unwrap();
+                data
+            }
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_tiered_cache_backfill() {
+        let tempdir = TempDir::new("sccache_test_tiered").unwrap();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let local: Arc<dyn Storage> = Arc::new(DiskCache::new(
+            &tempdir.path().join("local"),
+            u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
+        ));
+        let remote: Arc<dyn Storage> = Arc::new(DiskCache::new(
+            &tempdir.path().join("remote"),
+            u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
+        ));
+        let cache = TieredCache::new(vec![
+            CacheTier::new(local.clone(), CacheWritePolicy::WriteThrough),
+            CacheTier::new(remote.clone(), CacheWritePolicy::WriteThrough),
+        ]);
+
+        runtime
+            .block_on(remote.put("abcd", make_entry(b"remote contents")))
+            .unwrap();
+        match runtime.block_on(local.get("abcd")).unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+
+        let hit = runtime
+            .block_on(future::lazy(|| cache.get("abcd")))
+            .unwrap();
+        assert_eq!(read_object(hit), b"remote contents");
+        // Let the back-fill of the local tier finish.
+        runtime.run().unwrap();
+        let hit = runtime.block_on(local.get("abcd")).unwrap();
+        assert_eq!(read_object(hit), b"remote contents");
+    }
+
+    #[test]
+    fn test_tiered_cache_write_policies() {
+        let tempdir = TempDir::new("sccache_test_tiered").unwrap();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let local: Arc<dyn Storage> = Arc::new(DiskCache::new(
+            &tempdir.path().join("local"),
+            u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
+        ));
+        let remote: Arc<dyn Storage> = Arc::new(DiskCache::new(
+            &tempdir.path().join("remote"),
+            u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
+        ));
+        let cache = TieredCache::new(vec![
+            CacheTier::new(local.clone(), CacheWritePolicy::WriteThrough),
+            CacheTier::new(remote.clone(), CacheWritePolicy::WriteBack),
+        ]);
+
+        runtime
+            .block_on(future::lazy(|| cache.put("abcd", make_entry(b"contents"))))
+            .unwrap();
+        let hit = runtime.block_on(local.get("abcd")).unwrap();
+        assert_eq!(read_object(hit), b"contents");
+        // The write-back tier is written in the background.
+        runtime.run().unwrap();
+        let hit = runtime.block_on(remote.get("abcd")).unwrap();
+        assert_eq!(read_object(hit), b"contents");
+    }
+
+    #[test]
+    fn test_tiered_cache_read_only_tier() {
+        let tempdir = TempDir::new("sccache_test_tiered").unwrap();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let local: Arc<dyn Storage> = Arc::new(DiskCache::new(
+            &tempdir.path().join("local"),
+            u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
+        ));
+        let remote_dir = tempdir.path().join("remote");
+        let remote: Arc<dyn Storage> = Arc::new(DiskCache::new(
+            &remote_dir,
+            u64::MAX,
+            CacheRWMode::ReadOnly,
+            &pool,
+        ));
+        let cache = TieredCache::new(vec![
+            CacheTier::new(local.clone(), CacheWritePolicy::WriteThrough),
+            CacheTier::new(remote.clone(), CacheWritePolicy::WriteThrough),
+        ]);
+        assert_eq!(cache.rw_mode(), CacheRWMode::ReadWrite);
+
+        runtime
+            .block_on(future::lazy(|| cache.put("abcd", make_entry(b"contents"))))
+            .unwrap();
+        runtime.run().unwrap();
+        let hit = runtime.block_on(local.get("abcd")).unwrap();
+        assert_eq!(read_object(hit), b"contents");
+        assert!(!remote_dir.join("a").exists());
+    }
+
+    #[test]
+    fn test_merge_listings() {
+        let entry = |key: &str, secs| EntryInfo {
+            key: key.to_owned(),
+            size: 10,
+            modified: Some(UNIX_EPOCH + Duration::from_secs(secs)),
+        };
+        let mut merged = merge_listings(vec![
+            Ok(vec![entry("aaaa", 100), entry("bbbb", 100)]),
+            Err("unsupported".into()),
+            Ok(vec![entry("aaaa", 200)]),
+        ])
+        .unwrap();
+        merged.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(merged, vec![entry("aaaa", 200), entry("bbbb", 100)]);
+
+        assert!(merge_listings(vec![Err("unsupported".into())]).is_err());
+        assert_eq!(merge_listings(vec![]).unwrap(), vec![]);
+    }
+}
@@ -12,11 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::config::parse_duration;
 use crate::errors::*;
 use clap::{App, AppSettings, Arg};
 use std::env;
 use std::ffi::OsString;
 use std::path::PathBuf;
+use std::time::Duration;
 use which::which_in;
 
 arg_enum! {
@@ -46,6 +48,14 @@ pub enum Command {
     DistAuth,
     /// Package a toolchain for distributed compilation (executable, out)
     PackageToolchain(PathBuf, PathBuf),
+    /// List the entries in the cache.
+    ListCache,
+    /// Show the size and age of a cache entry.
+    StatCacheEntry(String),
+    /// Remove cache entries that haven't been used for the given time.
+    PruneCache(Duration),
+    /// Remove a cache entry.
+    RemoveCacheEntry(String),
     /// Run a compiler command.
     Compile {
         /// The binary to execute.
@@ -82,6 +92,16 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
                 )
         .arg(Arg::from_usage("--package-toolchain <executable> <out> 'package toolchain for distributed compilation'")
              .required(false))
+        .arg(Arg::from_usage("--cache-ls 'list the entries in the cache'"))
+        .arg(Arg::from_usage("--cache-stat <key> 'show the size and age of a cache entry'")
+             .required(false))
+        .arg(Arg::from_usage("--cache-prune 'remove cache entries older than --older-than'")
+             .requires("older-than"))
+        .arg(Arg::from_usage("--older-than <age> 'age of the entries to prune, such as 30d'")
+             .required(false)
+             .requires("cache-prune"))
+        .arg(Arg::from_usage("--cache-rm <key> 'remove a cache entry'")
+             .required(false))
         .arg(Arg::from_usage("--stats-format  'set output format of statistics'")
              .possible_values(&StatsFormat::variants())
              .default_value("text"))
@@ -151,6 +171,10 @@ pub fn parse() -> Result<Command> {
     let dist_auth = matches.is_present("dist-auth");
     let dist_status = matches.is_present("dist-status");
     let package_toolchain = matches.is_present("package-toolchain");
+    let cache_ls = matches.is_present("cache-ls");
+    let cache_stat = matches.is_present("cache-stat");
+    let cache_prune = matches.is_present("cache-prune");
+    let cache_rm = matches.is_present("cache-rm");
     let cmd = matches.values_of_os("cmd");
     // Ensure that we've only received one command to run.
     fn is_some<T>(x: &Option<T>) -> bool {
@@ -163,6 +187,10 @@ pub fn parse() -> Result<Command> {
         stop_server,
         zero_stats,
         package_toolchain,
+        cache_ls,
+        cache_stat,
+        cache_prune,
+        cache_rm,
         is_some(&cmd),
     ]
     .iter()
@@ -198,6 +226,19 @@ pub fn parse() -> Result<Command> {
             values.next().expect("package-toolchain missing value 2"),
         );
         Ok(Command::PackageToolchain(executable.into(), out.into()))
+    } else if cache_ls {
+        Ok(Command::ListCache)
+    } else if cache_stat {
+        let key = cache_key(matches.value_of("cache-stat"))?;
+        Ok(Command::StatCacheEntry(key))
+    } else if cache_prune {
+        let age = matches.value_of("older-than").unwrap_or("");
+        let older_than =
+            parse_duration(age).ok_or_else(|| format!("Invalid --older-than age: {:?}", age))?;
+        Ok(Command::PruneCache(older_than))
+    } else if cache_rm {
+        let key = cache_key(matches.value_of("cache-rm"))?;
+        Ok(Command::RemoveCacheEntry(key))
     } else if let Some(mut args) = cmd {
         if let Some(exe) = args.next() {
             let cmdline = args.map(|s| s.to_owned()).collect::<Vec<_>>();
@@ -225,3 +266,14 @@ pub fn parse() -> Result<Command> {
         bail!("No command specified");
     }
 }
+
+/// Check that a cache key given on the commandline looks like one sccache
+/// generated, which also keeps it from escaping the cache directory.
+fn cache_key(key: Option<&str>) -> Result<String> {
+    match key {
+        Some(key) if key.len() >= 3 && key.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            Ok(key.to_owned())
+        }
+        key => bail!("Invalid cache key: {:?}", key.unwrap_or("")),
+    }
+}
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cache::{EntryInfo, PruneStats};
 use crate::client::{connect_to_server, connect_with_retry, ServerConnection};
 use crate::cmdline::{Command, StatsFormat};
 use crate::compiler::ColorMode;
@@ -33,6 +34,7 @@ use std::io::{self, Write};
 use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
 use std::process;
+use std::time::{Duration, SystemTime};
 use strip_ansi_escapes::Writer;
 use tokio::runtime::current_thread::Runtime;
 use tokio_io::io::read_exact;
@@ -330,6 +332,81 @@ pub fn request_shutdown(mut conn: ServerConnection) -> Result<ServerInfo> {
     }
 }
 
+/// Send a `ListCache` request to the server, and return the cache entries if successful.
+pub fn request_list_cache(mut conn: ServerConnection) -> Result<Vec<EntryInfo>> {
+    debug!("request_list_cache");
+    let response = conn
+        .request(Request::ListCache)
+        .chain_err(|| "Failed to send data to or receive data from server")?;
+    match response {
+        Response::CacheEntries(entries) => Ok(entries),
+        Response::CacheError(e) => bail!(e),
+        _ => bail!("Unexpected server response!"),
+    }
+}
+
+/// Send a `StatCacheEntry` request to the server, and return the entry if the cache has it.
+pub fn request_stat_cache_entry(
+    mut conn: ServerConnection,
+    key: &str,
+) -> Result<Option<EntryInfo>> {
+    debug!("request_stat_cache_entry");
+    let response = conn
+        .request(Request::StatCacheEntry(key.to_owned()))
+        .chain_err(|| "Failed to send data to or receive data from server")?;
+    match response {
+        Response::CacheEntry(entry) => Ok(entry),
+        Response::CacheError(e) => bail!(e),
+        _ => bail!("Unexpected server response!"),
+    }
+}
+
+/// Send a `PruneCache` request to the server, and return what was removed if successful.
+pub fn request_prune_cache(mut conn: ServerConnection, older_than: Duration) -> Result<PruneStats> {
+    debug!("request_prune_cache");
+    let response = conn
+        .request(Request::PruneCache(older_than))
+        .chain_err(|| "Failed to send data to or receive data from server")?;
+    match response {
+        Response::CachePruned(stats) => Ok(stats),
+        Response::CacheError(e) => bail!(e),
+        _ => bail!("Unexpected server response!"),
+    }
+}
+
+/// Send a `RemoveCacheEntry` request to the server.
+pub fn request_remove_cache_entry(mut conn: ServerConnection, key: &str) -> Result<()> {
+    debug!("request_remove_cache_entry");
+    let response = conn
+        .request(Request::RemoveCacheEntry(key.to_owned()))
+        .chain_err(|| "Failed to send data to or receive data from server")?;
+    match response {
+        Response::CacheEntryRemoved => Ok(()),
+        Response::CacheError(e) => bail!(e),
+        _ => bail!("Unexpected server response!"),
+    }
+}
+
+/// Format how long ago `modified` was, in the largest whole unit.
+fn fmt_age(modified: Option<SystemTime>) -> String {
+    let secs = match modified.map(|t| SystemTime::now().duration_since(t)) {
+        Some(Ok(age)) => age.as_secs(),
+        Some(Err(_)) => 0,
+        None => return "-".to_owned(),
+    };
+    match secs {
+        s if s >= 24 * 60 * 60 => format!("{}d", s / (24 * 60 * 60)),
+        s if s >= 60 * 60 => format!("{}h", s / (60 * 60)),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+/// Print a cache entry as its key, size in bytes and age.
+fn print_entry(entry: &EntryInfo) {
+    println!("{}\t{}\t{}", entry.key, entry.size, fmt_age(entry.modified));
+}
+
 /// Send a `Compile` request to the server, and return the server response if successful.
 fn request_compile<W, X, Y>(
     conn: &mut ServerConnection,
@@ -672,6 +749,40 @@ pub fn run_command(cmd: Command) -> Result<i32> {
         Command::PackageToolchain(_executable, _out) => bail!(
             "Toolchain packaging not compiled in, please rebuild with the dist-client feature"
         ),
+        Command::ListCache => {
+            trace!("Command::ListCache");
+            let srv = connect_or_start_server(get_port())?;
+            let mut entries = request_list_cache(srv).chain_err(|| "failed to list cache")?;
+            entries.sort_by(|a, b| a.key.cmp(&b.key));
+            for entry in &entries {
+                print_entry(entry);
+            }
+            let bytes: u64 = entries.iter().map(|entry| entry.size).sum();
+            println!("{} entries, {} bytes", entries.len(), bytes);
+        }
+        Command::StatCacheEntry(key) => {
+            trace!("Command::StatCacheEntry({})", key);
+            let srv = connect_or_start_server(get_port())?;
+            match request_stat_cache_entry(srv, &key).chain_err(|| "failed to stat cache entry")? {
+                Some(entry) => print_entry(&entry),
+                None => {
+                    println!("No cache entry for {}", key);
+                    return Ok(1);
+                }
+            }
+        }
+        Command::PruneCache(older_than) => {
+            trace!("Command::PruneCache({:?})", older_than);
+            let srv = connect_or_start_server(get_port())?;
+            let stats =
+                request_prune_cache(srv, older_than).chain_err(|| "failed to prune cache")?;
+            println!("Removed {} entries, {} bytes", stats.entries, stats.bytes);
+        }
+        Command::RemoveCacheEntry(key) => {
+            trace!("Command::RemoveCacheEntry({})", key);
+            let srv = connect_or_start_server(get_port())?;
+            request_remove_cache_entry(srv, &key).chain_err(|| "failed to remove cache entry")?;
+        }
         Command::Compile {
             exe,
             cmdline,
@@ -12,17 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cache::{Cache, CacheWrite, Storage};
 use crate::compiler::{
     Cacheable, ColorMode, Compilation, CompileCommand, Compiler, CompilerArguments, CompilerHasher,
     CompilerKind, HashResult,
 };
 #[cfg(feature = "dist-client")]
 use crate::compiler::{NoopOutputsRewriter, OutputsRewriter};
+use crate::config::CompilerConfig;
 use crate::dist;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
 use crate::mock_command::CommandCreatorSync;
-use crate::util::{hash_all, Digest, HashToDigest};
+use crate::util::{hash_all, replace_path, Digest, HashToDigest};
 use futures::Future;
 use futures_cpupool::CpuPool;
 use std::borrow::Cow;
@@ -36,6 +38,9 @@ use std::hash::Hash;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::runtime::current_thread::TaskExecutor;
 
 use crate::errors::*;
 
@@ -133,8 +138,10 @@ impl Language {
 /// A generic implementation of the `Compilation` trait for C/C++ compilers.
 struct CCompilation<I: CCompilerImpl> {
     parsed_args: ParsedArguments,
+    /// The preprocessor output, unless the compilation was found in direct
+    /// mode without running the preprocessor.
     #[cfg(feature = "dist-client")]
-    preprocessed_input: Vec<u8>,
+    preprocessed_input: Option<Vec<u8>>,
     executable: PathBuf,
     compiler: I,
     cwd: PathBuf,
@@ -164,7 +171,9 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + 'static {
         arguments: &[OsString],
         cwd: &Path,
     ) -> CompilerArguments<ParsedArguments>;
-    /// Run the C preprocessor with the specified set of arguments.
+    /// Run the C preprocessor with the specified set of arguments. If
+    /// `list_includes` is set, also have it list the files it includes in the
+    /// form `included_files` expects.
     fn preprocess<T>(
         &self,
         creator: &T,
@@ -173,9 +182,16 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + 'static {
         cwd: &Path,
         env_vars: &[(OsString, OsString)],
         may_dist: bool,
+        list_includes: bool,
     ) -> SFuture<process::Output>
     where
         T: CommandCreatorSync;
+    /// Find the files included by a compilation in the output of `preprocess`
+    /// run with `list_includes`. Returns `None` if the compiler can't list
+    /// them, in which case compilations can't be looked up in direct mode.
+    fn included_files(&self, _output: &process::Output, _cwd: &Path) -> Option<Vec<PathBuf>> {
+        None
+    }
     /// Generate a command that can be used to invoke the C compiler to perform
     /// the compilation.
     fn generate_compile_commands(
@@ -218,6 +234,8 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
         &self,
         arguments: &[OsString],
         cwd: &Path,
+        _env_vars: &[(OsString, OsString)],
+        _config: &CompilerConfig,
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>> {
         match self.compiler.parse_arguments(arguments, cwd) {
             CompilerArguments::Ok(args) => CompilerArguments::Ok(Box::new(CCompilerHasher {
@@ -238,44 +256,43 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
     }
 }
 
-impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
-where
-    T: CommandCreatorSync,
-    I: CCompilerImpl,
-{
-    fn generate_hash_key(
-        self: Box<Self>,
+impl<I: CCompilerImpl> CCompilerHasher<I> {
+    /// Run the preprocessor and hash its output, leaving the location of
+    /// `base_dir` out of the hash if possible. If `manifest` is set, also
+    /// record the files the preprocessor read so that later compilations can
+    /// be looked up in direct mode.
+    fn preprocess_and_hash<T>(
+        self,
         creator: &T,
         cwd: PathBuf,
         env_vars: Vec<(OsString, OsString)>,
         may_dist: bool,
         pool: &CpuPool,
-    ) -> SFuture<HashResult> {
-        let me = *self;
-        let CCompilerHasher {
-            parsed_args,
-            executable,
-            executable_digest,
-            compiler,
-        } = me;
-        let result = compiler.preprocess(
+        extra_hashes: SFuture<Vec<String>>,
+        base_dir: Option<PathBuf>,
+        manifest: Option<ManifestWriter>,
+    ) -> SFuture<HashResult>
+    where
+        T: CommandCreatorSync,
+    {
+        let result = self.compiler.preprocess(
             creator,
-            &executable,
-            &parsed_args,
+            &self.executable,
+            &self.parsed_args,
             &cwd,
             &env_vars,
             may_dist,
+            manifest.is_some(),
         );
-        let out_pretty = parsed_args.output_pretty().into_owned();
-        let env_vars = env_vars.to_vec();
+        let out_pretty = self.parsed_args.output_pretty().into_owned();
         let result = result.map_err(move |e| {
             debug!("[{}]: preprocessor failed: {:?}", out_pretty, e);
             e
         });
-        let out_pretty = parsed_args.output_pretty().into_owned();
-        let extra_hashes = hash_all(&parsed_args.extra_hash_files, &pool.clone());
-        let outputs = parsed_args.outputs.clone();
+        let out_pretty = self.parsed_args.output_pretty().into_owned();
+        let outputs = self.parsed_args.outputs.clone();
         let args_cwd = cwd.clone();
+        let pool = pool.clone();
 
         Box::new(
             result
@@ -319,39 +336,199 @@ where
                 .and_then(move |preprocessor_result| {
                     trace!(
                         "[{}]: Preprocessor output is {} bytes",
-                        parsed_args.output_pretty(),
+                        self.parsed_args.output_pretty(),
                         preprocessor_result.stdout.len()
                     );
 
+                    let base_dir =
+                        base_dir.filter(|dir| outputs_independent_of(dir, &self.parsed_args));
                     Box::new(extra_hashes.and_then(move |extra_hashes| {
                         let key = {
                             hash_key(
-                                &executable_digest,
-                                parsed_args.language,
-                                &parsed_args.common_args,
+                                &self.executable_digest,
+                                self.parsed_args.language,
+                                &self.parsed_args.common_args,
                                 &extra_hashes,
                                 &env_vars,
                                 &preprocessor_result.stdout,
+                                base_dir.as_ref().map(|dir| dir.as_path()),
                             )
                         };
-                        // A compiler binary may be a symlink to another and so has the same digest, but that means
-                        // the toolchain will not contain the correct path to invoke the compiler! Add the compiler
-                        // executable path to try and prevent this
-                        let weak_toolchain_key =
-                            format!("{}-{}", executable.to_string_lossy(), executable_digest);
-                        Ok(HashResult {
-                            key: key,
-                            compilation: Box::new(CCompilation {
-                                parsed_args: parsed_args,
-                                #[cfg(feature = "dist-client")]
-                                preprocessed_input: preprocessor_result.stdout,
-                                executable: executable,
-                                compiler: compiler,
+                        if let Some(manifest) = manifest {
+                            match self.compiler.included_files(&preprocessor_result, &cwd) {
+                                Some(mut files) => {
+                                    files.push(cwd.join(&self.parsed_args.input));
+                                    manifest.write(key.clone(), files, &pool);
+                                }
+                                None => debug!(
+                                    "[{}]: Compiler didn't list the included files",
+                                    self.parsed_args.output_pretty()
+                                ),
+                            }
+                        }
+                        Ok(self.into_hash_result(
+                            key,
+                            Some(preprocessor_result.stdout),
+                            cwd,
+                            env_vars,
+                        ))
+                    }))
+                }),
+        )
+    }
+
+    /// Build the result of hashing this compilation, given its hash key.
+    fn into_hash_result(
+        self,
+        key: String,
+        preprocessed_input: Option<Vec<u8>>,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+    ) -> HashResult {
+        #[cfg(not(feature = "dist-client"))]
+        let _ = preprocessed_input;
+
+        let CCompilerHasher {
+            parsed_args,
+            executable,
+            executable_digest,
+            compiler,
+        } = self;
+        // A compiler binary may be a symlink to another and so has the same digest, but that means
+        // the toolchain will not contain the correct path to invoke the compiler! Add the compiler
+        // executable path to try and prevent this
+        let weak_toolchain_key = format!("{}-{}", executable.to_string_lossy(), executable_digest);
+        HashResult {
+            key: key,
+            compilation: Box::new(CCompilation {
+                parsed_args: parsed_args,
+                #[cfg(feature = "dist-client")]
+                preprocessed_input,
+                executable: executable,
+                compiler: compiler,
+                cwd,
+                env_vars,
+            }),
+            weak_toolchain_key,
+        }
+    }
+}
+
+impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
+where
+    T: CommandCreatorSync,
+    I: CCompilerImpl,
+{
+    fn generate_hash_key(
+        self: Box<Self>,
+        creator: &T,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+        may_dist: bool,
+        pool: &CpuPool,
+        storage: &Arc<dyn Storage>,
+        config: &CompilerConfig,
+    ) -> SFuture<HashResult> {
+        let me = *self;
+        let extra_hashes = hash_all(&me.parsed_args.extra_hash_files, &pool.clone());
+        let depfile = depfile_path(&me.parsed_args).map(|path| cwd.join(path));
+        if !config.direct_mode || (writes_depfile(&me.parsed_args) && depfile.is_none()) {
+            return me.preprocess_and_hash(
+                creator,
+                cwd,
+                env_vars,
+                may_dist,
+                pool,
+                extra_hashes,
+                config.base_dir.clone(),
+                None,
+            );
+        }
+
+        // Files modified after this point may have changed after the
+        // preprocessor read them, so they're never recorded in a manifest.
+        let start = SystemTime::now();
+        let input_hash = Digest::file(cwd.join(&me.parsed_args.input), pool);
+        let creator = creator.clone();
+        let pool = pool.clone();
+        let storage = storage.clone();
+        let base_dir = config.base_dir.clone();
+        Box::new(
+            extra_hashes
+                .join(input_hash)
+                .then(move |res| -> SFuture<HashResult> {
+                    let (extra_hashes, input_hash) = match res {
+                        Ok(hashes) => hashes,
+                        Err(e) => {
+                            // Leave reporting the problem to the preprocessor.
+                            debug!(
+                                "[{}]: Failed to hash inputs: {}",
+                                me.parsed_args.output_pretty(),
+                                e
+                            );
+                            let extra_hashes = hash_all(&me.parsed_args.extra_hash_files, &pool);
+                            return me.preprocess_and_hash(
+                                &creator,
                                 cwd,
                                 env_vars,
-                            }),
-                            weak_toolchain_key,
-                        })
+                                may_dist,
+                                &pool,
+                                extra_hashes,
+                                base_dir,
+                                None,
+                            );
+                        }
+                    };
+                    let manifest_key = manifest_key(
+                        &me.executable_digest,
+                        &me.parsed_args,
+                        &cwd,
+                        &env_vars,
+                        &extra_hashes,
+                        &input_hash,
+                    );
+                    let lookup = if storage.rw_mode().can_read() {
+                        lookup_manifest(&*storage, &manifest_key, depfile.clone(), &pool)
+                    } else {
+                        f_ok(None)
+                    };
+                    Box::new(lookup.then(move |res| -> SFuture<HashResult> {
+                        match res {
+                            Ok(Some(key)) => {
+                                debug!("[{}]: Direct mode hit", me.parsed_args.output_pretty());
+                                return f_ok(me.into_hash_result(key, None, cwd, env_vars));
+                            }
+                            Ok(None) => {
+                                debug!("[{}]: Direct mode miss", me.parsed_args.output_pretty())
+                            }
+                            Err(e) => {
+                                warn!(
+                                    "[{}]: Failed to look up manifest: {}",
+                                    me.parsed_args.output_pretty(),
+                                    e
+                                )
+                            }
+                        }
+                        let manifest = if storage.rw_mode().can_write() {
+                            Some(ManifestWriter {
+                                storage,
+                                manifest_key,
+                                depfile,
+                                start,
+                            })
+                        } else {
+                            None
+                        };
+                        me.preprocess_and_hash(
+                            &creator,
+                            cwd,
+                            env_vars,
+                            may_dist,
+                            &pool,
+                            f_ok(extra_hashes),
+                            base_dir,
+                            manifest,
+                        )
                     }))
                 }),
         )
@@ -404,6 +581,9 @@ impl<I: CCompilerImpl> Compilation for CCompilation<I> {
             ..
         } = *self;
         trace!("Dist inputs: {:?}", parsed_args.input);
+        let preprocessed_input = preprocessed_input.ok_or(
+            "No preprocessor output to distribute, the compilation was found in direct mode",
+        )?;
 
         let input_path = cwd.join(&parsed_args.input);
         let inputs_packager = Box::new(CInputsPackager {
@@ -606,6 +786,9 @@ lazy_static! {
 }
 
 /// Compute the hash key of `compiler` compiling `preprocessor_output` with `args`.
+///
+/// If `base_dir` is set, occurrences of it in `args` and in the linemarkers of
+/// `preprocessor_output` are replaced with a placeholder before hashing.
 pub fn hash_key(
     compiler_digest: &str,
     language: Language,
@@ -613,14 +796,25 @@ pub fn hash_key(
     extra_hashes: &[String],
     env_vars: &[(OsString, OsString)],
     preprocessor_output: &[u8],
+    base_dir: Option<&Path>,
 ) -> String {
     // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
     let mut m = Digest::new();
     m.update(compiler_digest.as_bytes());
     m.update(CACHE_VERSION);
     m.update(language.as_str().as_bytes());
+    let base_dir = base_dir.and_then(|dir| dir.to_str());
     for arg in arguments {
-        arg.hash(&mut HashToDigest { digest: &mut m });
+        match (base_dir, arg.to_str()) {
+            (Some(dir), Some(s)) => {
+                match replace_path(s.as_bytes(), dir.as_bytes(), BASE_DIR_PLACEHOLDER) {
+                    Cow::Owned(replaced) => OsString::from(String::from_utf8(replaced).unwrap())
+                        .hash(&mut HashToDigest { digest: &mut m }),
+                    Cow::Borrowed(_) => arg.hash(&mut HashToDigest { digest: &mut m }),
+                }
+            }
+            _ => arg.hash(&mut HashToDigest { digest: &mut m }),
+        }
     }
     for hash in extra_hashes {
         m.update(hash.as_bytes());
@@ -633,21 +827,345 @@ pub fn hash_key(
             val.hash(&mut HashToDigest { digest: &mut m });
         }
     }
-    m.update(preprocessor_output);
+    match base_dir {
+        Some(dir) => {
+            // Linemarkers escape backslashes in paths.
+            let patterns = vec![dir.to_owned(), dir.replace('\\', "\\\\")];
+            let mut rest = preprocessor_output;
+            while !rest.is_empty() {
+                let end = rest
+                    .iter()
+                    .position(|&b| b == b'\n')
+                    .map_or(rest.len(), |i| i + 1);
+                let (line, next) = rest.split_at(end);
+                if line.starts_with(b"#") {
+                    let mut line = line.to_vec();
+                    for pattern in &patterns {
+                        let replaced =
+                            match replace_path(&line, pattern.as_bytes(), BASE_DIR_PLACEHOLDER) {
+                                Cow::Owned(replaced) => Some(replaced),
+                                Cow::Borrowed(_) => None,
+                            };
+                        if let Some(replaced) = replaced {
+                            line = replaced;
+                        }
+                    }
+                    m.update(&line);
+                } else {
+                    m.update(line);
+                }
+                rest = next;
+            }
+        }
+        None => m.update(preprocessor_output),
+    }
     m.finish()
 }
 
+/// What occurrences of the base directory are replaced with when hashing.
+const BASE_DIR_PLACEHOLDER: &[u8] = b"$BASE_DIR";
+
+/// Whether the outputs of a compilation are the same wherever `base_dir` is.
+/// Debug info and coverage data refer to the source files by their full paths,
+/// unless `-fdebug-prefix-map` or `-ffile-prefix-map` remaps `base_dir`
+/// itself. Remapping one of its parents would leave the rest of the path in
+/// the outputs, which differs between checkouts.
+fn outputs_independent_of(base_dir: &Path, parsed_args: &ParsedArguments) -> bool {
+    if parsed_args.profile_generate || parsed_args.outputs.contains_key("gcno") {
+        return false;
+    }
+    let mut debug_info = false;
+    let mut remapped = false;
+    for arg in parsed_args
+        .preprocessor_args
+        .iter()
+        .chain(&parsed_args.common_args)
+    {
+        let arg = match arg.to_str() {
+            Some(arg) => arg,
+            None => continue,
+        };
+        if (arg.starts_with("-g") && arg != "-g0")
+            || ["/Z7", "-Z7", "/Zi", "-Zi", "/ZI", "-ZI"].contains(&arg)
+        {
+            debug_info = true;
+        }
+        for prefix in &["-fdebug-prefix-map=", "-ffile-prefix-map="] {
+            if arg.starts_with(prefix) {
+                let old = arg[prefix.len()..].splitn(2, '=').next().unwrap_or("");
+                if !old.is_empty() && Path::new(old) == base_dir {
+                    remapped = true;
+                }
+            }
+        }
+    }
+    !debug_info || remapped
+}
+
+/// The name of the object holding the manifest in its cache entry.
+const MANIFEST_OBJECT: &str = "manifest";
+/// The name of the object holding the dependency file written when the
+/// compilation was preprocessed, if it writes one, in a manifest's entry.
+const DEPFILE_OBJECT: &str = "depfile";
+
+/// Macros whose expansions change without any of the files read by the
+/// preprocessor changing.
+const TIME_MACROS: &[&[u8]] = &[b"__DATE__", b"__TIME__", b"__TIMESTAMP__"];
+
+lazy_static! {
+    /// Environment variables that change where the preprocessor finds headers.
+    static ref INCLUDE_ENV_VARS: HashSet<&'static OsStr> = [
+        "CPATH",
+        "C_INCLUDE_PATH",
+        "CPLUS_INCLUDE_PATH",
+        "OBJC_INCLUDE_PATH",
+        "INCLUDE",
+    ].iter().map(OsStr::new).collect();
+}
+
+/// The files a compilation read, recorded under its direct mode key so that
+/// later compilations can be looked up without running the preprocessor.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Manifest {
+    /// The hash key of the compilation.
+    key: String,
+    /// The files the compilation read, with the digests of their contents.
+    files: Vec<(PathBuf, String)>,
+}
+
+impl Manifest {
+    /// Return the hash key this manifest records if none of the files it lists
+    /// have changed.
+    fn key_if_unchanged(self) -> Option<String> {
+        for (path, digest) in &self.files {
+            match hash_included_file(path) {
+                Ok(Some(ref d)) if d == digest => {}
+                _ => {
+                    trace!("Included file changed: {}", path.display());
+                    return None;
+                }
+            }
+        }
+        Some(self.key)
+    }
+}
+
+/// Where to store the manifest of a compilation that missed in direct mode.
+struct ManifestWriter {
+    storage: Arc<dyn Storage>,
+    manifest_key: String,
+    /// The dependency file the preprocessor wrote, to store with the manifest.
+    depfile: Option<PathBuf>,
+    /// When hashing the compilation started.
+    start: SystemTime,
+}
+
+impl ManifestWriter {
+    /// Record that the compilation with hash key `key` read `files`, in the
+    /// background.
+    fn write(self, key: String, files: Vec<PathBuf>, pool: &CpuPool) {
+        let ManifestWriter {
+            storage,
+            manifest_key,
+            depfile,
+            start,
+        } = self;
+        // Read the dependency file now, as compiling rewrites it.
+        let depfile = match depfile.map(fs::read).transpose() {
+            Ok(depfile) => depfile,
+            Err(e) => {
+                debug!(
+                    "Not storing manifest, failed to read dependency file: {}",
+                    e
+                );
+                return;
+            }
+        };
+        let manifest = pool.spawn_fn(move || -> Result<_> {
+            let mut seen = HashSet::new();
+            let mut manifest = Manifest { key, files: vec![] };
+            for path in files {
+                if !seen.insert(path.clone()) {
+                    continue;
+                }
+                if fs::metadata(&path)?.modified()? >= start {
+                    bail!("{} was modified during the compilation", path.display());
+                }
+                match hash_included_file(&path)? {
+                    Some(digest) => manifest.files.push((path, digest)),
+                    None => bail!("{} uses the date or time", path.display()),
+                }
+            }
+            let mut entry = CacheWrite::new()?;
+            entry.put_object(
+                MANIFEST_OBJECT,
+                &mut &serde_json::to_vec(&manifest)?[..],
+                None,
+            )?;
+            if let Some(depfile) = depfile {
+                entry.put_object(DEPFILE_OBJECT, &mut &depfile[..], None)?;
+            }
+            Ok(entry)
+        });
+        let write = manifest
+            .and_then(move |entry| storage.put(&manifest_key, entry))
+            .then(|res| {
+                match res {
+                    Ok(_) => trace!("Stored manifest"),
+                    Err(e) => debug!("Not storing manifest: {}", e),
+                }
+                Ok::<(), ()>(())
+            });
+        if let Err(e) = TaskExecutor::current().spawn_local(Box::new(write)) {
+            warn!("Failed to spawn manifest write: {:?}", e);
+        }
+    }
+}
+
+/// Whether running the preprocessor for a compilation writes a dependency
+/// file, which compilations found in direct mode must write themselves.
+fn writes_depfile(parsed_args: &ParsedArguments) -> bool {
+    parsed_args.depfile.is_some()
+        || parsed_args
+            .preprocessor_args
+            .iter()
+            .any(|arg| arg == "-MD" || arg == "-MMD" || arg == "-dependency-file")
+}
+
+/// Where running the preprocessor for a compilation writes a dependency file,
+/// relative to the working directory. Returns `None` if it doesn't write one,
+/// or if it writes one that isn't named with `-MF` or `-dependency-file`.
+fn depfile_path(parsed_args: &ParsedArguments) -> Option<PathBuf> {
+    if !writes_depfile(parsed_args) {
+        return None;
+    }
+    if let Some(ref depfile) = parsed_args.depfile {
+        return Some(depfile.clone());
+    }
+    let mut args = parsed_args.preprocessor_args.iter();
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "-MF" || arg == "-dependency-file" {
+            path = args.next().map(PathBuf::from);
+            // Arguments passed with `-Xclang` each follow their own `-Xclang`.
+            if path
+                .as_ref()
+                .map_or(false, |path| path.as_os_str() == "-Xclang")
+            {
+                path = args.next().map(PathBuf::from);
+            }
+        }
+    }
+    path
+}
+
+/// Compute the key the manifest of `parsed_args` compiled by `compiler` is
+/// stored under. This covers everything that affects which files the
+/// preprocessor reads, other than the contents of the included files.
+fn manifest_key(
+    compiler_digest: &str,
+    parsed_args: &ParsedArguments,
+    cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+    extra_hashes: &[String],
+    input_digest: &str,
+) -> String {
+    let mut m = Digest::new();
+    m.update(b"manifest");
+    m.update(compiler_digest.as_bytes());
+    m.update(CACHE_VERSION);
+    m.update(parsed_args.language.as_str().as_bytes());
+    for arg in parsed_args
+        .preprocessor_args
+        .iter()
+        .chain(&parsed_args.common_args)
+    {
+        arg.hash(&mut HashToDigest { digest: &mut m });
+    }
+    for hash in extra_hashes {
+        m.update(hash.as_bytes());
+    }
+    for &(ref var, ref val) in env_vars.iter() {
+        if CACHED_ENV_VARS.contains(var.as_os_str()) || INCLUDE_ENV_VARS.contains(var.as_os_str()) {
+            var.hash(&mut HashToDigest { digest: &mut m });
+            m.update(&b"="[..]);
+            val.hash(&mut HashToDigest { digest: &mut m });
+        }
+    }
+    cwd.hash(&mut HashToDigest { digest: &mut m });
+    parsed_args.input.hash(&mut HashToDigest { digest: &mut m });
+    m.update(input_digest.as_bytes());
+    m.finish()
+}
+
+/// Look up the manifest stored under `manifest_key`, returning the hash key
+/// it records if the files it lists haven't changed. In that case, also write
+/// the dependency file stored with the manifest to `depfile`, if set.
+fn lookup_manifest(
+    storage: &dyn Storage,
+    manifest_key: &str,
+    depfile: Option<PathBuf>,
+    pool: &CpuPool,
+) -> SFuture<Option<String>> {
+    let pool = pool.clone();
+    Box::new(
+        storage
+            .get(manifest_key)
+            .and_then(move |cache| -> SFuture<Option<String>> {
+                let mut entry = match cache {
+                    Cache::Hit(entry) => entry,
+                    _ => return f_ok(None),
+                };
+                Box::new(pool.spawn_fn(move || -> Result<_> {
+                    let mut data = vec![];
+                    entry.get_object(MANIFEST_OBJECT, &mut data)?;
+                    let manifest: Manifest = serde_json::from_slice(&data)?;
+                    let key = match manifest.key_if_unchanged() {
+                        Some(key) => key,
+                        None => return Ok(None),
+                    };
+                    if let Some(depfile) = depfile {
+                        let mut file = fs::File::create(&depfile)?;
+                        entry.get_object(DEPFILE_OBJECT, &mut file)?;
+                    }
+                    Ok(Some(key))
+                }))
+            }),
+    )
+}
+
+/// Hash the contents of `path`, a file read by a compilation. Returns `None`
+/// if it uses `__DATE__`, `__TIME__` or `__TIMESTAMP__`.
+fn hash_included_file(path: &Path) -> Result<Option<String>> {
+    let contents = fs::read(path)?;
+    if TIME_MACROS
+        .iter()
+        .any(|name| contents.windows(name.len()).any(|w| w == *name))
+    {
+        return Ok(None);
+    }
+    let mut m = Digest::new();
+    m.update(&contents);
+    Ok(Some(m.finish()))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::config::CacheRWMode;
+    use crate::test::utils::*;
+    use std::io::Write;
+    use std::u64;
+    use tokio::runtime::current_thread::Runtime;
 
     #[test]
     fn test_hash_key_executable_contents_differs() {
         let args = ovec!["a", "b", "c"];
         const PREPROCESSED: &'static [u8] = b"hello world";
         assert_neq!(
-            hash_key("abcd", Language::C, &args, &[], &[], &PREPROCESSED),
-            hash_key("wxyz", Language::C, &args, &[], &[], &PREPROCESSED)
+            hash_key("abcd", Language::C, &args, &[], &[], &PREPROCESSED, None),
+            hash_key("wxyz", Language::C, &args, &[], &[], &PREPROCESSED, None)
         );
     }
 
@@ -660,18 +1178,18 @@ mod test {
         let a = ovec!["a"];
         const PREPROCESSED: &'static [u8] = b"hello world";
         assert_neq!(
-            hash_key(digest, Language::C, &abc, &[], &[], &PREPROCESSED),
-            hash_key(digest, Language::C, &xyz, &[], &[], &PREPROCESSED)
+            hash_key(digest, Language::C, &abc, &[], &[], &PREPROCESSED, None),
+            hash_key(digest, Language::C, &xyz, &[], &[], &PREPROCESSED, None)
         );
 
         assert_neq!(
-            hash_key(digest, Language::C, &abc, &[], &[], &PREPROCESSED),
-            hash_key(digest, Language::C, &ab, &[], &[], &PREPROCESSED)
+            hash_key(digest, Language::C, &abc, &[], &[], &PREPROCESSED, None),
+            hash_key(digest, Language::C, &ab, &[], &[], &PREPROCESSED, None)
         );
 
         assert_neq!(
-            hash_key(digest, Language::C, &abc, &[], &[], &PREPROCESSED),
-            hash_key(digest, Language::C, &a, &[], &[], &PREPROCESSED)
+            hash_key(digest, Language::C, &abc, &[], &[], &PREPROCESSED, None),
+            hash_key(digest, Language::C, &a, &[], &[], &PREPROCESSED, None)
         );
     }
 
@@ -679,8 +1197,16 @@ mod test {
     fn test_hash_key_preprocessed_content_differs() {
         let args = ovec!["a", "b", "c"];
         assert_neq!(
-            hash_key("abcd", Language::C, &args, &[], &[], &b"hello world"[..]),
-            hash_key("abcd", Language::C, &args, &[], &[], &b"goodbye"[..])
+            hash_key(
+                "abcd",
+                Language::C,
+                &args,
+                &[],
+                &[],
+                &b"hello world"[..],
+                None
+            ),
+            hash_key("abcd", Language::C, &args, &[], &[], &b"goodbye"[..], None)
         );
     }
 
@@ -690,11 +1216,11 @@ mod test {
         let digest = "abcd";
         const PREPROCESSED: &'static [u8] = b"hello world";
         for var in CACHED_ENV_VARS.iter() {
-            let h1 = hash_key(digest, Language::C, &args, &[], &[], &PREPROCESSED);
+            let h1 = hash_key(digest, Language::C, &args, &[], &[], &PREPROCESSED, None);
             let vars = vec![(OsString::from(var), OsString::from("something"))];
-            let h2 = hash_key(digest, Language::C, &args, &[], &vars, &PREPROCESSED);
+            let h2 = hash_key(digest, Language::C, &args, &[], &vars, &PREPROCESSED, None);
             let vars = vec![(OsString::from(var), OsString::from("something else"))];
-            let h3 = hash_key(digest, Language::C, &args, &[], &vars, &PREPROCESSED);
+            let h3 = hash_key(digest, Language::C, &args, &[], &vars, &PREPROCESSED, None);
             assert_neq!(h1, h2);
             assert_neq!(h2, h3);
         }
@@ -708,8 +1234,239 @@ mod test {
         let extra_data = stringvec!["hello", "world"];
 
         assert_neq!(
-            hash_key(digest, Language::C, &args, &extra_data, &[], &PREPROCESSED),
-            hash_key(digest, Language::C, &args, &[], &[], &PREPROCESSED)
+            hash_key(
+                digest,
+                Language::C,
+                &args,
+                &extra_data,
+                &[],
+                &PREPROCESSED,
+                None
+            ),
+            hash_key(digest, Language::C, &args, &[], &[], &PREPROCESSED, None)
+        );
+    }
+
+    #[test]
+    fn test_hash_key_base_dir() {
+        let hash = |dir: &str, base_dir: Option<&str>| {
+            let args = vec![OsString::from(format!("-I{}/include", dir)), "-O2".into()];
+            let preprocessed = format!("# 1 \"{}/foo.c\"\nint x = 1;\n", dir);
+            hash_key(
+                "abcd",
+                Language::C,
+                &args,
+                &[],
+                &[],
+                preprocessed.as_bytes(),
+                base_dir.map(Path::new),
+            )
+        };
+        assert_eq!(
+            hash("/home/a/src", Some("/home/a/src")),
+            hash("/home/b/src", Some("/home/b/src"))
+        );
+        assert_neq!(hash("/home/a/src", None), hash("/home/b/src", None));
+        assert_eq!(
+            hash("/home/a/src", Some("/other")),
+            hash("/home/a/src", None)
+        );
+    }
+
+    #[test]
+    fn test_outputs_independent_of() {
+        let base_dir = Path::new("/home/user/src");
+        assert!(outputs_independent_of(base_dir, &parsed_args(vec![])));
+        assert!(!outputs_independent_of(base_dir, &parsed_args(ovec!["-g"])));
+        assert!(outputs_independent_of(base_dir, &parsed_args(ovec!["-g0"])));
+        assert!(outputs_independent_of(
+            base_dir,
+            &parsed_args(ovec!["-g", "-fdebug-prefix-map=/home/user/src=."])
+        ));
+        assert!(outputs_independent_of(
+            base_dir,
+            &parsed_args(ovec!["-g", "-ffile-prefix-map=/home/user/src/=/src"])
+        ));
+        assert!(!outputs_independent_of(
+            base_dir,
+            &parsed_args(ovec!["-g", "-fdebug-prefix-map=/home/user=."])
+        ));
+        assert!(!outputs_independent_of(
+            base_dir,
+            &parsed_args(ovec!["-g", "-fdebug-prefix-map=/tmp=."])
+        ));
+    }
+
+    fn parsed_args(preprocessor_args: Vec<OsString>) -> ParsedArguments {
+        ParsedArguments {
+            input: "foo.c".into(),
+            language: Language::C,
+            depfile: None,
+            outputs: vec![("obj", "foo.o".into())].into_iter().collect(),
+            preprocessor_args,
+            common_args: ovec!["-O2"],
+            extra_hash_files: vec![],
+            msvc_show_includes: false,
+            profile_generate: false,
+            color_mode: ColorMode::Auto,
+        }
+    }
+
+    #[test]
+    fn test_manifest_key_differs() {
+        let args = parsed_args(ovec!["-Iinclude"]);
+        let cwd = Path::new("/src");
+        let key = manifest_key("abcd", &args, cwd, &[], &[], "1234");
+        assert_neq!(key, manifest_key("abcd", &args, cwd, &[], &[], "5678"));
+        assert_neq!(
+            key,
+            manifest_key("abcd", &args, Path::new("/other"), &[], &[], "1234")
+        );
+        let other_args = parsed_args(ovec!["-Iother"]);
+        assert_neq!(
+            key,
+            manifest_key("abcd", &other_args, cwd, &[], &[], "1234")
+        );
+        for var in INCLUDE_ENV_VARS.iter() {
+            let vars = vec![(OsString::from(var), OsString::from("/usr/include"))];
+            assert_neq!(key, manifest_key("abcd", &args, cwd, &vars, &[], "1234"));
+        }
+        let vars = vec![(OsString::from("FOO"), OsString::from("bar"))];
+        assert_eq!(key, manifest_key("abcd", &args, cwd, &vars, &[], "1234"));
+    }
+
+    #[test]
+    fn test_writes_depfile() {
+        assert!(!writes_depfile(&parsed_args(ovec!["-Iinclude"])));
+        assert!(writes_depfile(&parsed_args(ovec!["-MD", "-MF", "foo.d"])));
+        assert!(writes_depfile(&parsed_args(ovec!["-MMD"])));
+    }
+
+    #[test]
+    fn test_depfile_path() {
+        assert_eq!(depfile_path(&parsed_args(ovec!["-Iinclude"])), None);
+        assert_eq!(
+            depfile_path(&parsed_args(ovec!["-MD", "-MT", "foo.o", "-MF", "foo.d"])),
+            Some(PathBuf::from("foo.d"))
+        );
+        assert_eq!(
+            depfile_path(&parsed_args(ovec!["-MF", "foo.d", "-MMD", "-MT", "foo.o"])),
+            Some(PathBuf::from("foo.d"))
+        );
+        // Without `-MF`, the dependency file is named after the output.
+        assert_eq!(
+            depfile_path(&parsed_args(ovec!["-MD", "-MT", "foo.o"])),
+            None
+        );
+        // `-MF` does nothing on its own.
+        assert_eq!(depfile_path(&parsed_args(ovec!["-MF", "foo.d"])), None);
+        assert_eq!(
+            depfile_path(&parsed_args(ovec![
+                "-Xclang",
+                "-dependency-file",
+                "-Xclang",
+                "foo.d"
+            ])),
+            Some(PathBuf::from("foo.d"))
+        );
+        let mut args = parsed_args(ovec!["-Iinclude"]);
+        args.depfile = Some("foo.d".into());
+        assert_eq!(depfile_path(&args), Some(PathBuf::from("foo.d")));
+    }
+
+    #[test]
+    fn test_lookup_manifest_writes_depfile() {
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let storage = DiskCache::new(
+            &f.tempdir.path().join("cache"),
+            u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
         );
+        let header = create_file(f.tempdir.path(), "foo.h", |mut f| {
+            f.write_all(b"#define FOO 1")
+        })
+        .unwrap();
+        let manifest = Manifest {
+            key: "abcd".to_owned(),
+            files: vec![(
+                header.clone(),
+                hash_included_file(&header).unwrap().unwrap(),
+            )],
+        };
+        let mut entry = CacheWrite::new().unwrap();
+        let data = serde_json::to_vec(&manifest).unwrap();
+        entry
+            .put_object(MANIFEST_OBJECT, &mut &data[..], None)
+            .unwrap();
+        entry
+            .put_object(DEPFILE_OBJECT, &mut &b"foo.o: foo.c foo.h\n"[..], None)
+            .unwrap();
+        runtime.block_on(storage.put("manifest", entry)).unwrap();
+
+        let depfile = f.tempdir.path().join("foo.d");
+        let key = runtime
+            .block_on(lookup_manifest(
+                &storage,
+                "manifest",
+                Some(depfile.clone()),
+                &pool,
+            ))
+            .unwrap();
+        assert_eq!(key, Some("abcd".to_owned()));
+        assert_eq!(fs::read(&depfile).unwrap(), b"foo.o: foo.c foo.h\n");
+
+        fs::remove_file(&depfile).unwrap();
+        fs::write(&header, b"#define FOO 2").unwrap();
+        let key = runtime
+            .block_on(lookup_manifest(
+                &storage,
+                "manifest",
+                Some(depfile.clone()),
+                &pool,
+            ))
+            .unwrap();
+        assert_eq!(key, None);
+        assert!(!depfile.exists());
+    }
+
+    #[test]
+    fn test_hash_included_file() {
+        let f = TestFixture::new();
+        let header = create_file(f.tempdir.path(), "foo.h", |mut f| {
+            f.write_all(b"#define FOO 1")
+        })
+        .unwrap();
+        let mut m = Digest::new();
+        m.update(b"#define FOO 1");
+        assert_eq!(hash_included_file(&header).unwrap(), Some(m.finish()));
+        let header = create_file(f.tempdir.path(), "bar.h", |mut f| {
+            f.write_all(b"const char *built = __DATE__;")
+        })
+        .unwrap();
+        assert_eq!(hash_included_file(&header).unwrap(), None);
+        assert!(hash_included_file(&f.tempdir.path().join("missing.h")).is_err());
+    }
+
+    #[test]
+    fn test_manifest_key_if_unchanged() {
+        let f = TestFixture::new();
+        let header = create_file(f.tempdir.path(), "foo.h", |mut f| {
+            f.write_all(b"#define FOO 1")
+        })
+        .unwrap();
+        let digest = hash_included_file(&header).unwrap().unwrap();
+        let manifest = Manifest {
+            key: "abcd".to_owned(),
+            files: vec![(header.clone(), digest)],
+        };
+        let data = serde_json::to_vec(&manifest).unwrap();
+        let manifest: Manifest = serde_json::from_slice(&data).unwrap();
+        assert_eq!(manifest.key_if_unchanged(), Some("abcd".to_owned()));
+        let manifest: Manifest = serde_json::from_slice(&data).unwrap();
+        fs::write(&header, b"#define FOO 2").unwrap();
+        assert_eq!(manifest.key_if_unchanged(), None);
     }
 }
@@ -55,11 +55,24 @@ impl CCompilerImpl for Clang {
         cwd: &Path,
         env_vars: &[(OsString, OsString)],
         may_dist: bool,
+        list_includes: bool,
     ) -> SFuture<process::Output>
     where
         T: CommandCreatorSync,
     {
-        gcc::preprocess(creator, executable, parsed_args, cwd, env_vars, may_dist)
+        gcc::preprocess(
+            creator,
+            executable,
+            parsed_args,
+            cwd,
+            env_vars,
+            may_dist,
+            list_includes,
+        )
+    }
+
+    fn included_files(&self, output: &process::Output, cwd: &Path) -> Option<Vec<PathBuf>> {
+        gcc::included_files(&output.stderr, cwd)
     }
 
     fn generate_compile_commands(
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheWrite, Storage};
+use crate::cache::{entry_key, get_entry, Cache, CacheRead, CacheWrite, Storage};
 use crate::compiler::c::{CCompiler, CCompilerKind};
 use crate::compiler::clang::Clang;
 use crate::compiler::diab::Diab;
@@ -20,11 +20,12 @@ use crate::compiler::gcc::GCC;
 use crate::compiler::msvc;
 use crate::compiler::msvc::MSVC;
 use crate::compiler::rust::Rust;
+use crate::config::{CacheCompression, CompilerConfig};
 use crate::dist;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
 use crate::mock_command::{exit_status, CommandChild, CommandCreatorSync, RunCommand};
-use crate::util::{fmt_duration_as_secs, ref_env, run_input_output};
+use crate::util::{fmt_duration_as_secs, ref_env, replace_path, run_input_output};
 use futures::Future;
 use futures_cpupool::CpuPool;
 use std::borrow::Cow;
@@ -106,11 +107,14 @@ where
     /// Retrieve a packager
     #[cfg(feature = "dist-client")]
     fn get_toolchain_packager(&self) -> Box<dyn pkg::ToolchainPackager>;
-    /// Determine whether `arguments` are supported by this compiler.
+    /// Determine whether `arguments` are supported by this compiler, when run
+    /// in `cwd` with `env_vars`.
     fn parse_arguments(
         &self,
         arguments: &[OsString],
         cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        config: &CompilerConfig,
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>>;
     fn box_clone(&self) -> Box<dyn Compiler<T>>;
 }
@@ -130,6 +134,10 @@ where
     /// Given information about a compiler command, generate a hash key
     /// that can be used for cache lookups, as well as any additional
     /// information that can be reused for compilation if necessary.
+    ///
+    /// `storage` is the cache the result will be looked up in, which
+    /// compilers may also use to store information that speeds up hashing
+    /// later compilations.
     fn generate_hash_key(
         self: Box<Self>,
         creator: &T,
@@ -137,6 +145,8 @@ where
         env_vars: Vec<(OsString, OsString)>,
         may_dist: bool,
         pool: &CpuPool,
+        storage: &Arc<dyn Storage>,
+        config: &CompilerConfig,
     ) -> SFuture<HashResult>;
 
     /// Return the state of any `--color` option passed to the compiler.
@@ -149,6 +159,8 @@ where
         dist_client: Result<Option<Arc<dyn dist::Client>>>,
         creator: T,
         storage: Arc<dyn Storage>,
+        compression: CacheCompression,
+        config: &CompilerConfig,
         arguments: Vec<OsString>,
         cwd: PathBuf,
         env_vars: Vec<(OsString, OsString)>,
@@ -162,7 +174,15 @@ where
             Ok(Some(_)) => true,
             _ => false,
         };
-        let result = self.generate_hash_key(&creator, cwd.clone(), env_vars, may_dist, &pool);
+        let result = self.generate_hash_key(
+            &creator,
+            cwd.clone(),
+            env_vars,
+            may_dist,
+            &pool,
+            &storage,
+            config,
+        );
         Box::new(result.then(move |res| -> SFuture<_> {
             debug!(
                 "[{}]: generate_hash_key took {}",
@@ -180,13 +200,17 @@ where
                     weak_toolchain_key,
                 }) => (key, compilation, weak_toolchain_key),
             };
+            let hash_key = key;
+            let key = entry_key(&hash_key, compression);
             trace!("[{}]: Hash key: {}", out_pretty, key);
             // If `ForceRecache` is enabled, we won't check the cache.
             let start = Instant::now();
             let cache_status = if cache_control == CacheControl::ForceRecache {
-                f_ok(Cache::Recache)
+                f_ok((key.clone(), Cache::Recache))
+            } else if !storage.rw_mode().can_read() {
+                f_ok((key.clone(), Cache::Miss))
             } else {
-                storage.get(&key)
+                get_entry(storage.clone(), &hash_key, compression)
             };
 
             // Set a maximum time limit for the cache to respond before we forge
@@ -201,19 +225,153 @@ where
                     .outputs()
                     .map(|(key, path)| (key.to_string(), cwd.join(path)))
                     .collect::<HashMap<_, _>>();
+                let (rewritten_outputs, path_placeholders) = compilation.path_placeholders();
+
+                // Compile and store the compilation, when it can't be taken
+                // from the cache.
+                let compile_and_store = {
+                    let storage = storage.clone();
+                    let key = key.clone();
+                    let out_pretty = out_pretty.clone();
+                    let pool = pool.clone();
+                    let outputs = outputs.clone();
+                    let rewritten_outputs = rewritten_outputs.clone();
+                    let path_placeholders = path_placeholders.clone();
+                    move |miss_type| -> SFuture<_> {
+                        let start = Instant::now();
+                        let compile = dist_or_local_compile(
+                            dist_client,
+                            creator,
+                            cwd,
+                            compilation,
+                            weak_toolchain_key,
+                            out_pretty.clone(),
+                        );
+
+                        Box::new(
+                            compile.and_then(move |(cacheable, dist_type, compiler_result)| {
+                                let duration = start.elapsed();
+                                if !compiler_result.status.success() {
+                                    debug!(
+                                        "[{}]: Compiled but failed, not storing in cache",
+                                        out_pretty
+                                    );
+                                    return f_ok((CompileResult::CompileFailed, compiler_result))
+                                        as SFuture<_>;
+                                }
+                                if cacheable != Cacheable::Yes {
+                                    // Not cacheable
+                                    debug!("[{}]: Compiled but not cacheable", out_pretty);
+                                    return f_ok((CompileResult::NotCacheable, compiler_result));
+                                }
+                                if !storage.rw_mode().can_write() {
+                                    debug!(
+                                        "[{}]: Compiled in {}, cache is read-only so not storing",
+                                        out_pretty,
+                                        fmt_duration_as_secs(&duration)
+                                    );
+                                    let future = f_ok(CacheWriteInfo {
+                                        object_file_pretty: out_pretty,
+                                        duration: Duration::new(0, 0),
+                                    });
+                                    return f_ok((
+                                        CompileResult::CacheMiss(
+                                            miss_type, dist_type, duration, future,
+                                        ),
+                                        compiler_result,
+                                    ));
+                                }
+                                debug!(
+                                    "[{}]: Compiled in {}, storing in cache",
+                                    out_pretty,
+                                    fmt_duration_as_secs(&duration)
+                                );
+                                let write = pool.spawn_fn(move || -> Result<_> {
+                                    let mut entry = CacheWrite::with_compression(compression)?;
+                                    for (key, path) in &outputs {
+                                        let mut f = File::open(&path)?;
+                                        let mode = get_file_mode(&f)?;
+                                        if rewritten_outputs.contains(key) {
+                                            let mut contents = vec![];
+                                            f.read_to_end(&mut contents)?;
+                                            let placeholders =
+                                                path_placeholders.iter().map(|(p, ph)| (p, ph));
+                                            let contents = replace_paths(contents, placeholders);
+                                            entry.put_object(key, &mut &contents[..], mode)
+                                        } else {
+                                            entry.put_object(key, &mut f, mode)
+                                        }
+                                        .chain_err(|| {
+                                            format!("failed to put object `{:?}` in zip", path)
+                                        })?;
+                                    }
+                                    Ok(entry)
+                                });
+                                let write = write.chain_err(|| "failed to zip up compiler outputs");
+                                let o = out_pretty.clone();
+                                Box::new(
+                                    write
+                                        .and_then(move |mut entry| {
+                                            if !compiler_result.stdout.is_empty() {
+                                                let mut stdout = &compiler_result.stdout[..];
+                                                entry.put_object("stdout", &mut stdout, None)?;
+                                            }
+                                            if !compiler_result.stderr.is_empty() {
+                                                let mut stderr = &compiler_result.stderr[..];
+                                                entry.put_object("stderr", &mut stderr, None)?;
+                                            }
+
+                                            // Try to finish storing the newly-written cache
+                                            // entry. We'll get the result back elsewhere.
+                                            let future = storage.put(&key, entry).then(move |res| {
+                                                match res {
+                                                    Ok(_) => debug!(
+                                                        "[{}]: Stored in cache successfully!",
+                                                        out_pretty
+                                                    ),
+                                                    Err(ref e) => debug!(
+                                                        "[{}]: Cache write error: {:?}",
+                                                        out_pretty, e
+                                                    ),
+                                                }
+                                                res.map(|duration| CacheWriteInfo {
+                                                    object_file_pretty: out_pretty,
+                                                    duration: duration,
+                                                })
+                                            });
+                                            let future = Box::new(future);
+                                            Ok((
+                                                CompileResult::CacheMiss(
+                                                    miss_type, dist_type, duration, future,
+                                                ),
+                                                compiler_result,
+                                            ))
+                                        })
+                                        .chain_err(move || {
+                                            format!("failed to store `{}` to cache", o)
+                                        }),
+                                )
+                            }),
+                        )
+                    }
+                };
 
                 let miss_type = match result {
-                    Ok(Cache::Hit(mut entry)) => {
+                    Ok((hit_key, Cache::Hit(mut entry))) => {
                         debug!(
                             "[{}]: Cache hit in {}",
                             out_pretty,
                             fmt_duration_as_secs(&duration)
                         );
-                        let mut stdout = Vec::new();
-                        let mut stderr = Vec::new();
-                        drop(entry.get_object("stdout", &mut stdout));
-                        drop(entry.get_object("stderr", &mut stderr));
-                        let write = pool.spawn_fn(move || {
+                        // Objects are checked against their checksums as
+                        // they're extracted.
+                        let extract = pool.spawn_fn(move || -> Result<_> {
+                            let stdout = get_output_object(&mut entry, "stdout")?;
+                            let stderr = get_output_object(&mut entry, "stderr")?;
+                            // Extract every output before moving any of them
+                            // into place, so that a corrupt entry doesn't
+                            // leave only some of them from the cache.
+                            let mut extracted = vec![];
                             for (key, path) in &outputs {
                                 let dir = match path.parent() {
                                     Some(d) => d,
@@ -223,23 +381,56 @@ where
                                 // move it to its final location so that other rustc invocations
                                 // happening in parallel don't see a partially-written file.
                                 let mut tmp = NamedTempFile::new_in(dir)?;
-                                let mode = entry.get_object(&key, &mut tmp)?;
+                                let mode = if rewritten_outputs.contains(key) {
+                                    let mut contents = vec![];
+                                    let mode = entry.get_object(&key, &mut contents)?;
+                                    let placeholders =
+                                        path_placeholders.iter().map(|(p, ph)| (ph, p));
+                                    tmp.write_all(&replace_paths(contents, placeholders))?;
+                                    mode
+                                } else {
+                                    entry.get_object(&key, &mut tmp)?
+                                };
+                                extracted.push((tmp, path, mode));
+                            }
+                            for (tmp, path, mode) in extracted {
                                 tmp.persist(path)?;
                                 if let Some(mode) = mode {
                                     set_file_mode(&path, mode)?;
                                 }
                             }
-                            Ok(())
+                            Ok((stdout, stderr))
                         });
-                        let output = process::Output {
-                            status: exit_status(0),
-                            stdout: stdout,
-                            stderr: stderr,
-                        };
-                        let result = CompileResult::CacheHit(duration);
-                        return Box::new(write.map(|_| (result, output))) as SFuture<_>;
+                        return Box::new(extract.then(move |res| -> SFuture<_> {
+                            match res {
+                                Ok((stdout, stderr)) => {
+                                    let output = process::Output {
+                                        status: exit_status(0),
+                                        stdout: stdout,
+                                        stderr: stderr,
+                                    };
+                                    f_ok((CompileResult::CacheHit(duration), output))
+                                }
+                                Err(Error(ErrorKind::CorruptCacheEntry(name), _)) => {
+                                    warn!(
+                                        "[{}]: Corrupt cache entry, object `{}` doesn't match its checksum",
+                                        out_pretty, name
+                                    );
+                                    Box::new(storage.remove(&hit_key).then(move |removed| {
+                                        if let Err(e) = removed {
+                                            warn!(
+                                                "Failed to remove corrupt cache entry {}: {}",
+                                                hit_key, e
+                                            );
+                                        }
+                                        compile_and_store(MissType::CorruptEntry)
+                                    }))
+                                }
+                                Err(e) => f_err(e),
+                            }
+                        })) as SFuture<_>;
                     }
-                    Ok(Cache::Miss) => {
+                    Ok((_, Cache::Miss)) => {
                         debug!(
                             "[{}]: Cache miss in {}",
                             out_pretty,
@@ -247,7 +438,7 @@ where
                         );
                         MissType::Normal
                     }
-                    Ok(Cache::Recache) => {
+                    Ok((_, Cache::Recache)) => {
                         debug!(
                             "[{}]: Cache recache in {}",
                             out_pretty,
@@ -263,106 +454,31 @@ where
                                 fmt_duration_as_secs(&duration)
                             );
                             MissType::TimedOut
-                        } else {
-                            error!("[{}]: Cache read error: {}", out_pretty, err);
-                            if err.is_inner() {
-                                let err = err.into_inner().unwrap();
-                                for e in err.iter().skip(1) {
-                                    error!("[{}] \t{}", out_pretty, e);
+                        } else if err.is_inner() {
+                            match err.into_inner().unwrap() {
+                                Error(ErrorKind::CorruptCacheEntry(name), _) => {
+                                    warn!(
+                                        "[{}]: Corrupt cache entry, object `{}` doesn't match its checksum",
+                                        out_pretty, name
+                                    );
+                                    MissType::CorruptEntry
+                                }
+                                err => {
+                                    error!("[{}]: Cache read error: {}", out_pretty, err);
+                                    for e in err.iter().skip(1) {
+                                        error!("[{}] \t{}", out_pretty, e);
+                                    }
+                                    MissType::CacheReadError
                                 }
                             }
+                        } else {
+                            error!("[{}]: Cache read error: {}", out_pretty, err);
                             MissType::CacheReadError
                         }
                     }
                 };
 
-                // Cache miss, so compile it.
-                let start = Instant::now();
-                let compile = dist_or_local_compile(
-                    dist_client,
-                    creator,
-                    cwd,
-                    compilation,
-                    weak_toolchain_key,
-                    out_pretty.clone(),
-                );
-
-                Box::new(
-                    compile.and_then(move |(cacheable, dist_type, compiler_result)| {
-                        let duration = start.elapsed();
-                        if !compiler_result.status.success() {
-                            debug!(
-                                "[{}]: Compiled but failed, not storing in cache",
-                                out_pretty
-                            );
-                            return f_ok((CompileResult::CompileFailed, compiler_result))
-                                as SFuture<_>;
-                        }
-                        if cacheable != Cacheable::Yes {
-                            // Not cacheable
-                            debug!("[{}]: Compiled but not cacheable", out_pretty);
-                            return f_ok((CompileResult::NotCacheable, compiler_result));
-                        }
-                        debug!(
-                            "[{}]: Compiled in {}, storing in cache",
-                            out_pretty,
-                            fmt_duration_as_secs(&duration)
-                        );
-                        let write = pool.spawn_fn(move || -> Result<_> {
-                            let mut entry = CacheWrite::new();
-                            for (key, path) in &outputs {
-                                let mut f = File::open(&path)?;
-                                let mode = get_file_mode(&f)?;
-                                entry.put_object(key, &mut f, mode).chain_err(|| {
-                                    format!("failed to put object `{:?}` in zip", path)
-                                })?;
-                            }
-                            Ok(entry)
-                        });
-                        let write = write.chain_err(|| "failed to zip up compiler outputs");
-                        let o = out_pretty.clone();
-                        Box::new(
-                            write
-                                .and_then(move |mut entry| {
-                                    if !compiler_result.stdout.is_empty() {
-                                        let mut stdout = &compiler_result.stdout[..];
-                                        entry.put_object("stdout", &mut stdout, None)?;
-                                    }
-                                    if !compiler_result.stderr.is_empty() {
-                                        let mut stderr = &compiler_result.stderr[..];
-                                        entry.put_object("stderr", &mut stderr, None)?;
-                                    }
-
-                                    // Try to finish storing the newly-written cache
-                                    // entry. We'll get the result back elsewhere.
-                                    let future = storage.put(&key, entry).then(move |res| {
-                                        match res {
-                                            Ok(_) => debug!(
-                                                "[{}]: Stored in cache successfully!",
-                                                out_pretty
-                                            ),
-                                            Err(ref e) => debug!(
-                                                "[{}]: Cache write error: {:?}",
-                                                out_pretty, e
-                                            ),
-                                        }
-                                        res.map(|duration| CacheWriteInfo {
-                                            object_file_pretty: out_pretty,
-                                            duration: duration,
-                                        })
-                                    });
-                                    let future = Box::new(future);
-                                    Ok((
-                                        CompileResult::CacheMiss(
-                                            miss_type, dist_type, duration, future,
-                                        ),
-                                        compiler_result,
-                                    ))
-                                })
-                                .chain_err(move || format!("failed to store `{}` to cache", o)),
-                        )
-                    }),
-                )
+                compile_and_store(miss_type)
             }))
         }))
     }
@@ -376,6 +492,17 @@ where
     fn box_clone(&self) -> Box<dyn CompilerHasher<T>>;
 }
 
+/// Get the object `name` from `entry`, which holds the compiler's output
+/// on stdout or stderr if there was any.
+fn get_output_object(entry: &mut CacheRead, name: &str) -> Result<Vec<u8>> {
+    let mut contents = vec![];
+    match entry.get_object(name, &mut contents) {
+        Ok(_) => Ok(contents),
+        Err(e @ Error(ErrorKind::CorruptCacheEntry(..), _)) => Err(e),
+        Err(_) => Ok(vec![]),
+    }
+}
+
 #[cfg(not(feature = "dist-client"))]
 fn dist_or_local_compile<T>(
     _dist_client: Result<Option<Arc<dyn dist::Client>>>,
@@ -605,6 +732,26 @@ pub trait Compilation {
     /// Each item is a descriptive (and unique) name of the output paired with
     /// the path where it'll show up.
     fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a Path)> + 'a>;
+
+    /// Returns the names of the outputs that mention local paths which were
+    /// left out of the hash key, along with `(path, placeholder)` pairs for
+    /// those paths. The cached copies of these outputs have the paths replaced
+    /// with their placeholders, which are replaced back when they're fetched.
+    fn path_placeholders(&self) -> (Vec<String>, Vec<(String, String)>) {
+        (vec![], vec![])
+    }
+}
+
+/// Replace each `from` path in `contents` with `to`.
+fn replace_paths<'a, I>(contents: Vec<u8>, replacements: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = (&'a String, &'a String)>,
+{
+    replacements
+        .into_iter()
+        .fold(contents, |contents, (from, to)| {
+            replace_path(&contents, from.as_bytes(), to.as_bytes()).into_owned()
+        })
 }
 
 #[cfg(feature = "dist-client")]
@@ -691,6 +838,8 @@ pub enum MissType {
     TimedOut,
     /// Error reading from cache
     CacheReadError,
+    /// The cache entry failed its integrity check.
+    CorruptEntry,
 }
 
 /// Information about a successful cache write.
@@ -1008,14 +1157,16 @@ where
 mod test {
     use super::*;
     use crate::cache::disk::DiskCache;
-    use crate::cache::Storage;
+    use crate::cache::{CacheRead, Storage};
+    use crate::config::CacheRWMode;
     use crate::mock_command::*;
     use crate::test::mock_storage::MockStorage;
     use crate::test::utils::*;
+    use filetime::{set_file_times, FileTime};
     use futures::{future, Future};
     use futures_cpupool::CpuPool;
     use std::fs::{self, File};
-    use std::io::Write;
+    use std::io::{self, Write};
     use std::sync::Arc;
     use std::time::Duration;
     use std::u64;
@@ -1173,7 +1324,12 @@ LLVM version: 6.0",
         let f = TestFixture::new();
         let pool = CpuPool::new(1);
         let mut runtime = Runtime::new().unwrap();
-        let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage = DiskCache::new(
+            &f.tempdir.path().join("cache"),
+            u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
+        );
         let storage: Arc<dyn Storage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
@@ -1202,10 +1358,11 @@ LLVM version: 6.0",
         });
         let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
-            CompilerArguments::Ok(h) => h,
-            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
-        };
+        let hasher =
+            match c.parse_arguments(&arguments, ".".as_ref(), &[], &CompilerConfig::default()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            };
         let hasher2 = hasher.clone();
         let (cached, res) = runtime
             .block_on(future::lazy(|| {
@@ -1213,6 +1370,8 @@ LLVM version: 6.0",
                     Ok(None),
                     creator.clone(),
                     storage.clone(),
+                    CacheCompression::default(),
+                    &CompilerConfig::default(),
                     arguments.clone(),
                     cwd.to_path_buf(),
                     vec![],
@@ -1250,6 +1409,8 @@ LLVM version: 6.0",
                     Ok(None),
                     creator.clone(),
                     storage.clone(),
+                    CacheCompression::default(),
+                    &CompilerConfig::default(),
                     arguments,
                     cwd.to_path_buf(),
                     vec![],
@@ -1269,6 +1430,110 @@ LLVM version: 6.0",
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    fn test_compiler_get_cached_or_compile_direct_mode() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let storage = DiskCache::new(
+            &f.tempdir.path().join("cache"),
+            u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
+        );
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        let config = CompilerConfig {
+            direct_mode: true,
+            ..Default::default()
+        };
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator, &f.bins[0], &[], &pool)
+            .wait()
+            .unwrap();
+        // Files modified during a compilation aren't recorded in manifests.
+        let old = FileTime::from_seconds_since_1970(1000, 0);
+        let source = f.touch("foo.c").unwrap();
+        set_file_times(&source, old, old).unwrap();
+        let header = f.touch("foo.h").unwrap();
+        set_file_times(&header, old, old).unwrap();
+        // The preprocessor invocation, listing the included header.
+        next_command(
+            &creator,
+            Ok(MockChild::new(
+                exit_status(0),
+                "preprocessor output",
+                ". foo.h\n",
+            )),
+        );
+        // The compiler invocation.
+        let obj = f.tempdir.path().join("foo.o");
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            let mut f = File::create(&o)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher =
+            match c.parse_arguments(&arguments, ".".as_ref(), &[], &CompilerConfig::default()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+        let compile = |hasher: Box<dyn CompilerHasher<_>>, runtime: &mut Runtime| {
+            runtime
+                .block_on(future::lazy(|| {
+                    hasher.get_cached_or_compile(
+                        Ok(None),
+                        creator.clone(),
+                        storage.clone(),
+                        CacheCompression::default(),
+                        &config,
+                        arguments.clone(),
+                        cwd.to_path_buf(),
+                        vec![],
+                        CacheControl::Default,
+                        pool.clone(),
+                    )
+                }))
+                .unwrap()
+        };
+        match compile(hasher.clone(), &mut runtime).0 {
+            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, f) => {
+                f.wait().unwrap();
+            }
+            cached => panic!("Unexpected compile result: {:?}", cached),
+        }
+        // Wait for the manifest to be stored.
+        runtime.run().unwrap();
+        // Compiling again should be a cache hit without running the
+        // preprocessor.
+        fs::remove_file(&obj).unwrap();
+        let (cached, res) = compile(hasher.clone(), &mut runtime);
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(exit_status(0), res.status);
+        assert!(obj.is_file());
+        // Changing the header means the preprocessor has to run again, but
+        // its output is the same so the result is still cached.
+        fs::write(&header, b"#define FOO 1").unwrap();
+        set_file_times(&header, old, old).unwrap();
+        next_command(
+            &creator,
+            Ok(MockChild::new(
+                exit_status(0),
+                "preprocessor output",
+                ". foo.h\n",
+            )),
+        );
+        let (cached, _) = compile(hasher, &mut runtime);
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        // Ensure that we ran all processes.
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
     #[test]
     #[cfg(feature = "dist-client")]
     fn test_compiler_get_cached_or_compile_dist() {
@@ -1277,7 +1542,12 @@ LLVM version: 6.0",
         let f = TestFixture::new();
         let pool = CpuPool::new(1);
         let mut runtime = Runtime::new().unwrap();
-        let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage = DiskCache::new(
+            &f.tempdir.path().join("cache"),
+            u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
+        );
         let storage: Arc<dyn Storage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
@@ -1301,10 +1571,11 @@ LLVM version: 6.0",
         ));
         let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
-            CompilerArguments::Ok(h) => h,
-            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
-        };
+        let hasher =
+            match c.parse_arguments(&arguments, ".".as_ref(), &[], &CompilerConfig::default()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            };
         let hasher2 = hasher.clone();
         let (cached, res) = runtime
             .block_on(future::lazy(|| {
@@ -1312,6 +1583,8 @@ LLVM version: 6.0",
                     Ok(dist_client.clone()),
                     creator.clone(),
                     storage.clone(),
+                    CacheCompression::default(),
+                    &CompilerConfig::default(),
                     arguments.clone(),
                     cwd.to_path_buf(),
                     vec![],
@@ -1349,6 +1622,8 @@ LLVM version: 6.0",
                     Ok(dist_client.clone()),
                     creator,
                     storage,
+                    CacheCompression::default(),
+                    &CompilerConfig::default(),
                     arguments,
                     cwd.to_path_buf(),
                     vec![],
@@ -1406,10 +1681,11 @@ LLVM version: 6.0",
         });
         let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
-            CompilerArguments::Ok(h) => h,
-            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
-        };
+        let hasher =
+            match c.parse_arguments(&arguments, ".".as_ref(), &[], &CompilerConfig::default()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            };
         // The cache will return an error.
         storage.next_get(f_err("Some Error"));
         let (cached, res) = runtime
@@ -1418,6 +1694,8 @@ LLVM version: 6.0",
                     Ok(None),
                     creator.clone(),
                     storage.clone(),
+                    CacheCompression::default(),
+                    &CompilerConfig::default(),
                     arguments.clone(),
                     cwd.to_path_buf(),
                     vec![],
@@ -1444,6 +1722,96 @@ LLVM version: 6.0",
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    /// Test that a cache hit that fails its integrity check is treated as a
+    /// cache miss.
+    fn test_compiler_get_cached_or_compile_corrupt_entry() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let storage = MockStorage::new();
+        let storage: Arc<MockStorage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator, &f.bins[0], &[], &pool)
+            .wait()
+            .unwrap();
+        // The preprocessor invocation.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        // The compiler invocation.
+        const COMPILER_STDOUT: &'static [u8] = b"compiler stdout";
+        const COMPILER_STDERR: &'static [u8] = b"compiler stderr";
+        let obj = f.tempdir.path().join("foo.o");
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            // Pretend to compile something.
+            let mut f = File::create(&o)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(
+                exit_status(0),
+                COMPILER_STDOUT,
+                COMPILER_STDERR,
+            ))
+        });
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher =
+            match c.parse_arguments(&arguments, ".".as_ref(), &[], &CompilerConfig::default()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+        // The cache will return an entry whose object doesn't match its contents.
+        let mut entry = CacheWrite::with_compression(CacheCompression::None).unwrap();
+        entry
+            .put_object("obj", &mut &b"cached contents"[..], None)
+            .unwrap();
+        let mut data = entry.finish().unwrap();
+        let pos = data
+            .windows(15)
+            .position(|w| w == b"cached contents")
+            .unwrap();
+        data[pos] ^= 0xff;
+        let entry = CacheRead::from(io::Cursor::new(data)).unwrap();
+        storage.next_get(f_ok(Cache::Hit(entry)));
+        let (cached, res) = runtime
+            .block_on(future::lazy(|| {
+                hasher.get_cached_or_compile(
+                    Ok(None),
+                    creator.clone(),
+                    storage.clone(),
+                    CacheCompression::default(),
+                    &CompilerConfig::default(),
+                    arguments.clone(),
+                    cwd.to_path_buf(),
+                    vec![],
+                    CacheControl::Default,
+                    pool.clone(),
+                )
+            }))
+            .unwrap();
+        // Ensure that the object file was created.
+        assert_eq!(
+            true,
+            fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap()
+        );
+        match cached {
+            CompileResult::CacheMiss(MissType::CorruptEntry, DistType::NoDist, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+
+        assert_eq!(exit_status(0), res.status);
+        assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
+        assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
+    }
+
     #[test]
     fn test_compiler_get_cached_or_compile_force_recache() {
         drop(env_logger::try_init());
@@ -1451,7 +1819,12 @@ LLVM version: 6.0",
         let f = TestFixture::new();
         let pool = CpuPool::new(1);
         let mut runtime = Runtime::new().unwrap();
-        let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage = DiskCache::new(
+            &f.tempdir.path().join("cache"),
+            u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
+        );
         let storage: Arc<dyn Storage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
@@ -1484,10 +1857,11 @@ LLVM version: 6.0",
         }
         let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
-            CompilerArguments::Ok(h) => h,
-            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
-        };
+        let hasher =
+            match c.parse_arguments(&arguments, ".".as_ref(), &[], &CompilerConfig::default()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            };
         let hasher2 = hasher.clone();
         let (cached, res) = runtime
             .block_on(future::lazy(|| {
@@ -1495,6 +1869,8 @@ LLVM version: 6.0",
                     Ok(None),
                     creator.clone(),
                     storage.clone(),
+                    CacheCompression::default(),
+                    &CompilerConfig::default(),
                     arguments.clone(),
                     cwd.to_path_buf(),
                     vec![],
@@ -1525,6 +1901,8 @@ LLVM version: 6.0",
                 Ok(None),
                 creator,
                 storage,
+                CacheCompression::default(),
+                &CompilerConfig::default(),
                 arguments,
                 cwd.to_path_buf(),
                 vec![],
@@ -1557,7 +1935,12 @@ LLVM version: 6.0",
         let f = TestFixture::new();
         let pool = CpuPool::new(1);
         let mut runtime = Runtime::new().unwrap();
-        let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage = DiskCache::new(
+            &f.tempdir.path().join("cache"),
+            u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
+        );
         let storage: Arc<dyn Storage> = Arc::new(storage);
         // Pretend to be GCC.  Also inject a fake object file that the subsequent
         // preprocessor failure should remove.
@@ -1585,16 +1968,19 @@ LLVM version: 6.0",
         );
         let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
-            CompilerArguments::Ok(h) => h,
-            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
-        };
+        let hasher =
+            match c.parse_arguments(&arguments, ".".as_ref(), &[], &CompilerConfig::default()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            };
         let (cached, res) = runtime
             .block_on(future::lazy(|| {
                 hasher.get_cached_or_compile(
                     Ok(None),
                     creator,
                     storage,
+                    CacheCompression::default(),
+                    &CompilerConfig::default(),
                     arguments,
                     cwd.to_path_buf(),
                     vec![],
@@ -1625,7 +2011,12 @@ LLVM version: 6.0",
             test_dist::ErrorSubmitToolchainClient::new(),
             test_dist::ErrorRunJobClient::new(),
         ];
-        let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage = DiskCache::new(
+            &f.tempdir.path().join("cache"),
+            u64::MAX,
+            CacheRWMode::ReadWrite,
+            &pool,
+        );
         let storage: Arc<dyn Storage> = Arc::new(storage);
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
@@ -1658,10 +2049,11 @@ LLVM version: 6.0",
         }
         let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
-            CompilerArguments::Ok(h) => h,
-            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
-        };
+        let hasher =
+            match c.parse_arguments(&arguments, ".".as_ref(), &[], &CompilerConfig::default()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            };
         // All these dist clients will fail, but should still result in successful compiles
         for dist_client in dist_clients {
             if obj.is_file() {
@@ -1673,6 +2065,8 @@ LLVM version: 6.0",
                     Ok(Some(dist_client.clone())),
                     creator.clone(),
                     storage.clone(),
+                    CacheCompression::default(),
+                    &CompilerConfig::default(),
                     arguments.clone(),
                     cwd.to_path_buf(),
                     vec![],
@@ -54,6 +54,7 @@ impl CCompilerImpl for Diab {
         cwd: &Path,
         env_vars: &[(OsString, OsString)],
         may_dist: bool,
+        _list_includes: bool,
     ) -> SFuture<process::Output>
     where
         T: CommandCreatorSync,
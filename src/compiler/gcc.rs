@@ -25,6 +25,7 @@ use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::str;
 
 use crate::errors::*;
 
@@ -52,11 +53,24 @@ impl CCompilerImpl for GCC {
         cwd: &Path,
         env_vars: &[(OsString, OsString)],
         may_dist: bool,
+        list_includes: bool,
     ) -> SFuture<process::Output>
     where
         T: CommandCreatorSync,
     {
-        preprocess(creator, executable, parsed_args, cwd, env_vars, may_dist)
+        preprocess(
+            creator,
+            executable,
+            parsed_args,
+            cwd,
+            env_vars,
+            may_dist,
+            list_includes,
+        )
+    }
+
+    fn included_files(&self, output: &process::Output, cwd: &Path) -> Option<Vec<PathBuf>> {
+        included_files(&output.stderr, cwd)
     }
 
     fn generate_compile_commands(
@@ -436,6 +450,7 @@ pub fn preprocess<T>(
     cwd: &Path,
     env_vars: &[(OsString, OsString)],
     may_dist: bool,
+    list_includes: bool,
 ) -> SFuture<process::Output>
 where
     T: CommandCreatorSync,
@@ -456,6 +471,9 @@ where
     if !may_dist && !parsed_args.profile_generate {
         cmd.arg("-P");
     }
+    if list_includes {
+        cmd.arg("-H");
+    }
     cmd.arg(&parsed_args.input)
         .args(&parsed_args.preprocessor_args)
         .args(&parsed_args.common_args)
@@ -469,6 +487,24 @@ where
     run_input_output(cmd, None)
 }
 
+/// Find the files listed by `-H` in the preprocessor's `stderr`, which are
+/// each printed on a line starting with a dot per level of nesting.
+pub fn included_files(stderr: &[u8], cwd: &Path) -> Option<Vec<PathBuf>> {
+    let stderr = str::from_utf8(stderr).ok()?;
+    let files = stderr
+        .lines()
+        .filter_map(|line| {
+            let path = line.trim_start_matches('.');
+            if path.len() < line.len() && path.starts_with(' ') {
+                Some(cwd.join(&path[1..]))
+            } else {
+                None
+            }
+        })
+        .collect();
+    Some(files)
+}
+
 pub fn generate_compile_commands(
     path_transformer: &mut dist::PathTransformer,
     executable: &Path,
@@ -1144,4 +1180,26 @@ mod test {
         // Ensure that we ran all processes.
         assert_eq!(0, creator.lock().unwrap().children.len());
     }
+
+    #[test]
+    fn test_included_files() {
+        let cwd = Path::new("/src");
+        let stderr = b". /usr/include/stdio.h
+.. /usr/include/features.h
+. foo.h
+./foo.h:3:1: warning: something
+Multiple include guards may be useful for:
+/usr/include/bar.h
+";
+        assert_eq!(
+            included_files(stderr, cwd),
+            Some(vec![
+                cwd.join("/usr/include/stdio.h"),
+                cwd.join("/usr/include/features.h"),
+                cwd.join("foo.h"),
+            ])
+        );
+        assert_eq!(included_files(b"", cwd), Some(vec![]));
+        assert_eq!(included_files(b". foo\xff.h", cwd), None);
+    }
 }
@@ -63,6 +63,7 @@ impl CCompilerImpl for MSVC {
         cwd: &Path,
         env_vars: &[(OsString, OsString)],
         may_dist: bool,
+        list_includes: bool,
     ) -> SFuture<process::Output>
     where
         T: CommandCreatorSync,
@@ -74,10 +75,15 @@ impl CCompilerImpl for MSVC {
             cwd,
             env_vars,
             may_dist,
+            list_includes,
             &self.includes_prefix,
         )
     }
 
+    fn included_files(&self, output: &process::Output, cwd: &Path) -> Option<Vec<PathBuf>> {
+        included_files(&output.stderr, cwd, &self.includes_prefix)
+    }
+
     fn generate_compile_commands(
         &self,
         path_transformer: &mut dist::PathTransformer,
@@ -476,6 +482,7 @@ pub fn preprocess<T>(
     cwd: &Path,
     env_vars: &[(OsString, OsString)],
     _may_dist: bool,
+    list_includes: bool,
     includes_prefix: &str,
 ) -> SFuture<process::Output>
 where
@@ -490,7 +497,7 @@ where
         .env_clear()
         .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
         .current_dir(&cwd);
-    if parsed_args.depfile.is_some() || parsed_args.msvc_show_includes {
+    if parsed_args.depfile.is_some() || parsed_args.msvc_show_includes || list_includes {
         cmd.arg("-showIncludes");
     }
 
@@ -563,6 +570,17 @@ where
     }))
 }
 
+/// Find the files listed by `-showIncludes` in the preprocessor's `stderr`.
+fn included_files(stderr: &[u8], cwd: &Path, includes_prefix: &str) -> Option<Vec<PathBuf>> {
+    let stderr = Encoding::OEM.to_string(stderr).ok()?;
+    let files = stderr
+        .lines()
+        .filter(|line| line.starts_with(includes_prefix))
+        .map(|line| cwd.join(line[includes_prefix.len()..].trim()))
+        .collect();
+    Some(files)
+}
+
 fn generate_compile_commands(
     path_transformer: &mut dist::PathTransformer,
     executable: &Path,
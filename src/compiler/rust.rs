@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cache::Storage;
 use crate::compiler::args::*;
 #[cfg(feature = "dist-client")]
 use crate::compiler::OutputsRewriter;
@@ -19,12 +20,13 @@ use crate::compiler::{
     Cacheable, ColorMode, Compilation, CompileCommand, Compiler, CompilerArguments, CompilerHasher,
     CompilerKind, HashResult,
 };
+use crate::config::CompilerConfig;
 use crate::dist;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
 use crate::mock_command::{CommandCreatorSync, RunCommand};
 use crate::util::{fmt_duration_as_secs, hash_all, run_input_output, Digest};
-use crate::util::{ref_env, HashToDigest, OsStrExt};
+use crate::util::{ref_env, replace_path, HashToDigest, OsStrExt};
 use futures::Future;
 use futures_cpupool::CpuPool;
 use log::Level::Trace;
@@ -40,8 +42,7 @@ use std::collections::{HashMap, HashSet};
 use std::env::consts::DLL_EXTENSION;
 #[cfg(feature = "dist-client")]
 use std::env::consts::{DLL_PREFIX, EXE_EXTENSION};
-use std::ffi::OsString;
-use std::fmt;
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::hash::Hash;
 #[cfg(feature = "dist-client")]
@@ -50,10 +51,12 @@ use std::io::Read;
 use std::iter;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
 #[cfg(feature = "dist-client")]
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
 use std::time;
 use tempdir::TempDir;
+use which::which_in;
 
 use crate::errors::*;
 
@@ -123,6 +126,8 @@ pub struct ParsedArguments {
     crate_link_paths: Vec<PathBuf>,
     /// Static libraries linked to in the compile.
     staticlibs: Vec<PathBuf>,
+    /// Other native libraries found in the search paths, if rustc will link.
+    native_libs: Vec<PathBuf>,
     /// The crate name passed to --crate-name.
     crate_name: String,
     /// The crate types that will be generated
@@ -136,6 +141,16 @@ pub struct ParsedArguments {
     color_mode: ColorMode,
     /// Whether `--json` was passed to this invocation.
     has_json: bool,
+    /// The target passed to --target, if any.
+    target: Option<String>,
+    /// The linker passed to `-C linker`, if any. For linked crates,
+    /// `Rust::parse_arguments` replaces it with the path of the linker that
+    /// rustc will run.
+    linker: Option<PathBuf>,
+    /// Whether debug info will be generated.
+    debug_info: bool,
+    /// The value of any `-C split-debuginfo` option.
+    split_debuginfo: Option<String>,
 }
 
 /// A struct on which to hang a `Compilation` impl.
@@ -168,6 +183,8 @@ pub struct RustCompilation {
     cwd: PathBuf,
     /// The environment variables
     env_vars: Vec<(OsString, OsString)>,
+    /// The directories left out of the hash key, and their placeholders.
+    path_placeholders: Vec<(String, String)>,
 }
 
 // The selection of crate types for this compilation
@@ -175,8 +192,14 @@ pub struct RustCompilation {
 pub struct CrateTypes {
     rlib: bool,
     staticlib: bool,
+    /// Whether any crate types that rustc runs the linker for are generated:
+    /// bin, dylib, cdylib or proc-macro.
+    linked: bool,
 }
 
+/// The crate types that rustc runs the linker for.
+const LINKED_CRATE_TYPES: &[&str] = &["bin", "dylib", "cdylib", "proc-macro"];
+
 lazy_static! {
     /// Emit types that we will cache.
     static ref ALLOWED_EMIT: HashSet<&'static str> = [
@@ -334,6 +357,67 @@ where
     }))
 }
 
+/// Returns `(path, placeholder)` pairs for the directories that can be left
+/// out of the hash key: the configured base directory, usually the workspace
+/// root, and `CARGO_HOME`, which holds the sources of registry and git
+/// dependencies. A directory is only included if a `--remap-path-prefix`
+/// argument keeps its path out of the outputs by remapping the directory
+/// itself: remapping one of its parents would leave the rest of its path in
+/// the outputs. Longer paths come first.
+fn path_placeholders(
+    arguments: &[(OsString, Option<OsString>)],
+    env_vars: &[(OsString, OsString)],
+    base_dir: Option<&Path>,
+) -> Vec<(String, String)> {
+    // "The from=to parameter is scanned from right to left, so from may contain '='".
+    let remapped: Vec<&str> = arguments
+        .iter()
+        .filter(|&&(ref arg, _)| arg == "--remap-path-prefix")
+        .filter_map(|&(_, ref val)| val.as_ref()?.to_str()?.rsplitn(2, '=').nth(1))
+        .filter(|from| !from.is_empty())
+        .collect();
+    let env_var = |name: &str| {
+        env_vars
+            .iter()
+            .find(|&&(ref k, _)| k == name)
+            .map(|&(_, ref v)| PathBuf::from(v))
+    };
+    let cargo_home = env_var("CARGO_HOME").or_else(|| {
+        env_var("HOME")
+            .or_else(|| env_var("USERPROFILE"))
+            .map(|home| home.join(".cargo"))
+    });
+    let mut placeholders: Vec<_> = vec![
+        (base_dir.map(Path::to_owned), "$BASE_DIR"),
+        (cargo_home, "$CARGO_HOME"),
+    ]
+    .into_iter()
+    .filter_map(|(dir, placeholder)| {
+        // Drop any trailing separator.
+        let dir = dir?.components().collect::<PathBuf>();
+        if !dir.is_absolute() || !remapped.iter().any(|from| dir == Path::new(from)) {
+            return None;
+        }
+        Some((dir.to_str()?.to_owned(), placeholder.to_owned()))
+    })
+    .collect();
+    placeholders.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    placeholders
+}
+
+/// Replace the directories in `path_placeholders` in `s` with their placeholders.
+fn replace_paths(s: &OsStr, path_placeholders: &[(String, String)]) -> OsString {
+    let mut replaced = match s.to_str() {
+        Some(s) => s.to_owned(),
+        None => return s.to_owned(),
+    };
+    for (path, placeholder) in path_placeholders {
+        let bytes = replace_path(replaced.as_bytes(), path.as_bytes(), placeholder.as_bytes());
+        replaced = String::from_utf8(bytes.into_owned()).unwrap();
+    }
+    replaced.into()
+}
+
 impl Rust {
     /// Create a new Rust compiler instance, calculating the hashes of
     /// all the shared libraries in its sysroot.
@@ -455,17 +539,35 @@ where
         &self,
         arguments: &[OsString],
         cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        config: &CompilerConfig,
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>> {
-        match parse_arguments(arguments, cwd) {
-            CompilerArguments::Ok(args) => CompilerArguments::Ok(Box::new(RustHasher {
-                executable: self.executable.clone(),
-                host: self.host.clone(),
-                sysroot: self.sysroot.clone(),
-                compiler_shlibs_digests: self.compiler_shlibs_digests.clone(),
-                #[cfg(feature = "dist-client")]
-                rlib_dep_reader: self.rlib_dep_reader.clone(),
-                parsed_args: args,
-            })),
+        match parse_arguments(arguments, cwd, config) {
+            CompilerArguments::Ok(ref args) if !linker_outputs_listed(args, &self.host) => {
+                cannot_cache!(
+                    "crate-type",
+                    "linker outputs not listed by rustc".to_owned()
+                )
+            }
+            CompilerArguments::Ok(mut args) => {
+                // The linker is part of the hash key of linked crates.
+                if args.crate_types.linked {
+                    let linker = args.linker.as_ref().map(|l| l.as_path());
+                    match find_linker(linker, cwd, env_vars) {
+                        Some(linker) => args.linker = Some(linker),
+                        None => cannot_cache!("linker"),
+                    }
+                }
+                CompilerArguments::Ok(Box::new(RustHasher {
+                    executable: self.executable.clone(),
+                    host: self.host.clone(),
+                    sysroot: self.sysroot.clone(),
+                    compiler_shlibs_digests: self.compiler_shlibs_digests.clone(),
+                    #[cfg(feature = "dist-client")]
+                    rlib_dep_reader: self.rlib_dep_reader.clone(),
+                    parsed_args: args,
+                }))
+            }
             CompilerArguments::NotCompilation => CompilerArguments::NotCompilation,
             CompilerArguments::CannotCache(why, extra_info) => {
                 CompilerArguments::CannotCache(why, extra_info)
@@ -730,6 +832,7 @@ ArgData! {
     OutDir(PathBuf),
     CodeGen(ArgCodegen),
     PassThrough(OsString),
+    RemapPathPrefix(OsString),
     Target(ArgTarget),
 }
 
@@ -756,7 +859,7 @@ counted_array!(static ARGS: [ArgInfo<ArgData>; _] = [
     take_arg!("--out-dir", PathBuf, CanBeSeparated('='), OutDir),
     take_arg!("--pretty", OsString, CanBeSeparated('='), NotCompilation),
     take_arg!("--print", OsString, CanBeSeparated('='), NotCompilation),
-    take_arg!("--remap-path-prefix", OsString, CanBeSeparated('='), TooHard),
+    take_arg!("--remap-path-prefix", OsString, CanBeSeparated('='), RemapPathPrefix),
     take_arg!("--sysroot", PathBuf, CanBeSeparated('='), TooHardPath),
     take_arg!("--target", ArgTarget, CanBeSeparated('='), Target),
     take_arg!("--unpretty", OsString, CanBeSeparated('='), NotCompilation),
@@ -774,7 +877,55 @@ counted_array!(static ARGS: [ArgInfo<ArgData>; _] = [
     take_arg!("-o", PathBuf, CanBeSeparated, TooHardPath),
 ]);
 
-fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<ParsedArguments> {
+/// The prefixes and suffixes of the file names static libraries are looked
+/// for under.
+const STATIC_LIB_FILE_NAMES: &[(&str, &str)] = &[("lib", ".a"), ("", ".lib"), ("", ".a")];
+
+/// The prefixes and suffixes of the file names other native libraries are
+/// looked for under.
+const NATIVE_LIB_FILE_NAMES: &[(&str, &str)] = &[
+    ("lib", ".so"),
+    ("lib", ".dylib"),
+    ("", ".lib"),
+    ("lib", ".a"),
+    ("", ".a"),
+];
+
+/// The name of a native library passed with `-l`.
+struct NativeLibName {
+    name: String,
+    /// Whether `name` is the whole file name of the library, as with the
+    /// `+verbatim` modifier.
+    verbatim: bool,
+}
+
+impl NativeLibName {
+    /// Find the library in `paths`, under one of `file_names`.
+    fn find(&self, paths: &[PathBuf], file_names: &[(&str, &str)]) -> Option<PathBuf> {
+        for path in paths {
+            if self.verbatim {
+                let lib_path = path.join(&self.name);
+                if lib_path.exists() {
+                    return Some(lib_path);
+                }
+                continue;
+            }
+            for &(prefix, suffix) in file_names {
+                let lib_path = path.join(format!("{}{}{}", prefix, self.name, suffix));
+                if lib_path.exists() {
+                    return Some(lib_path);
+                }
+            }
+        }
+        None
+    }
+}
+
+fn parse_arguments(
+    arguments: &[OsString],
+    cwd: &Path,
+    config: &CompilerConfig,
+) -> CompilerArguments<ParsedArguments> {
     let mut args = vec![];
 
     let mut emit: Option<HashSet<String>> = None;
@@ -784,14 +935,22 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     let mut crate_types = CrateTypes {
         rlib: false,
         staticlib: false,
+        linked: false,
     };
     let mut extra_filename = None;
     let mut externs = vec![];
     let mut crate_link_paths = vec![];
     let mut static_lib_names = vec![];
+    let mut native_lib_names = vec![];
+    let mut unhashed_lib_kind = None;
+    let mut has_link_args = false;
     let mut static_link_paths: Vec<PathBuf> = vec![];
     let mut color_mode = ColorMode::Auto;
     let mut has_json = false;
+    let mut target = None;
+    let mut linker = None;
+    let mut debug_info = false;
+    let mut split_debuginfo = None;
 
     for arg in ArgsIter::new(arguments.iter().map(|s| s.clone()), &ARGS[..]) {
         let arg = try_or_cannot_cache!(arg, "argument parse");
@@ -803,8 +962,24 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                 return CompilerArguments::NotCompilation
             }
             Some(LinkLibrary(ArgLinkLibrary { kind, name })) => {
-                if kind == "static" {
-                    static_lib_names.push(name.to_owned())
+                // The kind may have modifiers, as in `static:+whole-archive`,
+                // and the name a name to link the library as, as in `foo:bar`.
+                let mut kind_parts = kind.splitn(2, ':');
+                let base_kind = kind_parts.next().unwrap_or("");
+                let verbatim = kind_parts
+                    .next()
+                    .map_or(false, |m| m.split(',').any(|m| m == "+verbatim"));
+                let name = NativeLibName {
+                    name: name.splitn(2, ':').next().unwrap_or("").to_owned(),
+                    verbatim,
+                };
+                match base_kind {
+                    "static" => static_lib_names.push(name),
+                    "dylib" => native_lib_names.push(name),
+                    // Frameworks are found by the linker in their own search
+                    // paths, which aren't hashed, and neither are libraries of
+                    // other kinds. They only matter to crates that are linked.
+                    _ => unhashed_lib_kind = Some(base_kind.to_owned()),
                 }
             }
             Some(LinkPath(ArgLinkPath { kind, path })) => {
@@ -829,15 +1004,19 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                 staticlib,
                 others,
             })) => {
-                // We can't cache non-rlib/staticlib crates, because rustc invokes the
-                // system linker to link them, and we don't know about all the linker inputs.
-                if !others.is_empty() {
-                    let others: Vec<&str> = others.iter().map(String::as_str).collect();
-                    let others_string = others.join(",");
-                    cannot_cache!("crate-type", others_string)
+                // rustc invokes the system linker for the other crate types, we hash
+                // the linker and the native libraries we can find in generate_hash_key.
+                let unknown: Vec<&str> = others
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|ty| !LINKED_CRATE_TYPES.contains(ty))
+                    .collect();
+                if !unknown.is_empty() {
+                    cannot_cache!("crate-type", unknown.join(","))
                 }
                 crate_types.rlib |= rlib;
                 crate_types.staticlib |= staticlib;
+                crate_types.linked |= !others.is_empty();
             }
             Some(CrateName(value)) => crate_name = Some(value.clone()),
             Some(OutDir(value)) => output_dir = Some(value.clone()),
@@ -846,15 +1025,23 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                 match (opt.as_ref(), value) {
                     ("extra-filename", Some(value)) => extra_filename = Some(value.to_owned()),
                     ("extra-filename", None) => cannot_cache!("extra-filename"),
+                    ("linker", Some(value)) => linker = Some(PathBuf::from(value)),
+                    // The files these pass to the linker aren't hashed.
+                    ("link-arg", _) | ("link-args", _) => has_link_args = true,
+                    ("debuginfo", Some(value)) => debug_info = value != "0",
+                    ("split-debuginfo", Some(value)) => split_debuginfo = Some(value.to_owned()),
                     // Incremental compilation makes a mess of sccache's entire world
                     // view. It produces additional compiler outputs that we don't cache,
                     // and just letting rustc do its work in incremental mode is likely
                     // to be faster than trying to fetch a result from cache anyway, so
-                    // don't bother caching compiles where it's enabled currently.
-                    // Longer-term we would like to figure out better integration between
-                    // sccache and rustc in the incremental scenario:
+                    // don't bother caching compiles where it's enabled unless asked to.
+                    // When we do, the incremental directory is left out of the hash key
+                    // and left to rustc: a cache hit doesn't update it, but rustc checks
+                    // what it can reuse from it on the next miss anyway.
                     // https://github.com/mozilla/sccache/issues/236
-                    ("incremental", _) => cannot_cache!("incremental"),
+                    ("incremental", _) if !config.rust_cache_incremental => {
+                        cannot_cache!("incremental")
+                    }
                     (_, _) => (),
                 }
             }
@@ -869,10 +1056,10 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
             Some(Json(_)) => {
                 has_json = true;
             }
-            Some(PassThrough(_)) => (),
-            Some(Target(target)) => match target {
+            Some(PassThrough(_)) | Some(RemapPathPrefix(_)) => (),
+            Some(Target(value)) => match value {
                 ArgTarget::Path(_) | ArgTarget::Unsure(_) => cannot_cache!("target"),
-                ArgTarget::Name(_) => (),
+                ArgTarget::Name(name) => target = Some(name.to_owned()),
             },
             None => {
                 match arg {
@@ -883,7 +1070,12 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                         }
                         input = Some(val.clone());
                     }
-                    Argument::UnknownFlag(_) => {}
+                    Argument::UnknownFlag(ref flag) => {
+                        // `-g` is short for `-C debuginfo=2`.
+                        if flag == "-g" {
+                            debug_info = true;
+                        }
+                    }
                     _ => unreachable!(),
                 }
             }
@@ -924,10 +1116,25 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     if let CrateTypes {
         rlib: false,
         staticlib: false,
+        linked: false,
     } = crate_types
     {
         cannot_cache!("crate-type", "No crate-type passed".to_owned())
     }
+    // `--print file-names` lists the linked outputs even when they aren't
+    // emitted, and doesn't list their metadata unless there's an rlib to take
+    // the name from, see `generate_hash_key`.
+    if crate_types.linked
+        && (!emit.contains("link") || (emit.contains("metadata") && !crate_types.rlib))
+    {
+        cannot_cache!("crate-type", "linked crate without --emit=link".to_owned())
+    }
+    if let (true, Some(kind)) = (crate_types.linked, unhashed_lib_kind) {
+        cannot_cache!("-l", kind)
+    }
+    if crate_types.linked && has_link_args {
+        cannot_cache!("link-arg")
+    }
     // We won't cache invocations that are outputting anything but
     // linker output and dep-info.
     if emit.iter().any(|e| !ALLOWED_EMIT.contains(e.as_str())) {
@@ -945,27 +1152,22 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     } else {
         None
     };
-    // Locate all static libs specified on the commandline.
+    // Locate all static libs specified on the commandline. rustc will just error if there's
+    // a missing static library, so don't worry about it too much.
     let staticlibs = static_lib_names
-        .into_iter()
-        .filter_map(|name| {
-            for path in static_link_paths.iter() {
-                for f in &[
-                    format_args!("lib{}.a", name),
-                    format_args!("{}.lib", name),
-                    format_args!("{}.a", name),
-                ] {
-                    let lib_path = path.join(fmt::format(*f));
-                    if lib_path.exists() {
-                        return Some(lib_path);
-                    }
-                }
-            }
-            // rustc will just error if there's a missing static library, so don't worry about
-            // it too much.
-            None
-        })
+        .iter()
+        .filter_map(|name| name.find(&static_link_paths, STATIC_LIB_FILE_NAMES))
         .collect();
+    // Locate the other native libs if we're linking. The linker will find those that aren't
+    // in the search paths on the commandline in the system directories, we don't hash them.
+    let native_libs = if crate_types.linked {
+        native_lib_names
+            .iter()
+            .filter_map(|name| name.find(&static_link_paths, NATIVE_LIB_FILE_NAMES))
+            .collect()
+    } else {
+        vec![]
+    };
     // We'll figure out the source files and outputs later in
     // `generate_hash_key` where we can run rustc.
     // Cargo doesn't deterministically order --externs, and we need the hash inputs in a
@@ -978,14 +1180,61 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
         externs: externs,
         crate_link_paths,
         staticlibs: staticlibs,
+        native_libs,
         crate_name: crate_name.to_string(),
         dep_info: dep_info.map(|s| s.into()),
         emit,
         color_mode,
         has_json,
+        target,
+        linker,
+        debug_info,
+        split_debuginfo,
     })
 }
 
+/// Whether `--print file-names` lists everything that the linker will write
+/// for a compilation, so that we can cache it. It doesn't list the import
+/// libraries written on Windows or debug info split out into other files.
+fn linker_outputs_listed(parsed_args: &ParsedArguments, host: &str) -> bool {
+    if !parsed_args.crate_types.linked {
+        return true;
+    }
+    let target = parsed_args.target.as_ref().map_or(host, String::as_str);
+    if target.contains("-windows") {
+        return false;
+    }
+    if !parsed_args.debug_info {
+        return true;
+    }
+    match parsed_args.split_debuginfo.as_ref().map(String::as_str) {
+        Some("off") => true,
+        Some(_) => false,
+        // rustc runs dsymutil by default on Apple targets.
+        None => !target.contains("-apple-"),
+    }
+}
+
+/// Find the linker rustc will run: the one passed to `-C linker`, or `cc`.
+fn find_linker(
+    linker: Option<&Path>,
+    cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+) -> Option<PathBuf> {
+    let linker = linker.map_or_else(|| PathBuf::from("cc"), Path::to_owned);
+    let path = env_vars
+        .iter()
+        .find(|&&(ref k, _)| k == "PATH")
+        .map(|&(_, ref v)| v.clone());
+    match which_in(&linker, path, cwd) {
+        Ok(linker) => Some(linker),
+        Err(_) => {
+            debug!("Couldn't find linker {:?}", linker);
+            None
+        }
+    }
+}
+
 impl<T> CompilerHasher<T> for RustHasher
 where
     T: CommandCreatorSync,
@@ -997,6 +1246,8 @@ where
         env_vars: Vec<(OsString, OsString)>,
         _may_dist: bool,
         pool: &CpuPool,
+        _storage: &Arc<dyn Storage>,
+        config: &CompilerConfig,
     ) -> SFuture<HashResult> {
         let me = *self;
         #[rustfmt::skip] // https://github.com/rust-lang/rustfmt/issues/3759
@@ -1014,12 +1265,17 @@ where
                     externs,
                     crate_link_paths,
                     staticlibs,
+                    native_libs,
                     crate_name,
                     crate_types,
                     dep_info,
                     emit,
                     color_mode: _,
                     has_json,
+                    target: _,
+                    linker,
+                    debug_info: _,
+                    split_debuginfo: _,
                 },
         } = me;
         trace!("[{}]: generate_hash_key", crate_name);
@@ -1071,6 +1327,34 @@ where
         trace!("[{}]: hashing {} staticlibs", crate_name, staticlibs.len());
         let abs_staticlibs = staticlibs.iter().map(|s| cwd.join(s)).collect::<Vec<_>>();
         let staticlib_hashes = hash_all(&abs_staticlibs, pool);
+        // If rustc will link, hash the linker and the other native libraries too.
+        let link_inputs: Vec<PathBuf> = if crate_types.linked {
+            linker
+                .iter()
+                .chain(native_libs.iter())
+                .map(|l| cwd.join(l))
+                .collect()
+        } else {
+            vec![]
+        };
+        trace!(
+            "[{}]: hashing {} link inputs",
+            crate_name,
+            link_inputs.len()
+        );
+        let staticlib_hashes = staticlib_hashes.join(hash_all(&link_inputs, pool)).map(
+            |(staticlib_hashes, link_input_hashes)| {
+                staticlib_hashes
+                    .into_iter()
+                    .chain(link_input_hashes)
+                    .collect::<Vec<_>>()
+            },
+        );
+        let path_placeholders = path_placeholders(
+            &os_string_arguments,
+            &env_vars,
+            config.base_dir.as_ref().map(|dir| dir.as_path()),
+        );
         let creator = creator.clone();
         let hashes = source_files_and_hashes.join3(extern_hashes, staticlib_hashes);
         Box::new(hashes.and_then(
@@ -1086,12 +1370,17 @@ where
                 }
                 let weak_toolchain_key = m.clone().finish();
                 // 3. The full commandline (self.arguments)
-                // TODO: there will be full paths here, it would be nice to
-                // normalize them so we can get cross-machine cache hits.
+                // The directories in `path_placeholders` are replaced in the
+                // arguments, the environment variables and the cwd below, so
+                // that compilations on different machines can share entries.
                 // A few argument types are not passed in a deterministic order
                 // by cargo: --extern, -L, --cfg. We'll filter those out, sort them,
                 // and append them to the rest of the arguments.
                 let args = {
+                    let is_incremental = |arg: &OsString, val: &OsString| {
+                        (arg == "-C" || arg == "--codegen") && val.starts_with("incremental=")
+                    };
+                    let incremental = OsString::from("incremental");
                     let (mut sortables, rest): (Vec<_>, Vec<_>) = os_string_arguments
                         .iter()
                         // We exclude a few arguments from the hash:
@@ -1109,9 +1398,17 @@ where
                     sortables.sort();
                     rest.into_iter()
                         .chain(sortables)
-                        .flat_map(|&(ref arg, ref val)| iter::once(arg).chain(val.as_ref()))
+                        .flat_map(|&(ref arg, ref val)| {
+                            // `-C incremental` is hashed without its directory, which only
+                            // holds rustc's intermediate state.
+                            let val = match *val {
+                                Some(ref val) if is_incremental(arg, val) => Some(&incremental),
+                                ref val => val.as_ref(),
+                            };
+                            iter::once(arg).chain(val)
+                        })
                         .fold(OsString::new(), |mut a, b| {
-                            a.push(b);
+                            a.push(replace_paths(b, &path_placeholders));
                             a
                         })
                 };
@@ -1119,6 +1416,7 @@ where
                 // 4. The digest of all source files (this includes src file from cmdline).
                 // 5. The digest of all files listed on the commandline (self.externs).
                 // 6. The digest of all static libraries listed on the commandline (self.staticlibs).
+                // 7. The digest of the linker and the other native libraries, if rustc will link.
                 for h in source_hashes
                     .into_iter()
                     .chain(extern_hashes)
@@ -1126,7 +1424,7 @@ where
                 {
                     m.update(h.as_bytes());
                 }
-                // 7. Environment variables. Ideally we'd use anything referenced
+                // 8. Environment variables. Ideally we'd use anything referenced
                 // via env! in the program, but we don't have a way to determine that
                 // currently, and hashing all environment variables is too much, so
                 // we'll just hash the CARGO_ env vars and hope that's sufficient.
@@ -1145,11 +1443,13 @@ where
                     if var.starts_with("CARGO_") && var != "CARGO_MAKEFLAGS" {
                         var.hash(&mut HashToDigest { digest: &mut m });
                         m.update(b"=");
-                        val.hash(&mut HashToDigest { digest: &mut m });
+                        replace_paths(val, &path_placeholders)
+                            .hash(&mut HashToDigest { digest: &mut m });
                     }
                 }
-                // 8. The cwd of the compile. This will wind up in the rlib.
-                cwd.hash(&mut HashToDigest { digest: &mut m });
+                // 9. The cwd of the compile. This will wind up in the rlib.
+                Path::new(&replace_paths(cwd.as_os_str(), &path_placeholders))
+                    .hash(&mut HashToDigest { digest: &mut m });
                 // Turn arguments into a simple Vec<OsString> to calculate outputs.
                 let flat_os_string_arguments: Vec<OsString> = os_string_arguments
                     .into_iter()
@@ -1232,6 +1532,7 @@ where
                                 dep_info,
                                 cwd,
                                 env_vars,
+                                path_placeholders,
                                 #[cfg(feature = "dist-client")]
                                 rlib_dep_reader,
                             }),
@@ -1269,10 +1570,11 @@ impl Compilation for RustCompilation {
             ref env_vars,
             ref host,
             ref sysroot,
+            ref crate_types,
             ..
         } = *self;
         #[cfg(not(feature = "dist-client"))]
-        let _ = path_transformer;
+        let _ = (path_transformer, crate_types);
 
         trace!("[{}]: compile", crate_name);
 
@@ -1302,6 +1604,11 @@ impl Compilation for RustCompilation {
                 };
             }
 
+            // The server doesn't have a linker.
+            if crate_types.linked {
+                return None;
+            }
+
             let mut dist_arguments = vec![];
             let mut saw_target = false;
 
@@ -1315,8 +1622,18 @@ impl Compilation for RustCompilation {
                         input_path.into_arg_string(path_transformer_fn)
                     ))
                 } else {
-                    if let Some(Target(_)) = argument.get_data() {
-                        saw_target = true
+                    match argument.get_data() {
+                        Some(Target(_)) => saw_target = true,
+                        // The incremental directory is local state, there's no use for
+                        // it on the server.
+                        Some(CodeGen(ArgCodegen { opt, .. })) if opt == "incremental" => continue,
+                        Some(RemapPathPrefix(_)) => {
+                            // The remappings we add for the dist paths below would take
+                            // precedence, so the outputs wouldn't match a local compile.
+                            debug!("Not distributing a compilation with --remap-path-prefix");
+                            return None;
+                        }
+                        _ => (),
                     }
                     for string_arg in argument.iter_strings(path_transformer_fn) {
                         dist_arguments.push(try_string_arg!(string_arg))
@@ -1436,6 +1753,20 @@ impl Compilation for RustCompilation {
     fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a Path)> + 'a> {
         Box::new(self.outputs.iter().map(|(k, v)| (k.as_str(), &**v)))
     }
+
+    fn path_placeholders(&self) -> (Vec<String>, Vec<(String, String)>) {
+        // The dep info lists the full paths of the source files.
+        let dep_info = self.dep_info.as_ref().and_then(|dep_info| {
+            self.outputs
+                .iter()
+                .find(|&(_, path)| path == dep_info)
+                .map(|(key, _)| key.clone())
+        });
+        (
+            dep_info.into_iter().collect(),
+            self.path_placeholders.clone(),
+        )
+    }
 }
 
 // TODO: we do end up with slashes facing the wrong way, but Windows is agnostic so it's
@@ -1604,6 +1935,7 @@ impl pkg::InputsPackager for RustInputsPackager {
         let can_trim_rlibs = if let CrateTypes {
             rlib: true,
             staticlib: false,
+            linked: false,
         } = crate_types
         {
             true
@@ -2035,6 +2367,7 @@ mod test {
 
     use crate::compiler::*;
     use crate::mock_command::*;
+    use crate::test::mock_storage::MockStorage;
     use crate::test::utils::*;
     use itertools::Itertools;
     use std::ffi::OsStr;
@@ -2044,7 +2377,7 @@ mod test {
 
     fn _parse_arguments(arguments: &[String]) -> CompilerArguments<ParsedArguments> {
         let arguments = arguments.iter().map(OsString::from).collect::<Vec<_>>();
-        parse_arguments(&arguments, ".".as_ref())
+        parse_arguments(&arguments, ".".as_ref(), &CompilerConfig::default())
     }
 
     macro_rules! parses {
@@ -2261,7 +2594,45 @@ mod test {
             "-C",
             "incremental=/foo"
         );
-        assert_eq!(r, CompilerArguments::CannotCache("incremental", None))
+        assert_eq!(r, CompilerArguments::CannotCache("incremental", None));
+        let config = CompilerConfig {
+            rust_cache_incremental: true,
+            ..Default::default()
+        };
+        let args = ovec![
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "-C",
+            "incremental=/foo"
+        ];
+        match parse_arguments(&args, ".".as_ref(), &config) {
+            CompilerArguments::Ok(_) => {}
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_remap_path_prefix() {
+        parses!(
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "--remap-path-prefix",
+            "/home/user/.cargo=/cargo"
+        );
     }
 
     #[test]
@@ -2320,6 +2691,107 @@ mod test {
             "--out-dir",
             "out"
         );
+        parses!(
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "bin",
+            "--emit",
+            "link",
+            "-l",
+            "static:+whole-archive,-bundle=bar",
+            "-l",
+            "dylib:+verbatim=libbaz.so",
+            "foo.rs",
+            "--out-dir",
+            "out"
+        );
+        // Frameworks aren't hashed, so only crates that aren't linked can be
+        // cached with them.
+        parses!(
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "--emit",
+            "link",
+            "-l",
+            "framework=Foundation",
+            "foo.rs",
+            "--out-dir",
+            "out"
+        );
+        assert_eq!(
+            fails!(
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                "bin",
+                "--emit",
+                "link",
+                "-l",
+                "framework=Foundation",
+                "foo.rs",
+                "--out-dir",
+                "out"
+            ),
+            CompilerArguments::CannotCache("-l", Some("framework".to_owned()))
+        );
+        parses!(
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "--emit",
+            "link",
+            "-l",
+            "static-nobundle=bar",
+            "foo.rs",
+            "--out-dir",
+            "out"
+        );
+        assert_eq!(
+            fails!(
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                "bin",
+                "--emit",
+                "link",
+                "-l",
+                "raw-dylib=bar",
+                "foo.rs",
+                "--out-dir",
+                "out"
+            ),
+            CompilerArguments::CannotCache("-l", Some("raw-dylib".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_native_lib_name_find() {
+        let f = TestFixture::new();
+        let dir = f.tempdir.path().to_owned();
+        f.touch("libbar.a").unwrap();
+        f.touch("baz.so").unwrap();
+        let name = |name: &str, verbatim| NativeLibName {
+            name: name.to_owned(),
+            verbatim,
+        };
+        let paths = &[dir.clone()];
+        assert_eq!(
+            name("bar", false).find(paths, STATIC_LIB_FILE_NAMES),
+            Some(dir.join("libbar.a"))
+        );
+        assert_eq!(
+            name("libbar.a", false).find(paths, STATIC_LIB_FILE_NAMES),
+            None
+        );
+        assert_eq!(
+            name("baz.so", true).find(paths, NATIVE_LIB_FILE_NAMES),
+            Some(dir.join("baz.so"))
+        );
+        assert_eq!(name("baz", false).find(paths, NATIVE_LIB_FILE_NAMES), None);
     }
 
     #[test]
@@ -2368,20 +2840,52 @@ mod test {
             "--crate-name",
             "foo"
         );
+        for crate_type in &["bin", "rlib,dylib", "cdylib", "proc-macro"] {
+            let h = parses!(
+                "--crate-type",
+                crate_type,
+                "--emit",
+                "link",
+                "foo.rs",
+                "--out-dir",
+                "out",
+                "--crate-name",
+                "foo"
+            );
+            assert!(h.crate_types.linked);
+        }
         fails!(
             "--crate-type",
             "bin",
             "--emit",
-            "link",
+            "link,metadata",
             "foo.rs",
             "--out-dir",
             "out",
             "--crate-name",
             "foo"
         );
-        fails!(
+    }
+
+    #[test]
+    fn test_linker_outputs_listed() {
+        let host = "x86_64-unknown-linux-gnu";
+        let h = parses!(
+            "--crate-type",
+            "rlib",
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "-g"
+        );
+        assert!(linker_outputs_listed(&h, "x86_64-pc-windows-msvc"));
+        let h = parses!(
             "--crate-type",
-            "rlib,dylib",
+            "proc-macro",
             "--emit",
             "link",
             "foo.rs",
@@ -2390,6 +2894,40 @@ mod test {
             "--crate-name",
             "foo"
         );
+        assert!(linker_outputs_listed(&h, host));
+        assert!(linker_outputs_listed(&h, "x86_64-apple-darwin"));
+        assert!(!linker_outputs_listed(&h, "x86_64-pc-windows-msvc"));
+        let h = parses!(
+            "--crate-type",
+            "bin",
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "-C",
+            "debuginfo=2"
+        );
+        assert!(h.debug_info);
+        assert!(linker_outputs_listed(&h, host));
+        assert!(!linker_outputs_listed(&h, "x86_64-apple-darwin"));
+        let h = parses!(
+            "--crate-type",
+            "bin",
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "-g",
+            "-C",
+            "split-debuginfo=unpacked"
+        );
+        assert!(!linker_outputs_listed(&h, host));
     }
 
     #[test]
@@ -2665,21 +3203,28 @@ c:/foo/bar.rs:
                 externs: vec!["bar.rlib".into()],
                 crate_link_paths: vec![],
                 staticlibs: vec![f.tempdir.path().join("libbaz.a")],
+                native_libs: vec![],
                 crate_name: "foo".into(),
                 crate_types: CrateTypes {
                     rlib: true,
                     staticlib: false,
+                    linked: false,
                 },
                 dep_info: None,
                 emit: emit,
                 color_mode: ColorMode::Auto,
                 has_json: false,
+                target: None,
+                linker: None,
+                debug_info: false,
+                split_debuginfo: None,
             },
         });
         let creator = new_creator();
         mock_dep_info(&creator, &["foo.rs", "bar.rs"]);
         mock_file_names(&creator, &["foo.rlib", "foo.a"]);
         let pool = CpuPool::new(1);
+        let storage: Arc<dyn Storage> = Arc::new(MockStorage::new());
         let res = hasher
             .generate_hash_key(
                 &creator,
@@ -2692,6 +3237,8 @@ c:/foo/bar.rs:
                 .to_vec(),
                 false,
                 &pool,
+                &storage,
+                &CompilerConfig::default(),
             )
             .wait()
             .unwrap();
@@ -2741,7 +3288,20 @@ c:/foo/bar.rs:
     where
         F: Fn(&Path) -> Result<()>,
     {
-        let parsed_args = match parse_arguments(args, &f.tempdir.path()) {
+        hash_key_with_config(f, args, env_vars, pre_func, &CompilerConfig::default())
+    }
+
+    fn hash_key_with_config<'a, F>(
+        f: &TestFixture,
+        args: &[OsString],
+        env_vars: &[(OsString, OsString)],
+        pre_func: F,
+        config: &CompilerConfig,
+    ) -> String
+    where
+        F: Fn(&Path) -> Result<()>,
+    {
+        let parsed_args = match parse_arguments(args, &f.tempdir.path(), config) {
             CompilerArguments::Ok(parsed_args) => parsed_args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
         };
@@ -2768,6 +3328,7 @@ c:/foo/bar.rs:
 
         let creator = new_creator();
         let pool = CpuPool::new(1);
+        let storage: Arc<dyn Storage> = Arc::new(MockStorage::new());
         mock_dep_info(&creator, &["foo.rs"]);
         mock_file_names(&creator, &["foo.rlib"]);
         hasher
@@ -2777,6 +3338,8 @@ c:/foo/bar.rs:
                 env_vars.to_owned(),
                 false,
                 &pool,
+                &storage,
+                config,
             )
             .wait()
             .unwrap()
@@ -2787,6 +3350,306 @@ c:/foo/bar.rs:
         Ok(())
     }
 
+    #[test]
+    fn test_path_placeholders() {
+        let args = |remaps: &[&str]| {
+            remaps
+                .iter()
+                .map(|r| {
+                    (
+                        OsString::from("--remap-path-prefix"),
+                        Some(OsString::from(r)),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let home = vec![(OsString::from("HOME"), OsString::from("/home/user"))];
+        let base_dir = Some(Path::new("/home/user/src/"));
+        assert_eq!(path_placeholders(&args(&[]), &home, base_dir), vec![]);
+        assert_eq!(
+            path_placeholders(&args(&["/home/user/.cargo=/cargo"]), &home, base_dir),
+            vec![("/home/user/.cargo".to_owned(), "$CARGO_HOME".to_owned())]
+        );
+        assert_eq!(
+            path_placeholders(
+                &args(&["/home/user/.cargo=/cargo", "/home/user/src/=/src"]),
+                &home,
+                base_dir
+            ),
+            vec![
+                ("/home/user/.cargo".to_owned(), "$CARGO_HOME".to_owned()),
+                ("/home/user/src".to_owned(), "$BASE_DIR".to_owned()),
+            ]
+        );
+        // Remapping a parent leaves the rest of the path in the outputs.
+        assert_eq!(
+            path_placeholders(&args(&["/home/user=/home"]), &home, base_dir),
+            vec![]
+        );
+        let cargo_home = vec![(OsString::from("CARGO_HOME"), OsString::from("/opt/cargo"))];
+        assert_eq!(
+            path_placeholders(&args(&["/opt/cargo=/cargo"]), &cargo_home, None),
+            vec![("/opt/cargo".to_owned(), "$CARGO_HOME".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_equal_hashes_base_dir() {
+        let hash = |f: &TestFixture, remap: bool| {
+            let dir = f.tempdir.path().to_str().unwrap();
+            let mut args = ovec![
+                "--emit",
+                "link",
+                "foo.rs",
+                "--out-dir",
+                "out",
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                "lib"
+            ];
+            if remap {
+                args.push(format!("--remap-path-prefix={}=/src", dir).into());
+            }
+            let env_vars = vec![(OsString::from("CARGO_MANIFEST_DIR"), OsString::from(dir))];
+            let config = CompilerConfig {
+                base_dir: Some(f.tempdir.path().to_owned()),
+                ..Default::default()
+            };
+            hash_key_with_config(f, &args, &env_vars, &nothing, &config)
+        };
+        let f1 = TestFixture::new();
+        let f2 = TestFixture::new();
+        assert_eq!(hash(&f1, true), hash(&f2, true));
+        assert_neq!(hash(&f1, false), hash(&f2, false));
+    }
+
+    #[test]
+    fn test_equal_hashes_incremental() {
+        let f = TestFixture::new();
+        let config = CompilerConfig {
+            rust_cache_incremental: true,
+            ..Default::default()
+        };
+        let hash = |incremental: &str| {
+            let args = ovec![
+                "--emit",
+                "link",
+                "foo.rs",
+                "--out-dir",
+                "out",
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                "lib",
+                "-C",
+                incremental
+            ];
+            hash_key_with_config(&f, &args, &[], &nothing, &config)
+        };
+        assert_eq!(hash("incremental=/foo"), hash("incremental=/bar"));
+    }
+
+    #[test]
+    fn test_hashes_incremental_differ_from_non_incremental() {
+        let f = TestFixture::new();
+        let config = CompilerConfig {
+            rust_cache_incremental: true,
+            ..Default::default()
+        };
+        let args = ovec![
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib"
+        ];
+        let mut incremental_args = args.clone();
+        incremental_args.extend(ovec!["-C", "incremental=/foo"]);
+        assert_neq!(
+            hash_key_with_config(&f, &args, &[], &nothing, &config),
+            hash_key_with_config(&f, &incremental_args, &[], &nothing, &config)
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_hash_key_linker() {
+        let f = TestFixture::new();
+        let hash = |crate_type: &str, linker: &[u8], lib: &[u8]| {
+            mk_bin_contents(f.tempdir.path(), "my-cc", |mut w| w.write_all(linker)).unwrap();
+            create_file(f.tempdir.path(), "libz.so", |mut w| w.write_all(lib)).unwrap();
+            hash_key(
+                &f,
+                &ovec![
+                    "--emit",
+                    "link",
+                    "foo.rs",
+                    "--out-dir",
+                    "out",
+                    "--crate-name",
+                    "foo",
+                    "--crate-type",
+                    crate_type,
+                    "-C",
+                    "linker=./my-cc",
+                    "-L",
+                    "native=.",
+                    "-l",
+                    "z"
+                ],
+                &[],
+                nothing,
+            )
+        };
+        // The linker and native libraries only matter to crates that are linked.
+        assert_eq!(hash("rlib", b"cc 1", b"z 1"), hash("rlib", b"cc 2", b"z 2"));
+        assert_neq!(hash("bin", b"cc 1", b"z 1"), hash("bin", b"cc 2", b"z 1"));
+        assert_neq!(hash("bin", b"cc 1", b"z 1"), hash("bin", b"cc 1", b"z 2"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_parse_arguments_linker_not_found() {
+        let f = TestFixture::new();
+        let rust = Rust {
+            executable: "rustc".into(),
+            host: "x86_64-unknown-linux-gnu".to_owned(),
+            sysroot: f.tempdir.path().join("sysroot"),
+            compiler_shlibs_digests: vec![],
+            #[cfg(feature = "dist-client")]
+            rlib_dep_reader: None,
+        };
+        let parse = |crate_type: &str| {
+            let args = ovec![
+                "--emit",
+                "link",
+                "foo.rs",
+                "--out-dir",
+                "out",
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                crate_type,
+                "-C",
+                "linker=./my-cc"
+            ];
+            Compiler::<MockCommandCreator>::parse_arguments(
+                &rust,
+                &args,
+                f.tempdir.path(),
+                &[],
+                &CompilerConfig::default(),
+            )
+        };
+        match parse("bin") {
+            CompilerArguments::CannotCache("linker", _) => {}
+            _ => panic!("Linked crate cached without a linker"),
+        }
+        // Crates that aren't linked don't need one.
+        match parse("lib") {
+            CompilerArguments::Ok(_) => {}
+            _ => panic!("Crate that isn't linked not cached"),
+        }
+        mk_bin_contents(f.tempdir.path(), "my-cc", |mut w| w.write_all(b"cc")).unwrap();
+        match parse("bin") {
+            CompilerArguments::Ok(_) => {}
+            _ => panic!("Linked crate not cached"),
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_link_args() {
+        // Linker arguments aren't hashed, so only crates that aren't linked
+        // can be cached with them.
+        parses!(
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "--emit",
+            "link",
+            "-C",
+            "link-arg=libbar.a",
+            "foo.rs",
+            "--out-dir",
+            "out"
+        );
+        assert_eq!(
+            fails!(
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                "bin",
+                "--emit",
+                "link",
+                "-C",
+                "link-arg=libbar.a",
+                "foo.rs",
+                "--out-dir",
+                "out"
+            ),
+            CompilerArguments::CannotCache("link-arg", None)
+        );
+        assert_eq!(
+            fails!(
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                "cdylib",
+                "--emit",
+                "link",
+                "-C",
+                "link-args=-lbar -lbaz",
+                "foo.rs",
+                "--out-dir",
+                "out"
+            ),
+            CompilerArguments::CannotCache("link-arg", None)
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_hash_key_native_lib_modifiers() {
+        let f = TestFixture::new();
+        let hash = |static_lib: &[u8], dylib: &[u8]| {
+            mk_bin_contents(f.tempdir.path(), "my-cc", |mut w| w.write_all(b"cc")).unwrap();
+            create_file(f.tempdir.path(), "libz.a", |mut w| w.write_all(static_lib)).unwrap();
+            create_file(f.tempdir.path(), "y.so", |mut w| w.write_all(dylib)).unwrap();
+            hash_key(
+                &f,
+                &ovec![
+                    "--emit",
+                    "link",
+                    "foo.rs",
+                    "--out-dir",
+                    "out",
+                    "--crate-name",
+                    "foo",
+                    "--crate-type",
+                    "bin",
+                    "-C",
+                    "linker=./my-cc",
+                    "-L",
+                    "native=.",
+                    "-l",
+                    "static:+whole-archive=z",
+                    "-l",
+                    "dylib:+verbatim=y.so:y"
+                ],
+                &[],
+                nothing,
+            )
+        };
+        assert_neq!(hash(b"z 1", b"y 1"), hash(b"z 2", b"y 1"));
+        assert_neq!(hash(b"z 1", b"y 1"), hash(b"z 1", b"y 2"));
+    }
+
     #[test]
     fn test_equal_hashes_externs() {
         // Put some content in the extern rlibs so we can verify that the content hashes are
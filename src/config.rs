@@ -18,7 +18,7 @@ use serde::de::{Deserialize, DeserializeOwned, Deserializer};
 #[cfg(any(feature = "dist-client", feature = "dist-server"))]
 #[cfg(any(feature = "dist-client", feature = "dist-server"))]
 use serde::ser::{Serialize, Serializer};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs::{self, File};
 use std::io::{Read, Write};
@@ -26,6 +26,7 @@ use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
 use std::str::FromStr;
 use std::sync::Mutex;
+use std::time::Duration;
 
 use crate::errors::*;
 
@@ -71,6 +72,9 @@ fn default_disk_cache_size() -> u64 {
 fn default_toolchain_cache_size() -> u64 {
     TEN_GIGS
 }
+fn default_zstd_level() -> i32 {
+    3
+}
 
 pub fn parse_size(val: &str) -> Option<u64> {
     let re = Regex::new(r"^(\d+)([KMGT])$").expect("Fixed regex parse failure");
@@ -89,6 +93,26 @@ pub fn parse_size(val: &str) -> Option<u64> {
         })
 }
 
+/// Parse a duration such as `30d`, in seconds, minutes, hours, days or weeks.
+pub fn parse_duration(val: &str) -> Option<Duration> {
+    let re = Regex::new(r"^(\d+)([smhdw])$").expect("Fixed regex parse failure");
+    re.captures(val)
+        .and_then(|caps| {
+            caps.get(1)
+                .and_then(|n| u64::from_str(n.as_str()).ok())
+                .and_then(|n| Some((n, caps.get(2))))
+        })
+        .and_then(|(n, suffix)| match suffix.map(|s| s.as_str()) {
+            Some("s") => Some(n),
+            Some("m") => n.checked_mul(60),
+            Some("h") => n.checked_mul(60 * 60),
+            Some("d") => n.checked_mul(24 * 60 * 60),
+            Some("w") => n.checked_mul(7 * 24 * 60 * 60),
+            _ => None,
+        })
+        .map(Duration::from_secs)
+}
+
 #[cfg(any(feature = "dist-client", feature = "dist-server"))]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct HTTPUrl(reqwest::Url);
@@ -142,9 +166,75 @@ impl HTTPUrl {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// Whether a cache may be read from, written to, or both.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum CacheRWMode {
+    #[serde(rename = "READ_ONLY")]
+    ReadOnly,
+    #[serde(rename = "WRITE_ONLY")]
+    WriteOnly,
+    #[serde(rename = "READ_WRITE")]
+    ReadWrite,
+}
+
+impl CacheRWMode {
+    pub fn can_read(self) -> bool {
+        self != CacheRWMode::WriteOnly
+    }
+    pub fn can_write(self) -> bool {
+        self != CacheRWMode::ReadOnly
+    }
+}
+
+impl Default for CacheRWMode {
+    fn default() -> Self {
+        CacheRWMode::ReadWrite
+    }
+}
+
+/// How requests to Azure Blob Storage are authorized.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct AzureCacheConfig;
+#[serde(tag = "type")]
+pub enum AzureCacheAuth {
+    /// Sign requests with the storage account key.
+    #[serde(rename = "shared_key")]
+    SharedKey { account_key: String },
+    /// Append a shared access signature to each request.
+    #[serde(rename = "sas")]
+    Sas { token: String },
+    /// Send a token for the machine's managed identity, fetched from the
+    /// instance metadata service.
+    #[serde(rename = "managed_identity")]
+    ManagedIdentity {
+        /// The client id of a user-assigned identity, if the machine has
+        /// more than one.
+        #[serde(default)]
+        client_id: Option<String>,
+    },
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct AzureCacheConfig {
+    /// A storage account connection string, holding the endpoint and either
+    /// an account key or a shared access signature.
+    pub connection_string: Option<String>,
+    /// The storage account name, if no connection string is given.
+    pub account_name: Option<String>,
+    /// The blob service endpoint, `https://<account_name>.blob.core.windows.net/`
+    /// by default.
+    pub endpoint: Option<String>,
+    /// The container entries are stored in. If unset, the endpoint is
+    /// expected to include the container.
+    pub container: Option<String>,
+    /// Overrides the credentials in the connection string, if any.
+    pub auth: Option<AzureCacheAuth>,
+    pub key_prefix: String,
+    pub rw_mode: CacheRWMode,
+}
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -153,6 +243,9 @@ pub struct DiskCacheConfig {
     pub dir: PathBuf,
     // TODO: use deserialize_with to allow human-readable sizes in toml
     pub size: u64,
+    pub rw_mode: CacheRWMode,
+    /// Store each output once by content hash, with entries referencing it.
+    pub dedup: bool,
 }
 
 impl Default for DiskCacheConfig {
@@ -160,55 +253,261 @@ impl Default for DiskCacheConfig {
         DiskCacheConfig {
             dir: default_disk_cache_dir(),
             size: default_disk_cache_size(),
+            rw_mode: CacheRWMode::ReadWrite,
+            dedup: false,
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-pub enum GCSCacheRWMode {
-    #[serde(rename = "READ_ONLY")]
-    ReadOnly,
-    #[serde(rename = "READ_WRITE")]
-    ReadWrite,
-}
-
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct GCSCacheConfig {
     pub bucket: String,
     pub cred_path: Option<PathBuf>,
     pub url: Option<String>,
-    pub rw_mode: GCSCacheRWMode,
+    #[serde(default)]
+    pub key_prefix: String,
+    pub rw_mode: CacheRWMode,
+}
+
+/// Credentials sent to an HTTP cache server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum HttpCacheAuth {
+    #[serde(rename = "bearer")]
+    Bearer { token: String },
+    #[serde(rename = "basic")]
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpCacheConfig {
+    pub url: String,
+    #[serde(default)]
+    pub auth: Option<HttpCacheAuth>,
+    #[serde(default)]
+    pub key_prefix: String,
+    #[serde(default)]
+    pub rw_mode: CacheRWMode,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct MemcachedCacheConfig {
     pub url: String,
+    #[serde(default)]
+    pub key_prefix: String,
+    /// How long entries are kept for, in seconds. Entries don't expire if unset.
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    /// Whether reading an entry resets its expiry.
+    #[serde(default)]
+    pub refresh_on_hit: bool,
+    #[serde(default)]
+    pub rw_mode: CacheRWMode,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReapiCacheConfig {
+    /// The address of the server, as `host:port` or `grpc://host:port`.
+    pub endpoint: String,
+    #[serde(default)]
+    pub instance_name: String,
+    #[serde(default)]
+    pub key_prefix: String,
+    #[serde(default)]
+    pub rw_mode: CacheRWMode,
+}
+
+/// How the servers listed in a Redis cache's `url` are deployed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum RedisMode {
+    /// A single server.
+    #[serde(rename = "standalone")]
+    Standalone,
+    /// Nodes of a Redis Cluster, which are asked for the other nodes.
+    #[serde(rename = "cluster")]
+    Cluster,
+    /// Sentinels monitoring the master named `master_name`.
+    #[serde(rename = "sentinel")]
+    Sentinel { master_name: String },
+}
+
+impl Default for RedisMode {
+    fn default() -> Self {
+        RedisMode::Standalone
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct RedisCacheConfig {
+    /// The URL of the server, or whitespace-separated URLs of the cluster
+    /// nodes or sentinels.
     pub url: String,
+    #[serde(default)]
+    pub mode: RedisMode,
+    #[serde(default)]
+    pub key_prefix: String,
+    /// How long entries are kept for, in seconds. Entries don't expire if unset.
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    /// Whether reading an entry resets its expiry.
+    #[serde(default)]
+    pub refresh_on_hit: bool,
+    #[serde(default)]
+    pub rw_mode: CacheRWMode,
+}
+
+/// How an S3 bucket is addressed in request URLs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum S3Addressing {
+    /// `bucket.endpoint/key`, which AWS prefers.
+    #[serde(rename = "virtual_host")]
+    VirtualHost,
+    /// `endpoint/bucket/key`, which most other S3 implementations prefer.
+    #[serde(rename = "path")]
+    Path,
+}
+
+/// How S3 encrypts the objects sccache stores.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum S3ServerSideEncryption {
+    /// With keys managed by S3.
+    #[serde(rename = "AES256")]
+    Aes256,
+    /// With keys managed by AWS KMS.
+    #[serde(rename = "aws:kms")]
+    AwsKms,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct S3CacheConfig {
     pub bucket: String,
+    /// The S3 endpoint. Without `addressing`, this must already include the
+    /// bucket, as either `bucket.host` or `host/bucket`.
     pub endpoint: String,
+    /// How to add the bucket to `endpoint`.
+    #[serde(default)]
+    pub addressing: Option<S3Addressing>,
+    /// The region requests are signed for. If unset, the region named by an
+    /// AWS `endpoint`, or `us-east-1`.
+    #[serde(default)]
+    pub region: Option<String>,
     pub use_ssl: bool,
+    /// A PEM file of CA certificates to trust in addition to the system ones.
+    #[serde(default)]
+    pub ca_bundle: Option<PathBuf>,
+    #[serde(default)]
+    pub key_prefix: String,
+    #[serde(default)]
+    pub rw_mode: CacheRWMode,
+    /// Store each output once by content hash, with entries referencing it.
+    #[serde(default)]
+    pub dedup: bool,
+    #[serde(default)]
+    pub server_side_encryption: Option<S3ServerSideEncryption>,
+    /// The KMS key to encrypt objects with, if not the default one.
+    #[serde(default)]
+    pub sse_kms_key_id: Option<String>,
+    /// The storage class of the objects, such as `STANDARD_IA`.
+    #[serde(default)]
+    pub storage_class: Option<String>,
+    /// Tags to add to every object.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// A cache directory shared between machines, for example over NFS.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SharedDirCacheConfig {
+    pub dir: PathBuf,
+    /// The size in bytes to periodically sweep the cache down to. If unset,
+    /// the size has to be limited externally.
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// How often to sweep the cache, in seconds.
+    #[serde(default = "default_sweep_interval")]
+    pub sweep_interval: u64,
+    #[serde(default)]
+    pub key_prefix: String,
+    #[serde(default)]
+    pub rw_mode: CacheRWMode,
+}
+
+fn default_sweep_interval() -> u64 {
+    60 * 60
+}
+
+/// How the objects in newly written cache entries are compressed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum CacheCompression {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "deflate")]
+    Deflate,
+    #[serde(rename = "zstd")]
+    Zstd {
+        #[serde(default = "default_zstd_level")]
+        level: i32,
+    },
+}
+
+impl Default for CacheCompression {
+    fn default() -> Self {
+        CacheCompression::Deflate
+    }
+}
+
+/// How a tier of a tiered cache handles writes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum CacheWritePolicy {
+    /// Cache writes complete once the entry has been stored in this tier.
+    #[serde(rename = "WRITE_THROUGH")]
+    WriteThrough,
+    /// Cache writes to this tier happen in the background.
+    #[serde(rename = "WRITE_BACK")]
+    WriteBack,
+}
+
+impl Default for CacheWritePolicy {
+    fn default() -> Self {
+        CacheWritePolicy::WriteThrough
+    }
+}
+
+/// Use the local disk cache in front of the remote cache.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct TieredCacheConfig {
+    pub disk_write_policy: CacheWritePolicy,
+    pub remote_write_policy: CacheWritePolicy,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum CacheType {
     Azure(AzureCacheConfig),
     GCS(GCSCacheConfig),
+    Http(HttpCacheConfig),
     Memcached(MemcachedCacheConfig),
+    Reapi(ReapiCacheConfig),
     Redis(RedisCacheConfig),
     S3(S3CacheConfig),
+    SharedDir(SharedDirCacheConfig),
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -217,22 +516,33 @@ pub struct CacheConfigs {
     pub azure: Option<AzureCacheConfig>,
     pub disk: Option<DiskCacheConfig>,
     pub gcs: Option<GCSCacheConfig>,
+    pub http: Option<HttpCacheConfig>,
     pub memcached: Option<MemcachedCacheConfig>,
+    pub reapi: Option<ReapiCacheConfig>,
     pub redis: Option<RedisCacheConfig>,
     pub s3: Option<S3CacheConfig>,
+    pub shared_dir: Option<SharedDirCacheConfig>,
+    pub tiered: Option<TieredCacheConfig>,
+    pub compression: Option<CacheCompression>,
 }
 
 impl CacheConfigs {
     /// Return a vec of the available cache types in an arbitrary but
-    /// consistent ordering
-    fn into_vec_and_fallback(self) -> (Vec<CacheType>, DiskCacheConfig) {
+    /// consistent ordering, along with the fallback disk cache and tiering
+    /// configuration
+    fn into_vec_and_fallback(self) -> (Vec<CacheType>, DiskCacheConfig, Option<TieredCacheConfig>) {
         let CacheConfigs {
             azure,
             disk,
             gcs,
+            http,
             memcached,
+            reapi,
             redis,
             s3,
+            shared_dir,
+            tiered,
+            compression: _,
         } = self;
 
         let caches = s3
@@ -242,10 +552,13 @@ impl CacheConfigs {
             .chain(memcached.map(CacheType::Memcached))
             .chain(gcs.map(CacheType::GCS))
             .chain(azure.map(CacheType::Azure))
+            .chain(http.map(CacheType::Http))
+            .chain(reapi.map(CacheType::Reapi))
+            .chain(shared_dir.map(CacheType::SharedDir))
             .collect();
         let fallback = disk.unwrap_or_else(Default::default);
 
-        (caches, fallback)
+        (caches, fallback, tiered)
     }
 
     /// Override self with any existing fields from other
@@ -254,9 +567,14 @@ impl CacheConfigs {
             azure,
             disk,
             gcs,
+            http,
             memcached,
+            reapi,
             redis,
             s3,
+            shared_dir,
+            tiered,
+            compression,
         } = other;
 
         if azure.is_some() {
@@ -268,15 +586,30 @@ impl CacheConfigs {
         if gcs.is_some() {
             self.gcs = gcs
         }
+        if http.is_some() {
+            self.http = http
+        }
         if memcached.is_some() {
             self.memcached = memcached
         }
+        if reapi.is_some() {
+            self.reapi = reapi
+        }
         if redis.is_some() {
             self.redis = redis
         }
         if s3.is_some() {
             self.s3 = s3
         }
+        if shared_dir.is_some() {
+            self.shared_dir = shared_dir
+        }
+        if tiered.is_some() {
+            self.tiered = tiered
+        }
+        if compression.is_some() {
+            self.compression = compression
+        }
     }
 }
 
@@ -397,12 +730,76 @@ impl Default for DistConfig {
     }
 }
 
+/// How compilations are hashed and looked up.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompilerConfig {
+    /// Look up C and C++ compilations by a manifest of the headers they
+    /// included, only running the preprocessor when the manifest misses.
+    pub direct_mode: bool,
+    /// A directory whose location is left out of hash keys, so that
+    /// compilations in different checkouts of a tree can share cache entries.
+    pub base_dir: Option<PathBuf>,
+    /// Cache rustc compilations that use `-C incremental`, leaving the
+    /// incremental directory to rustc.
+    pub rust_cache_incremental: bool,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct CompilerConfigs {
+    pub direct_mode: Option<bool>,
+    pub base_dir: Option<PathBuf>,
+    pub rust_cache_incremental: Option<bool>,
+}
+
+impl CompilerConfigs {
+    /// Resolve the settings, using defaults for any that aren't set.
+    fn into_config(self) -> CompilerConfig {
+        let CompilerConfigs {
+            direct_mode,
+            base_dir,
+            rust_cache_incremental,
+        } = self;
+        let base_dir = base_dir.filter(|dir| {
+            if !dir.is_absolute() {
+                warn!("Ignoring relative base_dir: {}", dir.display());
+            }
+            dir.is_absolute()
+        });
+        CompilerConfig {
+            direct_mode: direct_mode.unwrap_or(false),
+            base_dir,
+            rust_cache_incremental: rust_cache_incremental.unwrap_or(false),
+        }
+    }
+
+    /// Override settings in this configuration with those set in `other`.
+    fn merge(&mut self, other: Self) {
+        let CompilerConfigs {
+            direct_mode,
+            base_dir,
+            rust_cache_incremental,
+        } = other;
+        if direct_mode.is_some() {
+            self.direct_mode = direct_mode
+        }
+        if base_dir.is_some() {
+            self.base_dir = base_dir
+        }
+        if rust_cache_incremental.is_some() {
+            self.rust_cache_incremental = rust_cache_incremental
+        }
+    }
+}
+
 // TODO: fields only pub for tests
 #[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(default)]
 #[serde(deny_unknown_fields)]
 pub struct FileConfig {
     pub cache: CacheConfigs,
+    pub compiler: CompilerConfigs,
     pub dist: DistConfig,
 }
 
@@ -441,37 +838,153 @@ pub fn try_read_config_file<T: DeserializeOwned>(path: &Path) -> Result<Option<T
 #[derive(Debug)]
 pub struct EnvConfig {
     cache: CacheConfigs,
+    compiler: CompilerConfigs,
+}
+
+fn rw_mode_from_env(var: &str) -> Option<CacheRWMode> {
+    match env::var(var).as_ref().map(String::as_str) {
+        Ok("READ_ONLY") => Some(CacheRWMode::ReadOnly),
+        Ok("WRITE_ONLY") => Some(CacheRWMode::WriteOnly),
+        Ok("READ_WRITE") => Some(CacheRWMode::ReadWrite),
+        Ok(_) => {
+            warn!("Invalid {}-- ignoring.", var);
+            None
+        }
+        _ => None,
+    }
+}
+
+fn flag_from_env(var: &str) -> Option<bool> {
+    let value = env::var(var).map(|value| value.to_ascii_lowercase());
+    match value.as_ref().map(String::as_str) {
+        Ok("1") | Ok("true") | Ok("on") | Ok("yes") => Some(true),
+        Ok("0") | Ok("false") | Ok("off") | Ok("no") => Some(false),
+        Ok(_) => {
+            warn!("Invalid {}-- ignoring.", var);
+            None
+        }
+        _ => None,
+    }
+}
+
+fn ttl_from_env(var: &str) -> Option<u64> {
+    match env::var(var).map(|value| value.parse()) {
+        Ok(Ok(ttl)) => Some(ttl),
+        Ok(Err(_)) => {
+            warn!("Invalid {}-- ignoring.", var);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+fn key_prefix_from_env(var: &str) -> String {
+    env::var(var).unwrap_or_default()
+}
+
+/// Parse a comma-separated list of `key=value` tags.
+fn tags_from_env(var: &str) -> BTreeMap<String, String> {
+    let mut tags = BTreeMap::new();
+    for tag in env::var(var).unwrap_or_default().split(',') {
+        if tag.trim().is_empty() {
+            continue;
+        }
+        let mut parts = tag.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => {
+                tags.insert(key.trim().to_owned(), value.trim().to_owned());
+            }
+            _ => warn!("Invalid tag {:?} in {}-- ignoring.", tag, var),
+        }
+    }
+    tags
 }
 
 fn config_from_env() -> EnvConfig {
     let s3 = env::var("SCCACHE_BUCKET").ok().map(|bucket| {
-        let endpoint = match env::var("SCCACHE_ENDPOINT") {
-            Ok(endpoint) => format!("{}/{}", endpoint, bucket),
-            _ => match env::var("SCCACHE_REGION") {
-                Ok(ref region) if region != "us-east-1" => {
-                    format!("{}.s3-{}.amazonaws.com", bucket, region)
-                }
-                _ => format!("{}.s3.amazonaws.com", bucket),
+        let region = env::var("SCCACHE_REGION").ok();
+        let (endpoint, default_addressing) = match env::var("SCCACHE_ENDPOINT") {
+            Ok(endpoint) => (endpoint, S3Addressing::Path),
+            _ => match region {
+                Some(ref region) if region != "us-east-1" => (
+                    format!("s3.{}.amazonaws.com", region),
+                    S3Addressing::VirtualHost,
+                ),
+                _ => ("s3.amazonaws.com".to_owned(), S3Addressing::VirtualHost),
             },
         };
+        let addressing = match env::var("SCCACHE_S3_ADDRESSING")
+            .as_ref()
+            .map(String::as_str)
+        {
+            Ok("virtual_host") => S3Addressing::VirtualHost,
+            Ok("path") => S3Addressing::Path,
+            Ok(_) => {
+                warn!("Invalid SCCACHE_S3_ADDRESSING-- ignoring.");
+                default_addressing
+            }
+            Err(_) => default_addressing,
+        };
         let use_ssl = match env::var("SCCACHE_S3_USE_SSL") {
             Ok(ref value) if value != "off" => true,
             _ => false,
         };
+        let rw_mode = rw_mode_from_env("SCCACHE_S3_RW_MODE").unwrap_or_default();
+        let dedup = flag_from_env("SCCACHE_S3_DEDUP").unwrap_or(false);
+        let server_side_encryption = match env::var("SCCACHE_S3_SERVER_SIDE_ENCRYPTION")
+            .as_ref()
+            .map(String::as_str)
+        {
+            Ok("AES256") => Some(S3ServerSideEncryption::Aes256),
+            Ok("aws:kms") => Some(S3ServerSideEncryption::AwsKms),
+            Ok(_) => {
+                warn!("Invalid SCCACHE_S3_SERVER_SIDE_ENCRYPTION-- ignoring.");
+                None
+            }
+            Err(_) => None,
+        };
         S3CacheConfig {
             bucket,
             endpoint,
+            addressing: Some(addressing),
+            region,
             use_ssl,
+            ca_bundle: env::var_os("SCCACHE_S3_CA_BUNDLE").map(PathBuf::from),
+            key_prefix: key_prefix_from_env("SCCACHE_S3_KEY_PREFIX"),
+            rw_mode,
+            dedup,
+            server_side_encryption,
+            sse_kms_key_id: env::var("SCCACHE_S3_SSE_KMS_KEY_ID").ok(),
+            storage_class: env::var("SCCACHE_S3_STORAGE_CLASS").ok(),
+            tags: tags_from_env("SCCACHE_S3_TAGS"),
         }
     });
 
-    let redis = env::var("SCCACHE_REDIS")
-        .ok()
-        .map(|url| RedisCacheConfig { url });
+    let redis = env::var("SCCACHE_REDIS").ok().map(|url| {
+        let mode = match env::var("SCCACHE_REDIS_SENTINEL_MASTER") {
+            Ok(master_name) => RedisMode::Sentinel { master_name },
+            Err(_) if flag_from_env("SCCACHE_REDIS_CLUSTER") == Some(true) => RedisMode::Cluster,
+            Err(_) => RedisMode::Standalone,
+        };
+        RedisCacheConfig {
+            url,
+            mode,
+            key_prefix: key_prefix_from_env("SCCACHE_REDIS_KEY_PREFIX"),
+            ttl: ttl_from_env("SCCACHE_REDIS_TTL"),
+            refresh_on_hit: flag_from_env("SCCACHE_REDIS_REFRESH_ON_HIT").unwrap_or(false),
+            rw_mode: rw_mode_from_env("SCCACHE_REDIS_RW_MODE").unwrap_or_default(),
+        }
+    });
 
     let memcached = env::var("SCCACHE_MEMCACHED")
         .ok()
-        .map(|url| MemcachedCacheConfig { url });
+        .map(|url| MemcachedCacheConfig {
+            url,
+            key_prefix: key_prefix_from_env("SCCACHE_MEMCACHED_KEY_PREFIX"),
+            ttl: ttl_from_env("SCCACHE_MEMCACHED_TTL"),
+            refresh_on_hit: flag_from_env("SCCACHE_MEMCACHED_REFRESH_ON_HIT").unwrap_or(false),
+            rw_mode: rw_mode_from_env("SCCACHE_MEMCACHED_RW_MODE").unwrap_or_default(),
+        });
 
     let gcs = env::var("SCCACHE_GCS_BUCKET").ok().map(|bucket| {
         let url = env::var("SCCACHE_GCS_CREDENTIALS_URL").ok();
@@ -483,62 +996,186 @@ fn config_from_env() -> EnvConfig {
             warn!("SCCACHE_GCS_KEY_PATH will take precedence");
         }
 
-        let rw_mode = match env::var("SCCACHE_GCS_RW_MODE").as_ref().map(String::as_str) {
-            Ok("READ_ONLY") => GCSCacheRWMode::ReadOnly,
-            Ok("READ_WRITE") => GCSCacheRWMode::ReadWrite,
-            // TODO: unsure if these should warn during the configuration loading
-            // or at the time when they're actually used to connect to GCS
-            Ok(_) => {
-                warn!("Invalid SCCACHE_GCS_RW_MODE-- defaulting to READ_ONLY.");
-                GCSCacheRWMode::ReadOnly
-            }
-            _ => {
-                warn!("No SCCACHE_GCS_RW_MODE specified-- defaulting to READ_ONLY.");
-                GCSCacheRWMode::ReadOnly
-            }
-        };
+        // TODO: unsure if this should warn during the configuration loading
+        // or at the time when it's actually used to connect to GCS
+        let rw_mode = rw_mode_from_env("SCCACHE_GCS_RW_MODE").unwrap_or_else(|| {
+            warn!("No valid SCCACHE_GCS_RW_MODE specified-- defaulting to READ_ONLY.");
+            CacheRWMode::ReadOnly
+        });
         GCSCacheConfig {
             bucket,
             cred_path,
             url,
+            key_prefix: key_prefix_from_env("SCCACHE_GCS_KEY_PREFIX"),
             rw_mode,
         }
     });
 
-    let azure = env::var("SCCACHE_AZURE_CONNECTION_STRING")
+    let azure_connection_string = env::var("SCCACHE_AZURE_CONNECTION_STRING").ok();
+    let azure_account_name = env::var("SCCACHE_AZURE_ACCOUNT_NAME").ok();
+    let azure_endpoint = env::var("SCCACHE_AZURE_ENDPOINT").ok();
+    let azure = if azure_connection_string.is_some()
+        || azure_account_name.is_some()
+        || azure_endpoint.is_some()
+    {
+        let auth = if let Ok(account_key) = env::var("SCCACHE_AZURE_ACCOUNT_KEY") {
+            Some(AzureCacheAuth::SharedKey { account_key })
+        } else if let Ok(token) = env::var("SCCACHE_AZURE_SAS_TOKEN") {
+            Some(AzureCacheAuth::Sas { token })
+        } else if flag_from_env("SCCACHE_AZURE_USE_MANAGED_IDENTITY").unwrap_or(false) {
+            Some(AzureCacheAuth::ManagedIdentity {
+                client_id: env::var("SCCACHE_AZURE_MANAGED_IDENTITY_CLIENT_ID").ok(),
+            })
+        } else {
+            None
+        };
+        Some(AzureCacheConfig {
+            connection_string: azure_connection_string,
+            account_name: azure_account_name,
+            endpoint: azure_endpoint,
+            container: env::var("SCCACHE_AZURE_BLOB_CONTAINER")
+                .ok()
+                .filter(|c| !c.is_empty()),
+            auth,
+            key_prefix: key_prefix_from_env("SCCACHE_AZURE_KEY_PREFIX"),
+            rw_mode: rw_mode_from_env("SCCACHE_AZURE_RW_MODE").unwrap_or_default(),
+        })
+    } else {
+        None
+    };
+
+    let http = env::var("SCCACHE_HTTP_URL").ok().map(|url| {
+        let auth = match (
+            env::var("SCCACHE_HTTP_BEARER_TOKEN"),
+            env::var("SCCACHE_HTTP_USERNAME"),
+        ) {
+            (Ok(token), _) => Some(HttpCacheAuth::Bearer { token }),
+            (_, Ok(username)) => Some(HttpCacheAuth::Basic {
+                username,
+                password: env::var("SCCACHE_HTTP_PASSWORD").ok(),
+            }),
+            _ => None,
+        };
+        HttpCacheConfig {
+            url,
+            auth,
+            key_prefix: key_prefix_from_env("SCCACHE_HTTP_KEY_PREFIX"),
+            rw_mode: rw_mode_from_env("SCCACHE_HTTP_RW_MODE").unwrap_or_default(),
+        }
+    });
+
+    let reapi = env::var("SCCACHE_REAPI_ENDPOINT")
         .ok()
-        .map(|_| AzureCacheConfig);
+        .map(|endpoint| ReapiCacheConfig {
+            endpoint,
+            instance_name: env::var("SCCACHE_REAPI_INSTANCE_NAME").unwrap_or_default(),
+            key_prefix: key_prefix_from_env("SCCACHE_REAPI_KEY_PREFIX"),
+            rw_mode: rw_mode_from_env("SCCACHE_REAPI_RW_MODE").unwrap_or_default(),
+        });
+
+    let shared_dir = env::var_os("SCCACHE_SHARED_DIR").map(|dir| SharedDirCacheConfig {
+        dir: PathBuf::from(dir),
+        max_size: env::var("SCCACHE_SHARED_DIR_MAX_SIZE")
+            .ok()
+            .and_then(|v| parse_size(&v)),
+        sweep_interval: env::var("SCCACHE_SHARED_DIR_SWEEP_INTERVAL")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(default_sweep_interval),
+        key_prefix: key_prefix_from_env("SCCACHE_SHARED_DIR_KEY_PREFIX"),
+        rw_mode: rw_mode_from_env("SCCACHE_SHARED_DIR_RW_MODE").unwrap_or_default(),
+    });
 
     let disk_dir = env::var_os("SCCACHE_DIR").map(PathBuf::from);
     let disk_sz = env::var("SCCACHE_CACHE_SIZE")
         .ok()
         .and_then(|v| parse_size(&v));
+    let disk_rw_mode = rw_mode_from_env("SCCACHE_DISK_RW_MODE");
+    let disk_dedup = flag_from_env("SCCACHE_DISK_DEDUP");
 
-    let disk = if disk_dir.is_some() || disk_sz.is_some() {
+    let disk = if disk_dir.is_some()
+        || disk_sz.is_some()
+        || disk_rw_mode.is_some()
+        || disk_dedup.is_some()
+    {
         Some(DiskCacheConfig {
             dir: disk_dir.unwrap_or_else(default_disk_cache_dir),
             size: disk_sz.unwrap_or_else(default_disk_cache_size),
+            rw_mode: disk_rw_mode.unwrap_or_default(),
+            dedup: disk_dedup.unwrap_or(false),
         })
     } else {
         None
     };
 
+    let tiered = flag_from_env("SCCACHE_TIERED_CACHE")
+        .filter(|tiered| *tiered)
+        .map(|_| {
+            let policy_from_env = |var: &str| match env::var(var).as_ref().map(String::as_str) {
+                Ok("WRITE_THROUGH") => CacheWritePolicy::WriteThrough,
+                Ok("WRITE_BACK") => CacheWritePolicy::WriteBack,
+                Ok(_) => {
+                    warn!("Invalid {}-- defaulting to WRITE_THROUGH.", var);
+                    CacheWritePolicy::WriteThrough
+                }
+                _ => CacheWritePolicy::WriteThrough,
+            };
+            TieredCacheConfig {
+                disk_write_policy: policy_from_env("SCCACHE_TIERED_DISK_WRITE_POLICY"),
+                remote_write_policy: policy_from_env("SCCACHE_TIERED_REMOTE_WRITE_POLICY"),
+            }
+        });
+
+    let compression = match env::var("SCCACHE_CACHE_COMPRESSION")
+        .as_ref()
+        .map(String::as_str)
+    {
+        Ok("none") => Some(CacheCompression::None),
+        Ok("deflate") => Some(CacheCompression::Deflate),
+        Ok("zstd") => {
+            let level = env::var("SCCACHE_CACHE_ZSTD_LEVEL")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or_else(default_zstd_level);
+            Some(CacheCompression::Zstd { level })
+        }
+        Ok(_) => {
+            warn!("Invalid SCCACHE_CACHE_COMPRESSION-- ignoring.");
+            None
+        }
+        _ => None,
+    };
+
     let cache = CacheConfigs {
         azure,
         disk,
         gcs,
+        http,
         memcached,
+        reapi,
         redis,
         s3,
+        shared_dir,
+        tiered,
+        compression,
+    };
+
+    let compiler = CompilerConfigs {
+        direct_mode: flag_from_env("SCCACHE_DIRECT"),
+        base_dir: env::var_os("SCCACHE_BASE_DIR").map(PathBuf::from),
+        rust_cache_incremental: flag_from_env("SCCACHE_RUST_CACHE_INCREMENTAL"),
     };
 
-    EnvConfig { cache }
+    EnvConfig { cache, compiler }
 }
 
 #[derive(Debug, Default, PartialEq, Eq)]
 pub struct Config {
     pub caches: Vec<CacheType>,
     pub fallback_cache: DiskCacheConfig,
+    pub tiered: Option<TieredCacheConfig>,
+    pub compression: CacheCompression,
+    pub compiler: CompilerConfig,
     pub dist: DistConfig,
 }
 
@@ -563,16 +1200,28 @@ impl Config {
     fn from_env_and_file_configs(env_conf: EnvConfig, file_conf: FileConfig) -> Config {
         let mut conf_caches: CacheConfigs = Default::default();
 
-        let FileConfig { cache, dist } = file_conf;
+        let mut conf_compiler: CompilerConfigs = Default::default();
+
+        let FileConfig {
+            cache,
+            compiler,
+            dist,
+        } = file_conf;
         conf_caches.merge(cache);
+        conf_compiler.merge(compiler);
 
-        let EnvConfig { cache } = env_conf;
+        let EnvConfig { cache, compiler } = env_conf;
         conf_caches.merge(cache);
+        conf_compiler.merge(compiler);
 
-        let (caches, fallback_cache) = conf_caches.into_vec_and_fallback();
+        let compression = conf_caches.compression.unwrap_or_default();
+        let (caches, fallback_cache, tiered) = conf_caches.into_vec_and_fallback();
         Config {
             caches,
             fallback_cache,
+            tiered,
+            compression,
+            compiler: conf_compiler.into_config(),
             dist,
         }
     }
@@ -790,20 +1439,43 @@ fn test_parse_size() {
     assert_eq!(Some(1024 * TEN_GIGS), parse_size("10T"));
 }
 
+#[test]
+fn test_parse_duration() {
+    assert_eq!(None, parse_duration(""));
+    assert_eq!(None, parse_duration("30"));
+    assert_eq!(None, parse_duration("1y"));
+    assert_eq!(Some(Duration::from_secs(45)), parse_duration("45s"));
+    assert_eq!(Some(Duration::from_secs(2 * 60 * 60)), parse_duration("2h"));
+    assert_eq!(Some(Duration::from_secs(30 * 86400)), parse_duration("30d"));
+    assert_eq!(Some(Duration::from_secs(14 * 86400)), parse_duration("2w"));
+}
+
 #[test]
 fn config_overrides() {
     let env_conf = EnvConfig {
         cache: CacheConfigs {
-            azure: Some(AzureCacheConfig),
+            azure: Some(AzureCacheConfig {
+                connection_string: Some("AccountName=foo;AccountKey=bar".to_owned()),
+                rw_mode: CacheRWMode::ReadOnly,
+                ..Default::default()
+            }),
             disk: Some(DiskCacheConfig {
                 dir: "/env-cache".into(),
                 size: 5,
+                rw_mode: CacheRWMode::ReadWrite,
+                dedup: false,
             }),
             redis: Some(RedisCacheConfig {
                 url: "myotherredisurl".to_owned(),
+                mode: RedisMode::Standalone,
+                key_prefix: String::new(),
+                ttl: None,
+                refresh_on_hit: false,
+                rw_mode: CacheRWMode::WriteOnly,
             }),
             ..Default::default()
         },
+        compiler: Default::default(),
     };
 
     let file_conf = FileConfig {
@@ -811,15 +1483,27 @@ fn config_overrides() {
             disk: Some(DiskCacheConfig {
                 dir: "/file-cache".into(),
                 size: 15,
+                rw_mode: CacheRWMode::ReadOnly,
+                dedup: false,
             }),
             memcached: Some(MemcachedCacheConfig {
                 url: "memurl".to_owned(),
+                key_prefix: String::new(),
+                ttl: None,
+                refresh_on_hit: false,
+                rw_mode: CacheRWMode::ReadWrite,
             }),
             redis: Some(RedisCacheConfig {
                 url: "myredisurl".to_owned(),
+                mode: RedisMode::Standalone,
+                key_prefix: String::new(),
+                ttl: None,
+                refresh_on_hit: false,
+                rw_mode: CacheRWMode::ReadWrite,
             }),
             ..Default::default()
         },
+        compiler: Default::default(),
         dist: Default::default(),
     };
 
@@ -828,22 +1512,172 @@ fn config_overrides() {
         Config {
             caches: vec![
                 CacheType::Redis(RedisCacheConfig {
-                    url: "myotherredisurl".to_owned()
+                    url: "myotherredisurl".to_owned(),
+                    mode: RedisMode::Standalone,
+                    key_prefix: String::new(),
+                    ttl: None,
+                    refresh_on_hit: false,
+                    rw_mode: CacheRWMode::WriteOnly,
                 }),
                 CacheType::Memcached(MemcachedCacheConfig {
-                    url: "memurl".to_owned()
+                    url: "memurl".to_owned(),
+                    key_prefix: String::new(),
+                    ttl: None,
+                    refresh_on_hit: false,
+                    rw_mode: CacheRWMode::ReadWrite,
+                }),
+                CacheType::Azure(AzureCacheConfig {
+                    connection_string: Some("AccountName=foo;AccountKey=bar".to_owned()),
+                    rw_mode: CacheRWMode::ReadOnly,
+                    ..Default::default()
                 }),
-                CacheType::Azure(AzureCacheConfig),
             ],
             fallback_cache: DiskCacheConfig {
                 dir: "/env-cache".into(),
                 size: 5,
+                rw_mode: CacheRWMode::ReadWrite,
+                dedup: false,
             },
+            tiered: None,
+            compression: CacheCompression::Deflate,
+            compiler: Default::default(),
             dist: Default::default(),
         }
     );
 }
 
+#[test]
+fn config_compression() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.compression]
+        type = "zstd"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        file_conf.cache.compression,
+        Some(CacheCompression::Zstd { level: 3 })
+    );
+
+    let env_conf = EnvConfig {
+        cache: CacheConfigs {
+            compression: Some(CacheCompression::Zstd { level: 19 }),
+            ..Default::default()
+        },
+        compiler: Default::default(),
+    };
+    let config = Config::from_env_and_file_configs(env_conf, file_conf);
+    assert_eq!(config.compression, CacheCompression::Zstd { level: 19 });
+}
+
+#[test]
+fn config_compiler() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [compiler]
+        direct_mode = true
+        base_dir = "/home/user/src"
+        rust_cache_incremental = true
+        "#,
+    )
+    .unwrap();
+    assert_eq!(file_conf.compiler.direct_mode, Some(true));
+
+    let env_conf = EnvConfig {
+        cache: Default::default(),
+        compiler: Default::default(),
+    };
+    let config = Config::from_env_and_file_configs(env_conf, file_conf);
+    assert_eq!(
+        config.compiler,
+        CompilerConfig {
+            direct_mode: true,
+            base_dir: Some("/home/user/src".into()),
+            rust_cache_incremental: true,
+        }
+    );
+
+    let env_conf = EnvConfig {
+        cache: Default::default(),
+        compiler: CompilerConfigs {
+            direct_mode: Some(false),
+            base_dir: Some("relative/src".into()),
+            rust_cache_incremental: None,
+        },
+    };
+    let config = Config::from_env_and_file_configs(env_conf, Default::default());
+    assert_eq!(config.compiler, CompilerConfig::default());
+}
+
+#[test]
+fn config_tiered() {
+    let env_conf = EnvConfig {
+        cache: CacheConfigs {
+            tiered: Some(TieredCacheConfig {
+                disk_write_policy: CacheWritePolicy::WriteThrough,
+                remote_write_policy: CacheWritePolicy::WriteBack,
+            }),
+            ..Default::default()
+        },
+        compiler: Default::default(),
+    };
+
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.redis]
+        url = "myredisurl"
+
+        [cache.tiered]
+        remote_write_policy = "WRITE_THROUGH"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        file_conf.cache.tiered,
+        Some(TieredCacheConfig {
+            disk_write_policy: CacheWritePolicy::WriteThrough,
+            remote_write_policy: CacheWritePolicy::WriteThrough,
+        })
+    );
+
+    let config = Config::from_env_and_file_configs(env_conf, file_conf);
+    assert_eq!(
+        config.caches,
+        vec![CacheType::Redis(RedisCacheConfig {
+            url: "myredisurl".to_owned(),
+            mode: RedisMode::Standalone,
+            key_prefix: String::new(),
+            ttl: None,
+            refresh_on_hit: false,
+            rw_mode: CacheRWMode::ReadWrite,
+        })]
+    );
+    assert_eq!(
+        config.tiered,
+        Some(TieredCacheConfig {
+            disk_write_policy: CacheWritePolicy::WriteThrough,
+            remote_write_policy: CacheWritePolicy::WriteBack,
+        })
+    );
+}
+
+#[test]
+fn test_flag_from_env() {
+    for value in &["1", "true", "On", "yes"] {
+        env::set_var("SCCACHE_TEST_FLAG", value);
+        assert_eq!(flag_from_env("SCCACHE_TEST_FLAG"), Some(true));
+    }
+    for value in &["0", "false", "off", "NO"] {
+        env::set_var("SCCACHE_TEST_FLAG", value);
+        assert_eq!(flag_from_env("SCCACHE_TEST_FLAG"), Some(false));
+    }
+    env::set_var("SCCACHE_TEST_FLAG", "maybe");
+    assert_eq!(flag_from_env("SCCACHE_TEST_FLAG"), None);
+    env::remove_var("SCCACHE_TEST_FLAG");
+    assert_eq!(flag_from_env("SCCACHE_TEST_FLAG"), None);
+}
+
 #[test]
 fn test_gcs_credentials_url() {
     env::set_var("SCCACHE_GCS_BUCKET", "my-bucket");
@@ -855,6 +1689,7 @@ fn test_gcs_credentials_url() {
         Some(GCSCacheConfig {
             ref bucket,
             cred_path: _,
+            key_prefix: _,
             ref url,
             rw_mode,
         }) => {
@@ -863,8 +1698,350 @@ fn test_gcs_credentials_url() {
                 Some(ref url) => assert_eq!(url, "http://localhost/"),
                 None => panic!("URL can't be none"),
             };
-            assert_eq!(rw_mode, GCSCacheRWMode::ReadWrite);
+            assert_eq!(rw_mode, CacheRWMode::ReadWrite);
         }
         None => assert!(false),
     };
 }
+
+#[test]
+fn test_rw_mode_from_file() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.disk]
+        rw_mode = "WRITE_ONLY"
+
+        [cache.s3]
+        bucket = "name"
+        endpoint = "s3.amazonaws.com"
+        use_ssl = true
+        rw_mode = "READ_ONLY"
+
+        [cache.redis]
+        url = "myredisurl"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        file_conf.cache.disk.map(|disk| disk.rw_mode),
+        Some(CacheRWMode::WriteOnly)
+    );
+    assert_eq!(
+        file_conf.cache.s3,
+        Some(S3CacheConfig {
+            bucket: "name".to_owned(),
+            endpoint: "s3.amazonaws.com".to_owned(),
+            addressing: None,
+            region: None,
+            use_ssl: true,
+            ca_bundle: None,
+            key_prefix: String::new(),
+            rw_mode: CacheRWMode::ReadOnly,
+            dedup: false,
+            server_side_encryption: None,
+            sse_kms_key_id: None,
+            storage_class: None,
+            tags: BTreeMap::new(),
+        })
+    );
+    assert_eq!(
+        file_conf.cache.redis,
+        Some(RedisCacheConfig {
+            url: "myredisurl".to_owned(),
+            mode: RedisMode::Standalone,
+            key_prefix: String::new(),
+            ttl: None,
+            refresh_on_hit: false,
+            rw_mode: CacheRWMode::ReadWrite,
+        })
+    );
+}
+
+#[test]
+fn test_dedup_from_file() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.disk]
+        dedup = true
+
+        [cache.s3]
+        bucket = "name"
+        endpoint = "s3.amazonaws.com"
+        use_ssl = true
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(file_conf.cache.disk.map(|disk| disk.dedup), Some(true));
+    assert_eq!(file_conf.cache.s3.map(|s3| s3.dedup), Some(false));
+}
+
+#[test]
+fn test_key_prefix_from_file() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.s3]
+        bucket = "name"
+        endpoint = "s3.amazonaws.com"
+        use_ssl = true
+        key_prefix = "project/main"
+
+        [cache.redis]
+        url = "myredisurl"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        file_conf.cache.s3.map(|s3| s3.key_prefix),
+        Some("project/main".to_owned())
+    );
+    assert_eq!(
+        file_conf.cache.redis.map(|redis| redis.key_prefix),
+        Some(String::new())
+    );
+}
+
+#[test]
+fn test_s3_addressing_from_file() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.s3]
+        bucket = "name"
+        endpoint = "minio.example.com:9000"
+        addressing = "path"
+        region = "eu-west-1"
+        use_ssl = true
+        ca_bundle = "/etc/ssl/minio.pem"
+        "#,
+    )
+    .unwrap();
+
+    let s3 = file_conf.cache.s3.unwrap();
+    assert_eq!(s3.addressing, Some(S3Addressing::Path));
+    assert_eq!(s3.region, Some("eu-west-1".to_owned()));
+    assert_eq!(s3.ca_bundle, Some(PathBuf::from("/etc/ssl/minio.pem")));
+}
+
+#[test]
+fn test_s3_put_options_from_file() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.s3]
+        bucket = "name"
+        endpoint = "s3.amazonaws.com"
+        use_ssl = true
+        server_side_encryption = "aws:kms"
+        sse_kms_key_id = "alias/builds"
+        storage_class = "STANDARD_IA"
+
+        [cache.s3.tags]
+        cost-center = "1234"
+        retention = "30d"
+        "#,
+    )
+    .unwrap();
+
+    let s3 = file_conf.cache.s3.unwrap();
+    assert_eq!(
+        s3.server_side_encryption,
+        Some(S3ServerSideEncryption::AwsKms)
+    );
+    assert_eq!(s3.sse_kms_key_id, Some("alias/builds".to_owned()));
+    assert_eq!(s3.storage_class, Some("STANDARD_IA".to_owned()));
+    assert_eq!(
+        s3.tags.into_iter().collect::<Vec<_>>(),
+        vec![
+            ("cost-center".to_owned(), "1234".to_owned()),
+            ("retention".to_owned(), "30d".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn test_http_from_file() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.http]
+        url = "https://cache.example.com/sccache"
+        rw_mode = "READ_ONLY"
+
+        [cache.http.auth]
+        type = "basic"
+        username = "user"
+        password = "pass"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        file_conf.cache.http,
+        Some(HttpCacheConfig {
+            url: "https://cache.example.com/sccache".to_owned(),
+            auth: Some(HttpCacheAuth::Basic {
+                username: "user".to_owned(),
+                password: Some("pass".to_owned()),
+            }),
+            key_prefix: String::new(),
+            rw_mode: CacheRWMode::ReadOnly,
+        })
+    );
+}
+
+#[test]
+fn test_reapi_from_file() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.reapi]
+        endpoint = "grpc://localhost:8980"
+        instance_name = "main"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        file_conf.cache.reapi,
+        Some(ReapiCacheConfig {
+            endpoint: "grpc://localhost:8980".to_owned(),
+            instance_name: "main".to_owned(),
+            key_prefix: String::new(),
+            rw_mode: CacheRWMode::ReadWrite,
+        })
+    );
+}
+
+#[test]
+fn test_shared_dir_from_file() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.shared_dir]
+        dir = "/mnt/sccache"
+        max_size = 1073741824
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        file_conf.cache.shared_dir,
+        Some(SharedDirCacheConfig {
+            dir: PathBuf::from("/mnt/sccache"),
+            max_size: Some(1024 * 1024 * 1024),
+            sweep_interval: 60 * 60,
+            key_prefix: String::new(),
+            rw_mode: CacheRWMode::ReadWrite,
+        })
+    );
+}
+
+#[test]
+fn test_ttl_from_file() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.redis]
+        url = "myredisurl"
+        ttl = 604800
+        refresh_on_hit = true
+
+        [cache.memcached]
+        url = "memurl"
+        ttl = 86400
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        file_conf.cache.redis,
+        Some(RedisCacheConfig {
+            url: "myredisurl".to_owned(),
+            mode: RedisMode::Standalone,
+            key_prefix: String::new(),
+            ttl: Some(7 * 24 * 60 * 60),
+            refresh_on_hit: true,
+            rw_mode: CacheRWMode::ReadWrite,
+        })
+    );
+    assert_eq!(
+        file_conf.cache.memcached,
+        Some(MemcachedCacheConfig {
+            url: "memurl".to_owned(),
+            key_prefix: String::new(),
+            ttl: Some(24 * 60 * 60),
+            refresh_on_hit: false,
+            rw_mode: CacheRWMode::ReadWrite,
+        })
+    );
+}
+
+#[test]
+fn test_redis_mode_from_file() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.redis]
+        url = "redis://sentinel-1:26379 redis://sentinel-2:26379"
+
+        [cache.redis.mode]
+        type = "sentinel"
+        master_name = "mymaster"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        file_conf.cache.redis.map(|redis| redis.mode),
+        Some(RedisMode::Sentinel {
+            master_name: "mymaster".to_owned()
+        })
+    );
+}
+
+#[test]
+fn test_azure_from_file() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.azure]
+        account_name = "myaccount"
+        container = "sccache"
+        key_prefix = "project"
+
+        [cache.azure.auth]
+        type = "managed_identity"
+        client_id = "00000000-0000-0000-0000-000000000000"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        file_conf.cache.azure,
+        Some(AzureCacheConfig {
+            account_name: Some("myaccount".to_owned()),
+            container: Some("sccache".to_owned()),
+            auth: Some(AzureCacheAuth::ManagedIdentity {
+                client_id: Some("00000000-0000-0000-0000-000000000000".to_owned()),
+            }),
+            key_prefix: "project".to_owned(),
+            ..Default::default()
+        })
+    );
+
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.azure]
+        endpoint = "https://myaccount.blob.core.windows.net/sccache"
+
+        [cache.azure.auth]
+        type = "sas"
+        token = "sv=2019-02-02&sig=abc"
+        "#,
+    )
+    .unwrap();
+
+    let azure = file_conf.cache.azure.unwrap();
+    assert_eq!(azure.container, None);
+    assert_eq!(
+        azure.auth,
+        Some(AzureCacheAuth::Sas {
+            token: "sv=2019-02-02&sig=abc".to_owned(),
+        })
+    );
+}
@@ -27,6 +27,7 @@ use futures::Future;
 
 error_chain! {
     foreign_links {
+        Grpc(grpcio::Error) #[cfg(feature = "grpcio")];
         Hyper(hyper::Error) #[cfg(feature = "hyper")];
         Io(io::Error);
         Lru(lru_disk_cache::Error);
@@ -50,6 +51,10 @@ error_chain! {
             description("failed to get a successful HTTP status")
             display("didn't get a successful HTTP status, got `{}`", status)
         }
+        CorruptCacheEntry(name: String) {
+            description("cache entry failed its integrity check")
+            display("cache entry object `{}` doesn't match its checksum", name)
+        }
         HttpClientError(msg: String) {
             display("didn't get a successful HTTP status, got `{}`", msg)
         }
@@ -1,6 +1,8 @@
+use crate::cache::{EntryInfo, PruneStats};
 use crate::compiler::ColorMode;
 use crate::server::{DistInfo, ServerInfo};
 use std::ffi::OsString;
+use std::time::Duration;
 
 /// A client request.
 #[derive(Serialize, Deserialize, Debug)]
@@ -15,6 +17,14 @@ pub enum Request {
     Shutdown,
     /// Execute a compile or fetch a cached compilation result.
     Compile(Compile),
+    /// List the entries in the cache.
+    ListCache,
+    /// Get the size and age of the cache entry with the given key.
+    StatCacheEntry(String),
+    /// Remove the cache entries that haven't been used for the given time.
+    PruneCache(Duration),
+    /// Remove the cache entry with the given key.
+    RemoveCacheEntry(String),
 }
 
 /// A server response.
@@ -30,6 +40,16 @@ pub enum Response {
     ShuttingDown(ServerInfo),
     /// Second response for `Request::Compile`, containing the results of the compilation.
     CompileFinished(CompileFinished),
+    /// Response for `Request::ListCache`.
+    CacheEntries(Vec<EntryInfo>),
+    /// Response for `Request::StatCacheEntry`, if the cache has the entry.
+    CacheEntry(Option<EntryInfo>),
+    /// Response for `Request::PruneCache`, containing what was removed.
+    CachePruned(PruneStats),
+    /// Response for `Request::RemoveCacheEntry`.
+    CacheEntryRemoved,
+    /// Response for cache maintenance requests that failed, containing the error.
+    CacheError(String),
 }
 
 /// Possible responses from the server for a `Compile` request.
@@ -15,14 +15,14 @@
 // For tokio_io::codec::length_delimited::Framed;
 #![allow(deprecated)]
 
-use crate::cache::{storage_from_config, Storage};
+use crate::cache::{self, storage_from_config, Storage};
 use crate::compiler::{
     get_compiler_info, CacheControl, CompileResult, Compiler, CompilerArguments, CompilerHasher,
     CompilerKind, DistType, MissType,
 };
 #[cfg(feature = "dist-client")]
 use crate::config;
-use crate::config::Config;
+use crate::config::{CacheCompression, CompilerConfig, Config};
 use crate::dist;
 use crate::dist::Client as DistClient;
 use crate::jobserver::Client;
@@ -407,6 +407,8 @@ pub fn start_server(config: &Config, port: u16) -> Result<()> {
         client,
         dist_client,
         storage,
+        config.compression,
+        config.compiler.clone(),
     );
     let notify = env::var_os("SCCACHE_STARTUP_NOTIFY");
     match res {
@@ -443,6 +445,8 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         client: Client,
         dist_client: DistClientContainer,
         storage: Arc<dyn Storage>,
+        compression: CacheCompression,
+        compiler_config: CompilerConfig,
     ) -> Result<SccacheServer<C>> {
         let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
         let listener = TcpListener::bind(&SocketAddr::V4(addr))?;
@@ -451,7 +455,16 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         // connections.
         let (tx, rx) = mpsc::channel(1);
         let (wait, info) = WaitUntilZero::new();
-        let service = SccacheService::new(dist_client, storage, &client, pool, tx, info);
+        let service = SccacheService::new(
+            dist_client,
+            storage,
+            compression,
+            compiler_config,
+            &client,
+            pool,
+            tx,
+            info,
+        );
 
         Ok(SccacheServer {
             runtime: runtime,
@@ -609,6 +622,12 @@ struct SccacheService<C: CommandCreatorSync> {
     /// Cache storage.
     storage: Arc<dyn Storage>,
 
+    /// How new cache entries are compressed.
+    compression: CacheCompression,
+
+    /// How compilations are hashed and looked up.
+    compiler_config: Rc<CompilerConfig>,
+
     /// A cache of known compiler info.
     compilers: Rc<RefCell<HashMap<PathBuf, Option<(Box<dyn Compiler<C>>, FileTime)>>>>,
 
@@ -696,6 +715,32 @@ where
                         .map(move |(_, info)| Message::WithoutBody(Response::ShuttingDown(info))),
                 );
             }
+            Request::ListCache => {
+                debug!("handle_client: list_cache");
+                cache_response(self.storage.list(), Response::CacheEntries)
+            }
+            Request::StatCacheEntry(key) => {
+                debug!("handle_client: stat_cache_entry");
+                cache_response(self.storage.stat(&key), Response::CacheEntry)
+            }
+            Request::PruneCache(older_than) => {
+                debug!("handle_client: prune_cache");
+                let pruned = if self.storage.rw_mode().can_write() {
+                    cache::prune(self.storage.clone(), older_than)
+                } else {
+                    f_err("The cache is read-only")
+                };
+                cache_response(pruned, Response::CachePruned)
+            }
+            Request::RemoveCacheEntry(key) => {
+                debug!("handle_client: remove_cache_entry");
+                let removed = if self.storage.rw_mode().can_write() {
+                    self.storage.remove(&key)
+                } else {
+                    f_err("The cache is read-only")
+                };
+                cache_response(removed, |()| Response::CacheEntryRemoved)
+            }
         };
 
         Box::new(res.map(Message::WithoutBody))
@@ -706,6 +751,21 @@ where
     }
 }
 
+/// Turn the result of a cache maintenance request into a response, sending
+/// any error to the client instead of dropping the connection.
+fn cache_response<T: 'static>(
+    result: SFuture<T>,
+    response: fn(T) -> Response,
+) -> SFuture<Response> {
+    Box::new(result.then(move |result| match result {
+        Ok(value) => Ok(response(value)),
+        Err(e) => {
+            let message = e.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+            Ok(Response::CacheError(message.join(": ")))
+        }
+    }))
+}
+
 impl<C> SccacheService<C>
 where
     C: CommandCreatorSync,
@@ -713,6 +773,8 @@ where
     pub fn new(
         dist_client: DistClientContainer,
         storage: Arc<dyn Storage>,
+        compression: CacheCompression,
+        compiler_config: CompilerConfig,
         client: &Client,
         pool: CpuPool,
         tx: mpsc::Sender<ServerMessage>,
@@ -722,6 +784,8 @@ where
             stats: Rc::new(RefCell::new(ServerStats::default())),
             dist_client: Rc::new(dist_client),
             storage,
+            compression,
+            compiler_config: Rc::new(compiler_config),
             compilers: Rc::new(RefCell::new(HashMap::new())),
             pool,
             creator: C::new(client),
@@ -883,7 +947,7 @@ where
                 debug!("check_compiler: Supported compiler");
                 // Now check that we can handle this compiler with
                 // the provided commandline.
-                match c.parse_arguments(&cmd, &cwd) {
+                match c.parse_arguments(&cmd, &cwd, &env_vars, &self.compiler_config) {
                     CompilerArguments::Ok(hasher) => {
                         debug!("parse_arguments: Ok: {:?}", cmd);
                         stats.requests_executed += 1;
@@ -942,6 +1006,8 @@ where
             self.dist_client.get_client(),
             self.creator.clone(),
             self.storage.clone(),
+            self.compression,
+            &self.compiler_config,
             arguments,
             cwd,
             env_vars,
@@ -987,10 +1053,17 @@ where
                                 MissType::CacheReadError => {
                                     stats.cache_errors.increment(&kind);
                                 }
+                                MissType::CorruptEntry => {
+                                    stats.cache_corrupt_entries += 1;
+                                }
                             }
                             stats.cache_misses.increment(&kind);
                             stats.cache_read_miss_duration += duration;
-                            cache_write = Some(future);
+                            if me.storage.rw_mode().can_write() {
+                                cache_write = Some(future);
+                            } else {
+                                stats.cache_writes_skipped += 1;
+                            }
                         }
                         CompileResult::NotCacheable => {
                             stats.cache_misses.increment(&kind);
@@ -1134,6 +1207,8 @@ pub struct ServerStats {
     pub cache_timeouts: u64,
     /// The count of errors reading cache entries.
     pub cache_read_errors: u64,
+    /// The count of cache entries which failed their integrity check.
+    pub cache_corrupt_entries: u64,
     /// The count of compilations which were successful but couldn't be cached.
     pub non_cacheable_compilations: u64,
     /// The count of compilations which forcibly ignored the cache.
@@ -1142,6 +1217,8 @@ pub struct ServerStats {
     pub cache_write_errors: u64,
     /// The number of successful cache writes.
     pub cache_writes: u64,
+    /// The count of cache writes skipped because the cache is read-only.
+    pub cache_writes_skipped: u64,
     /// The total time spent writing cache entries.
     pub cache_write_duration: Duration,
     /// The total time spent reading cache hits.
@@ -1191,10 +1268,12 @@ impl Default for ServerStats {
             cache_misses: PerLanguageCount::new(),
             cache_timeouts: u64::default(),
             cache_read_errors: u64::default(),
+            cache_corrupt_entries: u64::default(),
             non_cacheable_compilations: u64::default(),
             forced_recaches: u64::default(),
             cache_write_errors: u64::default(),
             cache_writes: u64::default(),
+            cache_writes_skipped: u64::default(),
             cache_write_duration: Duration::new(0, 0),
             cache_read_hit_duration: Duration::new(0, 0),
             cache_read_miss_duration: Duration::new(0, 0),
@@ -1253,8 +1332,18 @@ impl ServerStats {
         set_lang_stat!(stats_vec, self.cache_misses, "Cache misses");
         set_stat!(stats_vec, self.cache_timeouts, "Cache timeouts");
         set_stat!(stats_vec, self.cache_read_errors, "Cache read errors");
+        set_stat!(
+            stats_vec,
+            self.cache_corrupt_entries,
+            "Corrupt cache entries"
+        );
         set_stat!(stats_vec, self.forced_recaches, "Forced recaches");
         set_stat!(stats_vec, self.cache_write_errors, "Cache write errors");
+        set_stat!(
+            stats_vec,
+            self.cache_writes_skipped,
+            "Cache writes skipped (read-only)"
+        );
         set_stat!(stats_vec, self.compile_fails, "Compilation failures");
         set_lang_stat!(stats_vec, self.cache_errors, "Cache errors");
         set_stat!(
@@ -2,7 +2,9 @@
 //! Types for loading and managing AWS access credentials for API requests.
 #![allow(dead_code)]
 
-use chrono::{offset, DateTime, Duration};
+use crate::simples3::s3::xml_element;
+use crate::simples3::sigv4::{self, amz_date, sha256_hex, uri_encode};
+use chrono::{offset, DateTime, Duration, TimeZone};
 use directories::UserDirs;
 use futures::future::{self, Shared};
 use futures::{Async, Future, Stream};
@@ -10,17 +12,20 @@ use hyper::client::HttpConnector;
 use hyper::{Client, Request};
 use hyperx::header::Connection;
 use regex::Regex;
+use reqwest::r#async::{Client as ReqwestClient, RequestBuilder};
+use reqwest::Url;
 use serde_json::{from_str, Value};
+use sha1::{Digest, Sha1};
 #[allow(unused_imports, deprecated)]
 use std::ascii::AsciiExt;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::env::*;
 use std::fs::{self, File};
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
-use std::time::Duration as StdDuration;
+use std::time::{Duration as StdDuration, Instant};
 use tokio_timer::Timeout;
 
 use crate::errors::*;
@@ -132,27 +137,33 @@ fn credentials_from_environment() -> Result<AwsCredentials> {
 }
 
 /// Provides AWS credentials from a profile in a credentials file.
+///
+/// Profiles may also be defined in an AWS config file, and may assume a role
+/// with `role_arn` or use AWS SSO with `sso_start_url`.
 #[derive(Clone, Debug)]
 pub struct ProfileProvider {
     credentials: Option<AwsCredentials>,
     file_path: PathBuf,
+    config_file_path: Option<PathBuf>,
     profile: String,
 }
 
 impl ProfileProvider {
-    /// Create a new `ProfileProvider` for the default credentials file path and profile name.
+    /// Create a new `ProfileProvider` for the default credentials and config file paths, and the
+    /// profile named by `AWS_PROFILE` or the default profile.
     pub fn new() -> Result<ProfileProvider> {
         // Default credentials file location:
         // ~/.aws/credentials (Linux/Mac)
         // %USERPROFILE%\.aws\credentials  (Windows)
-        let profile_location = UserDirs::new()
-            .map(|d| d.home_dir().join(".aws").join("credentials"))
+        let aws_dir = UserDirs::new()
+            .map(|d| d.home_dir().join(".aws"))
             .ok_or("Couldn't get user directories")?;
 
         Ok(ProfileProvider {
             credentials: None,
-            file_path: profile_location,
-            profile: "default".to_owned(),
+            file_path: aws_dir.join("credentials"),
+            config_file_path: Some(aws_dir.join("config")),
+            profile: var("AWS_PROFILE").unwrap_or_else(|_| "default".to_owned()),
         })
     }
 
@@ -166,6 +177,7 @@ impl ProfileProvider {
         ProfileProvider {
             credentials: None,
             file_path: file_path.into(),
+            config_file_path: None,
             profile: profile.into(),
         }
     }
@@ -175,6 +187,11 @@ impl ProfileProvider {
         self.file_path.as_ref()
     }
 
+    /// Get a reference to the config file path, if any.
+    pub fn config_file_path(&self) -> Option<&Path> {
+        self.config_file_path.as_ref().map(|p| p.as_ref())
+    }
+
     /// Get a reference to the profile name.
     pub fn profile(&self) -> &str {
         &self.profile
@@ -188,6 +205,14 @@ impl ProfileProvider {
         self.file_path = file_path.into();
     }
 
+    /// Set the config file path, which profiles are also read from.
+    pub fn set_config_file_path<F>(&mut self, config_file_path: F)
+    where
+        F: Into<PathBuf>,
+    {
+        self.config_file_path = Some(config_file_path.into());
+    }
+
     /// Set the profile name.
     pub fn set_profile<P>(&mut self, profile: P)
     where
@@ -199,92 +224,231 @@ impl ProfileProvider {
 
 impl ProvideAwsCredentials for ProfileProvider {
     fn credentials(&self) -> SFuture<AwsCredentials> {
-        let result = parse_credentials_file(self.file_path());
-        let result = result.and_then(|mut profiles| {
-            profiles
-                .remove(self.profile())
-                .ok_or("profile not found".into())
-        });
-        Box::new(future::result(result))
+        let mut profiles = match parse_profiles_file(self.file_path()) {
+            Ok(profiles) => profiles,
+            Err(e) => {
+                trace!("Couldn't read {:?}: {}", self.file_path(), e);
+                HashMap::new()
+            }
+        };
+        if let Some(config_file_path) = self.config_file_path() {
+            match parse_profiles_file(config_file_path) {
+                // Settings in the credentials file take precedence.
+                Ok(config_profiles) => {
+                    for (name, config_profile) in config_profiles {
+                        let profile = profiles.entry(name).or_insert_with(HashMap::new);
+                        for (key, value) in config_profile {
+                            profile.entry(key).or_insert(value);
+                        }
+                    }
+                }
+                Err(e) => trace!("Couldn't read {:?}: {}", config_file_path, e),
+            }
+        }
+        profile_credentials(&profiles, self.profile(), 0)
     }
 }
 
-fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredentials>> {
+/// The settings of a profile in a credentials or config file.
+type Profile = HashMap<String, String>;
+
+/// How many roles may be assumed in turn to get the credentials for a profile.
+const MAX_ROLE_CHAIN_LENGTH: usize = 5;
+
+/// Parse the profiles in a credentials or config file. In config files the
+/// sections of profiles other than the default one are named `profile <name>`.
+fn parse_profiles_file(file_path: &Path) -> Result<HashMap<String, Profile>> {
     let metadata = fs::metadata(file_path).chain_err(|| "couldn't stat credentials file")?;
     if !metadata.is_file() {
         bail!("Couldn't open file.");
     }
 
     let file = File::open(file_path)?;
+    parse_profiles(BufReader::new(&file))
+}
 
-    let profile_regex = Regex::new(r"^\[([^\]]+)\]$").unwrap();
-    let mut profiles: HashMap<String, AwsCredentials> = HashMap::new();
-    let mut access_key: Option<String> = None;
-    let mut secret_key: Option<String> = None;
+fn parse_profiles<R: BufRead>(reader: R) -> Result<HashMap<String, Profile>> {
+    let profile_regex = Regex::new(r"^\[(?:profile\s+)?([^\]]+)\]$").unwrap();
+    let mut profiles: HashMap<String, Profile> = HashMap::new();
     let mut profile_name: Option<String> = None;
 
-    let file_lines = BufReader::new(&file);
-    for line in file_lines.lines() {
-        let unwrapped_line: String = line.unwrap();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
 
         // skip comments
-        if unwrapped_line.starts_with('#') {
+        if line.starts_with('#') || line.starts_with(';') {
             continue;
         }
 
         // handle the opening of named profile blocks
-        if profile_regex.is_match(&unwrapped_line) {
-            if profile_name.is_some() && access_key.is_some() && secret_key.is_some() {
-                let creds = AwsCredentials::new(
-                    access_key.unwrap(),
-                    secret_key.unwrap(),
-                    None,
-                    in_ten_minutes(),
-                );
-                profiles.insert(profile_name.unwrap(), creds);
-            }
-
-            access_key = None;
-            secret_key = None;
-
-            let caps = profile_regex.captures(&unwrapped_line).unwrap();
-            profile_name = Some(caps.get(1).unwrap().as_str().to_string());
+        if let Some(caps) = profile_regex.captures(line) {
+            profile_name = Some(caps[1].trim().to_owned());
             continue;
         }
 
-        // otherwise look for key=value pairs we care about
-        let lower_case_line = unwrapped_line.to_ascii_lowercase().to_string();
-
-        if lower_case_line.contains("aws_access_key_id") && access_key.is_none() {
-            let v: Vec<&str> = unwrapped_line.split('=').collect();
-            if !v.is_empty() {
-                access_key = Some(v[1].trim_matches(' ').to_string());
-            }
-        } else if lower_case_line.contains("aws_secret_access_key") && secret_key.is_none() {
-            let v: Vec<&str> = unwrapped_line.split('=').collect();
-            if !v.is_empty() {
-                secret_key = Some(v[1].trim_matches(' ').to_string());
+        // otherwise collect key=value pairs in the current profile
+        let mut parts = line.splitn(2, '=');
+        match (profile_name.as_ref(), parts.next(), parts.next()) {
+            (Some(name), Some(key), Some(value)) => {
+                profiles
+                    .entry(name.clone())
+                    .or_insert_with(HashMap::new)
+                    .insert(key.trim().to_ascii_lowercase(), value.trim().to_owned());
             }
+            // we could potentially explode here to indicate that the file is invalid
+            _ => {}
         }
-
-        // we could potentially explode here to indicate that the file is invalid
     }
 
-    if profile_name.is_some() && access_key.is_some() && secret_key.is_some() {
-        let creds = AwsCredentials::new(
-            access_key.unwrap(),
-            secret_key.unwrap(),
-            None,
-            in_ten_minutes(),
-        );
-        profiles.insert(profile_name.unwrap(), creds);
+    Ok(profiles)
+}
+
+/// Get the credentials for the profile `name`, assuming roles as needed.
+/// `depth` is the number of roles that have been assumed to get here.
+fn profile_credentials(
+    profiles: &HashMap<String, Profile>,
+    name: &str,
+    depth: usize,
+) -> SFuture<AwsCredentials> {
+    let profile = match profiles.get(name) {
+        Some(profile) => profile,
+        None => return f_err(format!("profile {} not found", name)),
+    };
+
+    let role_arn = match profile.get("role_arn") {
+        Some(role_arn) => role_arn.clone(),
+        None if profile.contains_key("sso_start_url") => return sso_credentials(profile),
+        None => return f_res(static_credentials(profile)),
+    };
+    if depth >= MAX_ROLE_CHAIN_LENGTH {
+        return f_err(format!("too many chained roles in profile {}", name));
     }
+    let source_credentials = match (
+        profile.get("source_profile"),
+        profile.get("credential_source"),
+    ) {
+        // A profile may hold both the role and the credentials to assume it with.
+        (Some(source), _) if source == name => f_res(static_credentials(profile)),
+        (Some(source), _) => profile_credentials(profiles, source, depth + 1),
+        (None, Some(source)) => match source.as_str() {
+            "Environment" => EnvironmentProvider.credentials(),
+            "Ec2InstanceMetadata" => IamProvider::new().credentials(),
+            "EcsContainer" => ContainerProvider.credentials(),
+            _ => return f_err(format!("unsupported credential_source {}", source)),
+        },
+        (None, None) => {
+            return f_err(format!(
+                "profile {} has a role_arn but no source_profile or credential_source",
+                name
+            ))
+        }
+    };
+    let session_name = profile
+        .get("role_session_name")
+        .cloned()
+        .unwrap_or_else(|| "sccache".to_owned());
+    let external_id = profile.get("external_id").cloned();
+    Box::new(source_credentials.and_then(move |creds| {
+        assume_role(
+            &creds,
+            &role_arn,
+            &session_name,
+            external_id.as_ref().map(String::as_str),
+        )
+    }))
+}
 
-    if profiles.is_empty() {
-        bail!("No credentials found.")
+/// Get the credentials stored in `profile`.
+fn static_credentials(profile: &Profile) -> Result<AwsCredentials> {
+    let access_key = profile
+        .get("aws_access_key_id")
+        .ok_or("No aws_access_key_id in profile")?;
+    let secret_key = profile
+        .get("aws_secret_access_key")
+        .ok_or("No aws_secret_access_key in profile")?;
+    Ok(AwsCredentials::new(
+        access_key.clone(),
+        secret_key.clone(),
+        profile.get("aws_session_token").cloned(),
+        in_ten_minutes(),
+    ))
+}
+
+/// Get credentials for the account and role of an AWS SSO `profile`, using
+/// the access token cached by `aws sso login`.
+fn sso_credentials(profile: &Profile) -> SFuture<AwsCredentials> {
+    let setting = |key: &str| -> Result<String> {
+        profile
+            .get(key)
+            .cloned()
+            .ok_or_else(|| format!("No {} in SSO profile", key).into())
+    };
+    let start_url = ftry!(setting("sso_start_url"));
+    let region = ftry!(setting("sso_region"));
+    let account_id = ftry!(setting("sso_account_id"));
+    let role_name = ftry!(setting("sso_role_name"));
+
+    // The cached token is stored in a file named after the SHA-1 of the start URL.
+    let cache_file = format!("{:x}.json", Sha1::digest(start_url.as_bytes()));
+    let cache_path = ftry!(UserDirs::new()
+        .map(|d| d
+            .home_dir()
+            .join(".aws")
+            .join("sso")
+            .join("cache")
+            .join(cache_file))
+        .ok_or("Couldn't get user directories"));
+    let cache = ftry!(fs::read_to_string(&cache_path)
+        .chain_err(|| format!("couldn't read {:?}, run `aws sso login`", cache_path)));
+    let cache: Value = ftry!(from_str(&cache).chain_err(|| "couldn't parse SSO token cache"));
+    let token = ftry!(cache["accessToken"]
+        .as_str()
+        .ok_or("No accessToken in SSO token cache"));
+    // Older versions of the AWS CLI write the expiry time with a `UTC` suffix.
+    let expires_at = cache["expiresAt"]
+        .as_str()
+        .unwrap_or("")
+        .replace("UTC", "Z");
+    match expires_at.parse::<DateTime<offset::Utc>>() {
+        Ok(expires_at) if expires_at > offset::Utc::now() => {}
+        _ => return f_err("SSO access token has expired, run `aws sso login`"),
     }
 
-    Ok(profiles)
+    let url = format!(
+        "https://portal.sso.{}.amazonaws.com/federation/credentials?account_id={}&role_name={}",
+        region,
+        uri_encode(&account_id, true),
+        uri_encode(&role_name, true)
+    );
+    debug!("Attempting to fetch credentials from {}", url);
+    let request = ReqwestClient::new()
+        .get(&url)
+        .header("x-amz-sso_bearer_token", token);
+    Box::new(
+        fetch_body(request)
+            .and_then(|body| {
+                let json_object: Value =
+                    from_str(&body).chain_err(|| "Couldn't parse SSO response body.")?;
+                let creds = &json_object["roleCredentials"];
+                let field = |key: &str| -> Result<String> {
+                    creds[key]
+                        .as_str()
+                        .map(|s| s.to_owned())
+                        .ok_or_else(|| format!("Couldn't find {} in response.", key).into())
+                };
+                let expiration = creds["expiration"]
+                    .as_i64()
+                    .ok_or("Couldn't find expiration in response.")?;
+                Ok(AwsCredentials::new(
+                    field("accessKeyId")?,
+                    field("secretAccessKey")?,
+                    Some(field("sessionToken")?),
+                    offset::Utc.timestamp(expiration / 1000, 0),
+                ))
+            })
+            .chain_err(|| "failed to get AWS SSO credentials"),
+    )
 }
 
 /// Provides AWS credentials from a resource's IAM role.
@@ -366,83 +530,122 @@ impl ProvideAwsCredentials for IamProvider {
                 String::from_utf8(body).chain_err(|| "failed to read iam role response")
             });
 
-        let creds = body.and_then(|body| {
-            let json_object: Value;
-            match from_str(&body) {
-                Err(_) => bail!("Couldn't parse metadata response body."),
-                Ok(val) => json_object = val,
-            };
-
-            let access_key;
-            match json_object.get("AccessKeyId") {
-                None => bail!("Couldn't find AccessKeyId in response."),
-                Some(val) => {
-                    access_key = val
-                        .as_str()
-                        .expect("AccessKeyId value was not a string")
-                        .to_owned()
-                        .replace("\"", "")
-                }
-            };
+        let creds = body.and_then(|body| parse_credentials_json(&body));
 
-            let secret_key;
-            match json_object.get("SecretAccessKey") {
-                None => bail!("Couldn't find SecretAccessKey in response."),
-                Some(val) => {
-                    secret_key = val
-                        .as_str()
-                        .expect("SecretAccessKey value was not a string")
-                        .to_owned()
-                        .replace("\"", "")
-                }
-            };
+        //XXX: this is crappy, but this blocks on non-EC2 machines like
+        // our mac builders.
+        let timeout = Timeout::new(creds, StdDuration::from_secs(2));
 
-            let expiration;
-            match json_object.get("Expiration") {
-                None => bail!("Couldn't find Expiration in response."),
-                Some(val) => {
-                    expiration = val
-                        .as_str()
-                        .expect("Expiration value was not a string")
-                        .to_owned()
-                        .replace("\"", "")
+        Box::new(timeout.then(|result| match result {
+            Ok(creds) => Ok(creds),
+            Err(err) => match err.into_inner() {
+                None => bail!("took too long to fetch credentials"),
+                Some(e) => {
+                    warn!("Failed to fetch IAM credentials: {}", e);
+                    Err(e)
                 }
-            };
+            },
+        }))
+    }
+}
 
-            let expiration_time = expiration
-                .parse()
-                .chain_err(|| "failed to parse expiration time")?;
+/// Parse the JSON credentials returned by the EC2 instance metadata service
+/// and the ECS container credentials endpoint.
+fn parse_credentials_json(body: &str) -> Result<AwsCredentials> {
+    let json_object: Value;
+    match from_str(body) {
+        Err(_) => bail!("Couldn't parse metadata response body."),
+        Ok(val) => json_object = val,
+    };
 
-            let token_from_response;
-            match json_object.get("Token") {
-                None => bail!("Couldn't find Token in response."),
-                Some(val) => {
-                    token_from_response = val
-                        .as_str()
-                        .expect("Token value was not a string")
-                        .to_owned()
-                        .replace("\"", "")
-                }
-            };
+    let access_key;
+    match json_object.get("AccessKeyId") {
+        None => bail!("Couldn't find AccessKeyId in response."),
+        Some(val) => {
+            access_key = val
+                .as_str()
+                .expect("AccessKeyId value was not a string")
+                .to_owned()
+                .replace("\"", "")
+        }
+    };
 
-            Ok(AwsCredentials::new(
-                access_key,
-                secret_key,
-                Some(token_from_response),
-                expiration_time,
-            ))
-        });
+    let secret_key;
+    match json_object.get("SecretAccessKey") {
+        None => bail!("Couldn't find SecretAccessKey in response."),
+        Some(val) => {
+            secret_key = val
+                .as_str()
+                .expect("SecretAccessKey value was not a string")
+                .to_owned()
+                .replace("\"", "")
+        }
+    };
 
-        //XXX: this is crappy, but this blocks on non-EC2 machines like
-        // our mac builders.
-        let timeout = Timeout::new(creds, StdDuration::from_secs(2));
+    let expiration;
+    match json_object.get("Expiration") {
+        None => bail!("Couldn't find Expiration in response."),
+        Some(val) => {
+            expiration = val
+                .as_str()
+                .expect("Expiration value was not a string")
+                .to_owned()
+                .replace("\"", "")
+        }
+    };
 
+    let expiration_time = expiration
+        .parse()
+        .chain_err(|| "failed to parse expiration time")?;
+
+    let token_from_response;
+    match json_object.get("Token") {
+        None => bail!("Couldn't find Token in response."),
+        Some(val) => {
+            token_from_response = val
+                .as_str()
+                .expect("Token value was not a string")
+                .to_owned()
+                .replace("\"", "")
+        }
+    };
+
+    Ok(AwsCredentials::new(
+        access_key,
+        secret_key,
+        Some(token_from_response),
+        expiration_time,
+    ))
+}
+
+/// Provides AWS credentials from the ECS container credentials endpoint, for
+/// tasks with an IAM role.
+pub struct ContainerProvider;
+
+impl ProvideAwsCredentials for ContainerProvider {
+    fn credentials(&self) -> SFuture<AwsCredentials> {
+        let url = match (
+            var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"),
+            var("AWS_CONTAINER_CREDENTIALS_FULL_URI"),
+        ) {
+            (Ok(uri), _) => format!("http://169.254.170.2{}", uri),
+            (_, Ok(url)) => url,
+            _ => return f_err("No AWS_CONTAINER_CREDENTIALS_RELATIVE_URI in environment"),
+        };
+        debug!("Attempting to fetch credentials from {}", url);
+        let mut request = ReqwestClient::new().get(&url);
+        if let Ok(token) = var("AWS_CONTAINER_AUTHORIZATION_TOKEN") {
+            request = request.header("Authorization", token);
+        }
+        let creds = fetch_body(request).and_then(|body| parse_credentials_json(&body));
+
+        let timeout = Timeout::new(creds, StdDuration::from_secs(2));
         Box::new(timeout.then(|result| match result {
             Ok(creds) => Ok(creds),
             Err(err) => match err.into_inner() {
                 None => bail!("took too long to fetch credentials"),
                 Some(e) => {
-                    warn!("Failed to fetch IAM credentials: {}", e);
+                    warn!("Failed to fetch ECS container credentials: {}", e);
                     Err(e)
                 }
             },
@@ -450,12 +653,209 @@ impl ProvideAwsCredentials for IamProvider {
     }
 }
 
+/// Provides AWS credentials for a role by exchanging a web identity token for
+/// them, as on EKS with IAM roles for service accounts.
+///
+/// The role and token are read from `AWS_ROLE_ARN` and the file named by
+/// `AWS_WEB_IDENTITY_TOKEN_FILE`.
+pub struct WebIdentityProvider;
+
+impl ProvideAwsCredentials for WebIdentityProvider {
+    fn credentials(&self) -> SFuture<AwsCredentials> {
+        let token_file = ftry!(var("AWS_WEB_IDENTITY_TOKEN_FILE")
+            .chain_err(|| "No AWS_WEB_IDENTITY_TOKEN_FILE in environment"));
+        let role_arn = ftry!(var("AWS_ROLE_ARN").chain_err(|| "No AWS_ROLE_ARN in environment"));
+        let session_name = var("AWS_ROLE_SESSION_NAME").unwrap_or_else(|_| "sccache".to_owned());
+        // The token is rotated regularly, so it is read again every time.
+        let token = ftry!(fs::read_to_string(&token_file)
+            .chain_err(|| format!("couldn't read web identity token from {}", token_file)));
+
+        let endpoint = ftry!(sts_endpoint());
+        let body = form_encode(&[
+            ("Action", "AssumeRoleWithWebIdentity"),
+            ("RoleArn", &role_arn),
+            ("RoleSessionName", &session_name),
+            ("Version", "2011-06-15"),
+            ("WebIdentityToken", token.trim()),
+        ]);
+        debug!("Attempting to assume role {} with web identity", role_arn);
+        let request = ReqwestClient::new()
+            .post(endpoint.url)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body);
+        Box::new(
+            fetch_body(request)
+                .and_then(|body| parse_sts_credentials(&body))
+                .chain_err(|| "failed to assume role with web identity"),
+        )
+    }
+}
+
+/// Assume the role `role_arn` using `creds`, returning temporary credentials
+/// for the role.
+fn assume_role(
+    creds: &AwsCredentials,
+    role_arn: &str,
+    session_name: &str,
+    external_id: Option<&str>,
+) -> SFuture<AwsCredentials> {
+    let endpoint = ftry!(sts_endpoint());
+    let host = endpoint.host();
+    let mut params = vec![("Action", "AssumeRole")];
+    if let Some(external_id) = external_id {
+        params.push(("ExternalId", external_id));
+    }
+    params.push(("RoleArn", role_arn));
+    params.push(("RoleSessionName", session_name));
+    params.push(("Version", "2011-06-15"));
+    let body = form_encode(&params);
+
+    let date = offset::Utc::now();
+    let date_header = amz_date(&date);
+    let mut headers = vec![
+        ("content-type", "application/x-www-form-urlencoded"),
+        ("host", host.as_str()),
+        ("x-amz-date", date_header.as_str()),
+    ];
+    if let Some(token) = creds.token() {
+        headers.push(("x-amz-security-token", token.as_str()));
+    }
+    let auth = sigv4::authorization(
+        "POST",
+        endpoint.url.path(),
+        "",
+        &headers,
+        &sha256_hex(body.as_bytes()),
+        creds,
+        &sigv4::Scope {
+            region: &endpoint.region,
+            service: "sts",
+            date: &date,
+        },
+    );
+    debug!("Attempting to assume role {}", role_arn);
+    let mut request = ReqwestClient::new()
+        .post(endpoint.url.clone())
+        .header("Authorization", auth);
+    for (name, value) in headers {
+        // reqwest sets the `Host` header itself.
+        if name != "host" {
+            request = request.header(name, value);
+        }
+    }
+    Box::new(
+        fetch_body(request.body(body))
+            .and_then(|body| parse_sts_credentials(&body))
+            .chain_err(|| "failed to assume role"),
+    )
+}
+
+/// An STS endpoint to get credentials from.
+struct StsEndpoint {
+    url: Url,
+    /// The region requests are signed for.
+    region: String,
+}
+
+impl StsEndpoint {
+    /// Return the `Host` header requests to the endpoint are sent with.
+    fn host(&self) -> String {
+        let host = self.url.host_str().unwrap_or("");
+        match self.url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        }
+    }
+}
+
+/// Return the STS endpoint to use, which is the one in `AWS_ENDPOINT_URL_STS`
+/// if it is set, or else the regional one if `AWS_REGION` or
+/// `AWS_DEFAULT_REGION` is set.
+fn sts_endpoint() -> Result<StsEndpoint> {
+    let region = var("AWS_REGION").or_else(|_| var("AWS_DEFAULT_REGION"));
+    let url = match (var("AWS_ENDPOINT_URL_STS"), &region) {
+        (Ok(url), _) => url,
+        (Err(_), Ok(region)) if region.starts_with("cn-") => {
+            format!("https://sts.{}.amazonaws.com.cn/", region)
+        }
+        (Err(_), Ok(region)) => format!("https://sts.{}.amazonaws.com/", region),
+        (Err(_), Err(_)) => "https://sts.amazonaws.com/".to_owned(),
+    };
+    let url = Url::parse(&url).chain_err(|| format!("invalid STS endpoint {}", url))?;
+    if url.host_str().is_none() {
+        bail!("STS endpoint {} has no host", url);
+    }
+    Ok(StsEndpoint {
+        url,
+        region: region.unwrap_or_else(|_| "us-east-1".to_owned()),
+    })
+}
+
+fn form_encode(params: &[(&str, &str)]) -> String {
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, uri_encode(value, true)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Send `request`, returning the body of the response if it succeeds.
+fn fetch_body(request: RequestBuilder) -> SFuture<String> {
+    Box::new(
+        request
+            .send()
+            .map_err(Error::from)
+            .and_then(|res| {
+                let status = res.status();
+                res.into_body()
+                    .map_err(Error::from)
+                    .fold(Vec::new(), |mut body, chunk| -> Result<_> {
+                        body.extend_from_slice(&chunk);
+                        Ok(body)
+                    })
+                    .map(move |body| (status, body))
+            })
+            .and_then(|(status, body)| -> Result<String> {
+                let body = String::from_utf8(body).chain_err(|| "failed to read http body")?;
+                if !status.is_success() {
+                    debug!("Credentials request failed: {}", body);
+                    bail!(ErrorKind::BadHTTPStatus(status));
+                }
+                Ok(body)
+            }),
+    )
+}
+
+/// Parse the credentials in the response to an STS `AssumeRole` or
+/// `AssumeRoleWithWebIdentity` request.
+fn parse_sts_credentials(body: &str) -> Result<AwsCredentials> {
+    let field = |name: &str| -> Result<String> {
+        xml_element(body.as_bytes(), name)
+            .ok_or_else(|| format!("Couldn't find {} in response.", name).into())
+    };
+    let expiration_time = field("Expiration")?
+        .parse()
+        .chain_err(|| "failed to parse expiration time")?;
+    Ok(AwsCredentials::new(
+        field("AccessKeyId")?,
+        field("SecretAccessKey")?,
+        Some(field("SessionToken")?),
+        expiration_time,
+    ))
+}
+
+/// How long to wait before trying again to get credentials after failing to.
+const RETRY_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
 /// Wrapper for ProvideAwsCredentials that caches the credentials returned by the
 /// wrapped provider.  Each time the credentials are accessed, they are checked to see if
 /// they have expired, in which case they are retrieved from the wrapped provider again.
+/// Failures to get credentials are retried at most once every `RETRY_INTERVAL`.
 pub struct AutoRefreshingProvider<P> {
     credentials_provider: P,
     cached_credentials: RefCell<Shared<SFuture<AwsCredentials>>>,
+    /// When the cached credentials were requested.
+    requested_at: Cell<Instant>,
 }
 
 impl<P: ProvideAwsCredentials> AutoRefreshingProvider<P> {
@@ -463,6 +863,7 @@ impl<P: ProvideAwsCredentials> AutoRefreshingProvider<P> {
         AutoRefreshingProvider {
             cached_credentials: RefCell::new(provider.credentials().shared()),
             credentials_provider: provider,
+            requested_at: Cell::new(Instant::now()),
         }
     }
 }
@@ -470,10 +871,16 @@ impl<P: ProvideAwsCredentials> AutoRefreshingProvider<P> {
 impl<P: ProvideAwsCredentials> ProvideAwsCredentials for AutoRefreshingProvider<P> {
     fn credentials(&self) -> SFuture<AwsCredentials> {
         let mut future = self.cached_credentials.borrow_mut();
-        if let Ok(Async::Ready(creds)) = future.poll() {
-            if creds.credentials_are_expired() {
-                *future = self.credentials_provider.credentials().shared();
-            }
+        let refresh = match future.poll() {
+            Ok(Async::Ready(creds)) => creds.credentials_are_expired(),
+            Ok(Async::NotReady) => false,
+            // Retrying straight away would slow every request down when
+            // there are no credentials to be had.
+            Err(_) => self.requested_at.get().elapsed() >= RETRY_INTERVAL,
+        };
+        if refresh {
+            *future = self.credentials_provider.credentials().shared();
+            self.requested_at.set(Instant::now());
         }
         Box::new(future.clone().then(|result| match result {
             Ok(e) => Ok((*e).clone()),
@@ -487,8 +894,10 @@ impl<P: ProvideAwsCredentials> ProvideAwsCredentials for AutoRefreshingProvider<
 /// The following sources are checked in order for credentials when calling `credentials`:
 ///
 /// 1. Environment variables: `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
-/// 2. AWS credentials file. Usually located at `~/.aws/credentials`.
-/// 3. IAM instance profile. Will only work if running on an EC2 instance with an instance profile/role.
+/// 2. A web identity token: `AWS_WEB_IDENTITY_TOKEN_FILE` and `AWS_ROLE_ARN`
+/// 3. AWS credentials file. Usually located at `~/.aws/credentials`.
+/// 4. ECS container credentials. Will only work if running in an ECS task with a role.
+/// 5. IAM instance profile. Will only work if running on an EC2 instance with an instance profile/role.
 ///
 /// If the sources are exhausted without finding credentials, an error is returned.
 #[derive(Clone)]
@@ -502,13 +911,24 @@ impl ProvideAwsCredentials for ChainProvider {
             debug!("Using AWS credentials from environment");
             c
         });
-        let mut creds = Box::new(creds) as SFuture<_>;
-        for provider in self.profile_providers.iter() {
-            let alternate = provider.credentials();
-            creds = Box::new(creds.or_else(|_| alternate));
+        let mut creds = Box::new(creds.or_else(|_| {
+            WebIdentityProvider.credentials().map(|c| {
+                debug!("Using AWS credentials from web identity");
+                c
+            })
+        })) as SFuture<_>;
+        for provider in self.profile_providers.iter().cloned() {
+            // Profiles may assume roles, so only look at them if needed.
+            creds = Box::new(creds.or_else(move |_| provider.credentials()));
         }
         Box::new(
             creds
+                .or_else(|_| {
+                    ContainerProvider.credentials().map(|c| {
+                        debug!("Using AWS credentials from ECS container");
+                        c
+                    })
+                })
                 .or_else(move |_| {
                     IamProvider::new().credentials().map(|c| {
                         debug!("Using AWS credentials from IAM");
@@ -516,7 +936,8 @@ impl ProvideAwsCredentials for ChainProvider {
                     })
                 })
                 .map_err(|_| {
-                    "Couldn't find AWS credentials in environment, credentials file, or IAM role."
+                    "Couldn't find AWS credentials in environment, web identity token, \
+                     credentials file, ECS container, or IAM role."
                         .into()
                 }),
         )
@@ -542,3 +963,292 @@ impl ChainProvider {
 fn in_ten_minutes() -> DateTime<offset::Utc> {
     offset::Utc::now() + Duration::seconds(600)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use tempdir::TempDir;
+    use tokio::runtime::current_thread::Runtime;
+
+    const STS_RESPONSE: &str = "<AssumeRoleResponse>
+  <AssumeRoleResult>
+    <Credentials>
+      <SessionToken>ROLE_TOKEN</SessionToken>
+      <SecretAccessKey>ROLE_SECRET</SecretAccessKey>
+      <Expiration>2019-11-09T13:34:41Z</Expiration>
+      <AccessKeyId>ASIA</AccessKeyId>
+    </Credentials>
+  </AssumeRoleResult>
+</AssumeRoleResponse>";
+
+    /// A request received by a stand-in credentials endpoint.
+    struct Received {
+        path: String,
+        headers: HashMap<String, String>,
+        body: String,
+    }
+
+    /// Start a stand-in credentials endpoint that answers every request with
+    /// `response`, returning its URL and the requests it receives.
+    fn start_server(response: &'static str) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(vec![]));
+        let server_received = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let path = request_line.split_whitespace().nth(1).unwrap().to_owned();
+                let mut headers = HashMap::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let mut header = line.splitn(2, ':');
+                    let name = header.next().unwrap().to_ascii_lowercase();
+                    let value = header.next().unwrap_or("").trim().to_owned();
+                    headers.insert(name, value);
+                }
+                let content_length = headers
+                    .get("content-length")
+                    .map_or(0, |length| length.parse().unwrap());
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                server_received.lock().unwrap().push(Received {
+                    path,
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                });
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn assert_role_credentials(creds: &AwsCredentials) {
+        assert_eq!(creds.aws_access_key_id(), "ASIA");
+        assert_eq!(creds.aws_secret_access_key(), "ROLE_SECRET");
+        assert_eq!(
+            creds.token().as_ref().map(|s| s.as_str()),
+            Some("ROLE_TOKEN")
+        );
+        assert_eq!(
+            *creds.expires_at(),
+            offset::Utc.ymd(2019, 11, 9).and_hms(13, 34, 41)
+        );
+    }
+
+    fn profiles(contents: &str) -> HashMap<String, Profile> {
+        parse_profiles(Cursor::new(contents)).unwrap()
+    }
+
+    #[test]
+    fn test_parse_profiles() {
+        let profiles = profiles(
+            "# A comment
+[default]
+aws_access_key_id = AKID
+aws_secret_access_key=SECRET
+
+[profile dev]
+; another comment
+role_arn = arn:aws:iam::123456789012:role/dev
+source_profile = default
+",
+        );
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles["default"]["aws_access_key_id"], "AKID");
+        assert_eq!(profiles["default"]["aws_secret_access_key"], "SECRET");
+        assert_eq!(
+            profiles["dev"]["role_arn"],
+            "arn:aws:iam::123456789012:role/dev"
+        );
+        assert_eq!(profiles["dev"]["source_profile"], "default");
+    }
+
+    #[test]
+    fn test_profile_credentials() {
+        let profiles = profiles(
+            "[default]
+aws_access_key_id = AKID
+aws_secret_access_key = SECRET
+aws_session_token = TOKEN
+[no-source]
+role_arn = arn:aws:iam::123456789012:role/a
+[loop-a]
+role_arn = arn:aws:iam::123456789012:role/a
+source_profile = loop-b
+[loop-b]
+role_arn = arn:aws:iam::123456789012:role/b
+source_profile = loop-a
+",
+        );
+        let creds = profile_credentials(&profiles, "default", 0).wait().unwrap();
+        assert_eq!(creds.aws_access_key_id(), "AKID");
+        assert_eq!(creds.aws_secret_access_key(), "SECRET");
+        assert_eq!(creds.token().as_ref().map(|s| s.as_str()), Some("TOKEN"));
+
+        assert!(profile_credentials(&profiles, "missing", 0).wait().is_err());
+        assert!(profile_credentials(&profiles, "no-source", 0)
+            .wait()
+            .is_err());
+        // Roles that refer to each other are rejected before any are assumed.
+        assert!(profile_credentials(&profiles, "loop-a", 0).wait().is_err());
+    }
+
+    #[test]
+    fn test_parse_sts_credentials() {
+        let creds = parse_sts_credentials(
+            "<AssumeRoleWithWebIdentityResponse>
+  <AssumeRoleWithWebIdentityResult>
+    <Credentials>
+      <SessionToken>TOKEN</SessionToken>
+      <SecretAccessKey>SECRET</SecretAccessKey>
+      <Expiration>2019-11-09T13:34:41Z</Expiration>
+      <AccessKeyId>ASIA</AccessKeyId>
+    </Credentials>
+  </AssumeRoleWithWebIdentityResult>
+</AssumeRoleWithWebIdentityResponse>",
+        )
+        .unwrap();
+        assert_eq!(creds.aws_access_key_id(), "ASIA");
+        assert_eq!(creds.aws_secret_access_key(), "SECRET");
+        assert_eq!(creds.token().as_ref().map(|s| s.as_str()), Some("TOKEN"));
+        assert_eq!(
+            *creds.expires_at(),
+            offset::Utc.ymd(2019, 11, 9).and_hms(13, 34, 41)
+        );
+        assert!(parse_sts_credentials("<Error><Code>AccessDenied</Code></Error>").is_err());
+    }
+
+    // Both providers use the STS endpoint in the environment, so they're
+    // tested together.
+    #[test]
+    fn test_sts_providers() {
+        let (url, received) = start_server(STS_RESPONSE);
+        let mut runtime = Runtime::new().unwrap();
+        set_var("AWS_ENDPOINT_URL_STS", &url);
+
+        // Assuming a role with the credentials of another profile.
+        let profiles = profiles(
+            "[default]
+aws_access_key_id = AKID
+aws_secret_access_key = SECRET
+aws_session_token = TOKEN
+[dev]
+role_arn = arn:aws:iam::123456789012:role/dev
+source_profile = default
+external_id = ext
+",
+        );
+        let creds = runtime
+            .block_on(future::lazy(|| profile_credentials(&profiles, "dev", 0)))
+            .unwrap();
+        assert_role_credentials(&creds);
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            let request = &received[0];
+            assert_eq!(request.path, "/");
+            assert_eq!(
+                request.body,
+                "Action=AssumeRole&ExternalId=ext\
+                 &RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Fdev\
+                 &RoleSessionName=sccache&Version=2011-06-15"
+            );
+            assert_eq!(request.headers["x-amz-security-token"], "TOKEN");
+            // The request is signed with the source profile's credentials.
+            let signed_headers: Vec<_> =
+                ["content-type", "host", "x-amz-date", "x-amz-security-token"]
+                    .iter()
+                    .map(|name| (*name, request.headers[*name].as_str()))
+                    .collect();
+            let date = offset::Utc
+                .datetime_from_str(&request.headers["x-amz-date"], "%Y%m%dT%H%M%SZ")
+                .unwrap();
+            let expected = sigv4::authorization(
+                "POST",
+                "/",
+                "",
+                &signed_headers,
+                &sha256_hex(request.body.as_bytes()),
+                &AwsCredentials::new("AKID", "SECRET", Some("TOKEN".to_owned()), date),
+                &sigv4::Scope {
+                    region: &sts_endpoint().unwrap().region,
+                    service: "sts",
+                    date: &date,
+                },
+            );
+            assert_eq!(request.headers["authorization"], expected);
+        }
+
+        // Assuming a role with a web identity token.
+        let tempdir = TempDir::new("sccache_test_credential").unwrap();
+        let token_file = tempdir.path().join("token");
+        fs::write(&token_file, "web-token\n").unwrap();
+        set_var("AWS_WEB_IDENTITY_TOKEN_FILE", &token_file);
+        set_var("AWS_ROLE_ARN", "arn:aws:iam::123456789012:role/web");
+        set_var("AWS_ROLE_SESSION_NAME", "build");
+        let creds = runtime
+            .block_on(future::lazy(|| WebIdentityProvider.credentials()))
+            .unwrap();
+        remove_var("AWS_WEB_IDENTITY_TOKEN_FILE");
+        remove_var("AWS_ROLE_ARN");
+        remove_var("AWS_ROLE_SESSION_NAME");
+        remove_var("AWS_ENDPOINT_URL_STS");
+        assert_role_credentials(&creds);
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        let request = &received[1];
+        assert_eq!(
+            request.body,
+            "Action=AssumeRoleWithWebIdentity\
+             &RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Fweb\
+             &RoleSessionName=build&Version=2011-06-15&WebIdentityToken=web-token"
+        );
+        // The token is all the request needs.
+        assert!(!request.headers.contains_key("authorization"));
+    }
+
+    #[test]
+    fn test_container_provider() {
+        let (url, received) = start_server(
+            r#"{"AccessKeyId": "ASIA", "SecretAccessKey": "ROLE_SECRET",
+                "Token": "ROLE_TOKEN", "Expiration": "2019-11-09T13:34:41Z"}"#,
+        );
+        let mut runtime = Runtime::new().unwrap();
+        remove_var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI");
+        set_var(
+            "AWS_CONTAINER_CREDENTIALS_FULL_URI",
+            format!("{}creds", url),
+        );
+        set_var("AWS_CONTAINER_AUTHORIZATION_TOKEN", "auth-token");
+
+        let creds = runtime
+            .block_on(future::lazy(|| ContainerProvider.credentials()))
+            .unwrap();
+        remove_var("AWS_CONTAINER_CREDENTIALS_FULL_URI");
+        remove_var("AWS_CONTAINER_AUTHORIZATION_TOKEN");
+        assert_role_credentials(&creds);
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].path, "/creds");
+        assert_eq!(received[0].headers["authorization"], "auth-token");
+    }
+}
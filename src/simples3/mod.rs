@@ -14,6 +14,7 @@
 
 mod credential;
 mod s3;
+mod sigv4;
 
 pub use crate::simples3::credential::*;
 pub use crate::simples3::s3::*;
@@ -1,22 +1,46 @@
 // Originally from https://github.com/rust-lang/crates.io/blob/master/src/s3/lib.rs
 //#![deny(warnings)]
 
-#[allow(unused_imports, deprecated)]
-use std::ascii::AsciiExt;
+use std::cmp;
 use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::cache::{reader_len, ReadSeek, ReaderStream};
 use crate::simples3::credential::*;
-use futures::{Future, Stream};
-use hmac::{Hmac, Mac};
+use crate::simples3::sigv4::{self, amz_date, uri_encode};
+use bytes::Bytes;
+use chrono::{offset, DateTime, FixedOffset};
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
+use futures_cpupool::CpuPool;
 use hyper::header::HeaderValue;
-use hyper::Method;
+use hyper::{Body, Method, StatusCode};
 use hyperx::header;
-use reqwest::r#async::{Client, Request};
-use sha1::Sha1;
+use reqwest::r#async::{Client, Request, Response};
+use reqwest::Certificate;
 
 use crate::errors::*;
 use crate::util::HeadersExt;
 
+/// Objects larger than this are uploaded in parts.
+const MULTIPART_THRESHOLD: u64 = 64 * 1024 * 1024;
+/// The size of the parts of a multipart upload. S3 requires every part but
+/// the last to be at least 5MB.
+const PART_SIZE: u64 = 16 * 1024 * 1024;
+/// How many times to try uploading each part before giving up.
+const PART_ATTEMPTS: u32 = 3;
+/// The size of the ranges objects are downloaded in.
+const RANGE_SIZE: u64 = 16 * 1024 * 1024;
+/// How many parts or ranges to transfer at once.
+const MAX_CONCURRENT_TRANSFERS: usize = 4;
+/// The `x-amz-content-sha256` of requests whose bodies aren't signed, which
+/// saves hashing entries before uploading them.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
 /// Whether or not to use SSL.
@@ -38,22 +62,223 @@ fn base_url(endpoint: &str, ssl: Ssl) -> String {
     )
 }
 
-fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
-    let mut hmac = Hmac::<Sha1>::new_varkey(key).expect("HMAC can take key of any size");
-    hmac.input(data);
-    hmac.result().code().iter().map(|b| *b).collect::<Vec<u8>>()
+/// Split a PEM file into the certificates in it.
+fn split_pem(pem: &str) -> Vec<String> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    pem.split(END)
+        .filter_map(|block| block.find(BEGIN).map(|start| &block[start..]))
+        .map(|cert| format!("{}{}\n", cert, END))
+        .collect()
+}
+
+/// Read the CA certificates in the PEM file at `path`.
+fn read_ca_bundle(path: &Path) -> Result<Vec<Certificate>> {
+    let pem =
+        fs::read_to_string(path).chain_err(|| format!("failed to read CA bundle {:?}", path))?;
+    let certs = split_pem(&pem);
+    if certs.is_empty() {
+        bail!("No certificates found in CA bundle {:?}", path);
+    }
+    certs
+        .iter()
+        .map(|cert| {
+            Certificate::from_pem(cert.as_bytes())
+                .chain_err(|| format!("bad certificate in CA bundle {:?}", path))
+        })
+        .collect()
+}
+
+/// Return the text of the first `name` element in the XML document `body`.
+pub(crate) fn xml_element(body: &[u8], name: &str) -> Option<String> {
+    let body = String::from_utf8_lossy(body);
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = body.find(&open)? + open.len();
+    let end = start + body[start..].find(&close)?;
+    Some(body[start..end].to_owned())
+}
+
+/// Undo the escaping of the predefined entities in XML text.
+fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Convert a timestamp from a response into a `SystemTime`.
+fn system_time(time: DateTime<FixedOffset>) -> Option<SystemTime> {
+    if time.timestamp() < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(time.timestamp() as u64))
+}
+
+/// Parse a page of a `ListObjectsV2` response, returning the objects listed
+/// and the token to continue listing with, if the listing is truncated.
+fn parse_list_objects(body: &[u8]) -> Result<(Vec<ObjectInfo>, Option<String>)> {
+    let text = String::from_utf8_lossy(body);
+    if !text.contains("<ListBucketResult") {
+        bail!("Invalid ListObjectsV2 response");
+    }
+    let mut objects = vec![];
+    for contents in text.split("<Contents>").skip(1) {
+        let contents = contents.as_bytes();
+        let key = xml_element(contents, "Key").ok_or("Listed object has no key")?;
+        let size = xml_element(contents, "Size")
+            .and_then(|size| size.parse().ok())
+            .ok_or("Listed object has no valid size")?;
+        let last_modified = xml_element(contents, "LastModified")
+            .and_then(|time| DateTime::parse_from_rfc3339(&time).ok())
+            .and_then(system_time);
+        objects.push(ObjectInfo {
+            key: xml_unescape(&key),
+            size,
+            last_modified,
+        });
+    }
+    let truncated = xml_element(body, "IsTruncated").map_or(false, |t| t == "true");
+    let token = if truncated {
+        let token = xml_element(body, "NextContinuationToken")
+            .ok_or("Truncated listing has no continuation token")?;
+        Some(xml_unescape(&token))
+    } else {
+        None
+    };
+    Ok((objects, token))
+}
+
+/// Return the start of the range and the total size of the object from a
+/// `Content-Range` header value such as `bytes 0-1023/4096`.
+fn content_range(value: &str) -> Option<(u64, u64)> {
+    let value = value.trim();
+    if !value.starts_with("bytes ") {
+        return None;
+    }
+    let mut parts = value["bytes ".len()..].splitn(2, '/');
+    let start = parts.next()?.splitn(2, '-').next()?.trim().parse().ok()?;
+    let total = parts.next()?.trim().parse().ok()?;
+    Some((start, total))
+}
+
+/// The version of an object that a ranged `GET` read from.
+#[derive(Clone)]
+struct ObjectVersion {
+    /// The size of the whole object.
+    total: u64,
+    etag: Option<String>,
+}
+
+fn check_status(res: Response) -> Result<Response> {
+    if res.status().is_success() {
+        Ok(res)
+    } else {
+        Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+    }
+}
+
+fn read_body(res: Response) -> SFuture<Vec<u8>> {
+    Box::new(
+        res.into_body()
+            .map_err(Error::from)
+            .fold(Vec::new(), |mut body, chunk| -> Result<_> {
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            })
+            .chain_err(|| "failed to read HTTP body"),
+    )
+}
+
+/// Read the next part of at most `part_size` bytes from `content`.
+fn read_part(content: &mut dyn ReadSeek, part_size: u64) -> Result<Vec<u8>> {
+    let mut part = vec![];
+    content.take(part_size).read_to_end(&mut part)?;
+    Ok(part)
+}
+
+/// Settings applied to every object uploaded to a bucket.
+#[derive(Clone, Debug, Default)]
+pub struct PutOptions {
+    /// The server-side encryption mode, `AES256` or `aws:kms`.
+    pub server_side_encryption: Option<String>,
+    /// The KMS key to encrypt objects with, when using `aws:kms`.
+    pub sse_kms_key_id: Option<String>,
+    /// The storage class of objects, such as `STANDARD_IA`.
+    pub storage_class: Option<String>,
+    /// The tags to add to objects.
+    pub tags: Vec<(String, String)>,
+}
+
+impl PutOptions {
+    /// Return the headers of a request creating an object with these settings.
+    fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("content-type", "application/octet-stream".to_owned())];
+        if let Some(ref mode) = self.server_side_encryption {
+            headers.push(("x-amz-server-side-encryption", mode.clone()));
+        }
+        if let Some(ref key_id) = self.sse_kms_key_id {
+            headers.push((
+                "x-amz-server-side-encryption-aws-kms-key-id",
+                key_id.clone(),
+            ));
+        }
+        if let Some(ref storage_class) = self.storage_class {
+            headers.push(("x-amz-storage-class", storage_class.clone()));
+        }
+        if !self.tags.is_empty() {
+            let tags = self
+                .tags
+                .iter()
+                .map(|(key, value)| {
+                    format!("{}={}", uri_encode(key, true), uri_encode(value, true))
+                })
+                .collect::<Vec<_>>()
+                .join("&");
+            headers.push(("x-amz-tagging", tags));
+        }
+        headers
+    }
+
+    /// Check that these settings can be sent as header values, so that bad
+    /// ones are reported when the cache is set up instead of on every write.
+    pub fn validate(&self) -> Result<()> {
+        for (name, value) in self.headers() {
+            HeaderValue::from_str(&value)
+                .chain_err(|| format!("Invalid `{}` header value: {:?}", name, value))?;
+        }
+        Ok(())
+    }
 }
 
-fn signature(string_to_sign: &str, signing_key: &str) -> String {
-    let s = hmac(signing_key.as_bytes(), string_to_sign.as_bytes());
-    base64::encode_config::<Vec<u8>>(&s, base64::STANDARD)
+/// An object in a bucket, as listed by `Bucket::list` or looked up by
+/// `Bucket::head`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: Option<SystemTime>,
 }
 
 /// An S3 bucket.
+#[derive(Clone)]
 pub struct Bucket {
     name: String,
     base_url: String,
+    /// The value of the `Host` header of requests.
+    host: String,
+    /// The path of `base_url`, which keys are appended to.
+    base_path: String,
+    /// The region requests are signed for.
+    region: String,
     client: Client,
+    /// Objects larger than this are uploaded in parts.
+    multipart_threshold: u64,
+    /// The size of the parts of a multipart upload.
+    part_size: u64,
+    /// The size of the ranges objects are downloaded in.
+    range_size: u64,
 }
 
 impl fmt::Display for Bucket {
@@ -63,136 +288,220 @@ impl fmt::Display for Bucket {
 }
 
 impl Bucket {
-    pub fn new(name: &str, endpoint: &str, ssl: Ssl) -> Result<Bucket> {
+    /// Create a new `Bucket` named `name`, reached at `endpoint`, which must
+    /// include the bucket in either its host name or its path. Requests are
+    /// signed for `region`, and server certificates may also be signed by
+    /// the CAs in the `ca_bundle` PEM file.
+    pub fn new(
+        name: &str,
+        endpoint: &str,
+        ssl: Ssl,
+        region: &str,
+        ca_bundle: Option<&Path>,
+    ) -> Result<Bucket> {
         let base_url = base_url(&endpoint, ssl);
+        let url = base_url
+            .parse::<reqwest::Url>()
+            .chain_err(|| format!("Invalid S3 endpoint: {}", endpoint))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => bail!("Invalid S3 endpoint: {}", endpoint),
+        };
+        let mut client = Client::builder();
+        if let Some(ca_bundle) = ca_bundle {
+            for cert in read_ca_bundle(ca_bundle)? {
+                client = client.add_root_certificate(cert);
+            }
+        }
         Ok(Bucket {
             name: name.to_owned(),
+            base_path: url.path().to_owned(),
             base_url: base_url,
-            client: Client::new(),
+            host,
+            region: region.to_owned(),
+            client: client.build()?,
+            multipart_threshold: MULTIPART_THRESHOLD,
+            part_size: PART_SIZE,
+            range_size: RANGE_SIZE,
         })
     }
 
-    pub fn get(&self, key: &str, creds: Option<&AwsCredentials>) -> SFuture<Vec<u8>> {
-        let url = format!("{}{}", self.base_url, key);
-        debug!("GET {}", url);
-        let url2 = url.clone();
-        let mut request = Request::new(Method::GET, url.parse().unwrap());
-        match creds {
-            Some(creds) => {
-                let mut canonical_headers = String::new();
-
-                if let Some(token) = creds.token().as_ref().map(|s| s.as_str()) {
-                    request.headers_mut().insert(
-                        "x-amz-security-token",
-                        HeaderValue::from_str(token)
-                            .expect("Invalid `x-amz-security-token` header"),
-                    );
-                    canonical_headers
-                        .push_str(format!("{}:{}\n", "x-amz-security-token", token).as_ref());
-                }
-                let date = time::now_utc().rfc822().to_string();
-                let auth = self.auth("GET", &date, key, "", &canonical_headers, "", creds);
-                request.headers_mut().insert(
-                    "Date",
-                    HeaderValue::from_str(&date).expect("Invalid date header"),
-                );
-                request.headers_mut().insert(
-                    "Authorization",
-                    HeaderValue::from_str(&auth).expect("Invalid authentication"),
-                );
-            }
-            // request is fine as-is
-            None => {}
-        }
+    /// Download the object at `key` to a temporary file, returning the file
+    /// positioned at its start. The file is written on `pool`.
+    ///
+    /// Objects larger than a single range are downloaded with several ranged
+    /// `GET`s, some of them in parallel. Every range after the first must come
+    /// from the same version of the object as the first one.
+    pub fn get(&self, key: &str, creds: Option<&AwsCredentials>, pool: &CpuPool) -> SFuture<File> {
+        let bucket = self.clone();
+        let key = key.to_owned();
+        let creds = creds.cloned();
+        let pool = pool.clone();
+        let range_size = self.range_size;
+        // The first range also tells us how large the object is.
+        let first = self.get_range(&key, 0, range_size, None, creds.as_ref());
+        Box::new(first.and_then(move |(data, version)| {
+            let url = format!("{}{}", bucket.base_url, key);
+            let total = version.total;
+            // This covers servers that ignore the range and return the whole
+            // object.
+            let whole = data.len() as u64 == total;
+            let file = pool.spawn_fn(move || -> Result<_> {
+                let mut file =
+                    tempfile::tempfile().chain_err(|| "failed to create temporary file")?;
+                file.write_all(&data)?;
+                Ok(file)
+            });
+            let file: SFuture<File> = if whole {
+                Box::new(file)
+            } else {
+                let starts = (1..)
+                    .map(move |i| i * range_size)
+                    .take_while(move |start| *start < total);
+                let ranges = stream::iter_ok(starts)
+                    .map(move |start| {
+                        let len = cmp::min(range_size, total - start);
+                        bucket
+                            .get_range(&key, start, len, Some(&version), creds.as_ref())
+                            .map(|(data, _)| data)
+                    })
+                    .buffered(MAX_CONCURRENT_TRANSFERS);
+                Box::new(file.and_then(move |file| {
+                    ranges.fold(file, move |mut file, data| {
+                        pool.spawn_fn(move || -> Result<_> {
+                            file.write_all(&data)?;
+                            Ok(file)
+                        })
+                    })
+                }))
+            };
+            file.and_then(move |mut file| {
+                info!("Read {} bytes from {}", total, url);
+                file.seek(SeekFrom::Start(0))?;
+                Ok(file)
+            })
+        }))
+    }
 
+    /// Download `len` bytes of the object at `key` starting at `start`,
+    /// returning them along with the version of the object they came from.
+    ///
+    /// `first` is the version the first range came from, which later ranges
+    /// must match.
+    fn get_range(
+        &self,
+        key: &str,
+        start: u64,
+        len: u64,
+        first: Option<&ObjectVersion>,
+        creds: Option<&AwsCredentials>,
+    ) -> SFuture<(Vec<u8>, ObjectVersion)> {
+        let mut request = self.request(Method::GET, key, &[], &[], creds);
+        request.headers_mut().insert(
+            "Range",
+            HeaderValue::from_str(&format!("bytes={}-{}", start, start + len - 1))
+                .expect("Invalid range header"),
+        );
+        // Reject the range if the object was replaced since the first one.
+        if let Some(etag) = first.and_then(|first| first.etag.as_ref()) {
+            request.headers_mut().insert(
+                "If-Match",
+                ftry!(HeaderValue::from_str(etag).chain_err(|| "Invalid ETag")),
+            );
+        }
+        let first = first.cloned();
+        let url = request.url().to_string();
+        debug!("GET {} (bytes {}-{})", url, start, start + len - 1);
         Box::new(
             self.client
                 .execute(request)
                 .chain_err(move || format!("failed GET: {}", url))
-                .and_then(|res| {
-                    if res.status().is_success() {
-                        let content_length = res
-                            .headers()
-                            .get_hyperx::<header::ContentLength>()
-                            .map(|header::ContentLength(len)| len);
-                        Ok((res.into_body(), content_length))
-                    } else {
-                        Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
-                    }
-                })
-                .and_then(|(body, content_length)| {
-                    body.fold(Vec::new(), |mut body, chunk| {
-                        body.extend_from_slice(&chunk);
-                        Ok::<_, reqwest::Error>(body)
-                    })
-                    .chain_err(|| "failed to read HTTP body")
-                    .and_then(move |bytes| {
-                        if let Some(len) = content_length {
-                            if len != bytes.len() as u64 {
-                                bail!(format!(
-                                    "Bad HTTP body size read: {}, expected {}",
-                                    bytes.len(),
-                                    len
+                .and_then(move |res| -> SFuture<(Vec<u8>, ObjectVersion)> {
+                    let etag = res
+                        .headers()
+                        .get("ETag")
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_owned);
+                    let total = match (res.status(), first) {
+                        (StatusCode::PARTIAL_CONTENT, first) => {
+                            let range = res
+                                .headers()
+                                .get("Content-Range")
+                                .and_then(|value| value.to_str().ok())
+                                .and_then(content_range);
+                            let (range_start, total) =
+                                ftry!(range.ok_or("Bad Content-Range in ranged GET response"));
+                            if range_start != start
+                                || first.map_or(false, |first| first.total != total)
+                            {
+                                return f_err(format!(
+                                    "Unexpected Content-Range in ranged GET response: \
+                                     starts at {} of {} bytes",
+                                    range_start, total
                                 ));
-                            } else {
-                                info!("Read {} bytes from {}", bytes.len(), url2);
                             }
+                            total
                         }
-                        Ok(bytes)
-                    })
+                        // Servers may ignore the range and return the whole
+                        // object instead, which is only of use for the first
+                        // range.
+                        (StatusCode::OK, None) => {
+                            return Box::new(read_body(res).map(|data| {
+                                let total = data.len() as u64;
+                                (data, ObjectVersion { total, etag })
+                            }))
+                        }
+                        // An empty object has no satisfiable ranges.
+                        (StatusCode::RANGE_NOT_SATISFIABLE, None) if start == 0 => {
+                            return f_ok((vec![], ObjectVersion { total: 0, etag }))
+                        }
+                        (status, _) => return f_err(ErrorKind::BadHTTPStatus(status)),
+                    };
+                    let expected = cmp::min(len, total.saturating_sub(start));
+                    Box::new(read_body(res).and_then(move |data| {
+                        if data.len() as u64 != expected {
+                            bail!(format!(
+                                "Bad HTTP body size read: {}, expected {}",
+                                data.len(),
+                                expected
+                            ));
+                        }
+                        Ok((data, ObjectVersion { total, etag }))
+                    }))
                 }),
         )
     }
 
-    pub fn put(&self, key: &str, content: Vec<u8>, creds: &AwsCredentials) -> SFuture<()> {
-        let url = format!("{}{}", self.base_url, key);
-        debug!("PUT {}", url);
-        let mut request = Request::new(Method::PUT, url.parse().unwrap());
-
-        let content_type = "application/octet-stream";
-        let date = time::now_utc().rfc822().to_string();
-        let mut canonical_headers = String::new();
-        let token = creds.token().as_ref().map(|s| s.as_str());
-        // Keep the list of header values sorted!
-        for (header, maybe_value) in vec![("x-amz-security-token", token)] {
-            if let Some(ref value) = maybe_value {
-                request.headers_mut().insert(
-                    header,
-                    HeaderValue::from_str(value)
-                        .unwrap_or_else(|_| panic!("Invalid `{}` header", header)),
-                );
-                canonical_headers
-                    .push_str(format!("{}:{}\n", header.to_ascii_lowercase(), value).as_ref());
-            }
+    /// Upload the contents of `content` to `key`, streaming it from the reader,
+    /// with the settings in `options`. The reader is read on `pool`.
+    ///
+    /// Objects larger than the multipart threshold are uploaded in parts, some
+    /// of them in parallel, with each part retried on failure.
+    pub fn put(
+        &self,
+        key: &str,
+        mut content: Box<dyn ReadSeek>,
+        options: &PutOptions,
+        creds: &AwsCredentials,
+        pool: &CpuPool,
+    ) -> SFuture<()> {
+        let content_length = ftry!(reader_len(&mut content));
+        if content_length > self.multipart_threshold {
+            return self.put_multipart(key, content, content_length, options, creds, pool);
         }
-        let auth = self.auth(
-            "PUT",
-            &date,
-            key,
-            "",
-            &canonical_headers,
-            content_type,
-            creds,
-        );
-        request.headers_mut().insert(
-            "Date",
-            HeaderValue::from_str(&date).expect("Invalid date header"),
-        );
+        let headers = options.headers();
+        let headers: Vec<_> = headers.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        let mut request = self.request(Method::PUT, key, &[], &headers, Some(creds));
+        debug!("PUT {}", request.url());
         request
             .headers_mut()
-            .set(header::ContentType(content_type.parse().unwrap()));
-        request
-            .headers_mut()
-            .set(header::ContentLength(content.len() as u64));
+            .set(header::ContentLength(content_length));
         request.headers_mut().set(header::CacheControl(vec![
             // Two weeks
             header::CacheDirective::MaxAge(1296000),
         ]));
-        request.headers_mut().insert(
-            "Authorization",
-            HeaderValue::from_str(&auth).expect("Invalid authentication"),
-        );
-        *request.body_mut() = Some(content.into());
+        *request.body_mut() = Some(Body::wrap_stream(ReaderStream::new(content, pool)).into());
 
         Box::new(self.client.execute(request).then(|result| match result {
             Ok(res) => {
@@ -211,45 +520,1037 @@ impl Bucket {
         }))
     }
 
-    // http://docs.aws.amazon.com/AmazonS3/latest/dev/RESTAuthentication.html
-    fn auth(
+    /// Upload `content` to `key` with a multipart upload, aborting the upload
+    /// if any part fails.
+    fn put_multipart(
         &self,
-        verb: &str,
-        date: &str,
-        path: &str,
-        md5: &str,
-        headers: &str,
-        content_type: &str,
+        key: &str,
+        content: Box<dyn ReadSeek>,
+        content_length: u64,
+        options: &PutOptions,
+        creds: &AwsCredentials,
+        pool: &CpuPool,
+    ) -> SFuture<()> {
+        // The settings of the object are given when creating the upload.
+        let headers = options.headers();
+        let headers: Vec<_> = headers.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        let query = [("uploads", "")];
+        let mut request = self.request(Method::POST, key, &query, &headers, Some(creds));
+        request.headers_mut().set(header::CacheControl(vec![
+            // Two weeks
+            header::CacheDirective::MaxAge(1296000),
+        ]));
+        debug!("POST {}", request.url());
+        let bucket = self.clone();
+        let key = key.to_owned();
+        let creds = creds.clone();
+        let pool = pool.clone();
+        Box::new(
+            self.client
+                .execute(request)
+                .map_err(Error::from)
+                .and_then(check_status)
+                .and_then(read_body)
+                .chain_err(|| "failed to create multipart upload")
+                .and_then(move |body| -> SFuture<()> {
+                    let upload_id = ftry!(xml_element(&body, "UploadId")
+                        .ok_or("No UploadId in CreateMultipartUpload response"));
+                    let (bucket2, key2, upload_id2, creds2) = (
+                        bucket.clone(),
+                        key.clone(),
+                        upload_id.clone(),
+                        creds.clone(),
+                    );
+                    Box::new(
+                        bucket
+                            .upload_parts(&key, &upload_id, content, content_length, &creds, &pool)
+                            .and_then(move |etags| {
+                                bucket.complete_multipart(&key, &upload_id, &etags, &creds)
+                            })
+                            .or_else(move |e| {
+                                bucket2
+                                    .abort_multipart(&key2, &upload_id2, &creds2)
+                                    .then(move |_| Err(e))
+                            }),
+                    )
+                }),
+        )
+    }
+
+    /// Upload `content`, of `content_length` bytes, as the parts of the
+    /// multipart upload `upload_id`, returning their ETags in order. Each part
+    /// is read on `pool`.
+    fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        content: Box<dyn ReadSeek>,
+        content_length: u64,
+        creds: &AwsCredentials,
+        pool: &CpuPool,
+    ) -> SFuture<Vec<String>> {
+        let part_size = self.part_size;
+        let parts = (content_length + part_size - 1) / part_size;
+        let content = Arc::new(Mutex::new(content));
+        let pool = pool.clone();
+        let bucket = self.clone();
+        let key = key.to_owned();
+        let upload_id = upload_id.to_owned();
+        let creds = creds.clone();
+        Box::new(
+            stream::iter_ok(1..=parts as u32)
+                .map(move |number| {
+                    let content = content.clone();
+                    let offset = u64::from(number - 1) * part_size;
+                    let part = pool.spawn_fn(move || {
+                        let mut content = content.lock().unwrap();
+                        content.seek(SeekFrom::Start(offset))?;
+                        read_part(&mut content, part_size)
+                    });
+                    let (bucket, key, upload_id, creds) = (
+                        bucket.clone(),
+                        key.clone(),
+                        upload_id.clone(),
+                        creds.clone(),
+                    );
+                    part.and_then(move |part| {
+                        bucket.upload_part(&key, &upload_id, number, part.into(), &creds)
+                    })
+                })
+                .buffered(MAX_CONCURRENT_TRANSFERS)
+                .collect(),
+        )
+    }
+
+    /// Upload part `number` of the multipart upload `upload_id`, retrying on
+    /// failure, and return its ETag.
+    fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: u32,
+        part: Bytes,
+        creds: &AwsCredentials,
+    ) -> SFuture<String> {
+        let bucket = self.clone();
+        let key = key.to_owned();
+        let upload_id = upload_id.to_owned();
+        let creds = creds.clone();
+        Box::new(future::loop_fn(1, move |attempt| {
+            let number = number.to_string();
+            let query = [
+                ("partNumber", number.as_str()),
+                ("uploadId", upload_id.as_str()),
+            ];
+            let mut request = bucket.request(Method::PUT, &key, &query, &[], Some(&creds));
+            debug!("PUT {} (attempt {})", request.url(), attempt);
+            request
+                .headers_mut()
+                .set(header::ContentLength(part.len() as u64));
+            *request.body_mut() = Some(Body::from(part.clone()).into());
+            bucket
+                .client
+                .execute(request)
+                .map_err(Error::from)
+                .and_then(check_status)
+                .and_then(|res| -> Result<String> {
+                    let etag = res
+                        .headers()
+                        .get("ETag")
+                        .and_then(|value| value.to_str().ok())
+                        .ok_or("No ETag in UploadPart response")?;
+                    Ok(etag.to_owned())
+                })
+                .then(move |result| -> Result<Loop<String, u32>> {
+                    match result {
+                        Ok(etag) => Ok(Loop::Break(etag)),
+                        Err(ref e) if attempt < PART_ATTEMPTS => {
+                            warn!("Failed to upload part, retrying: {}", e);
+                            Ok(Loop::Continue(attempt + 1))
+                        }
+                        Err(e) => Err(e),
+                    }
+                })
+        }))
+    }
+
+    /// Assemble the parts with ETags `etags` into the object at `key`.
+    fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        etags: &[String],
         creds: &AwsCredentials,
-    ) -> String {
-        let string = format!(
-            "{verb}\n{md5}\n{ty}\n{date}\n{headers}{resource}",
-            verb = verb,
-            md5 = md5,
-            ty = content_type,
-            date = date,
-            headers = headers,
-            resource = format!("/{}/{}", self.name, path)
+    ) -> SFuture<()> {
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (i, etag) in etags.iter().enumerate() {
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                i + 1,
+                etag
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let query = [("uploadId", upload_id)];
+        let mut request = self.request(Method::POST, key, &query, &[], Some(creds));
+        debug!("POST {}", request.url());
+        request
+            .headers_mut()
+            .set(header::ContentLength(body.len() as u64));
+        *request.body_mut() = Some(Body::from(body).into());
+        Box::new(
+            self.client
+                .execute(request)
+                .map_err(Error::from)
+                .and_then(check_status)
+                .and_then(read_body)
+                .and_then(|body| -> Result<()> {
+                    // S3 may report failures in the body of a successful
+                    // response.
+                    if let Some(code) = xml_element(&body, "Code") {
+                        bail!("CompleteMultipartUpload failed: {}", code);
+                    }
+                    trace!("PUT succeeded");
+                    Ok(())
+                })
+                .chain_err(|| "failed to complete multipart upload"),
+        )
+    }
+
+    /// Abort the multipart upload `upload_id`, so that S3 discards its parts.
+    fn abort_multipart(&self, key: &str, upload_id: &str, creds: &AwsCredentials) -> SFuture<()> {
+        let query = [("uploadId", upload_id)];
+        let request = self.request(Method::DELETE, key, &query, &[], Some(creds));
+        debug!("DELETE {}", request.url());
+        Box::new(
+            self.client
+                .execute(request)
+                .map_err(Error::from)
+                .and_then(check_status)
+                .map(|_| ())
+                .map_err(|e| {
+                    warn!("Failed to abort multipart upload: {}", e);
+                    e
+                }),
+        )
+    }
+
+    /// List the objects whose keys start with `prefix`, following the
+    /// continuation tokens of truncated listings.
+    pub fn list(&self, prefix: &str, creds: Option<&AwsCredentials>) -> SFuture<Vec<ObjectInfo>> {
+        let bucket = self.clone();
+        let prefix = prefix.to_owned();
+        let creds = creds.cloned();
+        Box::new(future::loop_fn(
+            (vec![], None),
+            move |(mut objects, token): (Vec<ObjectInfo>, Option<String>)| {
+                bucket
+                    .list_page(&prefix, token.as_ref().map(String::as_str), creds.as_ref())
+                    .map(move |(page, token)| {
+                        objects.extend(page);
+                        match token {
+                            Some(token) => Loop::Continue((objects, Some(token))),
+                            None => Loop::Break(objects),
+                        }
+                    })
+            },
+        ))
+    }
+
+    /// List a page of the objects whose keys start with `prefix`, starting
+    /// from where the listing that returned `token` left off.
+    fn list_page(
+        &self,
+        prefix: &str,
+        token: Option<&str>,
+        creds: Option<&AwsCredentials>,
+    ) -> SFuture<(Vec<ObjectInfo>, Option<String>)> {
+        let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+        if let Some(token) = token {
+            query.push(("continuation-token", token));
+        }
+        let request = self.request(Method::GET, "", &query, &[], creds);
+        debug!("GET {}", request.url());
+        Box::new(
+            self.client
+                .execute(request)
+                .map_err(Error::from)
+                .and_then(check_status)
+                .and_then(read_body)
+                .and_then(|body| parse_list_objects(&body))
+                .chain_err(|| "failed to list objects"),
+        )
+    }
+
+    /// Look up the size and modification time of the object at `key`, if
+    /// there is one.
+    pub fn head(&self, key: &str, creds: Option<&AwsCredentials>) -> SFuture<Option<ObjectInfo>> {
+        let request = self.request(Method::HEAD, key, &[], &[], creds);
+        debug!("HEAD {}", request.url());
+        let key = key.to_owned();
+        Box::new(
+            self.client
+                .execute(request)
+                .map_err(Error::from)
+                .and_then(move |res| -> Result<Option<ObjectInfo>> {
+                    if res.status() == StatusCode::NOT_FOUND {
+                        return Ok(None);
+                    }
+                    let res = check_status(res)?;
+                    let size = res
+                        .headers()
+                        .get_hyperx::<header::ContentLength>()
+                        .map(|header::ContentLength(len)| len)
+                        .ok_or("HEAD response has no Content-Length")?;
+                    let last_modified = res
+                        .headers()
+                        .get("Last-Modified")
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+                        .and_then(system_time);
+                    Ok(Some(ObjectInfo {
+                        key,
+                        size,
+                        last_modified,
+                    }))
+                })
+                .chain_err(|| "failed to look up object"),
+        )
+    }
+
+    /// Delete the object at `key`. Deleting an object that doesn't exist
+    /// succeeds.
+    pub fn delete(&self, key: &str, creds: &AwsCredentials) -> SFuture<()> {
+        let request = self.request(Method::DELETE, key, &[], &[], Some(creds));
+        debug!("DELETE {}", request.url());
+        Box::new(
+            self.client
+                .execute(request)
+                .map_err(Error::from)
+                .and_then(check_status)
+                .map(|_| ())
+                .chain_err(|| "failed to delete object"),
+        )
+    }
+
+    /// Create a request for `key`, signed with `creds` if there are any.
+    /// `query` lists the parameters of the request, and `headers` the extra
+    /// headers to sign and send, with lowercase names.
+    fn request(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        creds: Option<&AwsCredentials>,
+    ) -> Request {
+        let key = uri_encode(key, false);
+        let mut query: Vec<_> = query
+            .iter()
+            .map(|&(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+        let mut url = format!("{}{}", self.base_url, key);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+        let mut request = Request::new(method.clone(), url.parse().unwrap());
+        // Header values from settings are checked by `PutOptions::validate`.
+        for &(header, value) in headers {
+            request.headers_mut().insert(
+                header,
+                HeaderValue::from_str(value)
+                    .unwrap_or_else(|_| panic!("Invalid `{}` header", header)),
+            );
+        }
+        let creds = match creds {
+            Some(creds) => creds,
+            // request is fine as-is
+            None => return request,
+        };
+
+        let date = offset::Utc::now();
+        let date_header = amz_date(&date);
+        let mut amz_headers = vec![
+            ("x-amz-content-sha256", UNSIGNED_PAYLOAD),
+            ("x-amz-date", date_header.as_str()),
+        ];
+        if let Some(token) = creds.token() {
+            amz_headers.push(("x-amz-security-token", token.as_str()));
+        }
+        let mut signed_headers = vec![("host", self.host.as_str())];
+        signed_headers.extend(headers.iter().cloned());
+        signed_headers.extend(amz_headers.iter().cloned());
+        let auth = sigv4::authorization(
+            method.as_str(),
+            &format!("{}{}", self.base_path, key),
+            &query,
+            &signed_headers,
+            UNSIGNED_PAYLOAD,
+            creds,
+            &sigv4::Scope {
+                region: &self.region,
+                service: "s3",
+                date: &date,
+            },
         );
-        let signature = signature(&string, creds.aws_secret_access_key());
-        format!("AWS {}:{}", creds.aws_access_key_id(), signature)
+        for (header, value) in amz_headers {
+            request.headers_mut().insert(
+                header,
+                HeaderValue::from_str(value)
+                    .unwrap_or_else(|_| panic!("Invalid `{}` header", header)),
+            );
+        }
+        request.headers_mut().insert(
+            "Authorization",
+            HeaderValue::from_str(&auth).expect("Invalid authentication"),
+        );
+        request
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use chrono::TimeZone;
+    use std::collections::HashMap;
+    use std::io::{self, BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use tokio::runtime::current_thread::Runtime;
+
+    const UPLOAD_ID: &str = "upload+1";
+
+    /// The state of a stand-in S3 server.
+    #[derive(Default)]
+    struct State {
+        objects: HashMap<String, Vec<u8>>,
+        /// The parts of the multipart upload in progress.
+        parts: HashMap<u32, Vec<u8>>,
+        /// How many more upload attempts of each part fail.
+        failing_parts: HashMap<u32, u32>,
+        /// Whether a multipart upload was aborted.
+        aborted: bool,
+        /// The ranges of every ranged `GET`.
+        ranges: Vec<String>,
+        /// Whether `GET`s return the whole object whatever their range.
+        ignore_ranges: bool,
+        /// What the object is replaced with once its first range is read.
+        replacement: Option<Vec<u8>>,
+        /// The region signed requests must be signed for.
+        region: String,
+    }
+
+    type SharedState = Arc<Mutex<State>>;
+
+    /// Return the `ETag` of an object with the given contents.
+    fn etag(object: &[u8]) -> String {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        object.hash(&mut hasher);
+        format!("\"{:x}\"", hasher.finish())
+    }
+
+    /// Handle a request, returning its status, extra headers and body.
+    fn handle(
+        state: &mut State,
+        method: &str,
+        path: &str,
+        query: &str,
+        range: Option<String>,
+        if_match: Option<String>,
+        body: Vec<u8>,
+    ) -> (&'static str, String, Vec<u8>) {
+        let upload_id = format!("uploadId={}", uri_encode(UPLOAD_ID, true));
+        match (method, query) {
+            ("POST", "uploads=") => {
+                let body = format!(
+                    "<InitiateMultipartUploadResult><UploadId>{}</UploadId>\
+                     </InitiateMultipartUploadResult>",
+                    UPLOAD_ID
+                );
+                ("200 OK", String::new(), body.into_bytes())
+            }
+            ("PUT", q) if q.starts_with("partNumber=") => {
+                let mut params = q.split('&');
+                let number: u32 = params.next().unwrap()[11..].parse().unwrap();
+                assert_eq!(params.next(), Some(upload_id.as_str()));
+                let failures = state.failing_parts.entry(number).or_insert(0);
+                if *failures > 0 {
+                    *failures -= 1;
+                    return ("500 Internal Server Error", String::new(), vec![]);
+                }
+                state.parts.insert(number, body);
+                let etag = format!("ETag: \"etag{}\"\r\n", number);
+                ("200 OK", etag, vec![])
+            }
+            ("POST", q) if q == upload_id => {
+                let body = String::from_utf8(body).unwrap();
+                let mut object = vec![];
+                for part in body.split("<Part>").skip(1) {
+                    let number: u32 = xml_element(part.as_bytes(), "PartNumber")
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    let etag = xml_element(part.as_bytes(), "ETag").unwrap();
+                    assert_eq!(etag, format!("\"etag{}\"", number));
+                    object.extend_from_slice(&state.parts[&number]);
+                }
+                state.parts.clear();
+                state.objects.insert(path.to_owned(), object);
+                let body = "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>";
+                ("200 OK", String::new(), body.as_bytes().to_owned())
+            }
+            ("DELETE", q) if q == upload_id => {
+                state.parts.clear();
+                state.aborted = true;
+                ("204 No Content", String::new(), vec![])
+            }
+            ("PUT", "") => {
+                state.objects.insert(path.to_owned(), body);
+                ("200 OK", String::new(), vec![])
+            }
+            ("GET", q) if path == "/" && q.contains("list-type=2") => {
+                let params: HashMap<_, _> = q
+                    .split('&')
+                    .map(|param| {
+                        let mut param = param.splitn(2, '=');
+                        let name = param.next().unwrap().to_owned();
+                        (name, percent_decode(param.next().unwrap_or("")))
+                    })
+                    .collect();
+                let mut keys: Vec<_> = state
+                    .objects
+                    .keys()
+                    .map(|path| path[1..].to_owned())
+                    .filter(|key| key.starts_with(&params["prefix"]))
+                    .filter(|key| match params.get("continuation-token") {
+                        Some(token) => key > token,
+                        None => true,
+                    })
+                    .collect();
+                keys.sort();
+                // Return two objects at a time, to exercise continuations.
+                let truncated = keys.len() > 2;
+                keys.truncate(2);
+                let mut body = String::from("<ListBucketResult>");
+                for key in &keys {
+                    body.push_str(&format!(
+                        "<Contents><Key>{}</Key><LastModified>2020-01-01T00:00:00.000Z\
+                         </LastModified><Size>{}</Size></Contents>",
+                        key,
+                        state.objects[&format!("/{}", key)].len()
+                    ));
+                }
+                body.push_str(&format!("<IsTruncated>{}</IsTruncated>", truncated));
+                if truncated {
+                    body.push_str(&format!(
+                        "<NextContinuationToken>{}</NextContinuationToken>",
+                        keys[1]
+                    ));
+                }
+                body.push_str("</ListBucketResult>");
+                ("200 OK", String::new(), body.into_bytes())
+            }
+            // The body is only used for the `Content-Length`.
+            ("HEAD", "") => match state.objects.get(path) {
+                Some(object) => {
+                    let headers = "Last-Modified: Wed, 01 Jan 2020 00:00:00 GMT\r\n".to_owned();
+                    ("200 OK", headers, object.clone())
+                }
+                None => ("404 Not Found", String::new(), vec![]),
+            },
+            ("DELETE", "") => {
+                state.objects.remove(path);
+                ("204 No Content", String::new(), vec![])
+            }
+            ("GET", "") => {
+                let object = match state.objects.get(path) {
+                    Some(object) => object.clone(),
+                    None => return ("404 Not Found", String::new(), vec![]),
+                };
+                let etag = etag(&object);
+                if if_match.map_or(false, |if_match| if_match != etag) {
+                    return ("412 Precondition Failed", String::new(), vec![]);
+                }
+                let etag_header = format!("ETag: {}\r\n", etag);
+                let range = match range {
+                    Some(ref range) if !state.ignore_ranges => range.clone(),
+                    _ => return ("200 OK", etag_header, object),
+                };
+                state.ranges.push(range.clone());
+                let mut bounds = range["bytes=".len()..].split('-');
+                let start: usize = bounds.next().unwrap().parse().unwrap();
+                let end: usize = bounds.next().unwrap().parse().unwrap();
+                let end = cmp::min(end, object.len() - 1);
+                if start == 0 {
+                    if let Some(replacement) = state.replacement.take() {
+                        state.objects.insert(path.to_owned(), replacement);
+                    }
+                }
+                let headers = format!(
+                    "{}Content-Range: bytes {}-{}/{}\r\n",
+                    etag_header,
+                    start,
+                    end,
+                    object.len()
+                );
+                (
+                    "206 Partial Content",
+                    headers,
+                    object[start..end + 1].to_owned(),
+                )
+            }
+            _ => ("405 Method Not Allowed", String::new(), vec![]),
+        }
+    }
+
+    /// Check the signature of a request, as S3 would for a bucket in
+    /// `region`, if it is signed. Requests must be signed with `creds()`.
+    fn signature_matches(
+        region: &str,
+        method: &str,
+        path: &str,
+        query: &str,
+        headers: &HashMap<String, String>,
+    ) -> bool {
+        let auth = match headers.get("authorization") {
+            Some(auth) => auth,
+            None => return true,
+        };
+        let signed_headers = match auth.split(", ").find(|p| p.starts_with("SignedHeaders=")) {
+            Some(signed_headers) => &signed_headers["SignedHeaders=".len()..],
+            None => return false,
+        };
+        let signed_headers: Vec<_> = signed_headers
+            .split(';')
+            .map(|name| (name, headers.get(name).map_or("", String::as_str)))
+            .collect();
+        let date = match headers.get("x-amz-date") {
+            Some(date) => match offset::Utc.datetime_from_str(date, "%Y%m%dT%H%M%SZ") {
+                Ok(date) => date,
+                Err(_) => return false,
+            },
+            None => return false,
+        };
+        let payload_hash = match headers.get("x-amz-content-sha256") {
+            Some(payload_hash) => payload_hash,
+            None => return false,
+        };
+        let expected = sigv4::authorization(
+            method,
+            path,
+            query,
+            &signed_headers,
+            payload_hash,
+            &creds(),
+            &sigv4::Scope {
+                region,
+                service: "s3",
+                date: &date,
+            },
+        );
+        *auth == expected
+    }
+
+    /// Serve a single keep-alive connection.
+    fn serve_connection(stream: TcpStream, state: SharedState) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        loop {
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().unwrap().to_owned();
+            let target = parts.next().unwrap().to_owned();
+            let mut target = target.splitn(2, '?');
+            let path = target.next().unwrap().to_owned();
+            let query = target.next().unwrap_or("").to_owned();
+            let mut request_headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let mut header = line.splitn(2, ':');
+                let name = header.next().unwrap().to_ascii_lowercase();
+                let value = header.next().unwrap_or("").trim().to_owned();
+                request_headers.insert(name, value);
+            }
+            let content_length = request_headers
+                .get("content-length")
+                .map_or(0, |length| length.parse().unwrap());
+            let range = request_headers.get("range").cloned();
+            let if_match = request_headers.get("if-match").cloned();
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let mut state = state.lock().unwrap();
+            let (status, headers, response) =
+                if signature_matches(&state.region, &method, &path, &query, &request_headers) {
+                    handle(&mut state, &method, &path, &query, range, if_match, body)
+                } else {
+                    ("403 Forbidden", String::new(), vec![])
+                };
+            drop(state);
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\n{}Content-Length: {}\r\n\r\n",
+                status,
+                headers,
+                response.len()
+            )
+            .unwrap();
+            if method != "HEAD" {
+                stream.write_all(&response).unwrap();
+            }
+        }
+    }
+
+    /// Decode a percent-encoded query parameter value.
+    fn percent_decode(value: &str) -> String {
+        let mut decoded = vec![];
+        let mut bytes = value.bytes();
+        while let Some(b) = bytes.next() {
+            if b == b'%' {
+                let hex: String = bytes.by_ref().take(2).map(char::from).collect();
+                decoded.push(u8::from_str_radix(&hex, 16).unwrap());
+            } else {
+                decoded.push(b);
+            }
+        }
+        String::from_utf8(decoded).unwrap()
+    }
+
+    fn start_server() -> (String, SharedState) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(Mutex::new(State {
+            region: "us-east-1".to_owned(),
+            ..State::default()
+        }));
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let state = server_state.clone();
+                thread::spawn(move || serve_connection(stream.unwrap(), state));
+            }
+        });
+        (endpoint, state)
+    }
+
+    fn test_bucket(endpoint: &str) -> Bucket {
+        let mut bucket = Bucket::new("bucket", endpoint, Ssl::No, "us-east-1", None).unwrap();
+        bucket.multipart_threshold = 10;
+        bucket.part_size = 4;
+        bucket.range_size = 4;
+        bucket
+    }
+
+    fn creds() -> AwsCredentials {
+        AwsCredentials::new("key", "secret", None, chrono::Utc::now())
+    }
 
     #[test]
-    fn test_signature() {
+    fn test_parse_responses() {
         assert_eq!(
-            signature("/foo/bar\nbar", "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY"),
-            "mwbstmHPMEJjTe2ksXi5H5f0c8U="
+            xml_element(b"<R><UploadId>abc</UploadId></R>", "UploadId"),
+            Some("abc".to_owned())
         );
+        assert_eq!(xml_element(b"<R></R>", "UploadId"), None);
+        assert_eq!(content_range("bytes 1024-2047/4096"), Some((1024, 4096)));
+        assert_eq!(content_range("bytes */*"), None);
+        let (objects, token) = parse_list_objects(
+            b"<ListBucketResult><IsTruncated>true</IsTruncated>\
+              <Contents><Key>a&amp;b</Key><LastModified>2020-01-01T00:00:00.000Z</LastModified>\
+              <Size>12</Size></Contents><Contents><Key>c</Key><Size>0</Size></Contents>\
+              <NextContinuationToken>t&lt;1</NextContinuationToken></ListBucketResult>",
+        )
+        .unwrap();
+        assert_eq!(
+            objects,
+            vec![
+                ObjectInfo {
+                    key: "a&b".to_owned(),
+                    size: 12,
+                    last_modified: Some(UNIX_EPOCH + Duration::from_secs(1_577_836_800)),
+                },
+                ObjectInfo {
+                    key: "c".to_owned(),
+                    size: 0,
+                    last_modified: None,
+                },
+            ]
+        );
+        assert_eq!(token, Some("t<1".to_owned()));
+        assert!(parse_list_objects(b"<Error></Error>").is_err());
+        let certs = split_pem(
+            "# A comment
+-----BEGIN CERTIFICATE-----
+AAAA
+-----END CERTIFICATE-----
+-----BEGIN CERTIFICATE-----
+BBBB
+-----END CERTIFICATE-----
+",
+        );
+        assert_eq!(
+            certs,
+            vec![
+                "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n",
+                "-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n",
+            ]
+        );
+    }
 
+    #[test]
+    fn test_put_options() {
+        let options = PutOptions {
+            server_side_encryption: Some("aws:kms".to_owned()),
+            sse_kms_key_id: Some("alias/builds".to_owned()),
+            storage_class: Some("STANDARD_IA".to_owned()),
+            tags: vec![
+                ("cost-center".to_owned(), "1234".to_owned()),
+                ("retention".to_owned(), "30 days".to_owned()),
+            ],
+        };
+        assert_eq!(
+            options.headers(),
+            vec![
+                ("content-type", "application/octet-stream".to_owned()),
+                ("x-amz-server-side-encryption", "aws:kms".to_owned()),
+                (
+                    "x-amz-server-side-encryption-aws-kms-key-id",
+                    "alias/builds".to_owned()
+                ),
+                ("x-amz-storage-class", "STANDARD_IA".to_owned()),
+                (
+                    "x-amz-tagging",
+                    "cost-center=1234&retention=30%20days".to_owned()
+                ),
+            ]
+        );
         assert_eq!(
-            signature("/bar/foo\nbaz", "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY"),
-            "F9gZMso3+P+QTEyRKQ6qhZ1YM6o="
+            PutOptions::default().headers(),
+            vec![("content-type", "application/octet-stream".to_owned())]
         );
+        assert!(options.validate().is_ok());
+        let options = PutOptions {
+            storage_class: Some("STANDARD\nX-Injected: 1".to_owned()),
+            ..PutOptions::default()
+        };
+        assert!(options.validate().is_err());
+        let options = PutOptions {
+            sse_kms_key_id: Some("key\u{7f}".to_owned()),
+            ..PutOptions::default()
+        };
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn test_multipart_put_and_ranged_get() {
+        let (endpoint, state) = start_server();
+        let mut runtime = Runtime::new().unwrap();
+        let pool = CpuPool::new(1);
+        let bucket = test_bucket(&endpoint);
+        // The first attempt at uploading the second part fails.
+        state.lock().unwrap().failing_parts.insert(2, 1);
+        let data: Vec<u8> = (0..18).collect();
+
+        let content = Box::new(io::Cursor::new(data.clone()));
+        runtime
+            .block_on(future::lazy(|| {
+                bucket.put("a/b/abcd", content, &PutOptions::default(), &creds(), &pool)
+            }))
+            .unwrap();
+        assert_eq!(state.lock().unwrap().objects.get("/a/b/abcd"), Some(&data));
+
+        let mut file = runtime
+            .block_on(future::lazy(|| bucket.get("a/b/abcd", None, &pool)))
+            .unwrap();
+        let mut read = vec![];
+        file.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(
+            state.lock().unwrap().ranges,
+            vec![
+                "bytes=0-3",
+                "bytes=4-7",
+                "bytes=8-11",
+                "bytes=12-15",
+                "bytes=16-17"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_ignoring_ranges() {
+        let (endpoint, state) = start_server();
+        let mut runtime = Runtime::new().unwrap();
+        let pool = CpuPool::new(1);
+        let bucket = Bucket::new("bucket", &endpoint, Ssl::No, "us-east-1", None).unwrap();
+        let data: Vec<u8> = (0..RANGE_SIZE + 10).map(|i| i as u8).collect();
+        {
+            let mut state = state.lock().unwrap();
+            state.objects.insert("/abcd".to_owned(), data.clone());
+            state.ignore_ranges = true;
+        }
+
+        let mut file = runtime
+            .block_on(future::lazy(|| bucket.get("abcd", None, &pool)))
+            .unwrap();
+        let mut read = vec![];
+        file.read_to_end(&mut read).unwrap();
+        assert!(read == data);
+    }
+
+    #[test]
+    fn test_get_of_replaced_object() {
+        let (endpoint, state) = start_server();
+        let mut runtime = Runtime::new().unwrap();
+        let pool = CpuPool::new(1);
+        let bucket = test_bucket(&endpoint);
+        {
+            let mut state = state.lock().unwrap();
+            state.objects.insert("/abcd".to_owned(), vec![1; 10]);
+            state.replacement = Some(vec![2; 10]);
+        }
+
+        assert!(runtime
+            .block_on(future::lazy(|| bucket.get("abcd", None, &pool)))
+            .is_err());
+        assert_eq!(state.lock().unwrap().ranges, vec!["bytes=0-3"]);
+    }
+
+    #[test]
+    fn test_small_put_and_get() {
+        let (endpoint, state) = start_server();
+        let mut runtime = Runtime::new().unwrap();
+        let pool = CpuPool::new(1);
+        let bucket = test_bucket(&endpoint);
+        let data = b"abc".to_vec();
+
+        let content = Box::new(io::Cursor::new(data.clone()));
+        runtime
+            .block_on(future::lazy(|| {
+                bucket.put("abcd", content, &PutOptions::default(), &creds(), &pool)
+            }))
+            .unwrap();
+        assert_eq!(state.lock().unwrap().objects.get("/abcd"), Some(&data));
+
+        let mut file = runtime
+            .block_on(future::lazy(|| bucket.get("abcd", None, &pool)))
+            .unwrap();
+        let mut read = vec![];
+        file.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        assert!(runtime
+            .block_on(future::lazy(|| bucket.get("missing", None, &pool)))
+            .is_err());
+    }
+
+    #[test]
+    fn test_multipart_put_failure() {
+        let (endpoint, state) = start_server();
+        let mut runtime = Runtime::new().unwrap();
+        let pool = CpuPool::new(1);
+        let bucket = test_bucket(&endpoint);
+        state.lock().unwrap().failing_parts.insert(1, PART_ATTEMPTS);
+
+        let content = Box::new(io::Cursor::new(vec![0; 18]));
+        assert!(runtime
+            .block_on(future::lazy(|| bucket.put(
+                "abcd",
+                content,
+                &PutOptions::default(),
+                &creds(),
+                &pool
+            )))
+            .is_err());
+        let state = state.lock().unwrap();
+        assert!(state.aborted);
+        assert!(state.objects.is_empty());
+    }
+
+    #[test]
+    fn test_list_head_and_delete() {
+        let (endpoint, state) = start_server();
+        let mut runtime = Runtime::new().unwrap();
+        let bucket = test_bucket(&endpoint);
+        {
+            let mut state = state.lock().unwrap();
+            for key in &["/p/a/b/c/abc1", "/p/a/b/c/abc2", "/p/d/e/f/def", "/q/abc"] {
+                state.objects.insert(key.to_string(), b"data".to_vec());
+            }
+        }
+
+        let objects = runtime
+            .block_on(future::lazy(|| bucket.list("p/", None)))
+            .unwrap();
+        let keys: Vec<_> = objects.iter().map(|o| o.key.as_str()).collect();
+        assert_eq!(keys, vec!["p/a/b/c/abc1", "p/a/b/c/abc2", "p/d/e/f/def"]);
+        assert!(objects.iter().all(|o| o.size == 4));
+
+        let object = runtime
+            .block_on(future::lazy(|| bucket.head("q/abc", None)))
+            .unwrap();
+        assert_eq!(
+            object,
+            Some(ObjectInfo {
+                key: "q/abc".to_owned(),
+                size: 4,
+                last_modified: Some(UNIX_EPOCH + Duration::from_secs(1_577_836_800)),
+            })
+        );
+
+        runtime
+            .block_on(future::lazy(|| bucket.delete("q/abc", &creds())))
+            .unwrap();
+        assert!(!state.lock().unwrap().objects.contains_key("/q/abc"));
+        assert_eq!(
+            runtime
+                .block_on(future::lazy(|| bucket.head("q/abc", None)))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_requests_signed_for_region() {
+        let (endpoint, state) = start_server();
+        let mut runtime = Runtime::new().unwrap();
+        let pool = CpuPool::new(1);
+        state.lock().unwrap().region = "eu-west-1".to_owned();
+        let bucket = Bucket::new("bucket", &endpoint, Ssl::No, "eu-west-1", None).unwrap();
+
+        let content = Box::new(io::Cursor::new(b"abc".to_vec()));
+        runtime
+            .block_on(future::lazy(|| {
+                bucket.put("a b+c", content, &PutOptions::default(), &creds(), &pool)
+            }))
+            .unwrap();
+        let object = runtime
+            .block_on(future::lazy(|| bucket.head("a b+c", Some(&creds()))))
+            .unwrap();
+        assert_eq!(object.map(|o| o.size), Some(3));
+
+        // S3 refuses requests signed for another region.
+        let bucket = test_bucket(&endpoint);
+        assert!(runtime
+            .block_on(future::lazy(|| bucket.head("a b+c", Some(&creds()))))
+            .is_err());
+        assert!(runtime
+            .block_on(future::lazy(|| bucket.delete("a b+c", &creds())))
+            .is_err());
     }
 }
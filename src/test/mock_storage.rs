@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use crate::cache::{Cache, CacheWrite, Storage};
+use crate::config::CacheRWMode;
 use crate::errors::*;
 use futures::future;
 use std::cell::RefCell;
@@ -52,6 +53,9 @@ impl Storage for MockStorage {
     fn location(&self) -> String {
         "Mock Storage".to_string()
     }
+    fn rw_mode(&self) -> CacheRWMode {
+        CacheRWMode::ReadWrite
+    }
     fn current_size(&self) -> SFuture<Option<u64>> {
         Box::new(future::ok(None))
     }
@@ -14,14 +14,19 @@
 
 use crate::cache::disk::DiskCache;
 use crate::client::connect_to_server;
-use crate::commands::{do_compile, request_shutdown, request_stats};
+use crate::commands::{
+    do_compile, request_list_cache, request_prune_cache, request_remove_cache_entry,
+    request_shutdown, request_stat_cache_entry, request_stats,
+};
+use crate::config::CacheRWMode;
 use crate::jobserver::Client;
 use crate::mock_command::*;
 use crate::server::{DistClientContainer, SccacheServer, ServerMessage};
 use crate::test::utils::*;
+use filetime::{set_file_times, FileTime};
 use futures::sync::oneshot::{self, Sender};
 use futures_cpupool::CpuPool;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{Cursor, Write};
 #[cfg(not(target_os = "macos"))]
 use std::net::TcpListener;
@@ -77,11 +82,26 @@ where
     let handle = thread::spawn(move || {
         let pool = CpuPool::new(1);
         let dist_client = DistClientContainer::new_disabled();
-        let storage = Arc::new(DiskCache::new(&cache_dir, cache_size, &pool));
+        let storage = Arc::new(DiskCache::new(
+            &cache_dir,
+            cache_size,
+            CacheRWMode::ReadWrite,
+            &pool,
+        ));
 
         let runtime = Runtime::new().unwrap();
         let client = unsafe { Client::new() };
-        let srv = SccacheServer::new(0, pool, runtime, client, dist_client, storage).unwrap();
+        let srv = SccacheServer::new(
+            0,
+            pool,
+            runtime,
+            client,
+            dist_client,
+            storage,
+            Default::default(),
+            Default::default(),
+        )
+        .unwrap();
         let mut srv: SccacheServer<Arc<Mutex<MockCommandCreator>>> = srv;
         assert!(srv.port() > 0);
         if let Some(options) = options {
@@ -161,6 +181,48 @@ fn test_server_stats() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_cache_maintenance() {
+    let f = TestFixture::new();
+    let cache_dir = f.tempdir.path().join("cache");
+    let entry_dir = cache_dir.join("a").join("b");
+    fs::create_dir_all(&entry_dir).unwrap();
+    for key in &["abc1", "abc2", "abc3"] {
+        File::create(entry_dir.join(key))
+            .unwrap()
+            .write_all(b"data")
+            .unwrap();
+    }
+    // Make one entry look like it hasn't been used for a long time.
+    let old = FileTime::from_seconds_since_1970(1000, 0);
+    set_file_times(entry_dir.join("abc1"), old, old).unwrap();
+    let (port, sender, _storage, child) = run_server_thread(&cache_dir, None);
+
+    let mut entries = request_list_cache(connect_to_server(port).unwrap()).unwrap();
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    let keys: Vec<_> = entries.iter().map(|e| e.key.as_str()).collect();
+    assert_eq!(keys, vec!["abc1", "abc2", "abc3"]);
+    assert!(entries.iter().all(|e| e.size == 4));
+
+    let stats = request_prune_cache(
+        connect_to_server(port).unwrap(),
+        Duration::from_secs(24 * 60 * 60),
+    )
+    .unwrap();
+    assert_eq!((stats.entries, stats.bytes), (1, 4));
+    assert!(!entry_dir.join("abc1").exists());
+
+    request_remove_cache_entry(connect_to_server(port).unwrap(), "abc2").unwrap();
+    assert!(!entry_dir.join("abc2").exists());
+    let entry = request_stat_cache_entry(connect_to_server(port).unwrap(), "abc2").unwrap();
+    assert_eq!(entry, None);
+    let entry = request_stat_cache_entry(connect_to_server(port).unwrap(), "abc3").unwrap();
+    assert_eq!(entry.map(|e| e.size), Some(4));
+
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_unsupported_compiler() {
     let f = TestFixture::new();
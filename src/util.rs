@@ -18,6 +18,7 @@ use futures::{future, Future};
 use futures_cpupool::CpuPool;
 use ring::digest::{Context, SHA512};
 use serde::Serialize;
+use std::borrow::Cow;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::hash::Hasher;
@@ -78,6 +79,66 @@ impl Digest {
     }
 }
 
+/// A reader that computes the digest of everything read through it.
+pub struct DigestReader<R> {
+    digest: Digest,
+    inner: R,
+}
+
+impl<R: Read> DigestReader<R> {
+    pub fn new(inner: R) -> DigestReader<R> {
+        DigestReader {
+            digest: Digest::new(),
+            inner,
+        }
+    }
+
+    /// Return the digest of everything read so far.
+    pub fn finish(self) -> String {
+        self.digest.finish()
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// A writer that computes the digest of everything written through it.
+pub struct DigestWriter<W> {
+    digest: Digest,
+    inner: W,
+}
+
+impl<W: Write> DigestWriter<W> {
+    pub fn new(inner: W) -> DigestWriter<W> {
+        DigestWriter {
+            digest: Digest::new(),
+            inner,
+        }
+    }
+
+    /// Return the digest of everything written so far, and the inner writer.
+    pub fn finish(self) -> (String, W) {
+        (self.digest.finish(), self.inner)
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 pub fn hex(bytes: &[u8]) -> String {
     let mut s = String::with_capacity(bytes.len() * 2);
     for &byte in bytes {
@@ -326,6 +387,37 @@ impl<'a> Hasher for HashToDigest<'a> {
     }
 }
 
+/// Replace the occurrences of `path` in `bytes` that aren't followed by more
+/// of a file name with `replacement`.
+pub fn replace_path<'a>(bytes: &'a [u8], path: &[u8], replacement: &[u8]) -> Cow<'a, [u8]> {
+    let mut replaced: Option<Vec<u8>> = None;
+    let mut copied = 0;
+    let mut i = 0;
+    while !path.is_empty() && i + path.len() <= bytes.len() {
+        let end = i + path.len();
+        let boundary = match bytes.get(end) {
+            Some(&b) => !(b.is_ascii_alphanumeric() || b"_-.+~".contains(&b)),
+            None => true,
+        };
+        if boundary && &bytes[i..end] == path {
+            let replaced = replaced.get_or_insert_with(Vec::new);
+            replaced.extend_from_slice(&bytes[copied..i]);
+            replaced.extend_from_slice(replacement);
+            copied = end;
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+    match replaced {
+        Some(mut replaced) => {
+            replaced.extend_from_slice(&bytes[copied..]);
+            Cow::Owned(replaced)
+        }
+        None => Cow::Borrowed(bytes),
+    }
+}
+
 /// Turns a slice of environment var tuples into the type expected by Command::envs.
 pub fn ref_env(env: &[(OsString, OsString)]) -> impl Iterator<Item = (&OsString, &OsString)> {
     env.iter().map(|&(ref k, ref v)| (k, v))
@@ -520,7 +612,7 @@ pub fn daemonize() -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::OsStrExt;
+    use super::{replace_path, OsStrExt};
     use std::ffi::{OsStr, OsString};
 
     #[test]
@@ -549,4 +641,15 @@ mod tests {
         assert_eq!(a.split_prefix("foo2"), None);
         assert_eq!(a.split_prefix("b"), None);
     }
+
+    #[test]
+    fn test_replace_path() {
+        let replace = |s: &str| replace_path(s.as_bytes(), b"/src", b"$BASE_DIR").into_owned();
+        assert_eq!(replace("-I/src/include"), b"-I$BASE_DIR/include".to_vec());
+        assert_eq!(replace("/src"), b"$BASE_DIR".to_vec());
+        assert_eq!(replace("/src:/src/a"), b"$BASE_DIR:$BASE_DIR/a".to_vec());
+        assert_eq!(replace("/src2/include"), b"/src2/include".to_vec());
+        assert_eq!(replace("/src.old"), b"/src.old".to_vec());
+        assert_eq!(replace("-O2"), b"-O2".to_vec());
+    }
 }
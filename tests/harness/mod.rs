@@ -184,9 +184,14 @@ pub fn sccache_client_cfg(tmpdir: &Path) -> sccache::config::FileConfig {
             azure: None,
             disk: Some(disk_cache),
             gcs: None,
+            http: None,
             memcached: None,
+            reapi: None,
             redis: None,
             s3: None,
+            shared_dir: None,
+            tiered: None,
+            compression: None,
         },
         dist: sccache::config::DistConfig {
             auth: Default::default(), // dangerously_insecure